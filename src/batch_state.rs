@@ -0,0 +1,147 @@
+//! The `--input-file` ledger, `.grab-batch-state` in the download
+//! directory: one JSON line per finished URL, so a batch that was stopped
+//! part-way can be run again and skip what's done. An entry only counts
+//! while its file is still there at the recorded size.
+//!
+//! The ledger is rewritten to a temporary file and renamed over the old one
+//! after each download, so an interrupted run never leaves it half-written.
+
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+pub const LEDGER_NAME: &str = ".grab-batch-state";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Entry {
+    url: String,
+    output: String,
+    size: u64,
+    /// Unix timestamp (seconds) of when the download completed
+    timestamp: i64,
+}
+
+pub struct Ledger {
+    path: PathBuf,
+    entries: Mutex<Vec<Entry>>,
+}
+
+impl Ledger {
+    /// The ledger in `dir`. A missing file is an empty ledger, and
+    /// unparseable lines are skipped.
+    pub fn load(dir: &Path) -> Ledger {
+        let path = dir.join(LEDGER_NAME);
+        let entries = std::fs::read_to_string(&path)
+            .map(|contents| {
+                contents
+                    .lines()
+                    .filter_map(|line| serde_json::from_str(line).ok())
+                    .collect()
+            })
+            .unwrap_or_default();
+        Ledger {
+            path,
+            entries: Mutex::new(entries),
+        }
+    }
+
+    /// Where `url` was saved, if it was and the file is still complete.
+    pub fn completed(&self, url: &str) -> Option<String> {
+        let entries = self.entries.lock().unwrap();
+        let entry = entries.iter().rev().find(|entry| entry.url == url)?;
+        std::fs::metadata(&entry.output)
+            .is_ok_and(|meta| meta.is_file() && meta.len() == entry.size)
+            .then(|| entry.output.clone())
+    }
+
+    /// Records `url` as saved to `output`, replacing any earlier entry.
+    pub fn record(&self, url: &str, output: &str, size: u64) -> std::io::Result<()> {
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|entry| entry.url != url);
+        // Absolute, so a re-run from another directory finds it
+        let output = std::path::absolute(output)
+            .map(|path| path.to_string_lossy().into_owned())
+            .unwrap_or_else(|_| output.to_string());
+        entries.push(Entry {
+            url: url.to_string(),
+            output,
+            size,
+            timestamp: chrono::Utc::now().timestamp(),
+        });
+
+        let mut contents = String::new();
+        for entry in entries.iter() {
+            contents.push_str(&serde_json::to_string(entry)?);
+            contents.push('\n');
+        }
+        let tmp = self.path.with_file_name(format!("{}.tmp", LEDGER_NAME));
+        std::fs::write(&tmp, contents)?;
+        std::fs::rename(&tmp, &self.path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::scratch_dir;
+
+    #[test]
+    fn recorded_downloads_survive_a_reload() {
+        let dir = scratch_dir("ledger");
+        let output = dir.join("a.bin");
+        std::fs::write(&output, b"12345").unwrap();
+        let output = output.to_string_lossy().into_owned();
+
+        let ledger = Ledger::load(&dir);
+        assert_eq!(ledger.completed("http://host/a.bin"), None);
+        ledger.record("http://host/a.bin", &output, 5).unwrap();
+        // A later record for the same URL replaces the first
+        ledger.record("http://host/a.bin", &output, 5).unwrap();
+
+        let reloaded = Ledger::load(&dir);
+        assert_eq!(reloaded.completed("http://host/a.bin"), Some(output));
+        assert_eq!(reloaded.completed("http://host/b.bin"), None);
+        let contents = std::fs::read_to_string(dir.join(LEDGER_NAME)).unwrap();
+        assert_eq!(contents.lines().count(), 1);
+        assert!(!dir.join(format!("{}.tmp", LEDGER_NAME)).exists());
+    }
+
+    #[test]
+    fn changed_or_missing_files_are_not_completed() {
+        let dir = scratch_dir("ledger-stale");
+        let changed = dir.join("changed.bin");
+        let removed = dir.join("removed.bin");
+        std::fs::write(&changed, b"12345").unwrap();
+        std::fs::write(&removed, b"12345").unwrap();
+
+        let ledger = Ledger::load(&dir);
+        ledger
+            .record("http://host/changed", &changed.to_string_lossy(), 5)
+            .unwrap();
+        ledger
+            .record("http://host/removed", &removed.to_string_lossy(), 5)
+            .unwrap();
+        std::fs::write(&changed, b"123").unwrap();
+        std::fs::remove_file(&removed).unwrap();
+
+        assert_eq!(ledger.completed("http://host/changed"), None);
+        assert_eq!(ledger.completed("http://host/removed"), None);
+    }
+
+    #[test]
+    fn unreadable_lines_are_skipped() {
+        let dir = scratch_dir("ledger-garbage");
+        let output = dir.join("a.bin");
+        std::fs::write(&output, b"1").unwrap();
+        let entry = serde_json::json!({
+            "url": "http://host/a.bin",
+            "output": output,
+            "size": 1,
+            "timestamp": 0,
+        });
+        std::fs::write(dir.join(LEDGER_NAME), format!("not json\n{}\n", entry)).unwrap();
+
+        let ledger = Ledger::load(&dir);
+        assert!(ledger.completed("http://host/a.bin").is_some());
+    }
+}
@@ -0,0 +1,222 @@
+//! `--combine`: joins part files downloaded separately, e.g. a range each
+//! on different machines, into one file in the order given.
+//!
+//! The result is written to `<output>.part` and only moved into place once
+//! it has passed the checksum, like a download.
+
+use crate::{Checksum, ManifestEntry, hash_range, move_file, verify_checksum};
+use indicatif::ProgressBar;
+use log::info;
+use tokio::fs::{File, metadata};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+/// Checks the parts against `--chunk-manifest`: one entry per part, in
+/// order, with no gaps or overlaps between them, and each part the size
+/// and SHA-256 its entry gives.
+async fn check_manifest(
+    parts: &[(String, u64)],
+    manifest: &[ManifestEntry],
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    if manifest.len() != parts.len() {
+        return Err(format!(
+            "The manifest has {} ranges for {} parts",
+            manifest.len(),
+            parts.len()
+        )
+        .into());
+    }
+    let mut offset = 0;
+    for ((path, len), entry) in parts.iter().zip(manifest) {
+        if entry.start != offset {
+            return Err(format!(
+                "{} should start at {} to follow the parts before it, but the manifest has it at {}",
+                path, offset, entry.start
+            )
+            .into());
+        }
+        let expected = entry.end - entry.start + 1;
+        if *len != expected {
+            return Err(format!(
+                "{} is {} bytes, but the manifest range {}-{} is {}",
+                path, len, entry.start, entry.end, expected
+            )
+            .into());
+        }
+        if hash_range(path, 0, len - 1).await? != entry.sha256 {
+            return Err(format!("{} doesn't match its manifest hash", path).into());
+        }
+        offset = entry.end + 1;
+    }
+    Ok(())
+}
+
+/// Writes `parts` one after another to `output`. Returns `false`, leaving
+/// nothing behind, if the result doesn't match `checksum`.
+pub async fn combine(
+    parts: &[String],
+    output: &str,
+    checksum: Option<&Checksum>,
+    manifest: Option<&[ManifestEntry]>,
+    sync: bool,
+    pb: &ProgressBar,
+) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
+    let mut sized = Vec::with_capacity(parts.len());
+    for path in parts {
+        if path == output {
+            return Err(format!("{} is both a part and the output", path).into());
+        }
+        let len = metadata(path)
+            .await
+            .map_err(|e| format!("Failed to read {}: {}", path, e))?
+            .len();
+        sized.push((path.clone(), len));
+    }
+    if let Some(manifest) = manifest {
+        pb.set_message("Checking parts...");
+        check_manifest(&sized, manifest).await?;
+    }
+
+    let total: u64 = sized.iter().map(|(_, len)| len).sum();
+    pb.set_length(total);
+    pb.set_message("Combining...");
+    let part_path = format!("{}.part", output);
+    let mut file = File::create(&part_path).await?;
+    let mut buffer = vec![0u8; 64 * 1024];
+    for (path, _) in &sized {
+        let mut source = File::open(path).await?;
+        loop {
+            let n = source.read(&mut buffer).await?;
+            if n == 0 {
+                break;
+            }
+            file.write_all(&buffer[..n]).await?;
+            pb.inc(n as u64);
+        }
+    }
+    file.flush().await?;
+    if sync {
+        file.sync_all().await?;
+    }
+    drop(file);
+
+    if let Some(checksum) = checksum {
+        pb.set_message("Verifying...");
+        if !verify_checksum(checksum, &part_path, None).await? {
+            let _ = tokio::fs::remove_file(&part_path).await;
+            pb.abandon_with_message("Checksum mismatch!");
+            return Ok(false);
+        }
+    }
+    move_file(&part_path, output, sync).await?;
+    info!("Combined {} parts into {}", parts.len(), output);
+    pb.finish_with_message(if checksum.is_some() { "Verified" } else { "" });
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::scratch_dir;
+    use sha2::{Digest, Sha256};
+
+    /// Writes `contents` as parts in `dir`, returning their paths.
+    fn write_parts(dir: &std::path::Path, contents: &[&[u8]]) -> Vec<String> {
+        contents
+            .iter()
+            .enumerate()
+            .map(|(i, data)| {
+                let path = dir.join(format!("part{}", i));
+                std::fs::write(&path, data).unwrap();
+                path.to_string_lossy().into_owned()
+            })
+            .collect()
+    }
+
+    fn sha256(data: &[u8]) -> String {
+        hex::encode(Sha256::digest(data))
+    }
+
+    #[tokio::test]
+    async fn parts_are_joined_in_order() {
+        let dir = scratch_dir("combine");
+        let parts = write_parts(&dir, &[b"first ", b"second ", b"third"]);
+        let output = dir.join("whole").to_string_lossy().into_owned();
+        let checksum = Checksum::Sha256(sha256(b"first second third"));
+
+        let pb = ProgressBar::hidden();
+        assert!(
+            combine(&parts, &output, Some(&checksum), None, true, &pb)
+                .await
+                .unwrap()
+        );
+        assert_eq!(std::fs::read(&output).unwrap(), b"first second third");
+        assert!(!std::path::Path::new(&format!("{}.part", output)).exists());
+        assert_eq!(pb.position(), 18);
+    }
+
+    #[tokio::test]
+    async fn checksum_mismatch_leaves_nothing_behind() {
+        let dir = scratch_dir("combine-mismatch");
+        // In the wrong order
+        let parts = write_parts(&dir, &[b"second ", b"first "]);
+        let output = dir.join("whole").to_string_lossy().into_owned();
+        let checksum = Checksum::Sha256(sha256(b"first second "));
+
+        let pb = ProgressBar::hidden();
+        assert!(
+            !combine(&parts, &output, Some(&checksum), None, false, &pb)
+                .await
+                .unwrap()
+        );
+        assert!(!std::path::Path::new(&output).exists());
+        assert!(!std::path::Path::new(&format!("{}.part", output)).exists());
+    }
+
+    #[tokio::test]
+    async fn parts_are_checked_against_the_manifest() {
+        let dir = scratch_dir("combine-manifest");
+        let parts = write_parts(&dir, &[b"abcd", b"efg"]);
+        let output = dir.join("whole").to_string_lossy().into_owned();
+        let entry = |start, end, data: &[u8]| ManifestEntry {
+            start,
+            end,
+            sha256: sha256(data),
+        };
+        let pb = ProgressBar::hidden();
+
+        let manifest = [entry(0, 3, b"abcd"), entry(4, 6, b"efg")];
+        assert!(
+            combine(&parts, &output, None, Some(&manifest), false, &pb)
+                .await
+                .unwrap()
+        );
+        assert_eq!(std::fs::read(&output).unwrap(), b"abcdefg");
+
+        let gap = [entry(0, 3, b"abcd"), entry(5, 7, b"efg")];
+        let err = combine(&parts, &output, None, Some(&gap), false, &pb)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("should start at 4"), "{}", err);
+
+        let wrong_hash = [entry(0, 3, b"abcd"), entry(4, 6, b"xyz")];
+        let err = combine(&parts, &output, None, Some(&wrong_hash), false, &pb)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("manifest hash"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn output_cannot_be_one_of_the_parts() {
+        let dir = scratch_dir("combine-self");
+        let parts = write_parts(&dir, &[b"a", b"b"]);
+        let pb = ProgressBar::hidden();
+        let err = combine(&parts, &parts[1], None, None, false, &pb)
+            .await
+            .unwrap_err();
+        assert!(
+            err.to_string().contains("both a part and the output"),
+            "{}",
+            err
+        );
+    }
+}
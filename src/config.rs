@@ -0,0 +1,444 @@
+//! Profile file with default settings, and `GRAB_*` environment variables.
+//!
+//! Precedence, highest first: command-line flags, environment variables,
+//! the profile file, built-in defaults. Keys use the long flag names, e.g.
+//! `user-agent = "Grab/2.0"`.
+
+use crate::{
+    Args, Connections, parse_bandwidth, parse_connections, parse_minutes, parse_rename_pattern,
+    parse_schedule, parse_size,
+};
+use clap::ValueEnum;
+use clap::parser::{ArgMatches, ValueSource};
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Profile {
+    output_template: Option<String>,
+    dir: Option<String>,
+    create_dirs: Option<bool>,
+    add_extension: Option<bool>,
+    temp_dir: Option<String>,
+    resume: Option<bool>,
+    keep_partial_on_error: Option<bool>,
+    clean_on_error: Option<bool>,
+    existing: Option<String>,
+    rename_pattern: Option<String>,
+    /// A number, or "auto"
+    threads: Option<Threads>,
+    adaptive_connections: Option<bool>,
+    tail_optimize: Option<bool>,
+    max_connections: Option<usize>,
+    parallel_downloads: Option<usize>,
+    chunk_size: Option<u64>,
+    /// Same syntax as `--write-buffer-size`, e.g. "1M"
+    write_buffer_size: Option<String>,
+    /// Same syntax as `--max-buffer`, e.g. "8M"
+    max_buffer: Option<String>,
+    fsync: Option<String>,
+    user_agent: Option<String>,
+    ua_preset: Option<String>,
+    referer: Option<String>,
+    origin: Option<String>,
+    /// Seconds
+    timeout: Option<u64>,
+    /// Seconds
+    max_time: Option<u64>,
+    /// Same syntax as `--max-file-size`, e.g. "2G"
+    max_file_size: Option<String>,
+    retries: Option<u32>,
+    tries_per_chunk: Option<u32>,
+    max_total_retries: Option<u64>,
+    retry_on_status: Option<Vec<u16>>,
+    retry_all_errors: Option<bool>,
+    rotate_on_failure: Option<bool>,
+    /// Same syntax as `--limit-rate`, e.g. "512K"
+    limit_rate: Option<String>,
+    /// Same syntax as `--schedule`
+    schedule: Option<String>,
+    /// Same syntax as `--limit-rate`
+    min_speed: Option<String>,
+    /// Seconds
+    min_speed_time: Option<u64>,
+    /// Minutes
+    target_duration: Option<f64>,
+    http_version: Option<String>,
+    /// Seconds
+    pool_idle_timeout: Option<u64>,
+    pool_max_idle_per_host: Option<usize>,
+    tcp_nodelay: Option<bool>,
+    inet4_only: Option<bool>,
+    inet6_only: Option<bool>,
+    cacert: Option<String>,
+    cert: Option<String>,
+    key: Option<String>,
+    insecure: Option<bool>,
+    max_redirects: Option<usize>,
+    no_redirects: Option<bool>,
+    compressed: Option<bool>,
+    no_compressed: Option<bool>,
+    post: Option<Vec<String>>,
+    on_complete: Option<String>,
+    webhook: Option<String>,
+    on_complete_always: Option<bool>,
+    no_history: Option<bool>,
+    store_xattr: Option<bool>,
+    dedup_dir: Option<String>,
+    dedup_link: Option<String>,
+    log_file: Option<String>,
+    /// Same syntax as `--log-max-size`, e.g. "10M"
+    log_max_size: Option<String>,
+    log_rotate: Option<usize>,
+    units: Option<String>,
+    progress_style: Option<String>,
+    progress_template: Option<String>,
+    force_progress: Option<bool>,
+    tui: Option<bool>,
+    status_only: Option<bool>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Threads {
+    Count(usize),
+    Named(String),
+}
+
+/// `config.toml` in the grab config directory
+pub fn default_path() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join("grab").join("config.toml"))
+}
+
+pub fn load(path: &Path) -> Result<Profile, String> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read config {}: {}", path.display(), e))?;
+    toml::from_str(&contents).map_err(|e| format!("Invalid config {}: {}", path.display(), e))
+}
+
+fn parse_enum<T: ValueEnum>(key: &str, value: &str) -> Result<T, String> {
+    T::from_str(value, true).map_err(|_| format!("Invalid value for {} in config: {}", key, value))
+}
+
+fn minutes(value: f64) -> Result<Option<Duration>, String> {
+    parse_minutes(&value.to_string()).map(Some)
+}
+
+fn number<T: std::str::FromStr>(value: &str) -> Result<T, String> {
+    value
+        .trim()
+        .parse()
+        .map_err(|_| format!("Invalid number: {}", value))
+}
+
+/// Fills in settings from `GRAB_*` environment variables that weren't
+/// given on the command line, for parameterizing a container with `-e`.
+/// A flag that replaces the setting, like `--ua-preset` for
+/// `GRAB_USER_AGENT`, wins over the variable too. Returns the settings
+/// that were filled in, which the profile then leaves alone.
+/// `GRAB_URL` is read along with the other sources of URLs.
+pub fn apply_env(args: &mut Args, matches: &ArgMatches) -> Result<Vec<&'static str>, String> {
+    apply_env_from(args, matches, |var| std::env::var(var).ok())
+}
+
+/// `apply_env`, with variables looked up through `var`.
+fn apply_env_from(
+    args: &mut Args,
+    matches: &ArgMatches,
+    var: impl Fn(&str) -> Option<String>,
+) -> Result<Vec<&'static str>, String> {
+    let given = |id: &str| matches!(matches.value_source(id), Some(ValueSource::CommandLine));
+    let mut applied = Vec::new();
+
+    macro_rules! from_env {
+        ($var:literal, $field:ident, $convert:expr $(, $replaced_by:literal)*) => {
+            if let Some(value) = var($var)
+                && !value.is_empty()
+                && !given(stringify!($field))
+                $(&& !given($replaced_by))*
+            {
+                args.$field = $convert(&value).map_err(|e| format!("{}: {}", $var, e))?;
+                applied.push(stringify!($field));
+            }
+        };
+    }
+
+    from_env!(
+        "GRAB_OUTPUT",
+        output,
+        |v: &str| Ok::<_, String>(Some(v.to_string())),
+        "output_template",
+        "extract"
+    );
+    from_env!("GRAB_DIR", dir, |v: &str| Ok::<_, String>(Some(
+        v.to_string()
+    )));
+    from_env!(
+        "GRAB_CONNECTIONS",
+        threads,
+        parse_connections,
+        "adaptive_connections"
+    );
+    from_env!("GRAB_CHUNK_SIZE", chunk_size, number);
+    from_env!(
+        "GRAB_USER_AGENT",
+        user_agent,
+        |v: &str| Ok::<_, String>(v.to_string()),
+        "ua_preset",
+        "random_user_agent",
+        "rotate_user_agent"
+    );
+    from_env!("GRAB_TIMEOUT", timeout, |v: &str| number(v)
+        .map(Duration::from_secs));
+    from_env!("GRAB_RETRIES", retries, number);
+    from_env!("GRAB_LIMIT_RATE", limit_rate, |v: &str| parse_bandwidth(v)
+        .map(Some));
+
+    Ok(applied)
+}
+
+impl Profile {
+    /// Fills in every setting that wasn't given on the command line or
+    /// `from_env`.
+    pub fn apply(
+        self,
+        args: &mut Args,
+        matches: &ArgMatches,
+        from_env: &[&str],
+    ) -> Result<(), String> {
+        let unset = |id: &str| {
+            !matches!(matches.value_source(id), Some(ValueSource::CommandLine))
+                && !from_env.contains(&id)
+        };
+
+        macro_rules! set {
+            ($field:ident) => {
+                if let Some(value) = self.$field
+                    && unset(stringify!($field))
+                {
+                    args.$field = value;
+                }
+            };
+            ($field:ident, Some) => {
+                if let Some(value) = self.$field
+                    && unset(stringify!($field))
+                {
+                    args.$field = Some(value);
+                }
+            };
+            ($field:ident, $convert:expr) => {
+                if let Some(value) = self.$field
+                    && unset(stringify!($field))
+                {
+                    args.$field = $convert(value)?;
+                }
+            };
+        }
+
+        if args.output.is_none() {
+            set!(output_template, Some);
+        }
+        set!(dir, Some);
+        set!(create_dirs);
+        set!(add_extension);
+        set!(temp_dir, Some);
+        set!(resume);
+        // Like the address families below, either one on the command line
+        // replaces the profile's choice
+        if unset("keep_partial_on_error") && unset("clean_on_error") {
+            set!(keep_partial_on_error);
+            set!(clean_on_error);
+        }
+        set!(existing, |v: String| parse_enum("existing", &v));
+        set!(rename_pattern, |v: String| parse_rename_pattern(&v));
+        set!(threads, |v| match v {
+            Threads::Count(count) => Ok(Connections::Count(count)),
+            Threads::Named(name) => parse_connections(&name),
+        });
+        set!(adaptive_connections);
+        set!(tail_optimize);
+        set!(max_connections);
+        set!(parallel_downloads);
+        set!(chunk_size);
+        set!(write_buffer_size, |v: String| parse_size(&v));
+        set!(max_buffer, |v: String| parse_size(&v).map(Some));
+        set!(fsync, |v: String| parse_enum("fsync", &v));
+        if self.user_agent.is_some() && self.ua_preset.is_some() {
+            return Err("user-agent and ua-preset can't both be set in config".to_string());
+        }
+        // Either one on the command line replaces the profile's choice
+        if unset("user_agent") && unset("ua_preset") {
+            set!(user_agent);
+            set!(ua_preset, |v: String| parse_enum("ua-preset", &v).map(Some));
+        }
+        set!(referer, Some);
+        set!(origin, Some);
+        set!(timeout, |v| Ok::<_, String>(Duration::from_secs(v)));
+        set!(max_time, |v| Ok::<_, String>(Some(Duration::from_secs(v))));
+        set!(max_file_size, |v: String| parse_size(&v).map(Some));
+        set!(retries);
+        set!(tries_per_chunk);
+        set!(max_total_retries, Some);
+        set!(retry_on_status);
+        set!(retry_all_errors);
+        set!(rotate_on_failure);
+        set!(limit_rate, |v: String| parse_bandwidth(&v).map(Some));
+        set!(schedule, |v: String| parse_schedule(&v).map(Some));
+        set!(min_speed, |v: String| parse_bandwidth(&v).map(Some));
+        set!(min_speed_time, |v| Ok::<_, String>(Duration::from_secs(v)));
+        set!(target_duration, minutes);
+        set!(http_version, |v: String| parse_enum("http-version", &v));
+        set!(pool_idle_timeout, |v| Ok::<_, String>(Duration::from_secs(
+            v
+        )));
+        set!(pool_max_idle_per_host, Some);
+        set!(tcp_nodelay);
+        // The two are mutually exclusive, so a family chosen on the command
+        // line replaces the profile's choice entirely
+        if unset("inet4_only") && unset("inet6_only") {
+            set!(inet4_only);
+            set!(inet6_only);
+        }
+        set!(cacert, Some);
+        set!(cert, Some);
+        set!(key, Some);
+        set!(insecure);
+        set!(max_redirects);
+        set!(no_redirects);
+        // Like the address families, one set on the command line replaces
+        // the profile's choice
+        if unset("compressed") && unset("no_compressed") {
+            set!(compressed);
+            set!(no_compressed);
+        }
+        set!(post);
+        set!(on_complete, Some);
+        set!(webhook, Some);
+        set!(on_complete_always);
+        set!(no_history);
+        set!(store_xattr);
+        set!(dedup_dir, Some);
+        set!(dedup_link, |v: String| parse_enum("dedup-link", &v));
+        set!(log_file, Some);
+        set!(log_max_size, |v: String| parse_size(&v).map(Some));
+        set!(log_rotate);
+        set!(units, |v: String| parse_enum("units", &v));
+        set!(progress_style, |v: String| parse_enum("progress-style", &v));
+        set!(progress_template, Some);
+        set!(force_progress);
+        set!(tui);
+        set!(status_only);
+
+        if args.inet4_only && args.inet6_only {
+            return Err("inet4-only and inet6-only can't both be set in config".to_string());
+        }
+        if args.keep_partial_on_error && args.clean_on_error {
+            return Err(
+                "keep-partial-on-error and clean-on-error can't both be set in config".to_string(),
+            );
+        }
+        if args.compressed && args.no_compressed {
+            return Err("compressed and no-compressed can't both be set in config".to_string());
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::{CommandFactory, FromArgMatches};
+
+    fn parse(argv: &[&str]) -> (Args, ArgMatches) {
+        let matches = Args::command().get_matches_from(argv);
+        (Args::from_arg_matches(&matches).unwrap(), matches)
+    }
+
+    fn profile(toml: &str) -> Profile {
+        toml::from_str(toml).unwrap()
+    }
+
+    #[test]
+    fn profile_fills_in_flags_not_given() {
+        let (mut args, matches) = parse(&["grab", "http://host/file"]);
+        profile("user-agent = \"Profile/1.0\"\nchunk-size = 4096\nretries = 9")
+            .apply(&mut args, &matches, &[])
+            .unwrap();
+        assert_eq!(args.user_agent, "Profile/1.0");
+        assert_eq!(args.chunk_size, 4096);
+        assert_eq!(args.retries, 9);
+    }
+
+    #[test]
+    fn flag_wins_over_profile() {
+        let (mut args, matches) = parse(&["grab", "--retries", "2", "http://host/file"]);
+        profile("retries = 9\ntimeout = 5")
+            .apply(&mut args, &matches, &[])
+            .unwrap();
+        assert_eq!(args.retries, 2);
+        assert_eq!(args.timeout, Duration::from_secs(5));
+    }
+
+    #[test]
+    fn unknown_profile_key_is_rejected() {
+        assert!(toml::from_str::<Profile>("no-such-key = 1").is_err());
+    }
+
+    /// `apply_env_from` with `vars` as the whole environment.
+    fn apply_vars(
+        args: &mut Args,
+        matches: &ArgMatches,
+        vars: &[(&str, &str)],
+    ) -> Vec<&'static str> {
+        apply_env_from(args, matches, |name| {
+            vars.iter()
+                .find(|(var, _)| *var == name)
+                .map(|(_, value)| value.to_string())
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn flag_wins_over_env_which_wins_over_profile() {
+        let (mut args, matches) = parse(&["grab", "--retries", "2", "http://host/file"]);
+        let vars = [
+            ("GRAB_RETRIES", "5"),
+            ("GRAB_TIMEOUT", "7"),
+            ("GRAB_CHUNK_SIZE", ""),
+        ];
+        let from_env = apply_vars(&mut args, &matches, &vars);
+        // The flag is left alone, and an empty variable counts as unset
+        assert_eq!(from_env, ["timeout"]);
+        profile(
+            "retries = 9
+timeout = 30
+chunk-size = 4096",
+        )
+        .apply(&mut args, &matches, &from_env)
+        .unwrap();
+        assert_eq!(args.retries, 2);
+        assert_eq!(args.timeout, Duration::from_secs(7));
+        assert_eq!(args.chunk_size, 4096);
+    }
+
+    #[test]
+    fn replacing_flag_wins_over_env() {
+        let (mut args, matches) = parse(&["grab", "--ua-preset", "curl", "http://host/file"]);
+        let from_env = apply_vars(&mut args, &matches, &[("GRAB_USER_AGENT", "Env/1.0")]);
+        assert!(from_env.is_empty());
+        assert_ne!(args.user_agent, "Env/1.0");
+    }
+
+    #[test]
+    fn bad_env_value_names_the_variable() {
+        let (mut args, matches) = parse(&["grab", "http://host/file"]);
+        let err = apply_env_from(&mut args, &matches, |name| {
+            (name == "GRAB_RETRIES").then(|| "many".to_string())
+        })
+        .unwrap_err();
+        assert!(err.starts_with("GRAB_RETRIES: "), "{}", err);
+    }
+}
@@ -0,0 +1,96 @@
+//! Lines printed while downloads run. They're drawn above the progress
+//! bars rather than through them, and while keys are read in raw mode,
+//! where a bare `\n` doesn't go back to the first column, each line ends
+//! in `\r\n`.
+
+use indicatif::MultiProgress;
+use std::fmt;
+use std::io::{IsTerminal, Write};
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static BARS: OnceLock<MultiProgress> = OnceLock::new();
+
+static RAW: AtomicBool = AtomicBool::new(false);
+
+/// Prints from now on go above `bars`.
+pub fn set_bars(bars: &MultiProgress) {
+    let _ = BARS.set(bars.clone());
+}
+
+/// Records whether the terminal is in raw mode.
+pub fn set_raw(raw: bool) {
+    RAW.store(raw, Ordering::Relaxed);
+}
+
+/// `eprintln!`, above the bars.
+pub fn eprintln(args: fmt::Arguments) {
+    print(true, &format!("{}\n", args));
+}
+
+/// `println!`, above the bars.
+pub fn println(args: fmt::Arguments) {
+    print(false, &format!("{}\n", args));
+}
+
+fn print(stderr: bool, text: &str) {
+    // Raw mode is only on with stderr on a terminal; stdout may be a file
+    let raw = RAW.load(Ordering::Relaxed) && (stderr || std::io::stdout().is_terminal());
+    let text = terminal_lines(text, raw);
+    let write = || {
+        let _ = if stderr {
+            std::io::stderr().write_all(text.as_bytes())
+        } else {
+            let mut stdout = std::io::stdout();
+            stdout
+                .write_all(text.as_bytes())
+                .and_then(|()| stdout.flush())
+        };
+    };
+    match BARS.get() {
+        Some(bars) => bars.suspend(write),
+        None => write(),
+    }
+}
+
+/// `text` with its line ends as the terminal needs them.
+fn terminal_lines(text: &str, raw: bool) -> std::borrow::Cow<'_, str> {
+    if raw {
+        text.replace("\r\n", "\n").replace('\n', "\r\n").into()
+    } else {
+        text.into()
+    }
+}
+
+/// Where log messages go without `--tui`.
+struct LogSink;
+
+impl Write for LogSink {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        print(true, &String::from_utf8_lossy(buf));
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        std::io::stderr().flush()
+    }
+}
+
+/// Sets up logging as `env_logger::init` does, but through `LogSink`.
+pub fn init_logger() {
+    env_logger::Builder::from_default_env()
+        .target(env_logger::Target::Pipe(Box::new(LogSink)))
+        .init();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn raw_mode_lines_return_to_the_first_column() {
+        assert_eq!(terminal_lines("a\nb\n", true), "a\r\nb\r\n");
+        assert_eq!(terminal_lines("a\r\nb\n", true), "a\r\nb\r\n");
+        assert_eq!(terminal_lines("a\nb\n", false), "a\nb\n");
+    }
+}
@@ -0,0 +1,150 @@
+//! Cookies from `--cookie` and Netscape-format cookie files.
+
+use reqwest::Url;
+use reqwest::cookie::Jar;
+use std::path::Path;
+
+/// Adds `name=value` as a cookie for the host of each URL.
+pub fn add_cookie(jar: &Jar, cookie: &str, urls: &[Url]) -> Result<(), String> {
+    if !cookie.contains('=') {
+        return Err(format!("Invalid cookie {:?} (expected name=value)", cookie));
+    }
+    for url in urls {
+        jar.add_cookie_str(&format!("{}; Path=/", cookie), url);
+    }
+    Ok(())
+}
+
+/// Loads a cookie file as written by curl, wget and browser export tools:
+/// tab-separated `domain  subdomains  path  secure  expiry  name  value`.
+/// Expired cookies are skipped. Returns the number of cookies loaded.
+pub fn load_netscape(jar: &Jar, path: &Path) -> Result<usize, String> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read cookie file {}: {}", path.display(), e))?;
+    let now = chrono::Utc::now().timestamp();
+    let mut loaded = 0;
+
+    for (number, line) in contents.lines().enumerate() {
+        // curl marks HttpOnly cookies with a prefix on an otherwise normal line
+        let line = line.strip_prefix("#HttpOnly_").unwrap_or(line);
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let fields: Vec<&str> = line.split('\t').collect();
+        let [
+            domain,
+            include_subdomains,
+            cookie_path,
+            secure,
+            expiry,
+            name,
+            value,
+        ] = fields[..]
+        else {
+            return Err(format!(
+                "{}:{}: expected 7 tab-separated fields",
+                path.display(),
+                number + 1
+            ));
+        };
+        let expiry: i64 = expiry.parse().map_err(|_| {
+            format!(
+                "{}:{}: invalid expiry {:?}",
+                path.display(),
+                number + 1,
+                expiry
+            )
+        })?;
+        // 0 marks a session cookie
+        if expiry != 0 && expiry < now {
+            continue;
+        }
+
+        let host = domain.trim_start_matches('.');
+        let secure = secure.eq_ignore_ascii_case("TRUE");
+        let url = Url::parse(&format!(
+            "{}://{}{}",
+            if secure { "https" } else { "http" },
+            host,
+            cookie_path
+        ))
+        .map_err(|e| format!("{}:{}: {}", path.display(), number + 1, e))?;
+
+        let mut cookie = format!("{}={}; Path={}", name, value, cookie_path);
+        if include_subdomains.eq_ignore_ascii_case("TRUE") {
+            cookie.push_str(&format!("; Domain={}", host));
+        }
+        if secure {
+            cookie.push_str("; Secure");
+        }
+        jar.add_cookie_str(&cookie, &url);
+        loaded += 1;
+    }
+
+    Ok(loaded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::cookie::CookieStore;
+
+    fn cookies_for(jar: &Jar, url: &str) -> String {
+        jar.cookies(&Url::parse(url).unwrap())
+            .map(|value| value.to_str().unwrap().to_string())
+            .unwrap_or_default()
+    }
+
+    fn cookie_file(name: &str, contents: &str) -> std::path::PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("grab-test-{}-{}.txt", std::process::id(), name));
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn netscape_file_loads_live_cookies() {
+        let path = cookie_file(
+            "cookies",
+            "# Netscape HTTP Cookie File\n\
+             \n\
+             example.com\tFALSE\t/\tFALSE\t0\tsession\tabc\n\
+             #HttpOnly_example.com\tFALSE\t/\tFALSE\t0\thidden\tdef\n\
+             .example.org\tTRUE\t/\tTRUE\t0\tshared\tghi\n\
+             example.com\tFALSE\t/\tFALSE\t1\texpired\told\n",
+        );
+        let jar = Jar::default();
+        assert_eq!(load_netscape(&jar, &path).unwrap(), 3);
+        std::fs::remove_file(path).unwrap();
+
+        let sent = cookies_for(&jar, "http://example.com/file");
+        assert!(sent.contains("session=abc"), "{}", sent);
+        assert!(sent.contains("hidden=def"), "{}", sent);
+        assert!(!sent.contains("expired"), "{}", sent);
+        // Secure, and for subdomains too
+        assert!(cookies_for(&jar, "https://www.example.org/").contains("shared=ghi"));
+        assert_eq!(cookies_for(&jar, "http://www.example.org/"), "");
+    }
+
+    #[test]
+    fn netscape_file_names_the_bad_line() {
+        let path = cookie_file("bad-cookies", "# comment\nexample.com\tFALSE\t/\n");
+        let err = load_netscape(&Jar::default(), &path).unwrap_err();
+        std::fs::remove_file(&path).unwrap();
+        assert!(
+            err.ends_with(":2: expected 7 tab-separated fields"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn cookie_needs_a_value() {
+        let url = Url::parse("http://example.com/").unwrap();
+        assert!(add_cookie(&Jar::default(), "name", std::slice::from_ref(&url)).is_err());
+        let jar = Jar::default();
+        add_cookie(&jar, "name=value", &[url]).unwrap();
+        assert_eq!(cookies_for(&jar, "http://example.com/x"), "name=value");
+    }
+}
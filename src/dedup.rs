@@ -0,0 +1,222 @@
+//! `--dedup-dir`: a content-addressed store of finished downloads, so
+//! URLs that serve the same bytes end up sharing one copy on disk.
+//!
+//! Each file is kept in the store as `ab/abcdef...`, named by its SHA-256.
+//! A download whose hash is already there is replaced by a link to the
+//! stored copy; otherwise it's added to the store for later downloads.
+
+use crate::{move_file, sha256_file};
+use std::path::{Path, PathBuf};
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LinkMode {
+    /// Hard links, sharing the data without the file looking any different
+    #[default]
+    Hard,
+    /// Symbolic links into the store
+    Sym,
+    /// Plain copies, for filesystems without links; only fills the store
+    Copy,
+}
+
+/// What `dedup` did with a file.
+pub enum Outcome {
+    /// It was new, and is now in the store
+    Stored,
+    /// The store already had it; the file now links to this copy
+    Linked(PathBuf),
+    /// The store already had it, and the file was left be: with
+    /// `--dedup-link copy`, or hard links to a store on another filesystem
+    Duplicate(PathBuf),
+}
+
+/// Looks `path` up in the store under `dir` by its content, and links it
+/// to the stored copy or adds it.
+pub async fn dedup(
+    path: &str,
+    dir: &Path,
+    mode: LinkMode,
+) -> Result<Outcome, Box<dyn std::error::Error + Send + Sync>> {
+    let hash = sha256_file(path).await?;
+    let shard = dir.join(&hash[..2]);
+    tokio::fs::create_dir_all(&shard).await?;
+    // Symbolic links have to point somewhere that works from `path`
+    let stored = tokio::fs::canonicalize(&shard).await?.join(&hash);
+
+    if tokio::fs::metadata(&stored).await.is_ok() {
+        if same_file(path, &stored) {
+            // Already linked, e.g. a file that was up to date
+            return Ok(Outcome::Linked(stored));
+        }
+        if mode == LinkMode::Copy {
+            return Ok(Outcome::Duplicate(stored));
+        }
+        // Made beside the file and renamed over it, so the file is never
+        // missing if linking fails
+        let staging = format!("{}.dedup", path);
+        let _ = tokio::fs::remove_file(&staging).await;
+        if let Err(e) = link(&stored, Path::new(&staging), mode).await {
+            // The store was filled with a copy; its files can't be linked
+            // to from here
+            if mode == LinkMode::Hard && crosses_devices(e.as_ref()) {
+                return Ok(Outcome::Duplicate(stored));
+            }
+            return Err(e);
+        }
+        if let Err(e) = tokio::fs::rename(&staging, path).await {
+            let _ = tokio::fs::remove_file(&staging).await;
+            return Err(e.into());
+        }
+        return Ok(Outcome::Linked(stored));
+    }
+
+    // Staged and renamed into place, so a stored file is always complete
+    let staging = stored.with_extension("part");
+    match mode {
+        LinkMode::Hard => {
+            if tokio::fs::hard_link(path, &staging).await.is_err() {
+                // On another filesystem, the store gets a copy
+                tokio::fs::copy(path, &staging).await?;
+            }
+        }
+        LinkMode::Copy => {
+            tokio::fs::copy(path, &staging).await?;
+        }
+        LinkMode::Sym => {
+            move_file(path, &staging.to_string_lossy(), false).await?;
+        }
+    }
+    tokio::fs::rename(&staging, &stored).await?;
+    if mode == LinkMode::Sym
+        && let Err(e) = link(&stored, Path::new(path), mode).await
+    {
+        // Put the file back where it was expected
+        move_file(&stored.to_string_lossy(), path, false).await?;
+        return Err(e);
+    }
+    Ok(Outcome::Stored)
+}
+
+/// Whether `a` and `b` are one file, as two hard links to it are.
+#[cfg(unix)]
+fn same_file(a: &str, b: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    match (std::fs::metadata(a), std::fs::metadata(b)) {
+        (Ok(a), Ok(b)) => a.dev() == b.dev() && a.ino() == b.ino(),
+        _ => false,
+    }
+}
+
+#[cfg(not(unix))]
+fn same_file(_a: &str, _b: &Path) -> bool {
+    false
+}
+
+fn crosses_devices(e: &(dyn std::error::Error + Send + Sync + 'static)) -> bool {
+    e.downcast_ref::<std::io::Error>()
+        .is_some_and(|e| e.kind() == std::io::ErrorKind::CrossesDevices)
+}
+
+async fn link(
+    stored: &Path,
+    at: &Path,
+    mode: LinkMode,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    match mode {
+        LinkMode::Hard => tokio::fs::hard_link(stored, at).await?,
+        #[cfg(unix)]
+        LinkMode::Sym => tokio::fs::symlink(stored, at).await?,
+        #[cfg(windows)]
+        LinkMode::Sym => tokio::fs::symlink_file(stored, at).await?,
+        LinkMode::Copy => {
+            tokio::fs::copy(stored, at).await?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::scratch_dir;
+
+    /// Two downloads with the same content in `dir`, and the store path.
+    fn two_copies(dir: &Path) -> (String, String, PathBuf) {
+        let a = dir.join("a.bin").to_string_lossy().into_owned();
+        let b = dir.join("b.bin").to_string_lossy().into_owned();
+        std::fs::write(&a, b"same bytes").unwrap();
+        std::fs::write(&b, b"same bytes").unwrap();
+        (a, b, dir.join("store"))
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn identical_downloads_share_one_copy() {
+        use std::os::unix::fs::MetadataExt;
+        let dir = scratch_dir("dedup-hard");
+        let (a, b, store) = two_copies(&dir);
+
+        assert!(matches!(
+            dedup(&a, &store, LinkMode::Hard).await.unwrap(),
+            Outcome::Stored
+        ));
+        let Outcome::Linked(stored) = dedup(&b, &store, LinkMode::Hard).await.unwrap() else {
+            panic!("b.bin wasn't linked");
+        };
+        let inode = |path: &Path| std::fs::metadata(path).unwrap().ino();
+        assert_eq!(inode(Path::new(&a)), inode(&stored));
+        assert_eq!(inode(Path::new(&b)), inode(&stored));
+        assert_eq!(std::fs::metadata(&stored).unwrap().nlink(), 3);
+        assert_eq!(std::fs::read(&b).unwrap(), b"same bytes");
+
+        // Running it again finds the link already there
+        assert!(matches!(
+            dedup(&b, &store, LinkMode::Hard).await.unwrap(),
+            Outcome::Linked(_)
+        ));
+        assert!(!dir.join("b.bin.dedup").exists());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn symbolic_links_point_into_the_store() {
+        let dir = scratch_dir("dedup-sym");
+        let (a, b, store) = two_copies(&dir);
+
+        dedup(&a, &store, LinkMode::Sym).await.unwrap();
+        dedup(&b, &store, LinkMode::Sym).await.unwrap();
+        let target_a = std::fs::read_link(&a).unwrap();
+        assert_eq!(target_a, std::fs::read_link(&b).unwrap());
+        assert!(target_a.starts_with(std::fs::canonicalize(&store).unwrap()));
+        assert_eq!(std::fs::read(&b).unwrap(), b"same bytes");
+    }
+
+    #[tokio::test]
+    async fn copy_mode_leaves_files_alone() {
+        let dir = scratch_dir("dedup-copy");
+        let (a, b, store) = two_copies(&dir);
+
+        assert!(matches!(
+            dedup(&a, &store, LinkMode::Copy).await.unwrap(),
+            Outcome::Stored
+        ));
+        assert!(matches!(
+            dedup(&b, &store, LinkMode::Copy).await.unwrap(),
+            Outcome::Duplicate(_)
+        ));
+        assert!(std::fs::symlink_metadata(&b).unwrap().is_file());
+    }
+
+    #[test]
+    fn only_a_cross_device_failure_counts() {
+        let err = |kind| -> Box<dyn std::error::Error + Send + Sync> {
+            std::io::Error::from(kind).into()
+        };
+        assert!(crosses_devices(
+            err(std::io::ErrorKind::CrossesDevices).as_ref()
+        ));
+        assert!(!crosses_devices(
+            err(std::io::ErrorKind::PermissionDenied).as_ref()
+        ));
+    }
+}
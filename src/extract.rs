@@ -0,0 +1,264 @@
+//! Unpacking `.tar.gz` and `.zip` downloads as they arrive (`--extract`).
+//!
+//! The archive crates read synchronously, so extraction runs on a blocking
+//! thread fed with downloaded chunks over a channel. Zip entries are read
+//! from their local headers in order; zips that only record an entry's size
+//! after its data (as streaming zip writers do) can't be read this way.
+
+use flate2::read::GzDecoder;
+use log::debug;
+use reqwest::Url;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Component, Path, PathBuf};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArchiveKind {
+    TarGz,
+    Zip,
+}
+
+impl ArchiveKind {
+    /// Recognizes an archive by the URL's extension, or failing that by its
+    /// Content-Type.
+    pub fn detect(url: &str, content_type: Option<&str>) -> Option<Self> {
+        let path = Url::parse(url)
+            .map(|u| u.path().to_lowercase())
+            .unwrap_or_default();
+        if path.ends_with(".tar.gz") || path.ends_with(".tgz") {
+            return Some(ArchiveKind::TarGz);
+        }
+        if path.ends_with(".zip") {
+            return Some(ArchiveKind::Zip);
+        }
+
+        let mime = content_type?.split(';').next()?.trim().to_lowercase();
+        match mime.as_str() {
+            "application/gzip" | "application/x-gzip" | "application/x-gtar"
+            | "application/x-tgz" => Some(ArchiveKind::TarGz),
+            "application/zip" | "application/x-zip-compressed" => Some(ArchiveKind::Zip),
+            _ => None,
+        }
+    }
+}
+
+/// An extraction running in the background.
+pub struct Extractor {
+    tx: mpsc::Sender<Vec<u8>>,
+    handle: JoinHandle<io::Result<usize>>,
+}
+
+impl Extractor {
+    pub fn spawn(kind: ArchiveKind, dir: PathBuf) -> Self {
+        let (tx, rx) = mpsc::channel(16);
+        let handle = tokio::task::spawn_blocking(move || {
+            let reader = ChannelReader {
+                rx,
+                buf: Vec::new(),
+                pos: 0,
+            };
+            match kind {
+                ArchiveKind::TarGz => unpack_tar_gz(reader, &dir),
+                ArchiveKind::Zip => unpack_zip(reader, &dir),
+            }
+        });
+        Extractor { tx, handle }
+    }
+
+    /// Passes on the next downloaded bytes. Fails once extraction has
+    /// stopped, either at the end of the archive or on an error that
+    /// `finish` returns.
+    pub async fn write(&self, data: Vec<u8>) -> Result<(), mpsc::error::SendError<Vec<u8>>> {
+        self.tx.send(data).await
+    }
+
+    /// Signals the end of the download and waits for extraction to finish.
+    /// Returns the number of files written.
+    pub async fn finish(self) -> io::Result<usize> {
+        drop(self.tx);
+        self.handle.await?
+    }
+}
+
+/// Blocking `Read` over the chunks sent to an `Extractor`.
+struct ChannelReader {
+    rx: mpsc::Receiver<Vec<u8>>,
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+impl Read for ChannelReader {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.buf.len() {
+            match self.rx.blocking_recv() {
+                Some(buf) => {
+                    self.buf = buf;
+                    self.pos = 0;
+                }
+                None => return Ok(0),
+            }
+        }
+        let n = out.len().min(self.buf.len() - self.pos);
+        out[..n].copy_from_slice(&self.buf[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+/// Where the archive member `name` goes under `dir`. Absolute paths and `..`
+/// would let a malicious archive write outside of `dir` ("zip slip"), so
+/// they're refused.
+fn target_path(dir: &Path, name: &Path) -> io::Result<PathBuf> {
+    let mut path = dir.to_path_buf();
+    for component in name.components() {
+        match component {
+            Component::Normal(part) => path.push(part),
+            Component::CurDir => {}
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "Archive entry {} points outside the target directory",
+                        name.display()
+                    ),
+                ));
+            }
+        }
+    }
+    Ok(path)
+}
+
+fn unpack_tar_gz(reader: impl Read, dir: &Path) -> io::Result<usize> {
+    let mut archive = tar::Archive::new(GzDecoder::new(reader));
+    let mut files = 0;
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let name = entry.path()?.into_owned();
+        target_path(dir, &name)?;
+        debug!("Extracting {}", name.display());
+        // unpack_in also refuses to write through symlinks leading out of dir
+        entry.unpack_in(dir)?;
+        if entry.header().entry_type().is_file() {
+            files += 1;
+        }
+    }
+    Ok(files)
+}
+
+fn unpack_zip(mut reader: impl Read, dir: &Path) -> io::Result<usize> {
+    let mut files = 0;
+    while let Some(mut entry) =
+        zip::read::read_zipfile_from_stream(&mut reader).map_err(io::Error::other)?
+    {
+        let name = entry.enclosed_name().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "Archive entry {} points outside the target directory",
+                    entry.name()
+                ),
+            )
+        })?;
+        let path = target_path(dir, &name)?;
+        if entry.is_dir() {
+            fs::create_dir_all(&path)?;
+            continue;
+        }
+        if entry.is_symlink() {
+            debug!("Skipping symlink {}", name.display());
+            continue;
+        }
+
+        debug!("Extracting {}", name.display());
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        io::copy(&mut entry, &mut File::create(&path)?)?;
+        files += 1;
+    }
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::scratch_dir;
+    use std::io::{Cursor, Write};
+
+    #[test]
+    fn target_path_stays_inside_the_directory() {
+        let dir = Path::new("/tmp/out");
+        assert_eq!(
+            target_path(dir, Path::new("a/b.txt")).unwrap(),
+            dir.join("a/b.txt")
+        );
+        assert_eq!(
+            target_path(dir, Path::new("./a/./b.txt")).unwrap(),
+            dir.join("a/b.txt")
+        );
+        for name in ["../evil", "a/../../evil", "a/../b", "/etc/passwd"] {
+            let e = target_path(dir, Path::new(name)).unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidData, "{}", name);
+        }
+    }
+
+    /// A `.tar.gz` of one file, with the name written into the header as
+    /// is: `tar` itself won't build a path with `..` in it.
+    fn tar_gz(name: &str, data: &[u8]) -> Vec<u8> {
+        let mut header = tar::Header::new_old();
+        header.as_old_mut().name[..name.len()].copy_from_slice(name.as_bytes());
+        header.set_size(data.len() as u64);
+        header.set_mode(0o644);
+        header.set_entry_type(tar::EntryType::Regular);
+        header.set_cksum();
+        let mut builder = tar::Builder::new(flate2::write::GzEncoder::new(
+            Vec::new(),
+            flate2::Compression::fast(),
+        ));
+        builder.append(&header, data).unwrap();
+        builder.into_inner().unwrap().finish().unwrap()
+    }
+
+    fn zip(name: &str, data: &[u8]) -> Vec<u8> {
+        let mut writer = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        writer
+            .start_file(name, zip::write::SimpleFileOptions::default())
+            .unwrap();
+        writer.write_all(data).unwrap();
+        writer.finish().unwrap().into_inner()
+    }
+
+    #[test]
+    fn archives_unpack_into_the_directory() {
+        let dir = scratch_dir("extract");
+        let out = dir.join("out");
+        fs::create_dir_all(&out).unwrap();
+        let tar = tar_gz("sub/a.txt", b"from tar");
+        assert_eq!(unpack_tar_gz(Cursor::new(tar), &out).unwrap(), 1);
+        assert_eq!(fs::read(out.join("sub/a.txt")).unwrap(), b"from tar");
+
+        let zip = zip("sub/b.txt", b"from zip");
+        assert_eq!(unpack_zip(Cursor::new(zip), &out).unwrap(), 1);
+        assert_eq!(fs::read(out.join("sub/b.txt")).unwrap(), b"from zip");
+    }
+
+    #[test]
+    fn archive_entries_leading_out_are_refused() {
+        let dir = scratch_dir("extract-slip");
+        let out = dir.join("out");
+        fs::create_dir_all(&out).unwrap();
+
+        for name in ["../evil.txt", "sub/../../evil.txt"] {
+            let e = unpack_tar_gz(Cursor::new(tar_gz(name, b"evil")), &out).unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidData, "{}", name);
+            let e = unpack_zip(Cursor::new(zip(name, b"evil")), &out).unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidData, "{}", name);
+        }
+        let e = unpack_zip(Cursor::new(zip("/tmp/evil.txt", b"evil")), &out).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        assert!(!dir.join("evil.txt").exists());
+        assert_eq!(fs::read_dir(&out).unwrap().count(), 0);
+    }
+}
@@ -0,0 +1,489 @@
+//! What follows a transfer: the final checks, moving the file into place,
+//! the `--post` steps, and the hooks, notifications and records of it.
+
+#[cfg(feature = "notify")]
+use crate::format_bytes;
+use crate::remote::Plan;
+use crate::{
+    DownloadError, FileDownloader, MAX_CHUNK_RETRIES, ManifestEntry, RemoteMetadata, RunState,
+    batch_state, console, content_type_extension, dedup, download_chunk, hash_range, history,
+    move_file, sha256_file, shell_command, sync_file, verify_checksum, with_tls_options, xattrs,
+};
+use indicatif::ProgressBar;
+use log::{debug, error, info};
+use std::path::Path;
+#[cfg(feature = "notify")]
+use std::time::Duration;
+use tokio::fs::metadata;
+
+impl FileDownloader {
+    /// Checks a transferred `.part` file and moves it into place: after the
+    /// final sync, its size, then `--chunk-manifest` and the checksum.
+    /// `streamed_digest` is the checksum's digest if it was computed as the
+    /// file arrived, and `note` says how it was fetched.
+    pub(super) async fn complete(
+        &self,
+        run: &mut RunState,
+        plan: &Plan,
+        remote: &RemoteMetadata,
+        streamed_digest: Option<String>,
+        note: &str,
+        pb: &ProgressBar,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let output_path = &self.config.output_path;
+        let part_path = self.part_path();
+        let total_size = plan.total_size;
+
+        // The size is only known now; the total bar counted `shown_size`
+        if total_size == 0 {
+            let size = pb.position();
+            if plan.shown_size > 0 && size != plan.shown_size {
+                info!(
+                    "{} is {} bytes, not the {} of --expected-size",
+                    output_path, size, plan.shown_size
+                );
+            }
+            self.state.total_pb.inc_length(size);
+            self.state.total_pb.dec_length(plan.shown_size);
+            pb.set_length(size);
+        }
+
+        // Writers were flushed as they finished; make it durable before the
+        // file is checked and moved into place
+        if self.config.fsync.syncs_final() {
+            sync_file(&part_path).await?;
+        }
+
+        // Verify final size (a device reports its own, not the file's)
+        if !self.config.device_target
+            && let Ok(meta) = metadata(&part_path).await
+            && meta.len() != total_size
+            && total_size > 0
+        {
+            let err = DownloadError::SizeMismatch {
+                expected: total_size,
+                actual: meta.len(),
+            };
+            pb.finish_with_message(err.to_string());
+            return Err(err.into());
+        }
+
+        if let Some(ref manifest) = self.config.chunk_manifest {
+            pb.set_message("Verifying chunks...");
+            if let Err(e) = self
+                .verify_chunks(run, manifest, plan.supports_range, pb)
+                .await
+            {
+                pb.finish_with_message(e.to_string());
+                return Err(e);
+            }
+        }
+
+        if let Some(ref checksum) = plan.checksum {
+            let verified = match streamed_digest {
+                Some(ref digest) => {
+                    debug!("{}: checksum computed while downloading", output_path);
+                    Ok(checksum.matches(digest))
+                }
+                None => {
+                    pb.set_message("Verifying...");
+                    // Past `total_size` is the rest of the device; without
+                    // a size the file is whatever arrived
+                    let len = self.config.device_target.then_some(total_size);
+                    verify_checksum(checksum, &part_path, len).await
+                }
+            };
+            match verified {
+                Ok(true) => {
+                    self.finish_part(run, &part_path, remote).await?;
+                    info!("{}: checksum verified", output_path);
+                    pb.finish_with_message(format!("Verified{}", note));
+                }
+                Ok(false) => {
+                    pb.finish_with_message("Checksum mismatch!");
+                    return Err(DownloadError::ChecksumMismatch.into());
+                }
+                Err(e) => {
+                    pb.finish_with_message(format!("Verification error: {}", e));
+                    return Err(format!("Verification error: {}", e).into());
+                }
+            }
+        } else {
+            self.finish_part(run, &part_path, remote).await?;
+            pb.finish_with_message(note.trim_start().to_string());
+        }
+        info!(
+            "Finished {} from {}",
+            self.output_path(run),
+            remote.final_url
+        );
+        Ok(())
+    }
+
+    /// What the finished bar adds about how the file was fetched: a redirect,
+    /// the User-Agent a rotation settled on, and the connections `-t auto`
+    /// chose.
+    pub(super) fn finished_note(
+        &self,
+        run: &RunState,
+        remote: &RemoteMetadata,
+        rotated: Option<&FileDownloader>,
+    ) -> String {
+        let mut note = if remote.final_url != self.config.url {
+            format!(" via {}", remote.final_url)
+        } else {
+            String::new()
+        };
+        if let Some(source) = rotated {
+            info!(
+                "{} succeeded from {} with User-Agent {:?}",
+                self.config.url, source.config.url, source.config.user_agent
+            );
+            if self.config.rotate_user_agents {
+                note.push_str(&format!(" as {}", source.config.user_agent));
+            }
+        }
+        if let Some(connections) = run.connections {
+            note.push_str(&format!(
+                " with {} connection{}",
+                connections,
+                if connections == 1 { "" } else { "s" }
+            ));
+        }
+        note
+    }
+
+    /// Checks every manifest range of the `.part` file and re-fetches the ones
+    /// whose hash doesn't match, up to `MAX_CHUNK_RETRIES` times.
+    async fn verify_chunks(
+        &self,
+        run: &RunState,
+        manifest: &[ManifestEntry],
+        supports_range: bool,
+        pb: &ProgressBar,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let part_path = self.part_path();
+
+        for attempt in 0..=MAX_CHUNK_RETRIES {
+            let mut corrupt = Vec::new();
+            for entry in manifest {
+                if hash_range(&part_path, entry.start, entry.end).await? != entry.sha256 {
+                    corrupt.push(entry);
+                }
+            }
+
+            if corrupt.is_empty() {
+                return Ok(());
+            }
+            if attempt == MAX_CHUNK_RETRIES {
+                break;
+            }
+            if !supports_range {
+                return Err(format!(
+                    "{} chunk(s) failed verification and server does not support ranges",
+                    corrupt.len()
+                )
+                .into());
+            }
+
+            let ctx = self.chunk_context(run, &part_path, pb);
+            for entry in corrupt {
+                info!(
+                    "Chunk {}-{} failed verification, re-downloading (attempt {}/{})",
+                    entry.start,
+                    entry.end,
+                    attempt + 1,
+                    MAX_CHUNK_RETRIES
+                );
+                let len = entry.end - entry.start + 1;
+                pb.set_position(pb.position().saturating_sub(len));
+                self.state
+                    .total_pb
+                    .set_position(self.state.total_pb.position().saturating_sub(len));
+                download_chunk(&ctx, entry.start, entry.end).await?;
+            }
+        }
+
+        Err("Chunks still corrupt after re-downloading".into())
+    }
+
+    /// Moves a complete `.part` file into place. Device targets already are.
+    async fn finish_part(
+        &self,
+        run: &mut RunState,
+        part_path: &str,
+        remote: &RemoteMetadata,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        if !self.config.device_target {
+            if self.config.add_extension
+                && let Some(extension) = remote
+                    .content_type
+                    .as_deref()
+                    .and_then(content_type_extension)
+            {
+                run.named_path = Some(format!("{}.{}", self.config.output_path, extension));
+            }
+            move_file(
+                part_path,
+                self.output_path(run),
+                self.config.fsync.syncs_final(),
+            )
+            .await?;
+            self.save_validators(remote).await;
+            if self.config.store_xattr {
+                xattrs::store(
+                    self.output_path(run),
+                    &xattrs::Provenance {
+                        url: self.config.url.clone(),
+                        etag: remote.etag.clone(),
+                        date: chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+                    },
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// The downloaded file's path, with any extension `--add-extension` gave it
+    pub(super) fn output_path<'a>(&'a self, run: &'a RunState) -> &'a str {
+        run.named_path
+            .as_deref()
+            .unwrap_or(&self.config.output_path)
+    }
+
+    /// The downloaded file's path once `--post` steps have run
+    pub(super) fn final_path<'a>(&'a self, run: &'a RunState) -> &'a str {
+        run.final_path
+            .as_deref()
+            .unwrap_or_else(|| self.output_path(run))
+    }
+
+    /// Runs the `--post` steps in order on a finished download. Each step's
+    /// last line of output, if any, is the path the file was moved to.
+    pub(super) async fn post_process(
+        &self,
+        run: &mut RunState,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let mut path = self.output_path(run).to_string();
+        for (i, command) in self.config.post.iter().enumerate() {
+            debug!("Post step {}: `{}` on {}", i + 1, command, path);
+            let output = shell_command(command)
+                .env("GRAB_URL", &self.config.url)
+                .env("GRAB_OUTPUT", &path)
+                .stderr(std::process::Stdio::inherit())
+                .output()
+                .await
+                .map_err(|e| {
+                    format!(
+                        "--post step {} (`{}`) failed to start: {}",
+                        i + 1,
+                        command,
+                        e
+                    )
+                })?;
+            if !output.status.success() {
+                return Err(format!(
+                    "--post step {} (`{}`) exited with {}",
+                    i + 1,
+                    command,
+                    output.status
+                )
+                .into());
+            }
+
+            let stdout = String::from_utf8_lossy(&output.stdout);
+            if let Some(moved) = stdout.lines().map(str::trim).rfind(|line| !line.is_empty()) {
+                if !Path::new(moved).exists() {
+                    return Err(format!(
+                        "--post step {} (`{}`) printed {}, which doesn't exist",
+                        i + 1,
+                        command,
+                        moved
+                    )
+                    .into());
+                }
+                info!("Post step {} moved {} to {}", i + 1, path, moved);
+                path = moved.to_string();
+            }
+        }
+        if let Some((ref dir, mode)) = self.config.dedup
+            && !self.config.device_target
+        {
+            // The file itself is fine either way
+            match dedup::dedup(&path, dir, mode).await {
+                Ok(dedup::Outcome::Stored) => info!("Added {} to {}", path, dir.display()),
+                Ok(dedup::Outcome::Linked(stored)) => {
+                    info!("{} has the content of {}; linked", path, stored.display())
+                }
+                Ok(dedup::Outcome::Duplicate(stored)) => {
+                    info!("{} has the content of {}", path, stored.display())
+                }
+                Err(e) => {
+                    console::eprintln(format_args!("Warning: can't deduplicate {}: {}", path, e))
+                }
+            }
+        }
+        if path != self.output_path(run) {
+            run.final_path = Some(path);
+        }
+        Ok(())
+    }
+
+    /// Runs the `--on-complete` command and `--webhook` for a finished download.
+    /// Hook failures are logged but never fail the download itself.
+    pub(super) async fn run_hooks(
+        &self,
+        run: &RunState,
+        result: &Result<(), Box<dyn std::error::Error + Send + Sync>>,
+    ) {
+        if self.config.on_complete.is_none() && self.config.webhook.is_none() {
+            return;
+        }
+        if result.is_err() && !self.config.hooks_always {
+            return;
+        }
+
+        let output_path = self.final_path(run);
+        let size = metadata(output_path).await.map(|m| m.len()).unwrap_or(0);
+        let sha256 = if result.is_ok() {
+            sha256_file(output_path).await.unwrap_or_default()
+        } else {
+            String::new()
+        };
+        let status = if result.is_ok() { "success" } else { "failure" };
+
+        if let Some(ref command) = self.config.on_complete {
+            let run = shell_command(command)
+                .env("GRAB_URL", &self.config.url)
+                .env("GRAB_OUTPUT", output_path)
+                .env("GRAB_SIZE", size.to_string())
+                .env("GRAB_SHA256", &sha256)
+                .env("GRAB_STATUS", status)
+                .status()
+                .await;
+            match run {
+                Ok(exit) if exit.success() => info!("Hook `{}` succeeded", command),
+                Ok(exit) => error!("Hook `{}` exited with {}", command, exit),
+                Err(e) => error!("Hook `{}` failed to start: {}", command, e),
+            }
+        }
+
+        if let Some(ref webhook) = self.config.webhook {
+            let summary = serde_json::json!({
+                "url": self.config.url,
+                "output": output_path,
+                "size": size,
+                "sha256": sha256,
+                "status": status,
+                "error": result.as_ref().err().map(|e| e.to_string()),
+            });
+            let sent = match self.webhook_client() {
+                Ok(client) => client
+                    .post(webhook)
+                    .header(reqwest::header::CONTENT_TYPE, "application/json")
+                    .body(summary.to_string())
+                    .send()
+                    .await
+                    .map_err(Into::into),
+                Err(e) => Err(e),
+            };
+            match sent {
+                Ok(response) if response.status().is_success() => {
+                    info!("Webhook {} returned {}", webhook, response.status())
+                }
+                Ok(response) => error!("Webhook {} returned {}", webhook, response.status()),
+                Err(e) => error!("Webhook {} failed: {}", webhook, e),
+            }
+        }
+    }
+
+    /// A client for `--webhook`. The download's client sends the `-H` and
+    /// `--headers-file` headers, its cookies and any client certificate with
+    /// every request, and those are only meant for the file's server.
+    fn webhook_client(&self) -> Result<reqwest::Client, Box<dyn std::error::Error + Send + Sync>> {
+        let builder = with_tls_options(
+            reqwest::Client::builder()
+                .user_agent(&self.config.user_agent)
+                .connect_timeout(self.config.timeout),
+            self.config.cacert.as_deref(),
+            None,
+            None,
+            self.config.insecure,
+        )?;
+        Ok(builder.build()?)
+    }
+
+    /// Shows a desktop notification for a finished download. Silently does
+    /// nothing when no notification service is available.
+    #[cfg(feature = "notify")]
+    pub(super) async fn notify_desktop(
+        &self,
+        run: &RunState,
+        result: &Result<(), Box<dyn std::error::Error + Send + Sync>>,
+        elapsed: Duration,
+    ) {
+        if !self.config.desktop_notify {
+            return;
+        }
+
+        let filename = Path::new(self.final_path(run))
+            .file_name()
+            .and_then(|n| n.to_str())
+            .unwrap_or("file")
+            .to_string();
+        let (summary, body) = match result {
+            Ok(()) => {
+                let size = metadata(self.final_path(run))
+                    .await
+                    .map(|m| m.len())
+                    .unwrap_or(0);
+                (
+                    format!("Downloaded {}", filename),
+                    format!(
+                        "{} in {}",
+                        format_bytes(size, self.config.units),
+                        indicatif::HumanDuration(elapsed)
+                    ),
+                )
+            }
+            Err(e) => (format!("Download failed: {}", filename), e.to_string()),
+        };
+
+        let shown = tokio::task::spawn_blocking(move || {
+            notify_rust::Notification::new()
+                .appname("grab")
+                .summary(&summary)
+                .body(&body)
+                .show()
+                .map(|_| ())
+        })
+        .await;
+        if let Ok(Err(e)) = shown {
+            debug!("Desktop notification unavailable: {}", e);
+        }
+    }
+
+    pub(super) async fn record_history(&self, run: &RunState) {
+        let output_path = self.final_path(run);
+        let size = metadata(output_path).await.map(|m| m.len()).unwrap_or(0);
+        if let Err(e) = history::append(&self.config.url, output_path, size) {
+            debug!("Could not write download history: {}", e);
+        }
+    }
+
+    /// Marks the download finished in the `--input-file` ledger.
+    pub(super) async fn record_batch_state(&self, run: &RunState, ledger: &batch_state::Ledger) {
+        if self.config.device_target {
+            return;
+        }
+        let output_path = self.final_path(run);
+        let size = metadata(output_path).await.map(|m| m.len()).unwrap_or(0);
+        if let Err(e) = ledger.record(&self.config.url, output_path, size) {
+            console::eprintln(format_args!(
+                "Warning: can't update {}: {}",
+                batch_state::LEDGER_NAME,
+                e
+            ));
+        }
+    }
+}
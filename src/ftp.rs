@@ -0,0 +1,143 @@
+//! FTP and FTPS downloads.
+//!
+//! `ftps://` uses explicit TLS (`AUTH TLS`) on the regular FTP port. FTP has
+//! no bounded ranges, only `REST` to start a transfer at an offset, so files
+//! are always fetched sequentially.
+
+use crate::{FileDownloader, RemoteMetadata, RunState, wait_while_paused};
+use indicatif::ProgressBar;
+use log::{debug, info};
+use percent_encoding::percent_decode_str;
+use reqwest::Url;
+use rustls_platform_verifier::BuilderVerifierExt;
+use std::sync::Arc;
+use suppaftp::tokio::{AsyncRustlsConnector, AsyncRustlsFtpStream};
+use suppaftp::tokio_rustls::{TlsConnector, rustls};
+use suppaftp::types::FileType;
+use tokio::io::AsyncReadExt;
+
+pub fn is_ftp_url(url: &str) -> bool {
+    Url::parse(url)
+        .map(|u| matches!(u.scheme(), "ftp" | "ftps"))
+        .unwrap_or(false)
+}
+
+impl FileDownloader {
+    /// Logs in to the server named by the URL and returns the session along
+    /// with the decoded path of the file.
+    async fn ftp_connect(
+        &self,
+    ) -> Result<(AsyncRustlsFtpStream, String), Box<dyn std::error::Error + Send + Sync>> {
+        let url = Url::parse(&self.config.url)?;
+        let host = url.host_str().ok_or("FTP URL has no host")?;
+        let port = url.port().unwrap_or(21);
+        let path = percent_decode_str(url.path()).decode_utf8()?.into_owned();
+
+        debug!("Connecting to {}:{}", host, port);
+        let mut ftp = tokio::time::timeout(
+            self.config.timeout,
+            AsyncRustlsFtpStream::connect((host, port)),
+        )
+        .await??;
+
+        if url.scheme() == "ftps" {
+            let config = rustls::ClientConfig::builder_with_provider(Arc::new(
+                rustls::crypto::aws_lc_rs::default_provider(),
+            ))
+            .with_safe_default_protocol_versions()?
+            .with_platform_verifier()?
+            .with_no_client_auth();
+            let connector = AsyncRustlsConnector::from(TlsConnector::from(Arc::new(config)));
+            ftp = ftp.into_secure(connector, host).await?;
+        }
+
+        let user = match url.username() {
+            "" => "anonymous".to_string(),
+            user => percent_decode_str(user).decode_utf8()?.into_owned(),
+        };
+        let password = match url.password() {
+            Some(password) => percent_decode_str(password).decode_utf8()?.into_owned(),
+            None => "anonymous@".to_string(),
+        };
+        ftp.login(&user, &password).await?;
+        ftp.transfer_type(FileType::Binary).await?;
+
+        Ok((ftp, path))
+    }
+
+    /// Size via `SIZE`, modification time via `MDTM` when the server has it.
+    pub(super) async fn fetch_ftp_metadata(
+        &self,
+    ) -> Result<RemoteMetadata, Box<dyn std::error::Error + Send + Sync>> {
+        let (mut ftp, path) = self.ftp_connect().await?;
+
+        // SIZE is an extension; without it the size is simply unknown
+        let total_size = match ftp.size(&path).await {
+            Ok(size) => size as u64,
+            Err(e) => {
+                debug!("SIZE {} failed: {}", path, e);
+                0
+            }
+        };
+        let last_modified = ftp
+            .mdtm(&path)
+            .await
+            .ok()
+            .map(|t| t.format("%a, %d %b %Y %H:%M:%S GMT").to_string());
+        debug!("FTP {} -> {} bytes", path, total_size);
+        let _ = ftp.quit().await;
+
+        Ok(RemoteMetadata {
+            final_url: self.config.url.clone(),
+            total_size,
+            supports_range: true,
+            content_type: None,
+            last_modified,
+            etag: None,
+        })
+    }
+
+    pub(super) async fn download_ftp(
+        &self,
+        run: &RunState,
+        start_pos: u64,
+        pb: ProgressBar,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let part_path = self.part_path();
+        let ctx = self.chunk_context(run, &part_path, &pb);
+        let _connection = ctx.connect().await?;
+
+        let (mut ftp, path) = self.ftp_connect().await?;
+
+        if start_pos > 0 {
+            ftp.resume_transfer(start_pos as usize).await?;
+        }
+        info!("RETR {} from byte {}", path, start_pos);
+        let mut stream = ftp.retr_as_stream(&path).await?;
+
+        let mut file = self.open_part_at(&part_path, start_pos).await?;
+
+        let mut buffer = vec![0u8; 64 * 1024];
+        let res: Result<(), Box<dyn std::error::Error + Send + Sync>> = async {
+            loop {
+                wait_while_paused(&ctx.control, &pb).await?;
+                let n =
+                    tokio::time::timeout(self.config.timeout, stream.read(&mut buffer)).await??;
+                if n == 0 {
+                    break;
+                }
+                file.write_all(&buffer[..n]).await?;
+                ctx.record(n as u64).await?;
+            }
+            Ok(())
+        }
+        .await;
+        file.finish().await?;
+        res?;
+
+        stream.finish().await?;
+        let _ = ftp.quit().await;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,195 @@
+//! `--dump-headers`: the request and response headers of each file's HEAD
+//! and first GET (every request with `--dump-all-headers`), written to a
+//! file in the style of `curl -v` for bug reports.
+//!
+//! reqwest adds the client-wide headers (User-Agent, `--header`, cookies)
+//! as a request goes out, after it's built, so those are merged in here
+//! from what they were set up with: each file's client registers its own
+//! User-Agent and headers, which can differ from file to file with
+//! `--random-user-agent` or `--referer auto`. `Authorization` and cookies are
+//! redacted unless `--dump-secrets` is given.
+
+use reqwest::cookie::{CookieStore, Jar};
+use reqwest::header::{
+    ACCEPT, ACCEPT_ENCODING, AUTHORIZATION, COOKIE, HeaderMap, HeaderName, HeaderValue,
+    PROXY_AUTHORIZATION, SET_COOKIE, USER_AGENT,
+};
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write as _;
+use std::fs::File;
+use std::io::Write;
+use std::sync::{Arc, Mutex, OnceLock};
+
+pub struct Options {
+    pub all: bool,
+    pub secrets: bool,
+    /// `--header` and friends, which every client sends
+    pub headers: HeaderMap,
+    pub user_agent: String,
+    pub compressed: bool,
+    pub cookie_jar: Option<Arc<Jar>>,
+}
+
+struct Dump {
+    path: String,
+    /// `None` once writing has failed
+    file: Option<File>,
+    options: Options,
+    /// Method and URL of every request dumped so far
+    seen: HashSet<(reqwest::Method, String)>,
+    /// User-Agent and headers of the client for each file's URL, in place
+    /// of the ones in `options`
+    clients: HashMap<String, (String, HeaderMap)>,
+}
+
+static DUMP: OnceLock<Mutex<Dump>> = OnceLock::new();
+
+/// Starts the dump in `path`, replacing what was there. Warns and carries
+/// on without it if the file can't be created.
+pub fn open(path: &str, options: Options) {
+    match File::create(path) {
+        Ok(file) => {
+            let _ = DUMP.set(Mutex::new(Dump {
+                path: path.to_string(),
+                file: Some(file),
+                options,
+                seen: HashSet::new(),
+                clients: HashMap::new(),
+            }));
+        }
+        Err(e) => eprintln!("Warning: can't create header dump {}: {}", path, e),
+    }
+}
+
+/// Notes the User-Agent and default headers of the client that fetches
+/// `url`, for the requests it sends.
+pub fn client(url: &str, user_agent: &str, headers: &HeaderMap) {
+    let Some(dump) = DUMP.get() else {
+        return;
+    };
+    // Keyed the way the request will spell it
+    let url = reqwest::Url::parse(url).map_or_else(|_| url.to_string(), String::from);
+    dump.lock()
+        .unwrap()
+        .clients
+        .insert(url, (user_agent.to_string(), headers.clone()));
+}
+
+fn redacted(name: &HeaderName, secrets: bool) -> bool {
+    !secrets && [AUTHORIZATION, PROXY_AUTHORIZATION, COOKIE, SET_COOKIE].contains(name)
+}
+
+fn write_headers(out: &mut String, prefix: &str, headers: &HeaderMap, secrets: bool) {
+    for (name, value) in headers {
+        let value = if redacted(name, secrets) {
+            "[redacted]".into()
+        } else {
+            String::from_utf8_lossy(value.as_bytes())
+        };
+        let _ = writeln!(out, "{} {}: {}", prefix, name, value);
+    }
+}
+
+/// A request's part of the dump, held until its response is in so that
+/// requests running side by side don't get mixed up in the file.
+pub struct Exchange(String);
+
+/// Starts recording a request that's about to be sent, if it's one to
+/// record. The exchange goes to `response` or `error` once it's over.
+pub fn request(request: &reqwest::Request) -> Option<Exchange> {
+    let mut dump = DUMP.get()?.lock().unwrap();
+    let key = (request.method().clone(), request.url().to_string());
+    if dump.file.is_none() || !(dump.seen.insert(key) || dump.options.all) {
+        return None;
+    }
+
+    let options = &dump.options;
+    let (user_agent, mut headers) = match dump.clients.get(request.url().as_str()) {
+        Some((user_agent, headers)) => (user_agent, headers.clone()),
+        None => (&options.user_agent, options.headers.clone()),
+    };
+    if let Ok(user_agent) = HeaderValue::from_str(user_agent) {
+        headers.entry(USER_AGENT).or_insert(user_agent);
+    }
+    if let Some(cookies) = options
+        .cookie_jar
+        .as_ref()
+        .and_then(|jar| jar.cookies(request.url()))
+    {
+        headers.insert(COOKIE, cookies);
+    }
+    headers.extend(request.headers().clone());
+    headers
+        .entry(ACCEPT)
+        .or_insert(HeaderValue::from_static("*/*"));
+    if options.compressed {
+        headers
+            .entry(ACCEPT_ENCODING)
+            .or_insert(HeaderValue::from_static("gzip, deflate, br"));
+    }
+
+    let url = request.url();
+    let mut out = format!(
+        "== {} {} {}\n",
+        chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+        request.method(),
+        url
+    );
+    let target = match url.query() {
+        Some(query) => format!("{}?{}", url.path(), query),
+        None => url.path().to_string(),
+    };
+    let _ = writeln!(out, "> {} {}", request.method(), target);
+    if let Some(host) = url.host_str() {
+        let _ = match url.port() {
+            Some(port) => writeln!(out, "> host: {}:{}", host, port),
+            None => writeln!(out, "> host: {}", host),
+        };
+    }
+    write_headers(&mut out, ">", &headers, options.secrets);
+    out.push_str(">\n");
+    Some(Exchange(out))
+}
+
+/// Records the response to the request for `requested`.
+pub fn response(exchange: Exchange, response: &reqwest::Response, requested: &reqwest::Url) {
+    let Some(dump) = DUMP.get() else {
+        return;
+    };
+    let mut dump = dump.lock().unwrap();
+    let Exchange(mut out) = exchange;
+    let _ = writeln!(out, "< {:?} {}", response.version(), response.status());
+    write_headers(&mut out, "<", response.headers(), dump.options.secrets);
+    out.push_str("<\n");
+    if response.url() != requested {
+        let _ = writeln!(out, "(after redirects, final URL {})", response.url());
+    }
+    out.push('\n');
+    dump.write(&out);
+}
+
+/// Records that the request got no response.
+pub fn error(exchange: Exchange, error: &dyn std::fmt::Display) {
+    let Some(dump) = DUMP.get() else {
+        return;
+    };
+    let Exchange(out) = exchange;
+    dump.lock()
+        .unwrap()
+        .write(&format!("{}! {}\n\n", out, error));
+}
+
+impl Dump {
+    fn write(&mut self, text: &str) {
+        let Some(ref mut file) = self.file else {
+            return;
+        };
+        if let Err(e) = file.write_all(text.as_bytes()) {
+            crate::console::eprintln(format_args!(
+                "Warning: can't write header dump {}: {}; dumping stopped",
+                self.path, e
+            ));
+            self.file = None;
+        }
+    }
+}
@@ -0,0 +1,120 @@
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+use std::path::PathBuf;
+
+/// Number of entries kept in the history file
+const HISTORY_LIMIT: usize = 100;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    pub url: String,
+    pub output: String,
+    pub size: u64,
+    /// Unix timestamp (seconds) of when the download completed
+    pub timestamp: i64,
+}
+
+/// `$GRAB_HISTORY_FILE`, or `history.jsonl` in the grab config directory.
+pub fn history_path() -> Option<PathBuf> {
+    if let Some(path) = std::env::var_os("GRAB_HISTORY_FILE") {
+        return Some(PathBuf::from(path));
+    }
+    dirs::config_dir().map(|dir| dir.join("grab").join("history.jsonl"))
+}
+
+/// Loads all readable entries, oldest first. Unparseable lines are skipped.
+pub fn load() -> Vec<HistoryEntry> {
+    let Some(path) = history_path() else {
+        return Vec::new();
+    };
+    let Ok(contents) = std::fs::read_to_string(path) else {
+        return Vec::new();
+    };
+    contents
+        .lines()
+        .filter_map(|line| serde_json::from_str(line).ok())
+        .collect()
+}
+
+pub fn append(url: &str, output: &str, size: u64) -> std::io::Result<()> {
+    let Some(path) = history_path() else {
+        return Ok(());
+    };
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+
+    let entry = HistoryEntry {
+        url: url.to_string(),
+        output: output.to_string(),
+        size,
+        timestamp: chrono::Utc::now().timestamp(),
+    };
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)?;
+    writeln!(file, "{}", serde_json::to_string(&entry)?)
+}
+
+/// Drops the oldest entries once the file grows past `HISTORY_LIMIT`.
+pub fn trim() -> std::io::Result<()> {
+    let entries = load();
+    if entries.len() <= HISTORY_LIMIT {
+        return Ok(());
+    }
+    let Some(path) = history_path() else {
+        return Ok(());
+    };
+
+    let mut contents = String::new();
+    for entry in &entries[entries.len() - HISTORY_LIMIT..] {
+        contents.push_str(&serde_json::to_string(entry)?);
+        contents.push('\n');
+    }
+    std::fs::write(path, contents)
+}
+
+/// Lists the most recent `count` entries and asks which one to repeat.
+pub fn pick(count: usize) -> std::io::Result<Option<HistoryEntry>> {
+    let entries = load();
+    if entries.is_empty() {
+        println!("No download history yet.");
+        return Ok(None);
+    }
+
+    let recent: Vec<&HistoryEntry> = entries.iter().rev().take(count).collect();
+    for (i, entry) in recent.iter().enumerate() {
+        let when = chrono::DateTime::from_timestamp(entry.timestamp, 0)
+            .map(|t| {
+                t.with_timezone(&chrono::Local)
+                    .format("%Y-%m-%d %H:%M")
+                    .to_string()
+            })
+            .unwrap_or_default();
+        println!(
+            "{:>3}. {}  {}  ({} bytes) -> {}",
+            i + 1,
+            when,
+            entry.url,
+            entry.size,
+            entry.output
+        );
+    }
+
+    print!(
+        "Select a download to repeat [1-{}, Enter to cancel]: ",
+        recent.len()
+    );
+    std::io::stdout().flush()?;
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+
+    Ok(answer
+        .trim()
+        .parse::<usize>()
+        .ok()
+        .and_then(|n| n.checked_sub(1))
+        .and_then(|i| recent.get(i))
+        .map(|entry| (*entry).clone()))
+}
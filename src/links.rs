@@ -0,0 +1,243 @@
+//! Links on HTML pages and directory listings (`--list-links`,
+//! `--download-links`).
+//!
+//! A deliberately small scanner rather than an HTML parser: it finds the
+//! `href` of every `<a>` tag (and a `<base href>`, if any), which is all
+//! a directory listing or a download page needs.
+
+use reqwest::Url;
+
+/// Absolute URLs of the page's links, in page order without duplicates.
+/// Fragment-only links, `javascript:` and `mailto:` are left out, and
+/// fragments are dropped.
+pub fn extract_links(html: &str, page: &Url) -> Vec<Url> {
+    let mut base = page.clone();
+    let mut links: Vec<Url> = Vec::new();
+
+    for (tag, href) in hrefs(html) {
+        if tag == "base" {
+            if let Ok(url) = page.join(&href) {
+                base = url;
+            }
+            continue;
+        }
+        if href.is_empty() || href.starts_with('#') {
+            continue;
+        }
+        // Url::join handles relative paths, `/abs` and `//host/path`
+        let Ok(mut url) = base.join(&href) else {
+            continue;
+        };
+        if !matches!(url.scheme(), "http" | "https" | "ftp" | "ftps") {
+            continue;
+        }
+        url.set_fragment(None);
+        if !links.contains(&url) {
+            links.push(url);
+        }
+    }
+
+    links
+}
+
+/// `(tag, href)` for every `<a>` and `<base>` tag with an `href`.
+fn hrefs(html: &str) -> Vec<(String, String)> {
+    let lower = html.to_ascii_lowercase();
+    let mut found = Vec::new();
+    let mut pos = 0;
+
+    while let Some(open) = lower[pos..].find('<').map(|i| pos + i) {
+        let Some(close) = lower[open..].find('>').map(|i| open + i) else {
+            break;
+        };
+        pos = close + 1;
+
+        let tag = &lower[open + 1..close];
+        let name_end = tag
+            .find(|c: char| c.is_ascii_whitespace())
+            .unwrap_or(tag.len());
+        let name = &tag[..name_end];
+        if name != "a" && name != "base" {
+            continue;
+        }
+        let attrs = open + 1 + name_end..close;
+        if let Some(href) = attribute(&html[attrs.clone()], &lower[attrs], "href") {
+            found.push((name.to_string(), decode_entities(href.trim())));
+        }
+    }
+
+    found
+}
+
+/// The value of attribute `name` among a tag's `attrs`; `lower_attrs` is the
+/// same text lowercased, for finding the name.
+fn attribute<'a>(attrs: &'a str, lower_attrs: &str, name: &str) -> Option<&'a str> {
+    let mut search = 0;
+    while let Some(found) = lower_attrs[search..].find(name).map(|i| search + i) {
+        search = found + name.len();
+        // Must be a whole attribute name: `data-href` doesn't count
+        let before = lower_attrs[..found].chars().next_back();
+        if !before.is_some_and(|c| c.is_ascii_whitespace()) {
+            continue;
+        }
+        let rest = lower_attrs[search..].trim_start();
+        let Some(rest) = rest.strip_prefix('=') else {
+            continue;
+        };
+        let value_start = lower_attrs.len() - rest.trim_start().len();
+        let value = &attrs[value_start..];
+        return Some(match value.chars().next() {
+            Some(quote @ ('"' | '\'')) => {
+                let value = &value[1..];
+                &value[..value.find(quote).unwrap_or(value.len())]
+            }
+            _ => {
+                let end = value
+                    .find(|c: char| c.is_ascii_whitespace())
+                    .unwrap_or(value.len());
+                &value[..end]
+            }
+        });
+    }
+    None
+}
+
+/// The handful of entities that show up in URLs.
+fn decode_entities(value: &str) -> String {
+    value
+        .replace("&amp;", "&")
+        .replace("&#38;", "&")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+}
+
+/// Shell-style glob: `*` matches any run of characters (including `/`),
+/// `?` any single one. Case-sensitive, like the URLs it's matched against.
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    // Where the last `*` was, and how much of the text it has taken so far
+    let mut star: Option<(usize, usize)> = None;
+
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p, t));
+            p += 1;
+        } else if let Some((star_p, star_t)) = star {
+            // Let the `*` take one more character and try again
+            p = star_p + 1;
+            t = star_t + 1;
+            star = Some((star_p, star_t + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn glob_matches_like_a_shell() {
+        let iso = "https://example.com/pub/os/disk-1.2.iso";
+        assert!(glob_match("*.iso", iso));
+        assert!(glob_match("https://example.com/*", iso));
+        assert!(glob_match("*/os/*", iso));
+        assert!(glob_match("*disk-?.?.iso", iso));
+        assert!(glob_match("*", iso));
+        assert!(glob_match(iso, iso));
+
+        assert!(!glob_match("*.ISO", iso));
+        assert!(!glob_match("*.iso.sig", iso));
+        assert!(!glob_match("*disk-?.iso", iso));
+        assert!(!glob_match("example.com/*", iso));
+        assert!(!glob_match("", iso));
+
+        assert!(glob_match("", ""));
+        assert!(glob_match("**", ""));
+        assert!(!glob_match("?", ""));
+        // A `*` that has to give back characters it first took
+        assert!(glob_match("*a*b", "xaxbxab"));
+        assert!(!glob_match("*a*b", "xaxbxa"));
+    }
+
+    #[test]
+    fn links_resolve_against_the_page_and_its_base() {
+        let page = Url::parse("https://example.com/pub/index.html").unwrap();
+        let html = r##"
+            <a href="a.iso">a</a>
+            <A HREF='/top.iso'>top</A>
+            <a class="x" href="//mirror.example.net/b.iso#sum">b</a>
+            <a href="a.iso">again</a>
+            <a href="#top">top</a>
+            <a href="mailto:me@example.com">mail</a>
+            <a href="javascript:void(0)">js</a>
+            <a href="c.iso?x=1&amp;y=2">c</a>
+            <a name="no-href">none</a>
+        "##;
+        let links: Vec<String> = extract_links(html, &page)
+            .iter()
+            .map(Url::to_string)
+            .collect();
+        assert_eq!(
+            links,
+            [
+                "https://example.com/pub/a.iso",
+                "https://example.com/top.iso",
+                "https://mirror.example.net/b.iso",
+                "https://example.com/pub/c.iso?x=1&y=2",
+            ]
+        );
+
+        let html = r#"<base href="/files/"><a href="d.iso">d</a>"#;
+        let links = extract_links(html, &page);
+        assert_eq!(links[0].as_str(), "https://example.com/files/d.iso");
+    }
+
+    #[test]
+    fn link_filter_picks_from_the_page() {
+        let page = Url::parse("https://example.com/pub/").unwrap();
+        let html = r#"
+            <a href="../">Parent</a>
+            <a href="disk.iso">disk.iso</a>
+            <a href="disk.iso.sig">disk.iso.sig</a>
+            <a href="SHA256SUMS">SHA256SUMS</a>
+            <a href="old/disk.iso">old/</a>
+        "#;
+        let wanted = |pattern: &str| -> Vec<String> {
+            extract_links(html, &page)
+                .iter()
+                .map(Url::to_string)
+                .filter(|link| glob_match(pattern, link))
+                .collect()
+        };
+        assert_eq!(
+            wanted("*.iso"),
+            [
+                "https://example.com/pub/disk.iso",
+                "https://example.com/pub/old/disk.iso"
+            ]
+        );
+        assert_eq!(
+            wanted("https://example.com/pub/*.iso*"),
+            [
+                "https://example.com/pub/disk.iso",
+                "https://example.com/pub/disk.iso.sig",
+                "https://example.com/pub/old/disk.iso"
+            ]
+        );
+        assert_eq!(
+            wanted("*/SHA???SUMS"),
+            ["https://example.com/pub/SHA256SUMS"]
+        );
+        assert!(wanted("*.zip").is_empty());
+    }
+}
@@ -0,0 +1,302 @@
+//! `file://` copies and inline `data:` URLs.
+//!
+//! Both go through the same progress bars, pausing and rate limiting as
+//! network downloads. A `file://` copy resumes by seeking the source to the
+//! length of the `.part` file. In `--input-file`, a `file://` URL may be a
+//! glob that stands for every file it matches.
+
+use crate::links::glob_match;
+use crate::{FileDownloader, RemoteMetadata, RunState, wait_while_paused};
+use base64::Engine;
+use indicatif::ProgressBar;
+use log::info;
+use percent_encoding::percent_decode_str;
+use reqwest::Url;
+use std::path::{Component, Path, PathBuf};
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, SeekFrom};
+
+pub fn is_local_url(url: &str) -> bool {
+    Url::parse(url)
+        .map(|u| matches!(u.scheme(), "file" | "data"))
+        .unwrap_or(false)
+}
+
+pub fn is_data_url(url: &str) -> bool {
+    url.get(..5)
+        .is_some_and(|scheme| scheme.eq_ignore_ascii_case("data:"))
+}
+
+/// Local path of a `file://` URL, including Windows drive letters
+/// (`file:///C:/dir/file`) and UNC hosts on Windows.
+fn file_path(url: &str) -> Result<PathBuf, String> {
+    Url::parse(url)
+        .ok()
+        .and_then(|u| u.to_file_path().ok())
+        .ok_or_else(|| format!("Not a valid local file URL: {}", url))
+}
+
+/// Whether `url` is a `file://` glob, with `*` or `?` in its path.
+pub fn is_file_glob(url: &str) -> bool {
+    url.get(..7)
+        .is_some_and(|scheme| scheme.eq_ignore_ascii_case("file://"))
+        && url[7..].contains(['*', '?'])
+}
+
+/// `file://` URLs of the files matching a `file://` glob, sorted. `*` and
+/// `?` match within one path component. A pattern that doesn't start with
+/// `/` (`file://data/*.bin`) is relative to `base`.
+pub fn expand_file_glob(url: &str, base: &Path) -> Result<Vec<String>, String> {
+    let pattern = percent_decode_str(&url[7..])
+        .decode_utf8()
+        .map_err(|_| format!("Not a valid local file URL: {}", url))?;
+    let pattern = Path::new(pattern.as_ref());
+    let root = if pattern.is_absolute() {
+        PathBuf::new()
+    } else {
+        base.to_path_buf()
+    };
+
+    let mut matches = vec![root];
+    for component in pattern.components() {
+        let Component::Normal(name) = component else {
+            matches = matches.into_iter().map(|m| m.join(component)).collect();
+            continue;
+        };
+        let name = name.to_string_lossy();
+        if !name.contains(['*', '?']) {
+            matches = matches.into_iter().map(|m| m.join(name.as_ref())).collect();
+            continue;
+        }
+        let mut next = Vec::new();
+        for dir in &matches {
+            // A directory that can't be read matches nothing, as in a shell
+            let Ok(entries) = std::fs::read_dir(if dir.as_os_str().is_empty() {
+                Path::new(".")
+            } else {
+                dir
+            }) else {
+                continue;
+            };
+            for entry in entries.flatten() {
+                let entry_name = entry.file_name().to_string_lossy().into_owned();
+                // Hidden files only match a pattern that asks for them
+                if entry_name.starts_with('.') && !name.starts_with('.') {
+                    continue;
+                }
+                if glob_match(&name, &entry_name) {
+                    next.push(dir.join(entry_name));
+                }
+            }
+        }
+        matches = next;
+    }
+
+    let mut files: Vec<String> = matches
+        .into_iter()
+        .filter(|path| path.is_file())
+        .filter_map(|path| std::path::absolute(&path).ok())
+        .filter_map(|path| Url::from_file_path(path).ok())
+        .map(String::from)
+        .collect();
+    files.sort();
+    Ok(files)
+}
+
+/// Media type and payload of `data:[<mediatype>][;base64],<data>`.
+fn parse_data_url(url: &str) -> Result<(String, Vec<u8>), String> {
+    let invalid = |why: &str| format!("Invalid data: URL ({})", why);
+    if !is_data_url(url) {
+        return Err(invalid("no data: prefix"));
+    }
+    let (header, payload) = url[5..]
+        .split_once(',')
+        .ok_or_else(|| invalid("no comma"))?;
+
+    let (media_type, base64) = match header.len().checked_sub(7) {
+        Some(at) if header[at..].eq_ignore_ascii_case(";base64") => (&header[..at], true),
+        _ => (header, false),
+    };
+    let media_type = if media_type.is_empty() {
+        "text/plain;charset=US-ASCII".to_string()
+    } else {
+        percent_decode_str(media_type)
+            .decode_utf8_lossy()
+            .into_owned()
+    };
+
+    let payload: Vec<u8> = percent_decode_str(payload).collect();
+    let data = if base64 {
+        let encoded: Vec<u8> = payload
+            .into_iter()
+            .filter(|b| !b.is_ascii_whitespace())
+            .collect();
+        base64::engine::general_purpose::STANDARD
+            .decode(&encoded)
+            .map_err(|e| invalid(&e.to_string()))?
+    } else {
+        payload
+    };
+
+    Ok((media_type, data))
+}
+
+impl FileDownloader {
+    /// Size and modification time of the source file, or the decoded size
+    /// and media type of a `data:` URL.
+    pub(super) async fn fetch_local_metadata(
+        &self,
+    ) -> Result<RemoteMetadata, Box<dyn std::error::Error + Send + Sync>> {
+        let (total_size, content_type, last_modified) = if is_data_url(&self.config.url) {
+            let (media_type, data) = parse_data_url(&self.config.url)?;
+            (data.len() as u64, Some(media_type), None)
+        } else {
+            let path = file_path(&self.config.url)?;
+            let meta = tokio::fs::metadata(&path)
+                .await
+                .map_err(|e| format!("{}: {}", path.display(), e))?;
+            if !meta.is_file() {
+                return Err(format!("{} is not a regular file", path.display()).into());
+            }
+            let modified = meta.modified().ok().map(|t| {
+                chrono::DateTime::<chrono::Utc>::from(t)
+                    .format("%a, %d %b %Y %H:%M:%S GMT")
+                    .to_string()
+            });
+            (meta.len(), None, modified)
+        };
+
+        Ok(RemoteMetadata {
+            final_url: self.config.url.clone(),
+            total_size,
+            supports_range: true,
+            content_type,
+            last_modified,
+            etag: None,
+        })
+    }
+
+    pub(super) async fn download_local(
+        &self,
+        run: &RunState,
+        start_pos: u64,
+        pb: ProgressBar,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let part_path = self.part_path();
+        let ctx = self.chunk_context(run, &part_path, &pb);
+
+        let mut file = self.open_part_at(&part_path, start_pos).await?;
+
+        if is_data_url(&self.config.url) {
+            let (_, data) = parse_data_url(&self.config.url)?;
+            let data = data.get(start_pos as usize..).unwrap_or_default();
+            for chunk in data.chunks(64 * 1024) {
+                wait_while_paused(&ctx.control, &pb).await?;
+                file.write_all(chunk).await?;
+                ctx.record(chunk.len() as u64).await?;
+            }
+            file.finish().await?;
+            return Ok(());
+        }
+
+        let path = file_path(&self.config.url)?;
+        info!("Copying {} from byte {}", path.display(), start_pos);
+        let mut source = File::open(&path)
+            .await
+            .map_err(|e| format!("{}: {}", path.display(), e))?;
+        source.seek(SeekFrom::Start(start_pos)).await?;
+
+        let mut buffer = vec![0u8; 64 * 1024];
+        let res: Result<(), Box<dyn std::error::Error + Send + Sync>> = async {
+            loop {
+                wait_while_paused(&ctx.control, &pb).await?;
+                let n = source.read(&mut buffer).await?;
+                if n == 0 {
+                    break;
+                }
+                file.write_all(&buffer[..n]).await?;
+                ctx.record(n as u64).await?;
+            }
+            Ok(())
+        }
+        .await;
+        file.finish().await?;
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn glob_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("grab-test-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        for file in [
+            "a.bin",
+            "b.bin",
+            "c.txt",
+            ".hidden.bin",
+            "sub/d.bin",
+            "sub/e.txt",
+        ] {
+            let path = dir.join(file);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, file).unwrap();
+        }
+        dir
+    }
+
+    fn names(urls: &[String]) -> Vec<String> {
+        urls.iter()
+            .map(|url| {
+                let path = file_path(url).unwrap();
+                path.file_name().unwrap().to_string_lossy().into_owned()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn file_glob_needs_a_wildcard() {
+        assert!(is_file_glob("file:///data/*.bin"));
+        assert!(is_file_glob("FILE:///data/part?.bin"));
+        assert!(!is_file_glob("file:///data/a.bin"));
+        assert!(!is_file_glob("http://host/*.bin"));
+    }
+
+    #[test]
+    fn file_glob_expands_to_sorted_matches() {
+        let dir = glob_dir("glob");
+        let url = Url::from_file_path(&dir).unwrap().to_string() + "/*.bin";
+        let files = expand_file_glob(&url, Path::new("/")).unwrap();
+        // Hidden files and subdirectories aren't matched by `*`
+        assert_eq!(names(&files), ["a.bin", "b.bin"]);
+        assert!(files.iter().all(|url| url.starts_with("file:///")));
+
+        let url = Url::from_file_path(&dir).unwrap().to_string() + "/*/?.bin";
+        assert_eq!(
+            names(&expand_file_glob(&url, Path::new("/")).unwrap()),
+            ["d.bin"]
+        );
+
+        let url = Url::from_file_path(&dir).unwrap().to_string() + "/.*.bin";
+        assert_eq!(
+            names(&expand_file_glob(&url, Path::new("/")).unwrap()),
+            [".hidden.bin"]
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn relative_file_glob_starts_from_base() {
+        let dir = glob_dir("relative-glob");
+        let files = expand_file_glob("file://sub/*", &dir).unwrap();
+        assert_eq!(names(&files), ["d.bin", "e.txt"]);
+        assert!(
+            expand_file_glob("file://missing/*", &dir)
+                .unwrap()
+                .is_empty()
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,17 +1,46 @@
-use clap::Parser;
+mod batch_state;
+mod combine;
+mod config;
+mod console;
+mod cookies;
+mod dedup;
+mod extract;
+mod finish;
+mod ftp;
+mod header_dump;
+mod history;
+mod links;
+mod local;
+mod multipart;
+mod partial;
+mod remote;
+mod simulate;
+mod transfer_log;
+mod tui;
+mod user_agents;
+mod xattrs;
+
+use clap::parser::ValueSource;
+use clap::{CommandFactory, FromArgMatches, Parser};
 use indicatif::{ProgressBar, ProgressStyle};
+use log::{debug, error, info};
+use remote::Plan;
 use reqwest::Client;
-use reqwest::header::{HeaderMap, RANGE};
+use reqwest::header::{
+    ACCEPT_ENCODING, HeaderMap, HeaderName, HeaderValue, ORIGIN, RANGE, REFERER, USER_AGENT,
+};
 use std::net::IpAddr;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use std::time::Duration;
 use tokio::fs::{File, OpenOptions, metadata};
-use tokio::io::{AsyncSeekExt, AsyncWriteExt, SeekFrom};
-use tokio::sync::Semaphore;
+use tokio::io::{AsyncSeekExt, AsyncWriteExt, BufWriter, SeekFrom};
+use tokio::sync::{Semaphore, watch};
+use user_agents::{UaPreset, browser_user_agent, random_user_agent};
 // Hashing algorithms
 use blake2::Blake2b512;
-use blake3;
+use md5::Md5;
 use sha1::Sha1;
 use sha2::{Digest, Sha224, Sha256, Sha384, Sha512};
 use tokio::io::AsyncReadExt;
@@ -19,6 +48,15 @@ use tokio::io::AsyncReadExt;
 #[derive(Parser, Debug)]
 #[command(name = "grab")]
 #[command(about = "Asynchronous file downloader")]
+#[command(after_help = "Exit status:
+  0  Everything succeeded
+  1  Any other failure, or failures of different kinds
+  2  Invalid command line
+  3  Network error: connection, timeout, HTTP error status, cut-off transfer
+  4  Checksum mismatch
+  5  Disk full
+  6  Cancelled with q or Ctrl-C
+  7  --max-time ran out")]
 struct Args {
     /// URLs to download
     #[arg(num_args = 0..)]
@@ -28,42 +66,545 @@ struct Args {
     #[arg(short = 'O', long)]
     output: Option<String>,
 
+    /// Name files from a template: {basename}, {host}, {ext}, {date}, {index}
+    #[arg(long, value_name = "TEMPLATE", conflicts_with = "output")]
+    output_template: Option<String>,
+
+    /// Directory to save into; joined with the derived or `--output` filename
+    #[arg(long, value_name = "DIR")]
+    dir: Option<String>,
+
+    /// Create the output directory (and any parents) if it doesn't exist
+    #[arg(long, default_value_t = false)]
+    create_dirs: bool,
+
+    /// Keep unfinished .part files in DIR (e.g. a fast scratch disk), moving
+    /// each to its destination when done
+    #[arg(long, value_name = "DIR")]
+    temp_dir: Option<String>,
+
+    /// Unpack .tar.gz/.tgz/.zip downloads into DIR as they arrive instead of saving them
+    #[arg(long, value_name = "DIR",
+          conflicts_with_all = ["output", "resume", "continue_at", "range", "chunk_manifest"])]
+    extract: Option<String>,
+
+    /// Cut filenames derived from URLs to N bytes, keeping the extension
+    #[arg(long, value_name = "N", default_value_t = MAX_FILENAME_BYTES,
+          value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    max_filename_length: usize,
+
+    /// Add the URL's query string to derived filenames, e.g. file_v=2.bin
+    #[arg(long)]
+    keep_query: bool,
+
+    /// Give derived filenames without an extension one from the response's
+    /// Content-Type, e.g. 12345.pdf for application/pdf
+    #[arg(long)]
+    add_extension: bool,
+
+    /// Read URLs (each optionally followed by a checksum) from a file, one per line
+    #[arg(short = 'i', long, value_name = "FILE")]
+    input_file: Option<String>,
+
+    /// Download every --input-file entry, including those the batch ledger
+    /// (.grab-batch-state) has as finished in an earlier run
+    #[arg(long, requires = "input_file")]
+    force_redownload: bool,
+
+    /// Read a single URL (the first non-empty line) from stdin
+    #[arg(long, conflicts_with_all = ["urls", "input_file", "history"])]
+    stdin_url: bool,
+
+    /// Print the links found on the given pages instead of downloading them
+    #[arg(long, conflicts_with_all = ["download_links", "spider", "dry_run"])]
+    list_links: bool,
+
+    /// Download the links found on the given pages instead of the pages
+    #[arg(long)]
+    download_links: bool,
+
+    /// Only links whose absolute URL matches this glob, e.g. "*.iso"
+    #[arg(long, value_name = "GLOB")]
+    link_filter: Option<String>,
+
+    /// Don't HEAD every URL up front to size the total progress bar
+    #[arg(long, default_value_t = false)]
+    no_prescan: bool,
+
     /// Resume download
     #[arg(short = 'c', long, default_value_t = false)]
     resume: bool,
 
-    /// Number of concurrent chunks per file
-    #[arg(short = 't', long, default_value_t = 1)]
-    threads: usize,
+    /// Keep the partial file of a failed download for -c (the default)
+    #[arg(long, conflicts_with = "clean_on_error")]
+    keep_partial_on_error: bool,
+
+    /// Delete the partial file of a failed or interrupted download
+    #[arg(long)]
+    clean_on_error: bool,
+
+    /// Only download bytes START-END (inclusive) or START- of the file; with
+    /// several comma-separated ranges, they're saved back to back
+    #[arg(long, value_name = "START-END", value_delimiter = ',', value_parser = parse_byte_range,
+          conflicts_with_all = ["resume", "continue_at", "chunk_manifest"])]
+    range: Vec<ByteRange>,
+
+    /// When resuming, check the partial file first: against --chunk-manifest
+    /// if given, otherwise by comparing its last KB with the server's
+    #[arg(long, default_value_t = false)]
+    verify_resume: bool,
+
+    /// Resume from this byte offset, or `-` to use the length of the existing partial file
+    #[arg(long, value_name = "OFFSET", value_parser = parse_continue_at)]
+    continue_at: Option<ContinueAt>,
+
+    /// Allow writing to a block device (e.g. a disk image to /dev/sdb)
+    #[arg(long, default_value_t = false)]
+    raw_device: bool,
+
+    /// What to do when the output file already exists
+    #[arg(long, value_enum, default_value_t = ExistingPolicy::Overwrite)]
+    existing: ExistingPolicy,
+
+    /// How `--existing rename` names the copy: {name}, {n} (counting from
+    /// 1) and {ext} (with its dot), e.g. "{name}-{n}{ext}"
+    #[arg(long, value_name = "PATTERN", default_value = DEFAULT_RENAME_PATTERN,
+          value_parser = parse_rename_pattern)]
+    rename_pattern: String,
+
+    /// Number of concurrent chunks per file, or `auto` to choose from the
+    /// file's size (up to --max-connections)
+    #[arg(short = 't', long, visible_alias = "connections", value_name = "N|auto",
+          default_value = "1", value_parser = parse_connections)]
+    threads: Connections,
+
+    /// Pick the connections per file while downloading: start with 2 and add
+    /// more while total throughput keeps improving
+    #[arg(long, conflicts_with_all = ["threads", "benchmark", "benchmark_then_download"])]
+    adaptive_connections: bool,
+
+    /// Cut the last stretch of each multi-connection download into smaller
+    /// segments, so the connections finish together instead of one
+    /// straggling; always on with --adaptive-connections
+    #[arg(long)]
+    tail_optimize: bool,
+
+    /// Experimental: deal segments out in turn, worker i fetching segments
+    /// i, i+N, i+2N, ... and no others, instead of sharing one queue
+    #[arg(long, conflicts_with_all = ["adaptive_connections", "tail_optimize"])]
+    interleave: bool,
+
+    /// Most connections per file --adaptive-connections or -t auto may open
+    #[arg(long, value_name = "N", default_value_t = 16, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    max_connections: usize,
 
     /// Number of parallel file downloads
     #[arg(short = 'j', long, default_value_t = 5)]
     parallel_downloads: usize,
 
+    /// Max open connections to any one host, across all files of a batch
+    #[arg(long, value_name = "N")]
+    max_connections_per_host: Option<usize>,
+
     /// Chunk size in bytes
     #[arg(short = 's', long, default_value_t = 1048576)]
     chunk_size: u64,
 
+    /// Buffer this much (e.g. 256K, 1M) before writing to disk; 0 writes every
+    /// network read straight through
+    #[arg(long, value_name = "SIZE", default_value = "256K", value_parser = parse_size)]
+    write_buffer_size: u64,
+
+    /// Cap on the bytes all connections together hold in write buffers (e.g.
+    /// 8M); a connection whose data can't be buffered waits for the disk
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    max_buffer: Option<u64>,
+
+    /// Testing only: inject latency, a per-connection rate cap and dropped
+    /// connections, e.g. "latency=200ms,rate=256K,drop-at=40%,drop-every=1M"
+    #[arg(long, hide = true, value_name = "SPEC", value_parser = simulate::parse)]
+    simulate: Option<simulate::Simulation>,
+
+    /// When to force written data onto the disk
+    #[arg(long, value_enum, default_value_t = FsyncMode::Final)]
+    fsync: FsyncMode,
+
     /// User Agent string
-    #[arg(short = 'u', long, default_value = "Grab/2.0")]
+    #[arg(short = 'u', long, default_value = DEFAULT_USER_AGENT)]
     user_agent: String,
 
+    /// Use a random browser User-Agent for each download
+    #[arg(long, conflicts_with = "user_agent")]
+    random_user_agent: bool,
+
+    /// Pick a new random browser User-Agent for every chunk request
+    #[arg(long, conflicts_with = "user_agent")]
+    rotate_user_agent: bool,
+
+    /// Send the User-Agent of a current release of this client
+    #[arg(long, value_enum, value_name = "CLIENT", conflicts_with_all = ["user_agent", "random_user_agent", "rotate_user_agent"])]
+    ua_preset: Option<UaPreset>,
+
     /// Timeout in seconds
     #[arg(short = 'T', long, default_value = "30", value_parser = parse_duration)]
     timeout: Duration,
 
+    /// Start a failed download over from scratch up to N times
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    retries: u32,
+
+    /// Tries for each request: a chunk, or the whole file when sequential
+    #[arg(long, value_name = "N", default_value_t = 4,
+          value_parser = clap::value_parser!(u32).range(1..))]
+    tries_per_chunk: u32,
+
+    /// Give up on a file after N retries in all, however they're spread over
+    /// its chunks and restarts
+    #[arg(long, value_name = "N")]
+    max_total_retries: Option<u64>,
+
+    /// HTTP statuses that are worth a retry
+    #[arg(
+        long,
+        value_name = "CODES",
+        value_delimiter = ',',
+        default_value = "408,429,500,502,503,504"
+    )]
+    retry_on_status: Vec<u16>,
+
+    /// Another URL serving the same file, tried in turn by
+    /// --rotate-on-failure; repeatable
+    #[arg(long, value_name = "URL", requires = "rotate_on_failure")]
+    mirror: Vec<String>,
+
+    /// Make each --retries restart move to the next of the URL and its
+    /// --mirror URLs, with the next browser User-Agent unless one was chosen,
+    /// keeping what was downloaded when the sizes match
+    #[arg(long, conflicts_with = "range")]
+    rotate_on_failure: bool,
+
+    /// Retry on every error status (including other 4xx) and any transport error
+    #[arg(long, default_value_t = false)]
+    retry_all_errors: bool,
+
+    /// Abort a download whose speed stays below this (e.g. 10K) for --min-speed-time
+    #[arg(long, value_name = "RATE", value_parser = parse_bandwidth)]
+    min_speed: Option<u64>,
+
+    /// How long (seconds) a download may stay below --min-speed
+    #[arg(long, value_name = "SECONDS", default_value = "30", value_parser = parse_duration)]
+    min_speed_time: Duration,
+
+    /// Give up on everything still running after this many seconds
+    #[arg(long, value_name = "SECONDS", value_parser = parse_duration)]
+    max_time: Option<Duration>,
+
+    /// Refuse files larger than this (e.g. 2G), and stop one of unknown
+    /// size once it grows past it
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    max_file_size: Option<u64>,
+
     /// Bandwidth limit (e.g. 512K, 1M, 2M)
     #[arg(short = 'l', long, value_parser = parse_bandwidth)]
     limit_rate: Option<u64>,
 
+    /// Bandwidth limits by local time, e.g. "22:00-06:00:unlimited,06:00-22:00:500K";
+    /// --limit-rate applies outside the windows
+    #[arg(long, value_name = "WINDOWS", value_parser = parse_schedule)]
+    schedule: Option<Schedule>,
+
+    /// Pace each download to finish in roughly this many minutes
+    #[arg(long, value_name = "MINUTES", value_parser = parse_minutes)]
+    target_duration: Option<Duration>,
+
+    /// HTTP version to use. With HTTP/2, all connections of a file may be
+    /// multiplexed over a single TCP connection
+    #[arg(long, value_enum, default_value_t = HttpVersion::Auto)]
+    http_version: HttpVersion,
+
+    /// Close connections left idle this many seconds; while open, they're
+    /// reused by the next chunk or file from the same host
+    #[arg(long, value_name = "SECONDS", default_value = "90", value_parser = parse_duration)]
+    pool_idle_timeout: Duration,
+
+    /// Keep at most this many idle connections per host (default: no limit)
+    #[arg(long, value_name = "N")]
+    pool_max_idle_per_host: Option<usize>,
+
+    /// Send small packets without waiting to coalesce them (TCP_NODELAY)
+    #[arg(long, value_name = "BOOL", default_value_t = true, action = clap::ArgAction::Set)]
+    tcp_nodelay: bool,
+
     /// Force IPv4 only
-    #[arg(short = '4', long, conflicts_with = "inet6_only")]
+    #[arg(
+        short = '4',
+        long,
+        visible_alias = "ipv4",
+        conflicts_with = "inet6_only"
+    )]
     inet4_only: bool,
 
     /// Force IPv6 only
-    #[arg(short = '6', long, conflicts_with = "inet4_only")]
+    #[arg(
+        short = '6',
+        long,
+        visible_alias = "ipv6",
+        conflicts_with = "inet4_only"
+    )]
     inet6_only: bool,
 
+    /// Additional root certificate(s) to trust, in PEM format
+    #[arg(long, value_name = "PATH")]
+    cacert: Option<String>,
+
+    /// Client certificate for TLS authentication, in PEM format
+    #[arg(long, value_name = "PEM")]
+    cert: Option<String>,
+
+    /// Private key for --cert, in PEM format (if not bundled in the cert file)
+    #[arg(long, value_name = "PEM", requires = "cert")]
+    key: Option<String>,
+
+    /// Skip TLS certificate verification. This makes the connection
+    /// vulnerable to man-in-the-middle attacks; only use it for servers
+    /// you control
+    #[arg(short = 'k', long)]
+    insecure: bool,
+
+    /// Extra request header, as "Name: Value" (repeatable)
+    #[arg(short = 'H', long, value_name = "NAME: VALUE")]
+    header: Vec<String>,
+
+    /// Read extra headers from a file of "Name: Value" lines; --header wins on conflict
+    #[arg(long, value_name = "FILE")]
+    headers_file: Option<String>,
+
+    /// Referer to send, or "auto" for the origin of each download's URL;
+    /// a Referer given with --header wins
+    #[arg(long, value_name = "URL")]
+    referer: Option<String>,
+
+    /// Origin to send; an Origin given with --header wins
+    #[arg(long, value_name = "URL")]
+    origin: Option<String>,
+
+    /// Method of the download request. Anything but GET is sent as a single
+    /// request, without ranges
+    #[arg(long, value_enum, ignore_case = true, default_value_t = RequestMethod::Get)]
+    method: RequestMethod,
+
+    /// Body of the download request; implies --method POST
+    #[arg(long, value_name = "BODY", conflicts_with = "data_file")]
+    data: Option<String>,
+
+    /// Like --data, with the body read from a file
+    #[arg(long, value_name = "FILE")]
+    data_file: Option<String>,
+
+    /// Send a cookie, as name=value (repeatable)
+    #[arg(short = 'b', long, value_name = "NAME=VALUE")]
+    cookie: Vec<String>,
+
+    /// Load cookies from a Netscape-format cookie file
+    #[arg(long, value_name = "FILE")]
+    cookie_jar: Option<String>,
+
+    /// Maximum number of redirects to follow
+    #[arg(long, default_value_t = 10, conflicts_with = "no_redirects")]
+    max_redirects: usize,
+
+    /// Do not follow redirects
+    #[arg(long)]
+    no_redirects: bool,
+
+    /// Ask for a gzip, deflate or brotli response and decompress it; always
+    /// uses one connection, as ranges of compressed data can't be stitched
+    #[arg(long, conflicts_with_all = ["range", "continue_at"])]
+    compressed: bool,
+
+    /// Send `Accept-Encoding: identity`, so servers that compress by default
+    /// send the file as is
+    #[arg(long, conflicts_with = "compressed")]
+    no_compressed: bool,
+
+    /// Verify against `<url>.sha256` (or `.sha1`, `.md5`) when the server has one
+    #[arg(long, default_value_t = false)]
+    auto_checksum: bool,
+
+    /// Check an already downloaded file against the checksum given after it
+    /// (e.g. sha256:HEX), or with --auto-checksum against the one next to the
+    /// URL given; exits non-zero on a mismatch
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["dry_run", "spider", "input_file", "stdin_url"])]
+    test_integrity: Option<String>,
+
+    /// Join these part files, in order, into --output instead of downloading.
+    /// A checksum after them (e.g. sha256:HEX) is verified on the result,
+    /// and a --chunk-manifest checks each part's range and hash
+    #[arg(long, value_name = "PART", num_args = 1.., requires = "output",
+          conflicts_with_all = ["urls", "input_file", "stdin_url", "test_integrity", "history", "dry_run", "spider", "extract"])]
+    combine: Vec<String>,
+
+    /// Manifest of per-range SHA-256 hashes (`start-end:sha256` per line)
+    #[arg(long, value_name = "FILE")]
+    chunk_manifest: Option<String>,
+
+    /// Write each chunk's range, timing, speed and retries to this CSV file
+    #[arg(long, value_name = "FILE")]
+    chunk_timings: Option<String>,
+
+    /// Write the request and response headers of each file's HEAD and
+    /// first GET to this file, curl -v style, with credentials redacted
+    #[arg(long, value_name = "FILE")]
+    dump_headers: Option<String>,
+
+    /// With --dump-headers, record every request, including each chunk and retry
+    #[arg(long, requires = "dump_headers")]
+    dump_all_headers: bool,
+
+    /// With --dump-headers, leave Authorization and cookies in the dump
+    #[arg(long, requires = "dump_headers")]
+    dump_secrets: bool,
+
+    /// Append timestamped download events (start, chunks, retries, result)
+    /// to this file
+    #[arg(long, value_name = "FILE")]
+    log_file: Option<String>,
+
+    /// Rotate the log file before it grows past this size (e.g. 10M)
+    #[arg(long, value_name = "SIZE", value_parser = parse_size, requires = "log_file")]
+    log_max_size: Option<u64>,
+
+    /// How many rotated log files to keep, FILE.1 being the newest
+    #[arg(long, value_name = "N", default_value_t = 3)]
+    log_rotate: usize,
+
+    /// Post-processing step to run after a successful download; repeat for a
+    /// pipeline. Each gets GRAB_OUTPUT and may print a new path for the next
+    #[arg(long = "post", value_name = "COMMAND")]
+    post: Vec<String>,
+
+    /// Shell command to run after a successful download (receives GRAB_URL,
+    /// GRAB_OUTPUT, GRAB_SIZE, GRAB_SHA256 and GRAB_STATUS in its environment)
+    #[arg(long, value_name = "COMMAND")]
+    on_complete: Option<String>,
+
+    /// URL to POST a JSON summary to after a successful download
+    #[arg(long, value_name = "URL")]
+    webhook: Option<String>,
+
+    /// Run --on-complete and --webhook after failed downloads too
+    #[arg(long)]
+    on_complete_always: bool,
+
+    /// Show a desktop notification when each download finishes
+    #[cfg(feature = "notify")]
+    #[arg(long)]
+    notify: bool,
+
+    /// Pick a recent download from the history to repeat
+    #[arg(long)]
+    history: bool,
+
+    /// Don't record completed downloads in the history file
+    #[arg(long)]
+    no_history: bool,
+
+    /// Units for displaying sizes and speeds
+    #[arg(long, value_enum, default_value_t = Units::Binary)]
+    units: Units,
+
+    /// Look of the progress bars
+    #[arg(long, value_enum, default_value_t = BarStyle::Unicode)]
+    progress_style: BarStyle,
+
+    /// indicatif template for each file's line, e.g. "{prefix} {percent}% {eta}"
+    #[arg(long, value_name = "TEMPLATE")]
+    progress_template: Option<String>,
+
+    /// Draw the progress bars even when stderr isn't a terminal, instead of
+    /// a progress line every few seconds
+    #[arg(long)]
+    force_progress: bool,
+
+    /// Full-screen view with each connection's progress, a speed graph and
+    /// retry counts; plain bars when not on a terminal
+    #[arg(long, conflicts_with_all = ["quiet", "status_only", "force_progress", "dry_run", "spider", "metadata_json"])]
+    tui: bool,
+
+    /// Keep this file updated with the overall progress as JSON, for another
+    /// program to show; removed at the end
+    #[arg(long, value_name = "FILE")]
+    progress_file: Option<String>,
+
+    /// Leave the --progress-file in place at the end, with how the run ended
+    #[arg(long, requires = "progress_file")]
+    keep_progress_file: bool,
+
+    /// Skip files that already exist and match the remote size, ETag or Last-Modified
+    #[arg(long)]
+    no_clobber: bool,
+
+    /// Keep finished files in this content-addressed store, linking a file
+    /// whose content is already there to the stored copy instead
+    #[arg(long, value_name = "DIR")]
+    dedup_dir: Option<String>,
+
+    /// How a duplicate is linked to the store
+    #[arg(long, value_enum, default_value_t = dedup::LinkMode::Hard, requires = "dedup_dir")]
+    dedup_link: dedup::LinkMode,
+
+    /// Record the source URL, ETag and download time on each finished file
+    /// as extended attributes (user.grab.url, user.grab.etag, user.grab.date)
+    #[arg(long)]
+    store_xattr: bool,
+
+    /// Only download if changed since this HTTP date, or since this file was
+    /// last modified
+    #[arg(long, visible_alias = "newer-than", value_name = "DATE|FILE")]
+    if_modified_since: Option<String>,
+
+    /// Fail unless the server reports this Content-Type (e.g. application/zip or image/*)
+    #[arg(long, value_name = "MIME")]
+    expect_type: Option<String>,
+
+    /// Size (e.g. 700M) to show progress against for files whose size the
+    /// server doesn't report, as with chunked transfers; only a hint
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    expected_size: Option<u64>,
+
+    /// No progress bars or summary; only errors are printed
+    #[arg(short = 'q', long, default_value_t = false)]
+    quiet: bool,
+
+    /// Like --quiet, but print one line per file to stdout when it's done:
+    /// `OK PATH SIZE` or `FAIL URL: REASON`. Errors still go to stderr
+    #[arg(long, conflicts_with_all = ["dry_run", "metadata_json", "spider", "list_links", "benchmark"])]
+    status_only: bool,
+
+    /// Show remote file information without downloading
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Like --dry-run, but print one JSON object per URL
+    #[arg(long, conflicts_with_all = ["dry_run", "spider"])]
+    metadata_json: bool,
+
+    /// Only check that each URL is reachable (status, size, type); exit non-zero if any isn't
+    #[arg(long, conflicts_with = "dry_run")]
+    spider: bool,
+
+    /// Measure throughput of the first 10 MB with 1, 2, 4 and 8 connections, then exit
+    #[arg(long)]
+    benchmark: bool,
+
+    /// Run the benchmark, then download with the fastest connection count
+    #[arg(long)]
+    benchmark_then_download: bool,
+
+    /// Config file with default settings [default: ~/.config/grab/config.toml]
+    #[arg(long, value_name = "PATH")]
+    config: Option<String>,
+
     /// Version
     #[arg(short = 'V', long)]
     version: bool,
@@ -87,615 +628,6123 @@ fn parse_bandwidth(arg: &str) -> Result<u64, String> {
         .map_err(|e| format!("Invalid bandwidth limit: {}", e))
 }
 
-fn parse_duration(arg: &str) -> Result<Duration, std::num::ParseIntError> {
-    let seconds = arg.parse::<u64>()?;
-    Ok(Duration::from_secs(seconds))
+/// Adds `--cacert`, `--cert`/`--key` and `--insecure` to a client.
+fn with_tls_options(
+    mut builder: reqwest::ClientBuilder,
+    cacert: Option<&str>,
+    client_cert: Option<&str>,
+    client_key: Option<&str>,
+    insecure: bool,
+) -> Result<reqwest::ClientBuilder, Box<dyn std::error::Error + Send + Sync>> {
+    if let Some(path) = cacert {
+        let pem = std::fs::read(path)
+            .map_err(|e| format!("Failed to read CA certificate {}: {}", path, e))?;
+        let certs = reqwest::Certificate::from_pem_bundle(&pem)
+            .map_err(|e| format!("Invalid CA certificate {}: {}", path, e))?;
+        builder = builder.tls_certs_merge(certs);
+    }
+
+    if let Some(cert_path) = client_cert {
+        let mut pem = std::fs::read(cert_path)
+            .map_err(|e| format!("Failed to read client certificate {}: {}", cert_path, e))?;
+        if let Some(key_path) = client_key {
+            let key = std::fs::read(key_path)
+                .map_err(|e| format!("Failed to read client key {}: {}", key_path, e))?;
+            pem.push(b'\n');
+            pem.extend_from_slice(&key);
+        }
+        let identity = reqwest::Identity::from_pem(&pem)
+            .map_err(|e| format!("Invalid client certificate or key: {}", e))?;
+        builder = builder.identity(identity);
+    }
+
+    if insecure {
+        builder = builder.tls_danger_accept_invalid_certs(true);
+    }
+
+    Ok(builder)
 }
 
-#[derive(Debug, Clone)]
-enum Checksum {
-    Sha1(String),
-    Sha224(String),
-    Sha256(String),
-    Sha384(String),
-    Sha512(String),
-    Blake2b(String),
-    Blake3(String),
+/// `--test-integrity`: hashes `path` and compares it with the checksum among
+/// the positional arguments, or the one `--auto-checksum` finds next to the
+/// URL among them. Only the checksum file is ever fetched.
+async fn test_integrity(
+    args: &Args,
+    path: &str,
+) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
+    metadata(path)
+        .await
+        .map_err(|e| format!("Failed to read {}: {}", path, e))?;
+
+    let mut checksum = args.urls.iter().find_map(|arg| Checksum::parse(arg));
+    if checksum.is_none()
+        && args.auto_checksum
+        && let Some(url) = args.urls.first()
+    {
+        let client = with_tls_options(
+            Client::builder()
+                .user_agent(&args.user_agent)
+                .connect_timeout(args.timeout),
+            args.cacert.as_deref(),
+            args.cert.as_deref(),
+            args.key.as_deref(),
+            args.insecure,
+        )?
+        .build()?;
+        checksum = find_sibling_checksum(&client, url, args.timeout).await;
+        if checksum.is_none() {
+            return Err(format!("No usable .sha256/.sha1/.md5 file next to {}", url).into());
+        }
+    }
+    let checksum = checksum.ok_or(
+        "--test-integrity needs a checksum (e.g. sha256:HEX) or --auto-checksum with the file's URL",
+    )?;
+
+    verify_checksum(&checksum, path, None).await
 }
 
-impl Checksum {
-    fn parse(s: &str) -> Option<Self> {
-        let parts: Vec<&str> = s.splitn(2, ':').collect();
-        if parts.len() != 2 {
-            return None;
+/// Fetches each page and returns the links on it that match `filter`, for
+/// `--list-links` and `--download-links`.
+async fn collect_links(
+    args: &Args,
+    pages: &[(String, Option<Checksum>)],
+    headers: &HeaderMap,
+    cookie_jar: Option<&Arc<reqwest::cookie::Jar>>,
+) -> Result<Vec<String>, Box<dyn std::error::Error + Send + Sync>> {
+    let mut builder = Client::builder()
+        .user_agent(&args.user_agent)
+        .default_headers(headers.clone())
+        .connect_timeout(args.timeout)
+        .read_timeout(args.timeout)
+        .gzip(args.compressed)
+        .deflate(args.compressed)
+        .brotli(args.compressed)
+        .redirect(if args.no_redirects {
+            reqwest::redirect::Policy::none()
+        } else {
+            reqwest::redirect::Policy::limited(args.max_redirects)
+        });
+    if let Some(jar) = cookie_jar {
+        builder = builder.cookie_provider(jar.clone());
+    }
+    let client = with_tls_options(
+        builder,
+        args.cacert.as_deref(),
+        args.cert.as_deref(),
+        args.key.as_deref(),
+        args.insecure,
+    )?
+    .build()?;
+
+    let mut links: Vec<String> = Vec::new();
+    for (page, _) in pages {
+        debug!("GET {} (links)", page);
+        let response = client
+            .get(page)
+            .send()
+            .await
+            .map_err(|e| format!("{}: {}", page, e))?;
+        if !response.status().is_success() {
+            return Err(
+                format!("{}: {}", page, DownloadError::HttpStatus(response.status())).into(),
+            );
         }
-        let hash_type = parts[0].to_lowercase();
-        let hash = parts[1].to_string();
-        match hash_type.as_str() {
-            "sha1" | "sha1sum" => Some(Checksum::Sha1(hash)),
-            "sha224" | "sha224sum" => Some(Checksum::Sha224(hash)),
-            "sha256" | "sha256sum" => Some(Checksum::Sha256(hash)),
-            "sha384" | "sha384sum" => Some(Checksum::Sha384(hash)),
-            "sha512" | "sha512sum" => Some(Checksum::Sha512(hash)),
-            "b2sum" | "blake2" => Some(Checksum::Blake2b(hash)),
-            "b3sum" | "blake3" => Some(Checksum::Blake3(hash)),
-            _ => None,
+        // Relative links resolve against where redirects ended up
+        let base = response.url().clone();
+        let html = response.text().await?;
+        let found = links::extract_links(&html, &base);
+        debug!("{}: {} links", page, found.len());
+        for link in found {
+            let link = link.to_string();
+            let wanted = args
+                .link_filter
+                .as_deref()
+                .is_none_or(|pattern| links::glob_match(pattern, &link));
+            if wanted && !links.contains(&link) {
+                links.push(link);
+            }
         }
     }
+    Ok(links)
 }
 
-#[derive(Debug)]
-struct DownloadConfig {
-    url: String,
-    output_path: String,
-    concurrent_chunks: usize,
-    chunk_size: u64,
-    resume: bool,
-    user_agent: String,
-    timeout: Duration,
-    force_ipv4: bool,
-    force_ipv6: bool,
-    checksum: Option<Checksum>,
+/// `scheme://host[:port]/` of `url`, as a header value for `--referer auto`
+fn url_origin(url: &str) -> Option<HeaderValue> {
+    let origin = reqwest::Url::parse(url).ok()?.origin();
+    if !origin.is_tuple() {
+        return None;
+    }
+    HeaderValue::from_str(&format!("{}/", origin.ascii_serialization())).ok()
 }
 
-struct BandwidthLimiter {
-    bytes_per_second: u64,
-    start_instant: tokio::time::Instant,
-    total_bytes_transferred: std::sync::atomic::AtomicU64,
+/// `command` run through the platform shell
+fn shell_command(command: &str) -> tokio::process::Command {
+    #[cfg(windows)]
+    let mut cmd = tokio::process::Command::new("cmd");
+    #[cfg(windows)]
+    cmd.arg("/C").arg(command);
+    #[cfg(not(windows))]
+    let mut cmd = tokio::process::Command::new("sh");
+    #[cfg(not(windows))]
+    cmd.arg("-c").arg(command);
+    cmd
 }
 
-impl BandwidthLimiter {
-    fn new(bytes_per_second: u64) -> Self {
-        Self {
-            bytes_per_second,
-            start_instant: tokio::time::Instant::now(),
-            total_bytes_transferred: std::sync::atomic::AtomicU64::new(0),
-        }
+/// A byte count with the same suffixes as a bandwidth limit
+fn parse_size(arg: &str) -> Result<u64, String> {
+    parse_bandwidth(arg).map_err(|_| format!("Invalid size: {}", arg))
+}
+
+/// `-t`: a fixed number of connections per file, or `auto`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Connections {
+    Count(usize),
+    Auto,
+}
+
+fn parse_connections(arg: &str) -> Result<Connections, String> {
+    if arg.eq_ignore_ascii_case("auto") {
+        return Ok(Connections::Auto);
     }
+    arg.parse()
+        .map(Connections::Count)
+        .map_err(|_| format!("Expected a number of connections or auto: {}", arg))
+}
 
-    async fn throttle(&self, bytes: u64) {
-        if self.bytes_per_second == 0 {
-            return;
-        }
+/// What `-t auto` opens for a file of `size` bytes: one below 1 MiB,
+/// doubling with every tenfold size after that, and no more than `max`.
+/// One when the server can't serve ranges or the size is unknown.
+fn auto_connections(size: u64, supports_range: bool, max: usize) -> usize {
+    const MIB: u64 = 1024 * 1024;
+    let connections = if !supports_range || size < MIB {
+        1
+    } else if size < 10 * MIB {
+        2
+    } else if size < 100 * MIB {
+        4
+    } else if size < 1000 * MIB {
+        8
+    } else {
+        16
+    };
+    connections.min(max).max(1)
+}
 
-        let total = self
-            .total_bytes_transferred
-            .fetch_add(bytes, std::sync::atomic::Ordering::Relaxed)
-            + bytes;
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum FsyncMode {
+    /// Leave it to the OS
+    None,
+    /// Once the file is complete, before it's moved into place
+    Final,
+    /// Also every --chunk-size bytes, so a resume after a crash never
+    /// builds on data that didn't reach the disk
+    PerChunk,
+}
 
-        let elapsed = self.start_instant.elapsed();
-        let expected_duration =
-            Duration::from_secs_f64(total as f64 / self.bytes_per_second as f64);
+impl FsyncMode {
+    /// Whether a finished file is synced before it's moved into place.
+    fn syncs_final(self) -> bool {
+        self != FsyncMode::None
+    }
 
-        if elapsed < expected_duration {
-            tokio::time::sleep(expected_duration - elapsed).await;
-        }
+    /// How many bytes may be written between syncs, if data is synced
+    /// along the way.
+    fn interval(self, chunk_size: u64) -> Option<u64> {
+        (self == FsyncMode::PerChunk).then_some(chunk_size.max(1))
     }
 }
-struct DownloadState {
-    total_files: usize,
-    finished_files: std::sync::atomic::AtomicUsize,
-    total_pb: ProgressBar,
-}
 
-struct FileDownloader {
-    client: Client,
-    config: Arc<DownloadConfig>,
-    limiter: Option<Arc<BandwidthLimiter>>,
-    multi_progress: indicatif::MultiProgress,
-    state: Arc<DownloadState>,
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum ExistingPolicy {
+    /// Leave the existing file alone and move on
+    Skip,
+    /// Replace the existing file
+    Overwrite,
+    /// Save under a new name: `file (1).ext`, `file (2).ext`, ...
+    Rename,
+    /// Continue a partial download (same as -c)
+    Resume,
 }
 
-impl FileDownloader {
-    fn new(
-        config: DownloadConfig,
-        multi_progress: indicatif::MultiProgress,
-        limiter: Option<Arc<BandwidthLimiter>>,
-        state: Arc<DownloadState>,
-    ) -> Self {
-        let mut builder = Client::builder()
-            .user_agent(&config.user_agent)
-            .connect_timeout(config.timeout);
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum HttpVersion {
+    /// HTTP/1.1 only
+    #[value(name = "1.1")]
+    Http1,
+    /// HTTP/2 with prior knowledge (no upgrade negotiation)
+    #[value(name = "2")]
+    Http2,
+    /// Negotiate with the server
+    Auto,
+}
 
-        if config.force_ipv4 {
-            builder = builder.local_address(IpAddr::V4(std::net::Ipv4Addr::UNSPECIFIED));
-        } else if config.force_ipv6 {
-            builder = builder.local_address(IpAddr::V6(std::net::Ipv6Addr::UNSPECIFIED));
+impl HttpVersion {
+    /// Makes a client speak this version.
+    fn apply(self, builder: reqwest::ClientBuilder) -> reqwest::ClientBuilder {
+        match self {
+            HttpVersion::Http1 => builder.http1_only(),
+            HttpVersion::Http2 => builder.http2_prior_knowledge(),
+            HttpVersion::Auto => builder,
         }
+    }
+}
 
-        let client = builder.build().expect("Failed to create HTTP client");
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+#[value(rename_all = "UPPER")]
+enum RequestMethod {
+    Get,
+    Post,
+    Put,
+}
 
-        Self {
-            client,
-            config: Arc::new(config),
-            limiter,
-            multi_progress,
-            state,
+impl RequestMethod {
+    fn as_reqwest(self) -> reqwest::Method {
+        match self {
+            RequestMethod::Get => reqwest::Method::GET,
+            RequestMethod::Post => reqwest::Method::POST,
+            RequestMethod::Put => reqwest::Method::PUT,
         }
     }
+}
 
-    async fn download(&self) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-        let url = &self.config.url;
-        let output_path = &self.config.output_path;
-        let filename = Path::new(output_path)
-            .file_name()
-            .and_then(|n| n.to_str())
-            .unwrap_or("file");
-
-        let response = self.client.head(url).send().await?;
-        let total_size = response
-            .headers()
-            .get(reqwest::header::CONTENT_LENGTH)
-            .and_then(|ct_len| ct_len.to_str().ok())
-            .and_then(|ct_len| ct_len.parse().ok())
-            .unwrap_or(0);
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum Units {
+    /// 1024-based, labeled KiB/MiB/GiB
+    Binary,
+    /// 1000-based, labeled KB/MB/GB
+    Decimal,
+}
 
-        if total_size > 0 {
-            self.state.total_pb.inc_length(total_size);
+impl Units {
+    /// Prefix selecting the matching indicatif byte keys, e.g. `{binary_bytes}`
+    fn template_prefix(self) -> &'static str {
+        match self {
+            Units::Binary => "binary_",
+            Units::Decimal => "decimal_",
         }
+    }
+}
 
-        let pb = self.multi_progress.insert(0, ProgressBar::new(total_size));
-        pb.set_style(
-            ProgressStyle::default_bar()
-                .template(&format!(" {{prefix:<28}} {{bytes:>10}}/{{total_bytes:<10}} {{bytes_per_sec:>12}} {{eta:>6}} [{{wide_bar}}] {{percent:>3}}% {{msg}}"))
-                .unwrap()
-                .progress_chars("---c  o "),
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum BarStyle {
+    /// The default bar
+    Unicode,
+    /// A `###>---` bar, for terminals with poor font support
+    Ascii,
+    /// Just the percentage
+    Minimal,
+    /// Sizes, speed and time left, without a bar
+    None,
+}
+
+impl BarStyle {
+    /// indicatif template for a file's line, or with `total`, the total line
+    fn template(self, units: Units, total: bool) -> String {
+        let numbers = format!(
+            "{{{u}bytes:>10}}/{{{u}total_bytes:<10}} {{{u}bytes_per_sec:>12}} {{eta:>8}}",
+            u = units.template_prefix()
         );
-        pb.set_prefix(filename.to_string());
+        match (self, total) {
+            (BarStyle::Minimal, false) => " {prefix:<28} {percent:>3}% {msg}".to_string(),
+            (BarStyle::Minimal, true) => "Total {msg:<22} {percent:>3}%".to_string(),
+            (BarStyle::None, false) => {
+                format!(" {{prefix:<28}} {} {{percent:>3}}% {{msg}}", numbers)
+            }
+            (BarStyle::None, true) => format!("Total {{msg:<22}} {} {{percent:3}}%", numbers),
+            (_, false) => format!(
+                " {{prefix:<28}} {} [{{wide_bar}}] {{percent:>3}}% {{msg}}",
+                numbers
+            ),
+            (_, true) => format!(
+                "Total {{msg:<22}} {} [ {{wide_bar}} ] {{percent:3}}%",
+                numbers
+            ),
+        }
+    }
 
-        if total_size == 0 {
-            return self.download_single_threaded(0, pb).await;
+    fn progress_chars(self) -> &'static str {
+        match self {
+            BarStyle::Ascii => "#>-",
+            _ => "---c  o ",
         }
+    }
+}
 
-        let supports_range = response
-            .headers()
-            .get(reqwest::header::ACCEPT_RANGES)
-            .map(|h| h == "bytes")
-            .unwrap_or(false);
+/// The style of each file's bar, or with `total`, of the total bar; every
+/// bar adds its own `{eta}` key. `--progress-template` only replaces the
+/// file lines.
+fn bar_style(args: &Args, total: bool) -> Result<ProgressStyle, String> {
+    let template = match args.progress_template {
+        Some(ref template) if !total => template.clone(),
+        _ => args.progress_style.template(args.units, total),
+    };
+    Ok(ProgressStyle::default_bar()
+        .template(&template)
+        .map_err(|e| format!("Invalid --progress-template {:?}: {}", template, e))?
+        .progress_chars(args.progress_style.progress_chars()))
+}
 
-        let part_path = format!("{}.part", output_path);
-        let mut already_downloaded = 0u64;
-        let file_exists = Path::new(output_path).exists();
-        let part_exists = Path::new(&part_path).exists();
+/// The style of a file's line while its size is unknown: what's arrived
+/// and how fast, with a spinner where the bar and percentage would be.
+/// `--progress-template` is kept as given.
+fn spinner_style(args: &Args) -> Result<ProgressStyle, String> {
+    if args.progress_template.is_some() {
+        return bar_style(args, false);
+    }
+    let template = match args.progress_style {
+        BarStyle::Minimal => " {prefix:<28} {spinner} {msg}".to_string(),
+        _ => format!(
+            " {{prefix:<28}} {{{u}bytes:>10}} {{{u}bytes_per_sec:>12}} {{spinner}} {{msg}}",
+            u = args.units.template_prefix()
+        ),
+    };
+    Ok(ProgressStyle::default_spinner()
+        .template(&template)
+        .map_err(|e| e.to_string())?
+        .tick_chars(match args.progress_style {
+            BarStyle::Ascii => "|/-\\ ",
+            _ => "⠋⠙⠹⠸⠼⠴⠦⠧⠇⠏ ",
+        }))
+}
 
-        if self.config.resume {
-            if file_exists {
-                if let Ok(meta) = metadata(output_path).await {
-                    if meta.len() >= total_size {
-                        pb.finish_with_message("Completed");
-                        return Ok(());
-                    }
-                }
-            }
-            if part_exists {
-                if let Ok(meta) = metadata(&part_path).await {
-                    already_downloaded = meta.len();
-                    pb.set_position(already_downloaded);
-                    self.state.total_pb.inc(already_downloaded);
-                }
-            }
-        }
+fn format_bytes(bytes: u64, units: Units) -> String {
+    let (base, labels) = match units {
+        Units::Binary => (1024.0, ["B", "KiB", "MiB", "GiB", "TiB"]),
+        Units::Decimal => (1000.0, ["B", "KB", "MB", "GB", "TB"]),
+    };
 
-        if !part_exists || !self.config.resume {
-            File::create(&part_path).await?;
-        }
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= base && unit < labels.len() - 1 {
+        value /= base;
+        unit += 1;
+    }
 
-        let res = if supports_range && !self.config.resume && total_size > self.config.chunk_size {
-            self.download_multi_threaded(total_size, pb.clone()).await
-        } else {
-            self.download_single_threaded(already_downloaded, pb.clone())
-                .await
+    if unit == 0 {
+        format!("{} {}", bytes, labels[0])
+    } else {
+        format!("{:.2} {}", value, labels[unit])
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum ContinueAt {
+    /// Continue from the end of the existing `.part` file
+    Auto,
+    Offset(u64),
+}
+
+fn parse_continue_at(arg: &str) -> Result<ContinueAt, String> {
+    if arg == "-" {
+        return Ok(ContinueAt::Auto);
+    }
+    arg.parse::<u64>()
+        .map(ContinueAt::Offset)
+        .map_err(|e| format!("Invalid offset: {}", e))
+}
+
+#[derive(Debug, Clone, Copy)]
+struct ByteRange {
+    start: u64,
+    /// Inclusive; `None` runs to the end of the file
+    end: Option<u64>,
+}
+
+fn parse_byte_range(arg: &str) -> Result<ByteRange, String> {
+    let (start, end) = arg
+        .split_once('-')
+        .ok_or_else(|| format!("Invalid range {} (expected START-END or START-)", arg))?;
+    let start = start
+        .parse::<u64>()
+        .map_err(|e| format!("Invalid range start: {}", e))?;
+    let end = match end {
+        "" => None,
+        end => Some(
+            end.parse::<u64>()
+                .map_err(|e| format!("Invalid range end: {}", e))?,
+        ),
+    };
+    if end.is_some_and(|end| end < start) {
+        return Err(format!("Range end is before its start: {}", arg));
+    }
+    Ok(ByteRange { start, end })
+}
+
+/// `--schedule`: rate limits for windows of the day, in local time
+#[derive(Debug, Clone)]
+struct Schedule {
+    windows: Vec<ScheduleWindow>,
+    /// For times outside every window, if given as a bare rate
+    default: Option<u64>,
+}
+
+#[derive(Debug, Clone)]
+struct ScheduleWindow {
+    /// Minutes since midnight; the window covers `start..end`, wrapping past
+    /// midnight when `end <= start`
+    start: u32,
+    end: u32,
+    /// Bytes per second, 0 for unlimited
+    rate: u64,
+}
+
+impl ScheduleWindow {
+    fn contains(&self, minute: u32) -> bool {
+        if self.start < self.end {
+            (self.start..self.end).contains(&minute)
+        } else {
+            minute >= self.start || minute < self.end
+        }
+    }
+}
+
+impl Schedule {
+    /// The rate for `minute` of the day: the first window holding it, then
+    /// the schedule's own default, then `fallback`.
+    fn rate_at(&self, minute: u32, fallback: u64) -> u64 {
+        self.windows
+            .iter()
+            .find(|window| window.contains(minute))
+            .map(|window| window.rate)
+            .or(self.default)
+            .unwrap_or(fallback)
+    }
+}
+
+fn parse_schedule(arg: &str) -> Result<Schedule, String> {
+    fn parse_rate(rate: &str) -> Result<u64, String> {
+        if rate.eq_ignore_ascii_case("unlimited") {
+            Ok(0)
+        } else {
+            parse_bandwidth(rate)
+        }
+    }
+    fn parse_time(time: &str) -> Result<u32, String> {
+        let (hours, minutes) = time
+            .split_once(':')
+            .ok_or_else(|| format!("Invalid time {:?} (expected HH:MM)", time))?;
+        let hours: u32 = hours
+            .parse()
+            .map_err(|_| format!("Invalid time {:?}", time))?;
+        let minutes: u32 = minutes
+            .parse()
+            .map_err(|_| format!("Invalid time {:?}", time))?;
+        // 24:00 is the end of the day
+        if hours > 24 || minutes > 59 || (hours == 24 && minutes > 0) {
+            return Err(format!("Invalid time {:?}", time));
+        }
+        Ok((hours * 60 + minutes) % (24 * 60))
+    }
+
+    let mut schedule = Schedule {
+        windows: Vec::new(),
+        default: None,
+    };
+    for entry in arg.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+        let Some((start, rest)) = entry.split_once('-') else {
+            if schedule.default.replace(parse_rate(entry)?).is_some() {
+                return Err(format!("More than one default rate in {}", arg));
+            }
+            continue;
+        };
+        let mut parts = rest.splitn(3, ':');
+        let (Some(hours), Some(minutes), Some(rate)) = (parts.next(), parts.next(), parts.next())
+        else {
+            return Err(format!(
+                "Invalid schedule entry {:?} (expected HH:MM-HH:MM:RATE)",
+                entry
+            ));
+        };
+        schedule.windows.push(ScheduleWindow {
+            start: parse_time(start)?,
+            end: parse_time(&format!("{}:{}", hours, minutes))?,
+            rate: parse_rate(rate)?,
+        });
+    }
+    if schedule.windows.is_empty() && schedule.default.is_none() {
+        return Err("Empty schedule".to_string());
+    }
+    Ok(schedule)
+}
+
+/// Keeps `limiter` at the rate `schedule` sets for the local time,
+/// checking every 15 seconds.
+fn start_schedule(
+    schedule: Schedule,
+    fallback: u64,
+    limiter: Arc<BandwidthLimiter>,
+) -> AbortOnDrop {
+    AbortOnDrop(tokio::spawn(async move {
+        let mut current = None;
+        let mut interval = tokio::time::interval(Duration::from_secs(15));
+        loop {
+            interval.tick().await;
+            use chrono::Timelike;
+            let now = chrono::Local::now();
+            let minute = now.hour() * 60 + now.minute();
+            let rate = schedule.rate_at(minute, fallback);
+            if current != Some(rate) {
+                if rate == 0 {
+                    info!("Schedule: no bandwidth limit from {}", now.format("%H:%M"));
+                } else {
+                    info!("Schedule: limit {} B/s from {}", rate, now.format("%H:%M"));
+                }
+                limiter.set_rate(rate);
+                current = Some(rate);
+            }
+        }
+    }))
+}
+
+fn parse_minutes(arg: &str) -> Result<Duration, String> {
+    let minutes = arg
+        .parse::<f64>()
+        .map_err(|e| format!("Invalid number of minutes: {}", e))?;
+    if !minutes.is_finite() || minutes <= 0.0 {
+        return Err("Duration must be greater than zero".to_string());
+    }
+    Ok(Duration::from_secs_f64(minutes * 60.0))
+}
+
+fn parse_duration(arg: &str) -> Result<Duration, std::num::ParseIntError> {
+    let seconds = arg.parse::<u64>()?;
+    Ok(Duration::from_secs(seconds))
+}
+
+#[derive(Debug, Clone)]
+enum Checksum {
+    Md5(String),
+    Sha1(String),
+    Sha224(String),
+    Sha256(String),
+    Sha384(String),
+    Sha512(String),
+    Blake2b(String),
+    Blake3(String),
+}
+
+impl Checksum {
+    fn parse(s: &str) -> Option<Self> {
+        let parts: Vec<&str> = s.splitn(2, ':').collect();
+        if parts.len() != 2 {
+            return None;
+        }
+        let hash_type = parts[0].to_lowercase();
+        let hash = parts[1].to_string();
+        match hash_type.as_str() {
+            "md5" | "md5sum" => Some(Checksum::Md5(hash)),
+            "sha1" | "sha1sum" => Some(Checksum::Sha1(hash)),
+            "sha224" | "sha224sum" => Some(Checksum::Sha224(hash)),
+            "sha256" | "sha256sum" => Some(Checksum::Sha256(hash)),
+            "sha384" | "sha384sum" => Some(Checksum::Sha384(hash)),
+            "sha512" | "sha512sum" => Some(Checksum::Sha512(hash)),
+            "b2sum" | "blake2" => Some(Checksum::Blake2b(hash)),
+            "b3sum" | "blake3" => Some(Checksum::Blake3(hash)),
+            _ => None,
+        }
+    }
+
+    /// Whether `digest` (lowercase hex) is the expected hash.
+    fn matches(&self, digest: &str) -> bool {
+        let (Checksum::Md5(expected)
+        | Checksum::Sha1(expected)
+        | Checksum::Sha224(expected)
+        | Checksum::Sha256(expected)
+        | Checksum::Sha384(expected)
+        | Checksum::Sha512(expected)
+        | Checksum::Blake2b(expected)
+        | Checksum::Blake3(expected)) = self;
+        digest == expected.to_lowercase()
+    }
+}
+
+const DEFAULT_USER_AGENT: &str = "Grab/2.0";
+
+/// Number of times a range failing manifest verification is re-downloaded
+const MAX_CHUNK_RETRIES: usize = 3;
+
+/// How much of the file `--benchmark` fetches per round
+const BENCHMARK_BYTES: u64 = 10 * 1024 * 1024;
+
+/// Connection counts tried by `--benchmark`
+const BENCHMARK_CONNECTIONS: [usize; 4] = [1, 2, 4, 8];
+
+/// Connections per file `--adaptive-connections` starts with
+const ADAPTIVE_START: usize = 2;
+
+/// How long `--adaptive-connections` measures each connection count. Long
+/// enough that the new connections' handshakes don't dominate.
+const ADAPTIVE_INTERVAL: Duration = Duration::from_secs(3);
+
+/// Throughput gain that makes more connections worth keeping
+const ADAPTIVE_MIN_GAIN: f64 = 0.1;
+
+/// Decides the connection count for `--adaptive-connections` from the
+/// throughput measured with each count. Doubles the count while that still
+/// pays off; once it doesn't, steps back once and stays there, so a noisy
+/// link can't make it swing back and forth.
+#[derive(Debug)]
+struct ConnectionTuner {
+    active: usize,
+    max: usize,
+    /// The count before the last step, and its throughput
+    previous: Option<(usize, f64)>,
+    settled: bool,
+}
+
+impl ConnectionTuner {
+    fn new(max: usize) -> Self {
+        ConnectionTuner {
+            active: ADAPTIVE_START.min(max),
+            max,
+            previous: None,
+            settled: false,
+        }
+    }
+
+    /// Takes the throughput (bytes per second) with the current count and
+    /// returns the count to use next.
+    fn observe(&mut self, throughput: f64) -> usize {
+        if self.settled {
+            return self.active;
+        }
+        if let Some((count, before)) = self.previous
+            && throughput < before * (1.0 + ADAPTIVE_MIN_GAIN)
+        {
+            // Each connection got slower and the total barely moved: the
+            // server or the link is saturated
+            self.active = count;
+            self.settled = true;
+        } else if self.active >= self.max {
+            self.settled = true;
+        } else {
+            self.previous = Some((self.active, throughput));
+            self.active = (self.active * 2).min(self.max);
+        }
+        self.active
+    }
+}
+
+#[derive(Debug, Clone)]
+struct ManifestEntry {
+    start: u64,
+    end: u64,
+    sha256: String,
+}
+
+fn parse_chunk_manifest(contents: &str) -> Result<Vec<ManifestEntry>, String> {
+    let mut entries = Vec::new();
+    for (lineno, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let invalid = || format!("Invalid manifest line {}: {}", lineno + 1, line);
+        let (range, hash) = line.split_once(':').ok_or_else(invalid)?;
+        let (start, end) = range.split_once('-').ok_or_else(invalid)?;
+        let start: u64 = start.trim().parse().map_err(|_| invalid())?;
+        let end: u64 = end.trim().parse().map_err(|_| invalid())?;
+        if end < start {
+            return Err(invalid());
+        }
+        entries.push(ManifestEntry {
+            start,
+            end,
+            sha256: hash.trim().to_lowercase(),
+        });
+    }
+    Ok(entries)
+}
+
+#[derive(Debug, Clone)]
+struct DownloadConfig {
+    url: String,
+    output_path: String,
+    /// The output is a device, written in place instead of via `.part`
+    device_target: bool,
+    /// `--temp-dir`
+    temp_dir: Option<String>,
+    extract: Option<String>,
+    concurrent_chunks: usize,
+    /// `--adaptive-connections`, with its `--max-connections`
+    adaptive_connections: Option<usize>,
+    /// `-t auto`, with its `--max-connections`
+    auto_connections: Option<usize>,
+    /// `--tail-optimize`, or adaptive connections
+    tail_optimize: bool,
+    interleave: bool,
+    /// Shared by every download from the same host
+    host_slots: Option<Arc<Semaphore>>,
+    chunk_size: u64,
+    write_buffer_size: usize,
+    fsync: FsyncMode,
+    resume: bool,
+    verify_resume: bool,
+    continue_at: Option<u64>,
+    skip_existing: bool,
+    /// `--add-extension`, for a derived filename without one
+    add_extension: bool,
+    metadata_json: bool,
+    user_agent: String,
+    rotate_user_agent: bool,
+    timeout: Duration,
+    retry: RetryPolicy,
+    target_duration: Option<Duration>,
+    max_file_size: Option<u64>,
+    /// `--range`; empty for the whole file
+    ranges: Vec<ByteRange>,
+    /// `--mirror`, when `--rotate-on-failure` is set
+    mirrors: Vec<String>,
+    rotate_on_failure: bool,
+    /// Whether rotating also changes the User-Agent
+    rotate_user_agents: bool,
+    /// `--clean-on-error`
+    clean_on_error: bool,
+    expect_type: Option<String>,
+    /// Progress hint for a file of unknown size
+    expected_size: Option<u64>,
+    no_clobber: bool,
+    store_xattr: bool,
+    /// `--dedup-dir` and `--dedup-link`
+    dedup: Option<(PathBuf, dedup::LinkMode)>,
+    if_modified_since: Option<chrono::DateTime<chrono::Utc>>,
+    min_speed: Option<u64>,
+    min_speed_time: Duration,
+    http_version: HttpVersion,
+    /// `--method`
+    method: reqwest::Method,
+    /// `--data` or `--data-file`
+    data: Option<Vec<u8>>,
+    pool_idle_timeout: Duration,
+    pool_max_idle_per_host: Option<usize>,
+    tcp_nodelay: bool,
+    force_ipv4: bool,
+    force_ipv6: bool,
+    max_redirects: usize,
+    follow_redirects: bool,
+    /// `--compressed`
+    compressed: bool,
+    cacert: Option<String>,
+    client_cert: Option<String>,
+    client_key: Option<String>,
+    insecure: bool,
+    cookie_jar: Option<Arc<reqwest::cookie::Jar>>,
+    headers: HeaderMap,
+    checksum: Option<Checksum>,
+    auto_checksum: bool,
+    chunk_manifest: Option<Arc<Vec<ManifestEntry>>>,
+    chunk_timings: Option<String>,
+    post: Vec<String>,
+    on_complete: Option<String>,
+    webhook: Option<String>,
+    hooks_always: bool,
+    #[cfg(feature = "notify")]
+    desktop_notify: bool,
+    units: Units,
+}
+
+#[derive(Debug)]
+enum DownloadError {
+    Cancelled,
+    HttpStatus(reqwest::StatusCode),
+    SizeMismatch {
+        expected: u64,
+        actual: u64,
+    },
+    /// A range response that ended cleanly before byte `end`
+    ChunkCutOff {
+        start: u64,
+        end: u64,
+        at: u64,
+    },
+    ChecksumMismatch,
+    Deadline,
+    TooSlow {
+        speed: u64,
+    },
+    UnexpectedType {
+        expected: String,
+        actual: String,
+    },
+    /// The server answered `--if-modified-since` with 304
+    NotModified,
+    /// Over `--max-file-size`: the size the server gave, or what had
+    /// arrived when the transfer was stopped
+    FileTooLarge {
+        size: u64,
+        max: u64,
+    },
+    RetryBudgetExhausted {
+        max: u64,
+        last: String,
+    },
+    /// A connection failure made up by `--simulate`
+    Simulated(String),
+}
+
+impl std::fmt::Display for DownloadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DownloadError::Cancelled => write!(f, "Cancelled by user"),
+            DownloadError::HttpStatus(status) => write!(f, "Server returned error: {}", status),
+            DownloadError::SizeMismatch { expected, actual } => {
+                write!(f, "Size mismatch: expected {}, got {}", expected, actual)
+            }
+            DownloadError::ChunkCutOff { start, end, at } => write!(
+                f,
+                "Server closed the connection at byte {} of chunk {}-{}",
+                at, start, end
+            ),
+            DownloadError::ChecksumMismatch => write!(f, "Checksum mismatch"),
+            DownloadError::Deadline => write!(
+                f,
+                "Gave up after --max-time; the partial file is kept for --resume"
+            ),
+            DownloadError::TooSlow { speed } => write!(
+                f,
+                "Too slow: {} B/s, below --min-speed for longer than --min-speed-time",
+                speed
+            ),
+            DownloadError::UnexpectedType { expected, actual } => {
+                write!(f, "Expected Content-Type {}, got {}", expected, actual)
+            }
+            DownloadError::NotModified => write!(f, "Not modified"),
+            DownloadError::FileTooLarge { size, max } => write!(
+                f,
+                "File too large: {} bytes, over --max-file-size {}",
+                size, max
+            ),
+            DownloadError::RetryBudgetExhausted { max, last } => write!(
+                f,
+                "Gave up after {} retries in all (--max-total-retries); last error: {}",
+                max, last
+            ),
+            DownloadError::Simulated(what) => write!(f, "Simulated failure: {}", what),
+        }
+    }
+}
+
+impl std::error::Error for DownloadError {}
+
+/// Exit codes, as listed in `--help`. 2 is clap's, for usage errors.
+const EXIT_FAILURE: i32 = 1;
+const EXIT_NETWORK: i32 = 3;
+const EXIT_CHECKSUM: i32 = 4;
+const EXIT_DISK_FULL: i32 = 5;
+const EXIT_CANCELLED: i32 = 6;
+const EXIT_DEADLINE: i32 = 7;
+
+/// The exit code for a failed download, from the first error in its chain
+/// that says what kind of failure it was.
+fn exit_code(error: &(dyn std::error::Error + 'static)) -> i32 {
+    let mut cause = Some(error);
+    while let Some(err) = cause {
+        if let Some(e) = err.downcast_ref::<DownloadError>() {
+            return match e {
+                DownloadError::Cancelled => EXIT_CANCELLED,
+                DownloadError::Deadline => EXIT_DEADLINE,
+                DownloadError::ChecksumMismatch => EXIT_CHECKSUM,
+                DownloadError::HttpStatus(_)
+                | DownloadError::SizeMismatch { .. }
+                | DownloadError::ChunkCutOff { .. }
+                | DownloadError::TooSlow { .. }
+                | DownloadError::RetryBudgetExhausted { .. }
+                | DownloadError::Simulated(_) => EXIT_NETWORK,
+                DownloadError::UnexpectedType { .. }
+                | DownloadError::NotModified
+                | DownloadError::FileTooLarge { .. } => EXIT_FAILURE,
+            };
+        }
+        if let Some(e) = err.downcast_ref::<std::io::Error>() {
+            match e.kind() {
+                std::io::ErrorKind::StorageFull | std::io::ErrorKind::QuotaExceeded => {
+                    return EXIT_DISK_FULL;
+                }
+                std::io::ErrorKind::TimedOut
+                | std::io::ErrorKind::ConnectionRefused
+                | std::io::ErrorKind::ConnectionReset
+                | std::io::ErrorKind::ConnectionAborted
+                | std::io::ErrorKind::UnexpectedEof => return EXIT_NETWORK,
+                _ => {}
+            }
+        }
+        if err
+            .downcast_ref::<reqwest::Error>()
+            .is_some_and(|e| !e.is_builder())
+            || err.is::<tokio::time::error::Elapsed>()
+            || err.is::<suppaftp::FtpError>()
+        {
+            return EXIT_NETWORK;
+        }
+        cause = err.source();
+    }
+    EXIT_FAILURE
+}
+
+#[derive(Debug)]
+struct RemoteMetadata {
+    final_url: String,
+    total_size: u64,
+    supports_range: bool,
+    content_type: Option<String>,
+    last_modified: Option<String>,
+    etag: Option<String>,
+}
+
+/// What the server said about a file when it was downloaded, kept in a
+/// `.grabmeta` sidecar so `--no-clobber` can tell whether it changed since.
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+struct SavedValidators {
+    size: u64,
+    etag: Option<String>,
+    last_modified: Option<String>,
+}
+
+struct BandwidthLimiter {
+    bytes_per_second: std::sync::atomic::AtomicU64,
+    /// Start of the current rate window and bytes transferred since then
+    window: std::sync::Mutex<(tokio::time::Instant, u64)>,
+}
+
+impl BandwidthLimiter {
+    fn new(bytes_per_second: u64) -> Self {
+        Self {
+            bytes_per_second: std::sync::atomic::AtomicU64::new(bytes_per_second),
+            window: std::sync::Mutex::new((tokio::time::Instant::now(), 0)),
+        }
+    }
+
+    /// Changes the rate, starting a fresh window so earlier traffic
+    /// doesn't skew the new average.
+    fn set_rate(&self, bytes_per_second: u64) {
+        self.bytes_per_second
+            .store(bytes_per_second, std::sync::atomic::Ordering::Relaxed);
+        *self.window.lock().unwrap() = (tokio::time::Instant::now(), 0);
+    }
+
+    async fn throttle(&self, bytes: u64) {
+        let bytes_per_second = self
+            .bytes_per_second
+            .load(std::sync::atomic::Ordering::Relaxed);
+        if bytes_per_second == 0 {
+            return;
+        }
+
+        let (elapsed, total) = {
+            let mut window = self.window.lock().unwrap();
+            window.1 += bytes;
+            (window.0.elapsed(), window.1)
+        };
+
+        let expected_duration = Duration::from_secs_f64(total as f64 / bytes_per_second as f64);
+
+        if elapsed < expected_duration {
+            tokio::time::sleep(expected_duration - elapsed).await;
+        }
+    }
+}
+
+/// Fails a download whose speed stays under `--min-speed` for longer than
+/// `--min-speed-time`.
+struct SpeedGuard {
+    min_speed: u64,
+    grace: Duration,
+    /// Start of the current one-second window, bytes since then, and when
+    /// the speed first dropped below the minimum
+    window: std::sync::Mutex<(std::time::Instant, u64, Option<std::time::Instant>)>,
+}
+
+impl SpeedGuard {
+    fn new(min_speed: u64, grace: Duration) -> Self {
+        Self {
+            min_speed,
+            grace,
+            window: std::sync::Mutex::new((std::time::Instant::now(), 0, None)),
+        }
+    }
+
+    fn check(&self, bytes: u64) -> Result<(), DownloadError> {
+        let mut window = self.window.lock().unwrap();
+        window.1 += bytes;
+        let elapsed = window.0.elapsed();
+        if elapsed < Duration::from_secs(1) {
+            return Ok(());
+        }
+
+        let speed = (window.1 as f64 / elapsed.as_secs_f64()) as u64;
+        let now = std::time::Instant::now();
+        let below_since = if speed < self.min_speed {
+            Some(window.2.unwrap_or(window.0))
+        } else {
+            None
+        };
+        *window = (now, 0, below_since);
+
+        match below_since {
+            Some(since) if now - since >= self.grace => Err(DownloadError::TooSlow { speed }),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Aborts a background task when dropped, so early returns can't leak it.
+struct AbortOnDrop(tokio::task::JoinHandle<()>);
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+struct DownloadState {
+    total_files: usize,
+    finished_files: std::sync::atomic::AtomicUsize,
+    total_pb: ProgressBar,
+    /// Style of the file bars
+    bar_style: ProgressStyle,
+    /// Style of a file's line while its size is unknown
+    spinner_style: ProgressStyle,
+    control: watch::Receiver<TransferState>,
+    stats: Arc<TransferStats>,
+    /// `--max-buffer`, shared by every writer
+    buffer_budget: Option<BufferBudget>,
+    /// What `--tui` shows, when it's up
+    board: Option<tui::Board>,
+    /// When `--max-time` runs out
+    deadline: Option<tokio::time::Instant>,
+}
+
+/// How long transfers get after `--max-time` to write out what they have
+const DEADLINE_GRACE: Duration = Duration::from_secs(1);
+
+/// How often `--progress-file` is rewritten
+const PROGRESS_FILE_INTERVAL: Duration = Duration::from_millis(250);
+
+/// How often a plain progress line is printed when stderr isn't a terminal
+const PROGRESS_LINE_INTERVAL: Duration = Duration::from_secs(10);
+
+impl DownloadState {
+    /// Runs `work` until `--max-time` runs out. Transfers are told to stop
+    /// at the deadline and get `DEADLINE_GRACE` to write out what they have
+    /// and cut the `.part` file back; whatever is still going after that,
+    /// like a request waiting for headers or a retry's wait, is dropped.
+    async fn before_deadline<T>(
+        &self,
+        work: impl Future<Output = Result<T, Box<dyn std::error::Error + Send + Sync>>>,
+    ) -> Result<T, Box<dyn std::error::Error + Send + Sync>> {
+        match self.deadline {
+            Some(deadline) => tokio::time::timeout_at(deadline + DEADLINE_GRACE, work)
+                .await
+                .unwrap_or_else(|_| Err(DownloadError::Deadline.into())),
+            None => work.await,
+        }
+    }
+
+    /// The overall progress as `--progress-file` JSON, with `status` being
+    /// "running", "paused", or how the run ended.
+    fn progress_json(&self, status: &str) -> String {
+        let downloaded = self.total_pb.position();
+        let total = self.total_pb.length().filter(|&total| total > 0);
+        let speed = self.total_pb.per_sec() as u64;
+        let eta = match total {
+            Some(total) if speed > 0 => Some(total.saturating_sub(downloaded) / speed),
+            _ => None,
+        };
+        serde_json::json!({
+            "downloaded": downloaded,
+            "total": total,
+            "speed": speed,
+            "eta": eta,
+            "state": status,
+            "files_done": self.finished_files.load(Ordering::Relaxed),
+            "files_total": self.total_files,
+        })
+        .to_string()
+    }
+
+    /// Rewrites `path` with the progress a few times a second, until the
+    /// returned guard is dropped.
+    fn start_progress_file(self: &Arc<Self>, path: String) -> AbortOnDrop {
+        let state = self.clone();
+        AbortOnDrop(tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(PROGRESS_FILE_INTERVAL);
+            let mut warned = false;
+            loop {
+                ticker.tick().await;
+                let status = match *state.control.borrow() {
+                    TransferState::Paused => "paused",
+                    _ => "running",
+                };
+                if let Err(e) = write_progress_file(&path, state.progress_json(status)).await
+                    && !warned
+                {
+                    warned = true;
+                    console::eprintln(format_args!(
+                        "Warning: can't write progress to {}: {}",
+                        path, e
+                    ));
+                }
+            }
+        }))
+    }
+
+    /// Prints the overall progress as a plain line every
+    /// `PROGRESS_LINE_INTERVAL`, for logs, where bars would be redrawn with
+    /// carriage returns. Stops when the returned guard is dropped.
+    fn start_progress_lines(self: &Arc<Self>, units: Units) -> AbortOnDrop {
+        let state = self.clone();
+        AbortOnDrop(tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(PROGRESS_LINE_INTERVAL);
+            // The first tick is immediate, before anything has happened
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                eprintln!(
+                    "{}",
+                    progress_line(
+                        &state.total_pb,
+                        state.finished_files.load(Ordering::Relaxed),
+                        state.total_files,
+                        *state.control.borrow() == TransferState::Paused,
+                        units
+                    )
+                );
+            }
+        }))
+    }
+}
+
+/// One `start_progress_lines` line for the overall bar `pb`.
+fn progress_line(
+    pb: &ProgressBar,
+    finished_files: usize,
+    total_files: usize,
+    paused: bool,
+    units: Units,
+) -> String {
+    let size = match pb.length().filter(|&len| len > 0) {
+        Some(len) => format!(
+            "{} of {} ({}%)",
+            format_bytes(pb.position(), units),
+            format_bytes(len, units),
+            pb.position() * 100 / len
+        ),
+        None => format_bytes(pb.position(), units),
+    };
+    format!(
+        "Progress: {} at {}/s, {}/{} files{}",
+        size,
+        format_bytes(pb.per_sec() as u64, units),
+        finished_files,
+        total_files,
+        if paused { ", paused" } else { "" }
+    )
+}
+
+/// stderr as a terminal whether or not it is one, for `--force-progress`:
+/// indicatif draws nothing on anything else.
+#[derive(Debug)]
+struct ForcedStderr;
+
+impl ForcedStderr {
+    fn write(&self, s: &str) -> std::io::Result<()> {
+        use std::io::Write;
+        std::io::stderr().write_all(s.as_bytes())
+    }
+}
+
+impl indicatif::TermLike for ForcedStderr {
+    fn width(&self) -> u16 {
+        std::env::var("COLUMNS")
+            .ok()
+            .and_then(|columns| columns.parse().ok())
+            .unwrap_or(80)
+    }
+
+    fn move_cursor_up(&self, n: usize) -> std::io::Result<()> {
+        if n == 0 {
+            return Ok(());
+        }
+        self.write(&format!("\x1b[{}A", n))
+    }
+
+    fn move_cursor_down(&self, n: usize) -> std::io::Result<()> {
+        if n == 0 {
+            return Ok(());
+        }
+        self.write(&format!("\x1b[{}B", n))
+    }
+
+    fn move_cursor_right(&self, n: usize) -> std::io::Result<()> {
+        if n == 0 {
+            return Ok(());
+        }
+        self.write(&format!("\x1b[{}C", n))
+    }
+
+    fn move_cursor_left(&self, n: usize) -> std::io::Result<()> {
+        if n == 0 {
+            return Ok(());
+        }
+        self.write(&format!("\x1b[{}D", n))
+    }
+
+    fn write_line(&self, s: &str) -> std::io::Result<()> {
+        self.write(&format!("{}\n", s))
+    }
+
+    fn write_str(&self, s: &str) -> std::io::Result<()> {
+        self.write(s)
+    }
+
+    fn clear_line(&self) -> std::io::Result<()> {
+        self.write("\r\x1b[2K")
+    }
+
+    fn flush(&self) -> std::io::Result<()> {
+        use std::io::Write;
+        std::io::stderr().flush()
+    }
+}
+
+/// Replaces `path` in one step, so a reader never sees half of it.
+async fn write_progress_file(path: &str, contents: String) -> std::io::Result<()> {
+    let tmp = format!("{}.tmp", path);
+    tokio::fs::write(&tmp, contents).await?;
+    tokio::fs::rename(&tmp, path).await
+}
+
+/// Counters behind the summary printed when all downloads are done.
+#[derive(Debug, Default)]
+struct TransferStats {
+    /// Received in this run, not counting data kept from earlier runs
+    bytes: AtomicU64,
+    active_connections: AtomicUsize,
+    peak_connections: AtomicUsize,
+    /// Highest count `--adaptive-connections` settled on for a file
+    adaptive_connections: AtomicUsize,
+    /// Estimated time `--tail-optimize` saved, over all files
+    tail_saved_ms: AtomicU64,
+    /// Bytes per second, sampled once a second
+    peak_speed: AtomicU64,
+}
+
+impl TransferStats {
+    /// Samples the speed over the last second for `peak_speed`, until the
+    /// returned guard is dropped.
+    fn track_peak_speed(self: &Arc<Self>) -> AbortOnDrop {
+        let stats = self.clone();
+        AbortOnDrop(tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(Duration::from_secs(1));
+            let mut last = 0;
+            loop {
+                ticker.tick().await;
+                let bytes = stats.bytes.load(Ordering::Relaxed);
+                stats.peak_speed.fetch_max(bytes - last, Ordering::Relaxed);
+                last = bytes;
+            }
+        }))
+    }
+}
+
+/// An open connection for a transfer. Holds the host's
+/// `--max-connections-per-host` slot, if any, and is counted in the summary.
+struct Connection<'a> {
+    _slot: Option<tokio::sync::SemaphorePermit<'a>>,
+    stats: &'a TransferStats,
+}
+
+impl Drop for Connection<'_> {
+    fn drop(&mut self) {
+        self.stats
+            .active_connections
+            .fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TransferState {
+    Running,
+    Paused,
+    Cancelled,
+    /// `--max-time` ran out
+    TimedOut,
+}
+
+/// No bytes for this long and a transfer shows as stalled
+const STALL_AFTER: Duration = Duration::from_secs(5);
+
+/// Time left at the current speed. `None` while nothing is arriving, when
+/// any estimate would be meaningless.
+fn eta(state: &indicatif::ProgressState) -> Option<Duration> {
+    let len = state.len()?;
+    let speed = state.per_sec();
+    if speed < 1.0 {
+        return None;
+    }
+    Some(Duration::from_secs_f64(
+        len.saturating_sub(state.pos()) as f64 / speed,
+    ))
+}
+
+/// Renders the `{eta}` column: the time left, or what the transfer is doing
+/// instead of making progress. `waiting` counts requests in a retry backoff.
+fn eta_column(
+    waiting: Option<Arc<AtomicUsize>>,
+    control: watch::Receiver<TransferState>,
+) -> impl Fn(&indicatif::ProgressState, &mut dyn std::fmt::Write) + Clone + Send + Sync + 'static {
+    // Position at the last draw, and when it last moved
+    let moved = Arc::new(std::sync::Mutex::new((0, std::time::Instant::now())));
+    move |state, w| {
+        let now = std::time::Instant::now();
+        let stalled = {
+            let mut moved = moved.lock().unwrap();
+            if moved.0 != state.pos() {
+                *moved = (state.pos(), now);
+            }
+            now - moved.1 >= STALL_AFTER
+        };
+
+        let _ = if state.is_finished() {
+            write!(w, "{:#}", indicatif::HumanDuration(Duration::ZERO))
+        } else if *control.borrow() == TransferState::Paused {
+            write!(w, "paused")
+        } else if waiting
+            .as_ref()
+            .is_some_and(|waiting| waiting.load(Ordering::Relaxed) > 0)
+        {
+            write!(w, "retrying")
+        } else if stalled {
+            write!(w, "stalled")
+        } else {
+            match eta(state) {
+                Some(left) => write!(w, "{:#}", indicatif::HumanDuration(left)),
+                None => write!(w, "-"),
+            }
+        };
+    }
+}
+
+/// Holds a transfer loop while the user has paused downloads; errors once
+/// they quit so the `.part` file is left in place for `--resume`.
+async fn wait_while_paused(
+    control: &watch::Receiver<TransferState>,
+    pb: &ProgressBar,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let mut control = control.clone();
+    loop {
+        let current = *control.borrow_and_update();
+        match current {
+            TransferState::Running => return Ok(()),
+            TransferState::Cancelled => {
+                pb.abandon_with_message("Cancelled");
+                return Err(DownloadError::Cancelled.into());
+            }
+            TransferState::TimedOut => {
+                pb.abandon_with_message("Timed out");
+                return Err(DownloadError::Deadline.into());
+            }
+            TransferState::Paused => pb.set_message("Paused"),
+        }
+        if control.changed().await.is_err() {
+            return Ok(());
+        }
+        pb.set_message("");
+    }
+}
+
+/// Reads `p` (pause/resume) and `q` (quit) from the terminal while downloads run.
+struct KeyListener {
+    done: Arc<std::sync::atomic::AtomicBool>,
+    handle: Option<std::thread::JoinHandle<()>>,
+}
+
+impl KeyListener {
+    fn spawn(control: watch::Sender<TransferState>) -> std::io::Result<Self> {
+        use crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
+
+        crossterm::terminal::enable_raw_mode()?;
+        console::set_raw(true);
+        let done = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let done_flag = done.clone();
+
+        let handle = std::thread::spawn(move || {
+            while !done_flag.load(std::sync::atomic::Ordering::Relaxed) {
+                if !event::poll(Duration::from_millis(100)).unwrap_or(false) {
+                    continue;
+                }
+                let Ok(Event::Key(key)) = event::read() else {
+                    continue;
+                };
+                if key.kind != KeyEventKind::Press {
+                    continue;
+                }
+                match key.code {
+                    KeyCode::Char('p') => control.send_modify(|state| {
+                        *state = match *state {
+                            TransferState::Running => TransferState::Paused,
+                            TransferState::Paused => TransferState::Running,
+                            stopped => stopped,
+                        }
+                    }),
+                    KeyCode::Char('q') => {
+                        control.send_replace(TransferState::Cancelled);
+                    }
+                    // Raw mode swallows SIGINT, so treat Ctrl-C like `q`
+                    KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                        control.send_replace(TransferState::Cancelled);
+                    }
+                    _ => {}
+                }
+            }
+        });
+
+        Ok(Self {
+            done,
+            handle: Some(handle),
+        })
+    }
+}
+
+/// The terminal leaves raw mode however `main` ends.
+impl Drop for KeyListener {
+    fn drop(&mut self) {
+        self.done.store(true, std::sync::atomic::Ordering::Relaxed);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+        let _ = crossterm::terminal::disable_raw_mode();
+        console::set_raw(false);
+    }
+}
+
+struct FileDownloader {
+    client: Client,
+    config: Arc<DownloadConfig>,
+    limiter: Option<Arc<BandwidthLimiter>>,
+    multi_progress: indicatif::MultiProgress,
+    state: Arc<DownloadState>,
+}
+
+/// What one file's download settles as it goes, from the pre-scan to the
+/// `--post` steps. It carries over when `--rotate-on-failure` switches to
+/// another downloader.
+#[derive(Default)]
+struct RunState {
+    /// Per-file limiter driven by `--target-duration`
+    pacer: Option<Arc<BandwidthLimiter>>,
+    /// Created when a transfer starts if `--min-speed` is set
+    speed_guard: Option<Arc<SpeedGuard>>,
+    /// Size already added to the total bar by the pre-scan
+    prescanned_size: u64,
+    /// What `-t auto` chose for this file
+    connections: Option<usize>,
+    /// The output path with the extension `--add-extension` gave it
+    named_path: Option<String>,
+    /// Where `--post` steps left the file, if they moved it
+    final_path: Option<String>,
+    /// The file's place on the `--tui` screen
+    view: Option<Arc<tui::FileView>>,
+}
+
+impl FileDownloader {
+    fn new(
+        config: DownloadConfig,
+        multi_progress: indicatif::MultiProgress,
+        limiter: Option<Arc<BandwidthLimiter>>,
+        state: Arc<DownloadState>,
+    ) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        let mut builder = Client::builder()
+            .user_agent(&config.user_agent)
+            .connect_timeout(config.timeout)
+            .pool_idle_timeout(config.pool_idle_timeout)
+            .tcp_nodelay(config.tcp_nodelay)
+            .gzip(config.compressed)
+            .deflate(config.compressed)
+            .brotli(config.compressed);
+
+        if let Some(max) = config.pool_max_idle_per_host {
+            builder = builder.pool_max_idle_per_host(max);
+        }
+
+        if let Some(ref jar) = config.cookie_jar {
+            builder = builder.cookie_provider(jar.clone());
+        }
+
+        // After user_agent(), so a User-Agent given with --header wins
+        builder = builder.default_headers(config.headers.clone());
+
+        if config.force_ipv4 {
+            builder = builder.local_address(IpAddr::V4(std::net::Ipv4Addr::UNSPECIFIED));
+        } else if config.force_ipv6 {
+            builder = builder.local_address(IpAddr::V6(std::net::Ipv6Addr::UNSPECIFIED));
+        }
+
+        builder = config.http_version.apply(builder);
+
+        builder = if config.follow_redirects {
+            builder.redirect(reqwest::redirect::Policy::limited(config.max_redirects))
+        } else {
+            builder.redirect(reqwest::redirect::Policy::none())
+        };
+
+        builder = with_tls_options(
+            builder,
+            config.cacert.as_deref(),
+            config.client_cert.as_deref(),
+            config.client_key.as_deref(),
+            config.insecure,
+        )?;
+
+        let client = builder.build()?;
+        header_dump::client(&config.url, &config.user_agent, &config.headers);
+
+        Ok(Self {
+            client,
+            config: Arc::new(config),
+            limiter,
+            multi_progress,
+            state,
+        })
+    }
+
+    /// Whether the URL goes over HTTP(S), as ranged requests, HEAD and
+    /// fetching sibling URLs all need.
+    fn is_http(&self) -> bool {
+        !ftp::is_ftp_url(&self.config.url) && !local::is_local_url(&self.config.url)
+    }
+
+    /// A request for the file, with `--method` and `--data`.
+    fn file_request(&self) -> reqwest::RequestBuilder {
+        let request = self
+            .client
+            .request(self.config.method.clone(), &self.config.url);
+        match self.config.data {
+            Some(ref data) => request.body(data.clone()),
+            None => request,
+        }
+    }
+
+    /// Turns redirect-limit failures into a readable error, passing others through.
+    fn request_error(
+        &self,
+        e: Box<dyn std::error::Error + Send + Sync>,
+    ) -> Box<dyn std::error::Error + Send + Sync> {
+        let Some(err) = e.downcast_ref::<reqwest::Error>() else {
+            return e;
+        };
+        if err.is_redirect() {
+            format!(
+                "Too many redirects (limit {}) for {}",
+                self.config.max_redirects, self.config.url
+            )
+            .into()
+        } else if err.is_connect() && (self.config.force_ipv4 || self.config.force_ipv6) {
+            // Without this the forced family's failure reads like any other
+            // connection error
+            let family = if self.config.force_ipv4 { 4 } else { 6 };
+            let mut cause: &dyn std::error::Error = err;
+            while let Some(source) = cause.source() {
+                cause = source;
+            }
+            format!(
+                "Could not connect to {} over IPv{} (forced with -{}): {}",
+                err.url().and_then(|u| u.host_str()).unwrap_or("server"),
+                family,
+                family,
+                cause
+            )
+            .into()
+        } else {
+            e
+        }
+    }
+
+    /// Checks the URL with a HEAD, or a one-byte GET if the server rejects
+    /// HEAD, and prints one line about it. Fails if it isn't reachable.
+    async fn spider(&self) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let checked = self
+            .state
+            .before_deadline(async {
+                if !self.is_http() || self.config.method != reqwest::Method::GET {
+                    self.fetch_remote_metadata()
+                        .await
+                        .map(|remote| ("OK".to_string(), remote))
+                } else {
+                    self.spider_http().await
+                }
+            })
+            .await;
+
+        match checked {
+            Ok((status, remote)) => {
+                let size = if remote.total_size > 0 {
+                    format_bytes(remote.total_size, self.config.units)
+                } else {
+                    "unknown size".to_string()
+                };
+                let redirect = if remote.final_url != self.config.url {
+                    format!(" -> {}", remote.final_url)
+                } else {
+                    String::new()
+                };
+                println!(
+                    "OK    {}  {}  {}  {}{}",
+                    self.config.url,
+                    status,
+                    size,
+                    remote.content_type.as_deref().unwrap_or("-"),
+                    redirect
+                );
+                Ok(())
+            }
+            Err(e) => {
+                // "error sending request" alone doesn't say what went wrong
+                let mut cause: &dyn std::error::Error = e.as_ref();
+                while let Some(source) = cause.source() {
+                    cause = source;
+                }
+                println!("DEAD  {}  {}", self.config.url, cause);
+                Err(e)
+            }
+        }
+    }
+
+    async fn spider_http(
+        &self,
+    ) -> Result<(String, RemoteMetadata), Box<dyn std::error::Error + Send + Sync>> {
+        let mut response = send_with_retry(&self.config.retry, self.config.timeout, || {
+            self.client.head(&self.config.url)
+        })
+        .await
+        .map_err(|e| self.request_error(e))?;
+        if !response.status().is_success() {
+            debug!(
+                "HEAD {} -> {}, trying GET",
+                self.config.url,
+                response.status()
+            );
+            response = send_with_retry(&self.config.retry, self.config.timeout, || {
+                self.client.get(&self.config.url).header(RANGE, "bytes=0-0")
+            })
+            .await
+            .map_err(|e| self.request_error(e))?;
+        }
+
+        let status = response.status();
+        if !status.is_success() {
+            return Err(status.to_string().into());
+        }
+
+        let headers = response.headers();
+        let header_string = |name| {
+            headers
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .map(|v| v.to_string())
+        };
+        let total_size = if status == reqwest::StatusCode::PARTIAL_CONTENT {
+            header_string(reqwest::header::CONTENT_RANGE)
+                .and_then(|v| parse_content_range_total(&v))
+        } else {
+            // Not `content_length()`, which is the (empty) body's for HEAD
+            header_string(reqwest::header::CONTENT_LENGTH).and_then(|v| v.parse().ok())
+        };
+
+        Ok((
+            status.to_string(),
+            RemoteMetadata {
+                final_url: response.url().to_string(),
+                total_size: total_size.unwrap_or(0),
+                supports_range: status == reqwest::StatusCode::PARTIAL_CONTENT,
+                content_type: header_string(reqwest::header::CONTENT_TYPE),
+                last_modified: header_string(reqwest::header::LAST_MODIFIED),
+                etag: header_string(reqwest::header::ETAG),
+            },
+        ))
+    }
+
+    async fn dry_run(&self) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let remote = self.fetch_remote_metadata().await?;
+
+        if self.config.metadata_json {
+            let filename = Path::new(&self.config.output_path)
+                .file_name()
+                .map(|name| name.to_string_lossy());
+            let metadata = serde_json::json!({
+                "url": self.config.url,
+                "final_url": remote.final_url,
+                "size": (remote.total_size > 0).then_some(remote.total_size),
+                "supports_range": remote.supports_range,
+                "content_type": remote.content_type,
+                "etag": remote.etag,
+                "last_modified": remote.last_modified,
+                "filename": filename,
+            });
+            println!("{}", metadata);
+            return Ok(());
+        }
+
+        let size = if remote.total_size > 0 {
+            format_bytes(remote.total_size, self.config.units)
+        } else {
+            "unknown".to_string()
+        };
+
+        println!(
+            "{}\n  Final URL:     {}\n  Filename:      {}\n  Size:          {}\n  Ranges:        {}\n  Content-Type:  {}\n  Last-Modified: {}",
+            self.config.url,
+            remote.final_url,
+            self.config.output_path,
+            size,
+            if remote.supports_range { "yes" } else { "no" },
+            remote.content_type.as_deref().unwrap_or("-"),
+            remote.last_modified.as_deref().unwrap_or("-"),
+        );
+
+        Ok(())
+    }
+
+    async fn download(
+        &self,
+        run: &mut RunState,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let output_path = &self.config.output_path;
+        if self.config.skip_existing && Path::new(output_path).exists() {
+            info!("{} already exists, skipping", output_path);
+            self.state.total_pb.dec_length(run.prescanned_size);
+            self.mark_finished();
+            return Ok(());
+        }
+
+        let Some(remote::Reached {
+            mut remote,
+            mut rotated,
+            mut rotation,
+        }) = self.reach(run).await?
+        else {
+            return Ok(());
+        };
+        let mut plan = self.plan(&remote).await?;
+        let pb = self.file_bar(run, plan.shown_size);
+
+        if self.config.no_clobber && self.is_up_to_date(&remote).await {
+            info!("{} is already up to date", output_path);
+            pb.set_position(plan.total_size);
+            self.state.total_pb.inc(plan.total_size);
+            self.mark_finished();
+            pb.finish_with_message("Already up to date");
+            return Ok(());
+        }
+
+        if let Some(max) = self.config.max_file_size
+            && plan.total_size > max
+        {
+            let err = DownloadError::FileTooLarge {
+                size: plan.total_size,
+                max,
+            };
+            self.mark_finished();
+            pb.finish_with_message(err.to_string());
+            return Err(err.into());
+        }
+
+        let _pacing = match self.config.target_duration {
+            Some(_) if plan.total_size == 0 => {
+                return Err("--target-duration needs a known file size".into());
+            }
+            Some(target) => Some(self.start_pacing(run, plan.total_size, target, &pb)),
+            None => None,
+        };
+
+        if let Some(min_speed) = self.config.min_speed {
+            run.speed_guard = Some(Arc::new(SpeedGuard::new(
+                min_speed,
+                self.config.min_speed_time,
+            )));
+        }
+
+        if let (Some(kind), Some(dir)) = (plan.archive, &self.config.extract) {
+            let res = self.download_extract(run, kind, dir, pb).await;
+            self.mark_finished();
+            return res;
+        }
+
+        let Some(mut already_downloaded) = self.prepare_part(&plan, &pb).await? else {
+            return Ok(());
+        };
+
+        if let Some(max) = self.config.auto_connections {
+            let connections = auto_connections(plan.total_size, plan.supports_range, max);
+            info!(
+                "Picked {} connection{} for {} ({} bytes)",
+                connections,
+                if connections == 1 { "" } else { "s" },
+                self.config.url,
+                plan.total_size
+            );
+            run.connections = Some(connections);
+        }
+        let mut resuming = self.config.resume || self.config.continue_at.is_some();
+        let mut restarts = 0;
+        transfer_log::event(format_args!(
+            "START {} -> {} ({} bytes{})",
+            self.config.url,
+            output_path,
+            plan.total_size,
+            if already_downloaded > 0 {
+                format!(", continuing at byte {}", already_downloaded)
+            } else {
+                String::new()
+            }
+        ));
+        let part_path = self.part_path();
+        let res = loop {
+            let source = rotated.as_ref().unwrap_or(self);
+            let (res, sequential) = self
+                .transfer(source, run, &plan, already_downloaded, resuming, &pb)
+                .await;
+            match res {
+                // Any failure is worth trying another mirror for
+                Err(e)
+                    if restarts < self.config.retry.retries
+                        && (self.config.retry.retries_failure(e.as_ref())
+                            || (self.config.rotate_on_failure
+                                && !self.config.mirrors.is_empty())) =>
+                {
+                    restarts += 1;
+                    if let Err(exhausted) = self.config.retry.count_retry(&e) {
+                        break Err(exhausted.into());
+                    }
+                    let wait = backoff(restarts);
+                    transfer_log::event(format_args!(
+                        "RETRY {}: {}; starting over (retry {}/{})",
+                        source.config.url, e, restarts, self.config.retry.retries
+                    ));
+                    info!(
+                        "{}: {}; starting over in {:.1}s (retry {}/{} of --retries)",
+                        source.config.url,
+                        e,
+                        wait.as_secs_f64(),
+                        restarts,
+                        self.config.retry.retries
+                    );
+                    if let Err(stopped) = self.config.retry.back_off(wait).await {
+                        break Err(stopped.into());
+                    }
+
+                    let mut keep = 0;
+                    if self.config.rotate_on_failure {
+                        rotation += 1;
+                        let next = self.rotate(rotation)?;
+                        keep = self
+                            .switch_to(&next, sequential, &mut plan, &mut remote, &pb)
+                            .await;
+                        rotated = Some(next);
+                    }
+
+                    self.state.total_pb.set_position(
+                        self.state.total_pb.position().saturating_sub(pb.position()) + keep,
+                    );
+                    pb.set_position(keep);
+                    already_downloaded = keep;
+                    if keep > 0 {
+                        resuming = true;
+                        info!("Continuing {} at byte {}", part_path, keep);
+                    } else {
+                        self.create_part(&part_path).await?;
+                    }
+                }
+                res => break res,
+            }
+        };
+
+        self.mark_finished();
+        let streamed_digest = res?;
+        let note = self.finished_note(run, &remote, rotated.as_ref());
+        self.complete(run, &plan, &remote, streamed_digest, &note, &pb)
+            .await
+    }
+
+    /// The file's progress bar, counting towards `shown_size`. The total bar
+    /// is brought in line with it, as the file may have changed since the
+    /// pre-scan.
+    fn file_bar(&self, run: &mut RunState, shown_size: u64) -> ProgressBar {
+        let prescanned = run.prescanned_size;
+        if shown_size >= prescanned {
+            self.state.total_pb.inc_length(shown_size - prescanned);
+        } else {
+            self.state.total_pb.dec_length(prescanned - shown_size);
+        }
+
+        let pb = self.multi_progress.insert(0, ProgressBar::new(shown_size));
+        let style = if shown_size == 0 {
+            &self.state.spinner_style
+        } else {
+            &self.state.bar_style
+        };
+        pb.set_style(style.clone().with_key(
+            "eta",
+            eta_column(
+                Some(self.config.retry.waiting.clone()),
+                self.state.control.clone(),
+            ),
+        ));
+        // Redraws a stalled bar so it says so
+        pb.enable_steady_tick(Duration::from_secs(1));
+        pb.set_prefix(self.file_name().to_string());
+        if let Some(ref board) = self.state.board {
+            run.view = Some(board.add(&pb, self.config.retry.file_retried.clone()));
+        }
+        pb
+    }
+
+    /// The output file's name, as the bars show it.
+    fn file_name(&self) -> &str {
+        Path::new(&self.config.output_path)
+            .file_name()
+            .and_then(|n| n.to_str())
+            .unwrap_or("file")
+    }
+
+    /// One attempt at the transfer from `source`, continuing the `.part`
+    /// file at `from`, in whichever way the URL and `plan` call for. Returns
+    /// whether it was sequential, and on success the checksum digest if it
+    /// was computed on the way.
+    async fn transfer(
+        &self,
+        source: &FileDownloader,
+        run: &RunState,
+        plan: &Plan,
+        from: u64,
+        resuming: bool,
+        pb: &ProgressBar,
+    ) -> (
+        Result<Option<String>, Box<dyn std::error::Error + Send + Sync>>,
+        bool,
+    ) {
+        let total_size = plan.total_size;
+        let mut streamed_digest = None;
+        let sequential;
+        let res = if plan.spans.len() > 1 {
+            sequential = false;
+            info!(
+                "Downloading {} ranges of {} in one request",
+                plan.spans.len(),
+                self.config.url
+            );
+            self.download_ranges(run, &plan.spans, pb.clone()).await
+        } else if !plan.spans.is_empty() {
+            sequential = false;
+            info!(
+                "Downloading bytes {}-{} of {}",
+                plan.range_start,
+                plan.range_start + total_size - 1,
+                self.config.url
+            );
+            self.download_multi_threaded(run, plan.range_start, total_size, pb.clone())
+                .await
+        } else if total_size > 0 && from >= total_size {
+            sequential = true;
+            // A resume that stopped exactly at the end; asking for
+            // `bytes=<size>-` would only get a 416
+            info!("{} is already complete", self.part_path());
+            Ok(())
+        } else if ftp::is_ftp_url(&self.config.url) {
+            sequential = true;
+            info!(
+                "Downloading {} ({} bytes) over FTP from byte {}",
+                self.config.url, total_size, from
+            );
+            self.download_ftp(run, from, pb.clone()).await
+        } else if local::is_local_url(&self.config.url) {
+            sequential = true;
+            self.download_local(run, from, pb.clone()).await
+        } else if plan.supports_range && !resuming && total_size > self.config.chunk_size {
+            sequential = false;
+            info!("Downloading {} ({} bytes)", source.config.url, total_size);
+            source
+                .download_multi_threaded(run, 0, total_size, pb.clone())
+                .await
+        } else {
+            sequential = true;
+            // Say why when more connections were asked for
+            let reason = if self.connections(run) <= 1 && self.config.adaptive_connections.is_none()
+            {
+                ""
+            } else if total_size == 0 {
+                ", as its size is unknown"
+            } else if !plan.supports_range {
+                ", as the server doesn't support ranges"
+            } else if resuming {
+                ", as resumes are sequential"
+            } else {
+                ", as it fits in one chunk"
+            };
+            info!(
+                "Downloading {} ({} bytes) sequentially from byte {}{}",
+                source.config.url, total_size, from, reason
+            );
+            // Hashed as it arrives, saving a second read for the checksum; a
+            // transfer continuing a partial file can't be
+            let mut hasher = plan
+                .checksum
+                .as_ref()
+                .filter(|_| from == 0)
+                .map(StreamingHash::new);
+            let res = source
+                .download_single_threaded(run, from, hasher.as_mut(), pb.clone())
+                .await;
+            if res.is_ok() {
+                streamed_digest = hasher.map(StreamingHash::finish);
+            }
+            res
+        };
+        (res.map(|()| streamed_digest), sequential)
+    }
+
+    /// A downloader for restart `n` of `--rotate-on-failure`: the URL and its
+    /// mirrors take turns, as do the browser User-Agents.
+    fn rotate(&self, n: usize) -> Result<FileDownloader, Box<dyn std::error::Error + Send + Sync>> {
+        let sources = 1 + self.config.mirrors.len();
+        let url = match n % sources {
+            0 => self.config.url.clone(),
+            i => self.config.mirrors[i - 1].clone(),
+        };
+        let user_agent = if self.config.rotate_user_agents {
+            browser_user_agent(n).to_string()
+        } else {
+            self.config.user_agent.clone()
+        };
+        info!("Switching to {} with User-Agent {:?}", url, user_agent);
+
+        FileDownloader::new(
+            DownloadConfig {
+                url,
+                user_agent,
+                ..(*self.config).clone()
+            },
+            self.multi_progress.clone(),
+            self.limiter.clone(),
+            self.state.clone(),
+        )
+    }
+
+    /// Starts a task that keeps the pacer's rate at whatever is needed to
+    /// finish the remaining bytes by the deadline.
+    fn start_pacing(
+        &self,
+        run: &mut RunState,
+        total_size: u64,
+        target: Duration,
+        pb: &ProgressBar,
+    ) -> AbortOnDrop {
+        let deadline = tokio::time::Instant::now() + target;
+        let pacer = run
+            .pacer
+            .get_or_insert_with(|| Arc::new(BandwidthLimiter::new(0)))
+            .clone();
+        let pb = pb.clone();
+
+        AbortOnDrop(tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(5));
+            loop {
+                interval.tick().await;
+                let remaining_bytes = total_size.saturating_sub(pb.position());
+                let remaining_time =
+                    deadline.saturating_duration_since(tokio::time::Instant::now());
+                // Past the deadline there's nothing to pace for; go flat out
+                let rate = if remaining_time.is_zero() {
+                    0
+                } else {
+                    ((remaining_bytes as f64 / remaining_time.as_secs_f64()) as u64).max(1)
+                };
+                debug!(
+                    "Pacing {} bytes over {:?} at {} B/s",
+                    remaining_bytes, remaining_time, rate
+                );
+                pacer.set_rate(rate);
+            }
+        }))
+    }
+
+    fn validators_path(&self) -> String {
+        format!("{}.grabmeta", self.config.output_path)
+    }
+
+    /// Whether the existing output matches the remote file. The size must
+    /// match, plus the ETag or Last-Modified saved from the last download,
+    /// or failing that the ETag `--store-xattr` put on the file; without
+    /// either, the local file must be no older than Last-Modified.
+    async fn is_up_to_date(&self, remote: &RemoteMetadata) -> bool {
+        let Ok(local) = metadata(&self.config.output_path).await else {
+            return false;
+        };
+        if remote.total_size == 0 || local.len() != remote.total_size {
+            return false;
+        }
+
+        let saved = tokio::fs::read_to_string(self.validators_path())
+            .await
+            .ok()
+            .and_then(|contents| serde_json::from_str::<SavedValidators>(&contents).ok());
+        if let Some(saved) = saved {
+            if saved.size != remote.total_size {
+                return false;
+            }
+            if let (Some(saved), Some(remote)) = (&saved.etag, &remote.etag) {
+                return saved == remote;
+            }
+            if let (Some(saved), Some(remote)) = (&saved.last_modified, &remote.last_modified) {
+                return saved == remote;
+            }
+        } else if let Some(stored) = xattrs::load(&self.config.output_path)
+            && stored.url == self.config.url
+            && let (Some(stored), Some(remote)) = (&stored.etag, &remote.etag)
+        {
+            return stored == remote;
+        }
+
+        let remote_modified = remote
+            .last_modified
+            .as_deref()
+            .and_then(|v| chrono::DateTime::parse_from_rfc2822(v).ok());
+        match (remote_modified, local.modified()) {
+            (Some(remote_modified), Ok(local_modified)) => {
+                chrono::DateTime::<chrono::Utc>::from(local_modified) >= remote_modified
+            }
+            _ => false,
+        }
+    }
+
+    /// Records the remote validators for the next `--no-clobber` run.
+    async fn save_validators(&self, remote: &RemoteMetadata) {
+        if !self.config.no_clobber {
+            return;
+        }
+        let saved = SavedValidators {
+            size: remote.total_size,
+            etag: remote.etag.clone(),
+            last_modified: remote.last_modified.clone(),
+        };
+        let written = match serde_json::to_string(&saved) {
+            Ok(json) => tokio::fs::write(self.validators_path(), json)
+                .await
+                .map_err(|e| e.to_string()),
+            Err(e) => Err(e.to_string()),
+        };
+        if let Err(e) = written {
+            debug!("Could not save {}: {}", self.validators_path(), e);
+        }
+    }
+
+    /// `--auto-checksum`: the checksum next to the download, warning when
+    /// there's none.
+    async fn discover_checksum(&self) -> Option<Checksum> {
+        let found =
+            find_sibling_checksum(&self.client, &self.config.url, self.config.timeout).await;
+        if found.is_none() {
+            console::eprintln(format_args!(
+                "Warning: no usable .sha256/.sha1/.md5 file next to {}; not verifying",
+                self.config.url
+            ));
+        }
+        found
+    }
+
+    /// Enforces `--expect-type`, and warns when a file that looks binary is
+    /// served as HTML (usually an error page or captive portal).
+    fn check_content_type(&self, remote: &RemoteMetadata) -> Result<(), DownloadError> {
+        let Some(ref content_type) = remote.content_type else {
+            return Ok(());
+        };
+        let mime = content_type
+            .split(';')
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_ascii_lowercase();
+
+        if let Some(ref expected) = self.config.expect_type {
+            let expected_lower = expected.to_ascii_lowercase();
+            let matches = match expected_lower.strip_suffix("/*") {
+                Some(kind) => mime.split('/').next() == Some(kind),
+                None => mime == expected_lower,
+            };
+            if !matches {
+                return Err(DownloadError::UnexpectedType {
+                    expected: expected.clone(),
+                    actual: content_type.clone(),
+                });
+            }
+        } else if mime == "text/html" && looks_binary(&self.config.output_path) {
+            console::eprintln(format_args!(
+                "Warning: {} is served as text/html, which may be an error page (use --expect-type to enforce a type)",
+                self.config.url
+            ));
+        }
+        Ok(())
+    }
+
+    /// Fetches the start of the file with each of `BENCHMARK_CONNECTIONS`,
+    /// discarding the data, and returns the fastest connection count.
+    async fn benchmark(&self) -> Result<usize, Box<dyn std::error::Error + Send + Sync>> {
+        let remote = self.fetch_remote_metadata().await?;
+        if !self.is_http() || !remote.supports_range || remote.total_size == 0 {
+            return Err("--benchmark needs an HTTP server that supports ranges".into());
+        }
+        let sample = remote.total_size.min(BENCHMARK_BYTES);
+
+        let print = |line: String| self.multi_progress.suspend(|| println!("{}", line));
+        print(format!(
+            "{} ({} per round)\n  {:>11} {:>9} {:>14}",
+            self.config.url,
+            format_bytes(sample, self.config.units),
+            "Connections",
+            "Time",
+            "Speed"
+        ));
+
+        let mut best = (1, 0.0);
+        for connections in BENCHMARK_CONNECTIONS {
+            let ranges = SegmentQueue::new(sample, sample.div_ceil(connections as u64));
+            let started = std::time::Instant::now();
+
+            let mut handles = Vec::new();
+            while let Some((start, end)) = ranges.next() {
+                let client = self.client.clone();
+                let url = self.config.url.clone();
+                let timeout = self.config.timeout;
+                handles.push(tokio::spawn(async move {
+                    let mut response = tokio::time::timeout(
+                        timeout,
+                        client
+                            .get(&url)
+                            .header(RANGE, format!("bytes={}-{}", start, end))
+                            .send(),
+                    )
+                    .await??;
+                    if response.status() != reqwest::StatusCode::PARTIAL_CONTENT {
+                        return Err("Server did not return partial content".into());
+                    }
+                    while tokio::time::timeout(timeout, response.chunk())
+                        .await??
+                        .is_some()
+                    {}
+                    Ok::<(), Box<dyn std::error::Error + Send + Sync>>(())
+                }));
+            }
+            for handle in handles {
+                handle.await??;
+            }
+
+            let elapsed = started.elapsed();
+            let speed = sample as f64 / elapsed.as_secs_f64().max(f64::EPSILON);
+            debug!("{} connections: {:?}", connections, elapsed);
+            print(format!(
+                "  {:>11} {:>8.2}s {:>12}/s",
+                connections,
+                elapsed.as_secs_f64(),
+                format_bytes(speed as u64, self.config.units)
+            ));
+            if speed > best.1 {
+                best = (connections, speed);
+            }
+        }
+
+        print(format!("  Fastest: -t {}", best.0));
+        Ok(best.0)
+    }
+
+    fn mark_finished(&self) {
+        let finished = self
+            .state
+            .finished_files
+            .fetch_add(1, std::sync::atomic::Ordering::Relaxed)
+            + 1;
+        self.state
+            .total_pb
+            .set_message(format!("({}/{})", finished, self.state.total_files));
+    }
+
+    /// Connections per file: `-t`, or what `-t auto` chose
+    fn connections(&self, run: &RunState) -> usize {
+        run.connections.unwrap_or(self.config.concurrent_chunks)
+    }
+
+    async fn download_single_threaded(
+        &self,
+        run: &RunState,
+        start_pos: u64,
+        mut hasher: Option<&mut StreamingHash>,
+        pb: ProgressBar,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let mut headers = HeaderMap::new();
+        if start_pos > 0 {
+            headers.insert(RANGE, format!("bytes={}-", start_pos).parse().unwrap());
+        }
+
+        let part_path = self.part_path();
+        let ctx = self.chunk_context(run, &part_path, &pb);
+        let _connection = ctx.connect().await?;
+
+        debug!("{} {} {:?}", self.config.method, self.config.url, headers);
+        let response = send_with_retry(&self.config.retry, self.config.timeout, || {
+            self.file_request().headers(headers.clone())
+        })
+        .await
+        .map_err(|e| self.request_error(e))?;
+        debug!(
+            "GET {} -> {} {:?}",
+            self.config.url,
+            response.status(),
+            response.headers()
+        );
+
+        if start_pos > 0 && response.status() != reqwest::StatusCode::PARTIAL_CONTENT {
+            return Err("Server does not support resume (Range request ignored)".into());
+        }
+
+        if !response.status().is_success() {
+            return Err(DownloadError::HttpStatus(response.status()).into());
+        }
+
+        let mut response = response;
+        let mut file = self.open_part_at(&part_path, start_pos).await?;
+
+        let res: Result<(), Box<dyn std::error::Error + Send + Sync>> = async {
+            loop {
+                wait_while_paused(&ctx.control, &pb).await?;
+                let Some(chunk) =
+                    tokio::time::timeout(self.config.timeout, response.chunk()).await??
+                else {
+                    break;
+                };
+                file.write_all(&chunk).await?;
+                if let Some(ref mut hasher) = hasher {
+                    hasher.update(&chunk);
+                }
+                ctx.record(chunk.len() as u64).await?;
+            }
+            Ok(())
+        }
+        .await;
+        // Whatever arrived before a failure is kept for resume
+        file.finish().await?;
+
+        // pb.finish();
+        res
+    }
+
+    /// Streams the download through an extractor into `dir`. Extraction
+    /// needs the bytes in order, so there's only one connection.
+    async fn download_extract(
+        &self,
+        run: &RunState,
+        kind: extract::ArchiveKind,
+        dir: &str,
+        pb: ProgressBar,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let ctx = self.chunk_context(run, dir, &pb);
+        let _connection = ctx.connect().await?;
+        tokio::fs::create_dir_all(dir).await?;
+
+        debug!(
+            "{} {} (extracting into {})",
+            self.config.method, self.config.url, dir
+        );
+        let mut response = send_with_retry(&self.config.retry, self.config.timeout, || {
+            self.file_request()
+        })
+        .await
+        .map_err(|e| self.request_error(e))?;
+        if !response.status().is_success() {
+            return Err(DownloadError::HttpStatus(response.status()).into());
+        }
+
+        let extractor = extract::Extractor::spawn(kind, dir.into());
+        loop {
+            wait_while_paused(&ctx.control, &pb).await?;
+            let Some(chunk) = tokio::time::timeout(self.config.timeout, response.chunk()).await??
+            else {
+                break;
+            };
+            ctx.record(chunk.len() as u64).await?;
+            // Extraction stopped early; `finish` says why
+            if extractor.write(chunk.to_vec()).await.is_err() {
+                break;
+            }
+        }
+
+        let files = extractor.finish().await?;
+        info!(
+            "Extracted {} files from {} into {}",
+            files, self.config.url, dir
+        );
+        pb.finish_with_message(format!("Extracted {} files", files));
+        Ok(())
+    }
+
+    /// Runs `tuner` on the file's throughput, storing its count in `active`,
+    /// until it settles or the returned guard is dropped.
+    fn start_tuner(
+        &self,
+        mut tuner: ConnectionTuner,
+        active: Arc<AtomicUsize>,
+        pb: ProgressBar,
+    ) -> AbortOnDrop {
+        let control = self.state.control.clone();
+        AbortOnDrop(tokio::spawn(async move {
+            let mut last = pb.position();
+            while !tuner.settled {
+                tokio::time::sleep(ADAPTIVE_INTERVAL).await;
+                let bytes = pb.position();
+                let received = bytes.saturating_sub(last);
+                last = bytes;
+                // A paused interval says nothing about the connections
+                if *control.borrow() != TransferState::Running {
+                    continue;
+                }
+                let throughput = received as f64 / ADAPTIVE_INTERVAL.as_secs_f64();
+                let count = tuner.active;
+                debug!(
+                    "{} connections: {:.0} B/s total, {:.0} B/s each",
+                    count,
+                    throughput,
+                    throughput / count as f64
+                );
+                let next = tuner.observe(throughput);
+                if next != count {
+                    debug!("Switching from {} to {} connections", count, next);
+                }
+                active.store(next, Ordering::Relaxed);
+            }
+        }))
+    }
+
+    fn chunk_context(&self, run: &RunState, part_path: &str, pb: &ProgressBar) -> ChunkContext {
+        ChunkContext {
+            client: self.client.clone(),
+            url: self.config.url.clone(),
+            output_path: part_path.to_string(),
+            base: 0,
+            rotate_user_agent: self.config.rotate_user_agent,
+            pb: pb.clone(),
+            total_pb: self.state.total_pb.clone(),
+            timeout: self.config.timeout,
+            write_buffer_size: self.config.write_buffer_size,
+            sync_every: self.sync_every(),
+            retry: self.config.retry.clone(),
+            limiter: self.limiter.clone(),
+            pacer: run.pacer.clone(),
+            speed_guard: run.speed_guard.clone(),
+            host_slots: self.config.host_slots.clone(),
+            stats: self.state.stats.clone(),
+            control: self.state.control.clone(),
+            buffer_budget: self.state.buffer_budget.clone(),
+            simulated: Arc::new(AtomicU64::new(0)),
+            worker: 0,
+            timings: None,
+            max_file_size: self.config.max_file_size,
+            view: None,
+        }
+    }
+
+    /// Fetches `total_size` bytes starting at remote offset `range_start`.
+    async fn download_multi_threaded(
+        &self,
+        run: &RunState,
+        range_start: u64,
+        total_size: u64,
+        pb: ProgressBar,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let max_workers = self
+            .config
+            .adaptive_connections
+            .unwrap_or_else(|| self.connections(run));
+        // No more workers than segments, and never none: an empty queue
+        // still needs a worker to find that out
+        let segments = total_size.div_ceil(self.config.chunk_size.max(1));
+        let num_workers = std::cmp::min(max_workers as u64, segments).max(1) as usize;
+        let queue = Arc::new(if self.config.tail_optimize {
+            SegmentQueue::with_tail(total_size, self.config.chunk_size, num_workers)
+        } else if self.config.interleave {
+            SegmentQueue::interleaved(total_size, self.config.chunk_size, num_workers)
+        } else {
+            SegmentQueue::new(total_size, self.config.chunk_size)
+        });
+        // When each worker that fetched anything found the queue empty
+        let idle = Arc::new(std::sync::Mutex::new(Vec::new()));
+        // Workers numbered from here on wait until the tuner wants them
+        let active = Arc::new(AtomicUsize::new(num_workers));
+        let _tuner = self.config.adaptive_connections.map(|_| {
+            let tuner = ConnectionTuner::new(num_workers);
+            active.store(tuner.active, Ordering::Relaxed);
+            self.start_tuner(tuner, active.clone(), pb.clone())
+        });
+
+        let part_path = self.part_path();
+        self.create_part(&part_path).await?;
+
+        info!(
+            "Using {}{} connection{} for {} segment{}",
+            if self.config.adaptive_connections.is_some() {
+                "up to "
+            } else {
+                ""
+            },
+            num_workers,
+            if num_workers == 1 { "" } else { "s" },
+            queue.len(),
+            if queue.len() == 1 { "" } else { "s" }
+        );
+
+        let (timings, collector) = match self.config.chunk_timings {
+            Some(ref path) => {
+                let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+                (Some(tx), Some(self.collect_timings(path.clone(), rx)))
+            }
+            None => (None, None),
+        };
+
+        let mut handles = Vec::new();
+        for worker in 0..num_workers {
+            let ctx = ChunkContext {
+                base: range_start,
+                worker,
+                timings: timings.clone(),
+                view: run.view.as_ref().map(|file| file.connection(worker)),
+                ..self.chunk_context(run, &part_path, &pb)
+            };
+            let queue = queue.clone();
+            let active = active.clone();
+            let idle = idle.clone();
+            // Workers pull segments until the queue drains, so a slow
+            // connection simply ends up fetching fewer of them (unless
+            // --interleave fixed each one's share).
+            let handle = tokio::spawn(async move {
+                let mut fetched = false;
+                loop {
+                    while worker >= active.load(Ordering::Relaxed) && queue.len() > 0 {
+                        tokio::time::sleep(Duration::from_millis(200)).await;
+                    }
+                    let Some((start, end)) = queue.next_for(worker) else {
+                        if fetched {
+                            idle.lock().unwrap().push(std::time::Instant::now());
+                        }
+                        if let Some(ref view) = ctx.view {
+                            view.finish();
+                        }
+                        break;
+                    };
+                    if let Err(e) =
+                        download_chunk(&ctx, range_start + start, range_start + end).await
+                    {
+                        // The download has failed; the others stop after
+                        // their current segment instead of fetching the
+                        // rest only for it to be cut off
+                        queue.clear();
+                        return Err(e);
+                    }
+                    queue.complete(start, end);
+                    fetched = true;
+                }
+                Ok::<(), Box<dyn std::error::Error + Send + Sync>>(())
+            });
+            handles.push(handle);
+        }
+
+        let mut result = Ok(());
+        for handle in handles {
+            let res = handle.await.map_err(Into::into).and_then(|r| r);
+            if result.is_ok() {
+                result = res;
+            }
+        }
+        // The workers' senders are gone with them; this closes the channel
+        drop(timings);
+        if let Some(collector) = collector {
+            let _ = collector.await;
+        }
+        if self.config.adaptive_connections.is_some() {
+            let settled = active.load(Ordering::Relaxed);
+            info!("{} settled on {} connections", self.config.url, settled);
+            self.state
+                .stats
+                .adaptive_connections
+                .fetch_max(settled, Ordering::Relaxed);
+        }
+        if let Err(e) = result {
+            if self.config.device_target {
+                return Err(e);
+            }
+            // Segments finish out of order; keep only the leading run so
+            // `--resume` can carry on sequentially from there
+            let kept = queue.completed_prefix();
+            debug!("Keeping {} bytes of {} for resume", kept, part_path);
+            OpenOptions::new()
+                .write(true)
+                .open(&part_path)
+                .await?
+                .set_len(kept)
+                .await?;
+            return Err(e);
+        }
+
+        // The last workers finished `spread` apart. The stragglers were on
+        // tail segments, so with full-size ones the spread would have been
+        // about `split` times as long.
+        let spread = {
+            let idle = idle.lock().unwrap();
+            match (idle.iter().min(), idle.iter().max()) {
+                (Some(first), Some(last)) => Some(last.duration_since(*first)),
+                _ => None,
+            }
+        };
+        if let Some(split) = queue.tail_split
+            && let Some(spread) = spread
+        {
+            let saved = spread.mul_f64(split - 1.0);
+            debug!(
+                "{}: connections finished within {:.2}s; about {:.2}s saved by splitting the tail",
+                self.config.url,
+                spread.as_secs_f64(),
+                saved.as_secs_f64()
+            );
+            self.state
+                .stats
+                .tail_saved_ms
+                .fetch_add(saved.as_millis() as u64, Ordering::Relaxed);
+        }
+
+        // Cheap insurance against a dropped or mis-bounded segment
+        let actual = metadata(&part_path).await?.len();
+        if actual != total_size && !self.config.device_target {
+            return Err(DownloadError::SizeMismatch {
+                expected: total_size,
+                actual,
+            }
+            .into());
+        }
+
+        Ok(())
+    }
+
+    /// Gathers the chunks' timings as they finish and appends them to the
+    /// `--chunk-timings` file once the workers are done. A file that can't be
+    /// written is only a warning.
+    fn collect_timings(
+        &self,
+        path: String,
+        mut rx: tokio::sync::mpsc::UnboundedReceiver<ChunkTiming>,
+    ) -> tokio::task::JoinHandle<()> {
+        let file = self.config.output_path.clone();
+        tokio::spawn(async move {
+            let mut rows = String::new();
+            while let Some(timing) = rx.recv().await {
+                rows.push_str(&timing.csv_row(&file));
+            }
+            let written = async {
+                OpenOptions::new()
+                    .append(true)
+                    .create(true)
+                    .open(&path)
+                    .await?
+                    .write_all(rows.as_bytes())
+                    .await
+            };
+            if let Err(e) = written.await {
+                console::eprintln(format_args!(
+                    "Warning: can't write chunk timings to {}: {}",
+                    path, e
+                ));
+            }
+        })
+    }
+
+    /// Fetches several `(start, end)` spans in one request and writes them
+    /// back to back. The server may answer with a `multipart/byteranges`
+    /// body, or with a single range covering them all; either way, each span
+    /// takes the bytes that fall inside it.
+    async fn download_ranges(
+        &self,
+        run: &RunState,
+        spans: &[(u64, u64)],
+        pb: ProgressBar,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let part_path = self.part_path();
+        let ctx = self.chunk_context(run, &part_path, &pb);
+        let _connection = ctx.connect().await?;
+
+        let ranges = spans
+            .iter()
+            .map(|(start, end)| format!("{}-{}", start, end))
+            .collect::<Vec<_>>()
+            .join(",");
+        debug!("GET {} bytes={}", self.config.url, ranges);
+        let mut response = send_with_retry(&self.config.retry, self.config.timeout, || {
+            self.client
+                .get(&self.config.url)
+                .header(RANGE, format!("bytes={}", ranges))
+        })
+        .await
+        .map_err(|e| self.request_error(e))?;
+        if response.status() == reqwest::StatusCode::OK {
+            return Err("Server sent the whole file instead of the requested ranges".into());
+        }
+        if response.status() != reqwest::StatusCode::PARTIAL_CONTENT {
+            return Err(DownloadError::HttpStatus(response.status()).into());
+        }
+
+        let header = |name| {
+            response
+                .headers()
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .map(str::to_string)
+        };
+        let mut parser = header(reqwest::header::CONTENT_TYPE)
+            .as_deref()
+            .and_then(multipart::boundary)
+            .map(|boundary| multipart::Parser::new(&boundary));
+        // Without a multipart body, the one range sent stands for all of them
+        let mut single = match parser {
+            Some(_) => None,
+            None => Some(
+                header(reqwest::header::CONTENT_RANGE)
+                    .as_deref()
+                    .and_then(multipart::content_range)
+                    .ok_or("Partial response without a Content-Range")?
+                    .0,
+            ),
+        };
+
+        // Where each span starts in the output, and how much of it arrived
+        let mut outputs = Vec::with_capacity(spans.len());
+        let mut offset = 0;
+        for (start, end) in spans {
+            outputs.push(offset);
+            offset += end - start + 1;
+        }
+        let mut covered = vec![0u64; spans.len()];
+
+        let mut file = self.open_part_at(&part_path, 0).await?;
+        let mut position = 0;
+        let res: Result<(), Box<dyn std::error::Error + Send + Sync>> = async {
+            loop {
+                wait_while_paused(&ctx.control, &pb).await?;
+                let Some(chunk) =
+                    tokio::time::timeout(self.config.timeout, response.chunk()).await??
+                else {
+                    break;
+                };
+                let pieces = match (&mut parser, &mut single) {
+                    (Some(parser), _) => parser.feed(&chunk)?,
+                    (None, Some(at)) => {
+                        let piece = (*at, chunk.to_vec());
+                        *at += chunk.len() as u64;
+                        vec![piece]
+                    }
+                    (None, None) => unreachable!(),
+                };
+                for (at, data) in pieces {
+                    let data_end = at + data.len() as u64;
+                    for (i, &(start, end)) in spans.iter().enumerate() {
+                        let from = at.max(start);
+                        let to = data_end.min(end + 1);
+                        if from >= to {
+                            continue;
+                        }
+                        let target = outputs[i] + (from - start);
+                        if target != position {
+                            file.seek(target).await?;
+                        }
+                        let piece = &data[(from - at) as usize..(to - at) as usize];
+                        file.write_all(piece).await?;
+                        position = target + piece.len() as u64;
+                        covered[i] += piece.len() as u64;
+                        ctx.record(piece.len() as u64).await?;
+                    }
+                }
+            }
+            if let Some(ref parser) = parser {
+                parser.finish()?;
+            }
+            Ok(())
+        }
+        .await;
+        file.finish().await?;
+        res?;
+
+        if let Some(i) = (0..spans.len()).find(|&i| covered[i] < spans[i].1 - spans[i].0 + 1) {
+            return Err(format!(
+                "Server left out part of range {}-{}",
+                spans[i].0, spans[i].1
+            )
+            .into());
+        }
+        Ok(())
+    }
+}
+
+/// `--max-buffer`: how many bytes all writers together may hold in their
+/// buffers. Each permit is a byte.
+#[derive(Clone)]
+struct BufferBudget {
+    permits: Arc<Semaphore>,
+    size: usize,
+}
+
+impl BufferBudget {
+    fn new(size: u64) -> Self {
+        let size = (size as usize).clamp(1, Semaphore::MAX_PERMITS);
+        BufferBudget {
+            permits: Arc::new(Semaphore::new(size)),
+            size,
+        }
+    }
+}
+
+/// Buffered writes to the `.part` file (`--write-buffer-size`), synced to
+/// disk every `sync_every` bytes if set. Call `finish` before dropping it.
+struct PartWriter {
+    file: BufWriter<File>,
+    sync_every: Option<u64>,
+    unsynced: u64,
+    budget: Option<BufferBudget>,
+    /// Bytes of the budget taken for what's in the buffer
+    held: usize,
+}
+
+impl PartWriter {
+    fn new(
+        file: File,
+        buffer_size: usize,
+        sync_every: Option<u64>,
+        budget: Option<BufferBudget>,
+    ) -> Self {
+        PartWriter {
+            file: BufWriter::with_capacity(buffer_size, file),
+            sync_every,
+            unsynced: 0,
+            budget,
+            held: 0,
+        }
+    }
+
+    async fn write_all(&mut self, data: &[u8]) -> std::io::Result<()> {
+        if let Some(budget) = self.budget.clone() {
+            let wanted = data.len().min(budget.size).min(u32::MAX as usize);
+            match budget.permits.try_acquire_many(wanted as u32) {
+                Ok(permit) => permit.forget(),
+                Err(_) => {
+                    // Wait with nothing held: writers waiting for each
+                    // other's buffered bytes would wait forever
+                    self.file.flush().await?;
+                    self.release_written();
+                    budget
+                        .permits
+                        .acquire_many(wanted as u32)
+                        .await
+                        .map_err(std::io::Error::other)?
+                        .forget();
+                }
+            }
+            self.held += wanted;
+        }
+        self.file.write_all(data).await?;
+        self.release_written();
+        if let Some(every) = self.sync_every {
+            self.unsynced += data.len() as u64;
+            if self.unsynced >= every {
+                self.sync().await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Moves on to `pos` for the next write, writing out what's buffered.
+    async fn seek(&mut self, pos: u64) -> std::io::Result<()> {
+        self.file.seek(SeekFrom::Start(pos)).await?;
+        self.release_written();
+        Ok(())
+    }
+
+    /// Gives back the budget of whatever has left the buffer.
+    fn release_written(&mut self) {
+        if let Some(ref budget) = self.budget {
+            let buffered = self.file.buffer().len().min(self.held);
+            budget.permits.add_permits(self.held - buffered);
+            self.held = buffered;
+        }
+    }
+
+    async fn sync(&mut self) -> std::io::Result<()> {
+        self.file.flush().await?;
+        self.release_written();
+        self.file.get_ref().sync_data().await?;
+        self.unsynced = 0;
+        Ok(())
+    }
+
+    /// Writes out whatever is still buffered, and syncs it if syncing along
+    /// the way.
+    async fn finish(&mut self) -> std::io::Result<()> {
+        if self.sync_every.is_some() {
+            self.sync().await
+        } else {
+            self.file.flush().await?;
+            self.release_written();
+            Ok(())
+        }
+    }
+}
+
+impl Drop for PartWriter {
+    fn drop(&mut self) {
+        if let Some(ref budget) = self.budget {
+            budget.permits.add_permits(self.held);
+        }
+    }
+}
+
+/// With `--tail-optimize`, the segments of the last stretch of a file are
+/// this many times smaller than the rest
+const TAIL_SPLIT: u64 = 4;
+/// Tail segments are never cut smaller than this
+const MIN_TAIL_SEGMENT: u64 = 64 * 1024;
+
+/// Byte ranges of a file still waiting to be fetched, shared by its workers.
+struct SegmentQueue {
+    segments: std::sync::Mutex<std::collections::VecDeque<(u64, u64)>>,
+    /// Finished segments, as start -> end
+    completed: std::sync::Mutex<std::collections::BTreeMap<u64, u64>>,
+    /// How many times smaller the tail segments are, if the tail was split
+    tail_split: Option<f64>,
+    /// `--interleave`: a queue of its own for each worker, in place of
+    /// `segments`
+    lanes: Vec<std::sync::Mutex<std::collections::VecDeque<(u64, u64)>>>,
+}
+
+impl SegmentQueue {
+    fn new(total_size: u64, segment_size: u64) -> Self {
+        Self::with_tail(total_size, segment_size, 0)
+    }
+
+    /// Like `new`, but with a segment's worth for each of `workers` at the
+    /// end cut into smaller segments (`--tail-optimize`). Whichever workers
+    /// are free take those, so the last of them finish close together
+    /// instead of one connection finishing a full segment alone. At most
+    /// half the file is split, and none with fewer than two workers.
+    fn with_tail(total_size: u64, segment_size: u64, workers: usize) -> Self {
+        let segment_size = segment_size.max(1);
+        let tail_segment = (segment_size / TAIL_SPLIT).max(MIN_TAIL_SEGMENT);
+        let tail = if workers > 1 && tail_segment < segment_size {
+            (segment_size.saturating_mul(workers as u64)).min(total_size / 2)
+        } else {
+            0
+        };
+        let mut segments = std::collections::VecDeque::new();
+        Self::split(&mut segments, 0, total_size - tail, segment_size);
+        Self::split(&mut segments, total_size - tail, tail, tail_segment);
+        Self {
+            segments: std::sync::Mutex::new(segments),
+            completed: std::sync::Mutex::new(std::collections::BTreeMap::new()),
+            tail_split: (tail > 0).then(|| segment_size as f64 / tail_segment as f64),
+            lanes: Vec::new(),
+        }
+    }
+
+    /// Like `new`, but with segment `k` given to worker `k % workers` for
+    /// good. Neighbouring ranges go to different connections, which may
+    /// suit CDN edges that cache small popular ranges; there's no stealing,
+    /// so the slowest connection sets the pace.
+    fn interleaved(total_size: u64, segment_size: u64, workers: usize) -> Self {
+        let mut segments = std::collections::VecDeque::new();
+        Self::split(&mut segments, 0, total_size, segment_size.max(1));
+        let mut lanes: Vec<_> = (0..workers.max(1))
+            .map(|_| std::collections::VecDeque::new())
+            .collect();
+        let count = lanes.len();
+        for (k, segment) in segments.into_iter().enumerate() {
+            lanes[k % count].push_back(segment);
+        }
+        Self {
+            lanes: lanes.into_iter().map(std::sync::Mutex::new).collect(),
+            ..Self::new(0, segment_size)
+        }
+    }
+
+    /// Cuts `len` bytes from `offset` into equal segments of at most `size`.
+    fn split(
+        segments: &mut std::collections::VecDeque<(u64, u64)>,
+        offset: u64,
+        len: u64,
+        size: u64,
+    ) {
+        let count = len.div_ceil(size);
+        // Each boundary is derived from the segment index alone, so segment
+        // `i` always ends exactly one byte before segment `i + 1` starts
+        let boundary =
+            |i: u64| offset + (u128::from(i) * u128::from(len) / u128::from(count.max(1))) as u64;
+        segments.extend((0..count).map(|i| (boundary(i), boundary(i + 1) - 1)));
+    }
+
+    fn next(&self) -> Option<(u64, u64)> {
+        self.segments.lock().unwrap().pop_front()
+    }
+
+    /// The next segment for `worker`: its own with `--interleave`, else
+    /// whichever is next.
+    fn next_for(&self, worker: usize) -> Option<(u64, u64)> {
+        match self.lanes.get(worker) {
+            Some(lane) => lane.lock().unwrap().pop_front(),
+            None => self.next(),
+        }
+    }
+
+    /// Drops every segment not yet taken.
+    fn clear(&self) {
+        self.segments.lock().unwrap().clear();
+        for lane in &self.lanes {
+            lane.lock().unwrap().clear();
+        }
+    }
+
+    fn complete(&self, start: u64, end: u64) {
+        self.completed.lock().unwrap().insert(start, end);
+    }
+
+    /// Length of the unbroken run of finished segments from the start of
+    /// the file, i.e. how much of it a sequential resume can keep.
+    fn completed_prefix(&self) -> u64 {
+        let mut prefix = 0;
+        for (&start, &end) in self.completed.lock().unwrap().iter() {
+            if start != prefix {
+                break;
+            }
+            prefix = end + 1;
+        }
+        prefix
+    }
+
+    fn len(&self) -> usize {
+        self.segments.lock().unwrap().len()
+            + self
+                .lanes
+                .iter()
+                .map(|lane| lane.lock().unwrap().len())
+                .sum::<usize>()
+    }
+}
+
+/// Everything a worker needs to fetch a byte range into the `.part` file.
+#[derive(Clone)]
+struct ChunkContext {
+    client: Client,
+    url: String,
+    output_path: String,
+    /// Remote offset stored at the start of the `.part` file
+    base: u64,
+    rotate_user_agent: bool,
+    pb: ProgressBar,
+    total_pb: ProgressBar,
+    timeout: Duration,
+    write_buffer_size: usize,
+    /// Sync written data every this many bytes (`--fsync per-chunk`)
+    sync_every: Option<u64>,
+    retry: RetryPolicy,
+    limiter: Option<Arc<BandwidthLimiter>>,
+    pacer: Option<Arc<BandwidthLimiter>>,
+    speed_guard: Option<Arc<SpeedGuard>>,
+    host_slots: Option<Arc<Semaphore>>,
+    stats: Arc<TransferStats>,
+    control: watch::Receiver<TransferState>,
+    buffer_budget: Option<BufferBudget>,
+    /// Bytes since `--simulate` last dropped this connection
+    simulated: Arc<AtomicU64>,
+    /// Which of the download's workers this is
+    worker: usize,
+    /// Where finished chunks are reported for `--chunk-timings`
+    timings: Option<tokio::sync::mpsc::UnboundedSender<ChunkTiming>>,
+    /// `--max-file-size`, checked against the file's bar
+    max_file_size: Option<u64>,
+    /// This connection's line on the `--tui` screen
+    view: Option<Arc<tui::ConnectionView>>,
+}
+
+impl ChunkContext {
+    /// Waits for a free connection to the host under
+    /// `--max-connections-per-host`. Keep it while transferring.
+    async fn connect(&self) -> Result<Connection<'_>, tokio::sync::AcquireError> {
+        let slot = match self.host_slots {
+            Some(ref slots) => Some(slots.acquire().await?),
+            None => None,
+        };
+        let active = self
+            .stats
+            .active_connections
+            .fetch_add(1, Ordering::Relaxed)
+            + 1;
+        self.stats
+            .peak_connections
+            .fetch_max(active, Ordering::Relaxed);
+        Ok(Connection {
+            _slot: slot,
+            stats: &self.stats,
+        })
+    }
+
+    /// Accounts for `bytes` just written and applies rate limiting. Fails
+    /// once the transfer has been too slow for too long, or the file has
+    /// grown past `--max-file-size`.
+    async fn record(&self, bytes: u64) -> Result<(), DownloadError> {
+        self.pb.inc(bytes);
+        self.total_pb.inc(bytes);
+        self.stats.bytes.fetch_add(bytes, Ordering::Relaxed);
+        if let Some(ref view) = self.view {
+            view.add(bytes);
+        }
+        if let Some(max) = self.max_file_size
+            && self.pb.position() > max
+        {
+            return Err(DownloadError::FileTooLarge {
+                size: self.pb.position(),
+                max,
+            });
+        }
+        simulate::after_read(
+            &self.output_path,
+            self.pb.position(),
+            self.pb.length(),
+            &self.simulated,
+            bytes,
+        )
+        .await
+        .map_err(DownloadError::Simulated)?;
+        if let Some(ref guard) = self.speed_guard {
+            guard.check(bytes)?;
+        }
+        if let Some(ref limiter) = self.limiter {
+            limiter.throttle(bytes).await;
+        }
+        if let Some(ref pacer) = self.pacer {
+            pacer.throttle(bytes).await;
+        }
+        Ok(())
+    }
+}
+
+/// Hashes `path`, or only its first `len` bytes if given: a device target
+/// reports its own size rather than the file's.
+async fn verify_checksum(
+    checksum: &Checksum,
+    path: &str,
+    len: Option<u64>,
+) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
+    let mut file = File::open(path).await?.take(len.unwrap_or(u64::MAX));
+    let mut buffer = vec![0u8; 8192];
+    let mut hasher = StreamingHash::new(checksum);
+    loop {
+        let n = file.read(&mut buffer).await?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buffer[..n]);
+    }
+    Ok(checksum.matches(&hasher.finish()))
+}
+
+/// A hash of the kind a `Checksum` names, fed a piece at a time.
+enum StreamingHash {
+    Md5(Md5),
+    Sha1(Sha1),
+    Sha224(Sha224),
+    Sha256(Sha256),
+    Sha384(Sha384),
+    Sha512(Sha512),
+    Blake2b(Blake2b512),
+    Blake3(Box<blake3::Hasher>),
+}
+
+impl StreamingHash {
+    fn new(checksum: &Checksum) -> Self {
+        match checksum {
+            Checksum::Md5(_) => StreamingHash::Md5(Md5::new()),
+            Checksum::Sha1(_) => StreamingHash::Sha1(Sha1::new()),
+            Checksum::Sha224(_) => StreamingHash::Sha224(Sha224::new()),
+            Checksum::Sha256(_) => StreamingHash::Sha256(Sha256::new()),
+            Checksum::Sha384(_) => StreamingHash::Sha384(Sha384::new()),
+            Checksum::Sha512(_) => StreamingHash::Sha512(Sha512::new()),
+            Checksum::Blake2b(_) => StreamingHash::Blake2b(Blake2b512::new()),
+            Checksum::Blake3(_) => StreamingHash::Blake3(Box::new(blake3::Hasher::new())),
+        }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        match self {
+            StreamingHash::Md5(hasher) => hasher.update(data),
+            StreamingHash::Sha1(hasher) => hasher.update(data),
+            StreamingHash::Sha224(hasher) => hasher.update(data),
+            StreamingHash::Sha256(hasher) => hasher.update(data),
+            StreamingHash::Sha384(hasher) => hasher.update(data),
+            StreamingHash::Sha512(hasher) => hasher.update(data),
+            StreamingHash::Blake2b(hasher) => hasher.update(data),
+            StreamingHash::Blake3(hasher) => {
+                hasher.update(data);
+            }
+        }
+    }
+
+    /// The digest, as lowercase hex.
+    fn finish(self) -> String {
+        match self {
+            StreamingHash::Md5(hasher) => hex::encode(hasher.finalize()),
+            StreamingHash::Sha1(hasher) => hex::encode(hasher.finalize()),
+            StreamingHash::Sha224(hasher) => hex::encode(hasher.finalize()),
+            StreamingHash::Sha256(hasher) => hex::encode(hasher.finalize()),
+            StreamingHash::Sha384(hasher) => hex::encode(hasher.finalize()),
+            StreamingHash::Sha512(hasher) => hex::encode(hasher.finalize()),
+            StreamingHash::Blake2b(hasher) => hex::encode(hasher.finalize()),
+            StreamingHash::Blake3(hasher) => hasher.finalize().to_hex().to_string(),
+        }
+    }
+}
+
+/// Looks for a `sha256sum`-style file next to `url`, trying `<url>.sha256`,
+/// `.sha1` and `.md5` in turn.
+async fn find_sibling_checksum(client: &Client, url: &str, timeout: Duration) -> Option<Checksum> {
+    let filename = derive_filename(url, false);
+    for (ext, make) in [
+        ("sha256", Checksum::Sha256 as fn(String) -> Checksum),
+        ("sha1", Checksum::Sha1),
+        ("md5", Checksum::Md5),
+    ] {
+        let sums_url = format!("{}.{}", url, ext);
+        debug!("GET {} (checksum discovery)", sums_url);
+        let response = match tokio::time::timeout(timeout, client.get(&sums_url).send()).await {
+            Ok(Ok(response)) if response.status().is_success() => response,
+            _ => continue,
+        };
+        let Ok(Ok(contents)) = tokio::time::timeout(timeout, response.text()).await else {
+            continue;
+        };
+        if let Some(hash) = parse_checksum_file(&contents, &filename) {
+            info!("{}: using checksum from {}", url, sums_url);
+            return Some(make(hash));
+        }
+        debug!("{} has no usable entry for {}", sums_url, filename);
+    }
+    None
+}
+
+/// Renames `from` to `to`, or copies it when they're on different
+/// filesystems (`--temp-dir`). The copy goes to `TO.part` first, so `to`
+/// never exists half-written.
+async fn move_file(from: &str, to: &str, sync: bool) -> std::io::Result<()> {
+    match tokio::fs::rename(from, to).await {
+        Err(e) if e.kind() == std::io::ErrorKind::CrossesDevices => {
+            info!("Copying {} to {} on another filesystem", from, to);
+            let staging = format!("{}.part", to);
+            tokio::fs::copy(from, &staging).await?;
+            if sync {
+                sync_file(&staging).await?;
+            }
+            tokio::fs::rename(&staging, to).await?;
+            tokio::fs::remove_file(from).await
+        }
+        res => res,
+    }
+}
+
+/// Makes `path`'s data and metadata durable.
+async fn sync_file(path: &str) -> std::io::Result<()> {
+    OpenOptions::new()
+        .write(true)
+        .open(path)
+        .await?
+        .sync_all()
+        .await
+}
+
+async fn sha256_file(path: &str) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+    let mut file = File::open(path).await?;
+    let mut buffer = vec![0u8; 8192];
+    let mut hasher = Sha256::new();
+    loop {
+        let n = file.read(&mut buffer).await?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buffer[..n]);
+    }
+    Ok(hex::encode(hasher.finalize()))
+}
+
+async fn hash_range(
+    path: &str,
+    start: u64,
+    end: u64,
+) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+    let mut file = File::open(path).await?;
+    file.seek(SeekFrom::Start(start)).await?;
+    let mut reader = file.take(end - start + 1);
+    let mut buffer = vec![0u8; 8192];
+    let mut hasher = Sha256::new();
+    loop {
+        let n = reader.read(&mut buffer).await?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buffer[..n]);
+    }
+    Ok(hex::encode(hasher.finalize()))
+}
+
+/// When and how often failures are retried. There are two budgets:
+/// `tries_per_chunk` for each request (a chunk, or the whole file when
+/// sequential) and `retries` for starting a failed download over, and
+/// `max_total` caps all of a file's retries together.
+#[derive(Debug, Clone)]
+struct RetryPolicy {
+    retries: u32,
+    tries_per_chunk: u32,
+    max_total: Option<u64>,
+    statuses: Vec<u16>,
+    all_errors: bool,
+    /// Retries made so far, shared by every download of the run
+    retried: Arc<AtomicU64>,
+    /// This file's retries so far, of any kind
+    file_retried: Arc<AtomicU64>,
+    /// This file's requests now waiting out a backoff
+    waiting: Arc<AtomicUsize>,
+    /// Stops a wait on `q` or `--max-time`
+    control: watch::Receiver<TransferState>,
+}
+
+impl RetryPolicy {
+    /// A copy for one file, with its own counts of retries and waiting
+    /// requests
+    fn for_file(&self) -> Self {
+        RetryPolicy {
+            file_retried: Arc::default(),
+            waiting: Arc::default(),
+            ..self.clone()
+        }
+    }
+
+    /// Counts a retry about to be made after `failure`, unless that would
+    /// go over `--max-total-retries`.
+    fn count_retry(&self, failure: &dyn std::fmt::Display) -> Result<(), DownloadError> {
+        let count = self.file_retried.fetch_add(1, Ordering::Relaxed) + 1;
+        if let Some(max) = self.max_total
+            && count > max
+        {
+            return Err(DownloadError::RetryBudgetExhausted {
+                max,
+                last: failure.to_string(),
+            });
+        }
+        self.retried.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Sleeps for `wait` before a retry, showing "retrying" meanwhile.
+    /// Fails as soon as the transfers are cancelled or run out of time.
+    async fn back_off(&self, wait: Duration) -> Result<(), DownloadError> {
+        struct Waiting<'a>(&'a AtomicUsize);
+        impl Drop for Waiting<'_> {
+            fn drop(&mut self) {
+                self.0.fetch_sub(1, Ordering::Relaxed);
+            }
+        }
+
+        self.waiting.fetch_add(1, Ordering::Relaxed);
+        let _waiting = Waiting(&self.waiting);
+        let mut control = self.control.clone();
+        let sleep = tokio::time::sleep(wait);
+        tokio::pin!(sleep);
+        loop {
+            match *control.borrow_and_update() {
+                TransferState::Cancelled => return Err(DownloadError::Cancelled),
+                TransferState::TimedOut => return Err(DownloadError::Deadline),
+                TransferState::Running | TransferState::Paused => {}
+            }
+            tokio::select! {
+                () = &mut sleep => return Ok(()),
+                changed = control.changed() => {
+                    // Nothing can stop the wait any more
+                    if changed.is_err() {
+                        sleep.await;
+                        return Ok(());
+                    }
+                }
+            }
+        }
+    }
+
+    fn retries_status(&self, status: reqwest::StatusCode) -> bool {
+        self.statuses.contains(&status.as_u16())
+            || (self.all_errors && (status.is_client_error() || status.is_server_error()))
+    }
+
+    /// Connection failures and timeouts are transient; anything else (a bad
+    /// URL, too many redirects, ...) only with `--retry-all-errors`.
+    fn retries_error(&self, e: &reqwest::Error) -> bool {
+        e.is_connect() || e.is_timeout() || (self.all_errors && !e.is_redirect())
+    }
+
+    /// Whether trying again could help after a chunk or download failed.
+    /// Not once the user or `--max-time` stopped things, nor for a wrong
+    /// content type, a hopelessly slow server or a status not worth it.
+    fn retries_failure(&self, e: &(dyn std::error::Error + Send + Sync + 'static)) -> bool {
+        match e.downcast_ref::<DownloadError>() {
+            Some(DownloadError::HttpStatus(status)) => self.retries_status(*status),
+            Some(
+                DownloadError::Cancelled
+                | DownloadError::Deadline
+                | DownloadError::TooSlow { .. }
+                | DownloadError::UnexpectedType { .. }
+                | DownloadError::FileTooLarge { .. }
+                | DownloadError::RetryBudgetExhausted { .. },
+            ) => false,
+            _ => true,
+        }
+    }
+}
+
+/// 1s, 2s, 4s, ... capped at 30s, for the `attempt`th retry (from 1).
+fn backoff(attempt: u32) -> Duration {
+    Duration::from_secs((1u64 << (attempt - 1).min(5)).min(30))
+}
+
+/// Sends the request built by `request`, retrying failed attempts within
+/// `--tries-per-chunk`. Each attempt gets `timeout` to produce response
+/// headers. Once tries run out, the last response is returned even if it's
+/// an error status, so callers report it as before.
+async fn send_with_retry(
+    policy: &RetryPolicy,
+    timeout: Duration,
+    request: impl Fn() -> reqwest::RequestBuilder,
+) -> Result<reqwest::Response, Box<dyn std::error::Error + Send + Sync>> {
+    send_with_tries(policy, timeout, &mut 0, request).await
+}
+
+/// `send_with_retry` with the tries counted in `tries`, so a chunk that
+/// also fails while reading its body draws on the same budget.
+async fn send_with_tries(
+    policy: &RetryPolicy,
+    timeout: Duration,
+    tries: &mut u32,
+    request: impl Fn() -> reqwest::RequestBuilder,
+) -> Result<reqwest::Response, Box<dyn std::error::Error + Send + Sync>> {
+    loop {
+        *tries += 1;
+        let can_retry = *tries < policy.tries_per_chunk;
+        simulate::before_request().await;
+        let (client, built) = request().build_split();
+        let built = built?;
+        let url = built.url().clone();
+        let exchange = header_dump::request(&built);
+        let sent = tokio::time::timeout(timeout, client.execute(built)).await;
+        if let Some(exchange) = exchange {
+            match sent {
+                Ok(Ok(ref response)) => header_dump::response(exchange, response, &url),
+                Ok(Err(ref e)) => header_dump::error(exchange, e),
+                Err(ref e) => header_dump::error(exchange, e),
+            }
+        }
+        let (failure, retry_after) = match sent {
+            Ok(Ok(response)) if can_retry && policy.retries_status(response.status()) => {
+                let retry_after = match response.status() {
+                    reqwest::StatusCode::TOO_MANY_REQUESTS
+                    | reqwest::StatusCode::SERVICE_UNAVAILABLE => response
+                        .headers()
+                        .get(reqwest::header::RETRY_AFTER)
+                        .and_then(|v| v.to_str().ok())
+                        .and_then(|v| parse_retry_after(v, chrono::Utc::now())),
+                    _ => None,
+                };
+                (
+                    format!("{}: {}", response.url(), response.status()),
+                    retry_after,
+                )
+            }
+            Ok(Ok(response)) => return Ok(response),
+            Ok(Err(e)) if can_retry && policy.retries_error(&e) => (e.to_string(), None),
+            Ok(Err(e)) => return Err(e.into()),
+            Err(elapsed) if can_retry => (elapsed.to_string(), None),
+            Err(elapsed) => return Err(elapsed.into()),
+        };
+
+        policy.count_retry(&failure)?;
+        // The server's Retry-After wins over the backoff, within reason
+        let wait = match retry_after {
+            Some(wait) if wait > MAX_RETRY_AFTER => {
+                console::eprintln(format_args!(
+                    "Warning: {} asks to wait {}s before retrying; waiting {}s instead",
+                    url,
+                    wait.as_secs(),
+                    MAX_RETRY_AFTER.as_secs()
+                ));
+                MAX_RETRY_AFTER
+            }
+            Some(wait) => wait,
+            None => backoff(*tries),
+        };
+        transfer_log::event(format_args!(
+            "RETRY {} (try {}/{})",
+            failure,
+            *tries + 1,
+            policy.tries_per_chunk
+        ));
+        info!(
+            "{}; retrying in {:.1}s (try {}/{} of --tries-per-chunk)",
+            failure,
+            wait.as_secs_f64(),
+            *tries + 1,
+            policy.tries_per_chunk
+        );
+        policy.back_off(wait).await?;
+    }
+}
+
+/// Longest a server's `Retry-After` is waited for
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(300);
+
+/// Wait from a `Retry-After` value: either delay-seconds or an HTTP date.
+/// A date in the past means no wait at all.
+fn parse_retry_after(value: &str, now: chrono::DateTime<chrono::Utc>) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date = chrono::DateTime::parse_from_rfc2822(value).ok()?;
+    Some(
+        (date.with_timezone(&chrono::Utc) - now)
+            .to_std()
+            .unwrap_or(Duration::ZERO),
+    )
+}
+
+/// Fetches bytes `start..=end` into the `.part` file. A failure part-way
+/// through picks up where it stopped, within `--tries-per-chunk`.
+async fn download_chunk(
+    ctx: &ChunkContext,
+    start: u64,
+    end: u64,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let started = chrono::Utc::now();
+    let mut tries = 0;
+    let mut received = 0;
+    if let Some(ref view) = ctx.view {
+        view.begin(start, end);
+    }
+    let res = loop {
+        match fetch_range(ctx, start, end, &mut tries, &mut received).await {
+            Ok(()) => break Ok(()),
+            Err(e)
+                if tries < ctx.retry.tries_per_chunk && ctx.retry.retries_failure(e.as_ref()) =>
+            {
+                if let Err(exhausted) = ctx.retry.count_retry(&e) {
+                    break Err(exhausted.into());
+                }
+                if let Some(ref view) = ctx.view {
+                    view.retried();
+                }
+                let wait = backoff(tries);
+                transfer_log::event(format_args!(
+                    "RETRY {} bytes {}-{}: {}; continuing at byte {} (try {}/{})",
+                    ctx.output_path,
+                    start,
+                    end,
+                    e,
+                    start + received,
+                    tries + 1,
+                    ctx.retry.tries_per_chunk
+                ));
+                info!(
+                    "Chunk {}-{}: {}; retrying from byte {} in {:.1}s (try {}/{} of --tries-per-chunk)",
+                    start,
+                    end,
+                    e,
+                    start + received,
+                    wait.as_secs_f64(),
+                    tries + 1,
+                    ctx.retry.tries_per_chunk
+                );
+                if let Err(stopped) = ctx.retry.back_off(wait).await {
+                    break Err(stopped.into());
+                }
+            }
+            Err(e) => break Err(e),
+        }
+    };
+
+    transfer_log::event(format_args!(
+        "CHUNK {} bytes {}-{} {} ({} bytes, {} retries)",
+        ctx.output_path,
+        start,
+        end,
+        if res.is_ok() { "done" } else { "failed" },
+        received,
+        tries.saturating_sub(1)
+    ));
+    if let Some(ref timings) = ctx.timings {
+        let _ = timings.send(ChunkTiming {
+            worker: ctx.worker,
+            start,
+            end,
+            started,
+            finished: chrono::Utc::now(),
+            bytes: received,
+            retries: tries.saturating_sub(1),
+            ok: res.is_ok(),
+        });
+    }
+    res
+}
+
+const CHUNK_TIMINGS_HEADER: &str =
+    "file,worker,start,end,started,finished,bytes,seconds,bytes_per_sec,retries,status\n";
+
+/// How one chunk went, for `--chunk-timings`.
+struct ChunkTiming {
+    worker: usize,
+    start: u64,
+    end: u64,
+    started: chrono::DateTime<chrono::Utc>,
+    finished: chrono::DateTime<chrono::Utc>,
+    bytes: u64,
+    retries: u32,
+    ok: bool,
+}
+
+impl ChunkTiming {
+    fn csv_row(&self, file: &str) -> String {
+        let seconds = (self.finished - self.started).as_seconds_f64();
+        let timestamp = |t: chrono::DateTime<chrono::Utc>| {
+            t.to_rfc3339_opts(chrono::SecondsFormat::Millis, true)
+        };
+        // A path may hold commas or quotes
+        let file = if file.contains([',', '"', '\n']) {
+            format!("\"{}\"", file.replace('"', "\"\""))
+        } else {
+            file.to_string()
+        };
+        format!(
+            "{},{},{},{},{},{},{},{:.3},{},{},{}\n",
+            file,
+            self.worker,
+            self.start,
+            self.end,
+            timestamp(self.started),
+            timestamp(self.finished),
+            self.bytes,
+            seconds,
+            (self.bytes as f64 / seconds.max(0.001)) as u64,
+            self.retries,
+            if self.ok { "ok" } else { "failed" }
+        )
+    }
+}
+
+/// One try at the rest of a chunk: from `start + received` to `end`,
+/// adding what arrives to `received`.
+async fn fetch_range(
+    ctx: &ChunkContext,
+    start: u64,
+    end: u64,
+    tries: &mut u32,
+    received: &mut u64,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let from = start + *received;
+    let mut headers = HeaderMap::new();
+    headers.insert(RANGE, format!("bytes={}-{}", from, end).parse().unwrap());
+    if ctx.rotate_user_agent {
+        headers.insert(USER_AGENT, HeaderValue::from_static(random_user_agent()));
+    }
+
+    let _connection = ctx.connect().await?;
+
+    let response = send_with_tries(&ctx.retry, ctx.timeout, tries, || {
+        ctx.client.get(&ctx.url).headers(headers.clone())
+    })
+    .await?;
+    debug!(
+        "Chunk {}-{} -> {} {:?}",
+        from,
+        end,
+        response.status(),
+        response.headers()
+    );
+
+    if response.status() != reqwest::StatusCode::PARTIAL_CONTENT {
+        return Err("Server did not return partial content for chunk request".into());
+    }
+
+    let mut response = response;
+
+    let mut file = OpenOptions::new()
+        .write(true)
+        .open(&ctx.output_path)
+        .await?;
+    file.seek(SeekFrom::Start(from - ctx.base)).await?;
+    // Each worker buffers only its own range
+    let mut file = PartWriter::new(
+        file,
+        ctx.write_buffer_size,
+        ctx.sync_every,
+        ctx.buffer_budget.clone(),
+    );
+
+    let res: Result<(), Box<dyn std::error::Error + Send + Sync>> = async {
+        loop {
+            wait_while_paused(&ctx.control, &ctx.pb).await?;
+            let Some(chunk) = tokio::time::timeout(ctx.timeout, response.chunk()).await?? else {
+                break;
+            };
+            file.write_all(&chunk).await?;
+            *received += chunk.len() as u64;
+            ctx.record(chunk.len() as u64).await?;
+        }
+        // Without a Content-Length, a server that hangs up early looks
+        // like the end of the body; treated as a failure, the retry
+        // fetches what's missing
+        let at = start + *received;
+        if at <= end {
+            return Err(DownloadError::ChunkCutOff { start, end, at }.into());
+        }
+        Ok(())
+    }
+    .await;
+    // The next try picks up from `received`, so it all has to be on disk
+    file.finish().await?;
+    res?;
+    debug!("Chunk {}-{} complete", start, end);
+
+    Ok(())
+}
+
+/// Total length from a `Content-Range: bytes 0-0/12345` value. `None` for
+/// an unknown total (`bytes 0-0/*`) or a malformed header.
+fn parse_content_range_total(value: &str) -> Option<u64> {
+    let (unit, rest) = value.trim().split_once(' ')?;
+    if !unit.eq_ignore_ascii_case("bytes") {
+        return None;
+    }
+    let (range, total) = rest.trim().split_once('/')?;
+    if range != "*" {
+        let (start, end) = range.split_once('-')?;
+        if start.parse::<u64>().ok()? > end.parse::<u64>().ok()? {
+            return None;
+        }
+    }
+    total.parse().ok()
+}
+
+/// Whether an `Accept-Ranges` value such as `bytes` or `bytes, none` lists
+/// byte ranges. Without the header, nothing says they're supported.
+fn accepts_byte_ranges(value: Option<&HeaderValue>) -> bool {
+    value.and_then(|v| v.to_str().ok()).is_some_and(|v| {
+        v.split(',')
+            .any(|unit| unit.trim().eq_ignore_ascii_case("bytes"))
+    })
+}
+
+/// Finds the hash for `filename` in `sha256sum`-style output: `HASH  name`,
+/// or `HASH *name` for binary mode. A file with a single bare hash, or a
+/// single entry under another name, is taken as is.
+fn parse_checksum_file(contents: &str, filename: &str) -> Option<String> {
+    let entries: Vec<(&str, Option<&str>)> = contents
+        .lines()
+        .filter_map(|line| {
+            let line = line.trim();
+            let (hash, name) = match line.split_once(char::is_whitespace) {
+                Some((hash, name)) => (hash, Some(name.trim_start().trim_start_matches('*'))),
+                None => (line, None),
+            };
+            (!hash.is_empty() && hash.chars().all(|c| c.is_ascii_hexdigit()))
+                .then_some((hash, name))
+        })
+        .collect();
+
+    let named = entries
+        .iter()
+        .find(|(_, name)| name.is_some_and(|name| name.rsplit('/').next() == Some(filename)));
+    match (named, entries.as_slice()) {
+        (Some((hash, _)), _) | (None, [(hash, _)]) => Some(hash.to_lowercase()),
+        _ => None,
+    }
+}
+
+/// Parses a `Name: Value` request header.
+fn parse_header(line: &str) -> Result<(HeaderName, HeaderValue), String> {
+    let (name, value) = line
+        .split_once(':')
+        .ok_or_else(|| format!("Invalid header {:?} (expected Name: Value)", line))?;
+    let name = HeaderName::from_bytes(name.trim().as_bytes())
+        .map_err(|_| format!("Invalid header name {:?}", name.trim()))?;
+    let value = HeaderValue::from_str(value.trim())
+        .map_err(|_| format!("Invalid value for header {}", name))?;
+    Ok((name, value))
+}
+
+/// Parses a `--headers-file`: one `Name: Value` per line, skipping blank
+/// lines and `#` comments. Errors start with the line number.
+fn parse_headers_file(contents: &str) -> Result<HeaderMap, String> {
+    let mut headers = HeaderMap::new();
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (name, value) = parse_header(line).map_err(|e| format!("{}: {}", number + 1, e))?;
+        // A repeated name is sent repeatedly, as in a real header block
+        headers.append(name, value);
+    }
+    Ok(headers)
+}
+
+/// Parses a `URL [checksum]` line from stdin or `--input-file`. Blank lines
+/// give nothing.
+fn parse_task_line(line: &str) -> Option<(String, Option<Checksum>)> {
+    let mut parts = line.split_whitespace();
+    let url = parts.next()?.to_string();
+    Some((url, parts.next().and_then(Checksum::parse)))
+}
+
+const DEFAULT_RENAME_PATTERN: &str = "{name} ({n}){ext}";
+
+/// Checks a `--rename-pattern`: only known placeholders, and `{n}` among
+/// them, or no name it gives would ever differ from the last.
+fn parse_rename_pattern(pattern: &str) -> Result<String, String> {
+    let mut rest = pattern;
+    let mut numbered = false;
+    while let Some(open) = rest.find('{') {
+        let close = rest[open..]
+            .find('}')
+            .map(|close| open + close)
+            .ok_or_else(|| format!("Unclosed {{ in rename pattern: {}", pattern))?;
+        match &rest[open + 1..close] {
+            "n" => numbered = true,
+            "name" | "ext" => {}
+            other => {
+                return Err(format!(
+                    "Unknown placeholder {{{}}} in rename pattern (expected name, n or ext)",
+                    other
+                ));
+            }
+        }
+        rest = &rest[close + 1..];
+    }
+    if !numbered {
+        return Err(format!("Rename pattern {:?} needs {{n}}", pattern));
+    }
+    if pattern.contains(['/', '\\']) {
+        return Err(format!(
+            "Rename pattern {:?} can only change the file name",
+            pattern
+        ));
+    }
+    Ok(pattern.to_string())
+}
+
+/// Finds the first name from `pattern` with `{n}` = 1, 2, ... that doesn't
+/// exist yet, e.g. `name (1).ext`.
+fn next_free_path(path: &str, pattern: &str) -> String {
+    if !Path::new(path).exists() {
+        return path.to_string();
+    }
+
+    let path = Path::new(path);
+    let name = path.file_name().and_then(|s| s.to_str()).unwrap_or("file");
+    // `a.tar.gz` becomes `a (1).tar.gz`, not `a.tar (1).gz`
+    let ext = filename_extension(name);
+    let stem = &name[..name.len() - ext.len()];
+    // Split first, so a `{n}` in the file's own name stays as it is
+    let pieces: Vec<String> = pattern
+        .split("{n}")
+        .map(|piece| piece.replace("{name}", stem).replace("{ext}", ext))
+        .collect();
+
+    (1..)
+        .map(|n| path.with_file_name(pieces.join(&n.to_string())))
+        .find(|candidate| !candidate.exists())
+        .map(|candidate| candidate.to_string_lossy().into_owned())
+        .unwrap()
+}
+
+/// How many recent entries `--history` offers
+const HISTORY_PICK_COUNT: usize = 20;
+
+/// Longest filename (in bytes) most filesystems accept
+const MAX_FILENAME_BYTES: usize = 255;
+
+/// Percent-encodes what a network URL can't hold as typed, like spaces
+/// and non-ASCII characters, leaving existing `%XX` escapes alone. Local
+/// paths and anything that doesn't parse are passed through.
+fn normalize_url(url: &str) -> String {
+    match reqwest::Url::parse(url) {
+        Ok(parsed) if matches!(parsed.scheme(), "http" | "https" | "ftp" | "ftps") => {
+            parsed.to_string()
+        }
+        _ => url.to_string(),
+    }
+}
+
+/// The last path segment of `url`, percent-decoded. The query string is
+/// dropped unless `keep_query`, and the fragment always is.
+fn derive_filename(url: &str, keep_query: bool) -> String {
+    // The "path" of a data: URL is its payload
+    if url
+        .get(..5)
+        .is_some_and(|s| s.eq_ignore_ascii_case("data:"))
+    {
+        return "data".to_string();
+    }
+
+    let (segment, query) = match reqwest::Url::parse(url) {
+        Ok(parsed) => (
+            parsed
+                .path_segments()
+                .and_then(|mut segments| segments.next_back())
+                .unwrap_or_default()
+                .to_string(),
+            parsed.query().map(str::to_string),
+        ),
+        Err(_) => {
+            let (rest, _) = url.split_once('#').unwrap_or((url, ""));
+            let (path, query) = match rest.split_once('?') {
+                Some((path, query)) => (path, Some(query.to_string())),
+                None => (rest, None),
+            };
+            (
+                path.rsplit('/').next().unwrap_or_default().to_string(),
+                query,
+            )
+        }
+    };
+    let mut name = percent_encoding::percent_decode_str(&segment)
+        .decode_utf8_lossy()
+        .into_owned();
+    if name.is_empty() {
+        name = "index.html".to_string();
+    }
+
+    if keep_query && let Some(query) = query.filter(|q| !q.is_empty()) {
+        // Keep it shell-friendly and ahead of the extension
+        let query: String = percent_encoding::percent_decode_str(&query)
+            .decode_utf8_lossy()
+            .chars()
+            .map(|c| {
+                if c.is_alphanumeric() || "-._=".contains(c) {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect();
+        name = match name.rsplit_once('.') {
+            Some((stem, ext)) if !stem.is_empty() => format!("{}_{}.{}", stem, query, ext),
+            _ => format!("{}_{}", name, query),
+        };
+    }
+    sanitize_filename(&name)
+}
+
+/// `name` under `--dir`, if given. `name` may have directories of its own.
+fn output_in_dir(dir: Option<&str>, name: String) -> String {
+    match dir {
+        Some(dir) => Path::new(dir).join(name).to_string_lossy().into_owned(),
+        None => name,
+    }
+}
+
+/// Creates the directories `path` is to go in (`--create-dirs`).
+async fn create_parent_dirs(path: &str) -> std::io::Result<()> {
+    match Path::new(path).parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => tokio::fs::create_dir_all(parent).await,
+        _ => Ok(()),
+    }
+}
+
+/// Whether `path` has an extension that should never hold an HTML page.
+fn looks_binary(path: &str) -> bool {
+    const BINARY_EXTENSIONS: &[&str] = &[
+        "7z", "apk", "bin", "bz2", "deb", "dmg", "exe", "flac", "gz", "img", "iso", "jar", "jpg",
+        "jpeg", "mkv", "mov", "mp3", "mp4", "msi", "pdf", "png", "rar", "rpm", "tar", "tgz", "xz",
+        "zip", "zst",
+    ];
+    Path::new(path)
+        .extension()
+        .and_then(|e| e.to_str())
+        .is_some_and(|e| BINARY_EXTENSIONS.contains(&e.to_ascii_lowercase().as_str()))
+}
+
+/// Expands an `--output-template` for one URL. `index` is the URL's
+/// position in the batch, counting from 1.
+fn expand_output_template(
+    template: &str,
+    url: &str,
+    index: usize,
+    keep_query: bool,
+) -> Result<String, String> {
+    let basename = derive_filename(url, keep_query);
+    let mut expanded = String::new();
+    let mut rest = template;
+
+    while let Some(open) = rest.find('{') {
+        expanded.push_str(&rest[..open]);
+        let close = rest[open..]
+            .find('}')
+            .map(|close| open + close)
+            .ok_or_else(|| format!("Unclosed {{ in output template: {}", template))?;
+        let value = match &rest[open + 1..close] {
+            "basename" => basename.clone(),
+            "host" => reqwest::Url::parse(url)
+                .ok()
+                .and_then(|u| u.host_str().map(str::to_string))
+                .unwrap_or_default(),
+            "ext" => Path::new(&basename)
+                .extension()
+                .and_then(|e| e.to_str())
+                .unwrap_or_default()
+                .to_string(),
+            "date" => chrono::Local::now().format("%Y-%m-%d").to_string(),
+            "index" => index.to_string(),
+            other => {
+                return Err(format!(
+                    "Unknown placeholder {{{}}} in output template (expected basename, host, ext, date or index)",
+                    other
+                ));
+            }
+        };
+        expanded.push_str(&value);
+        rest = &rest[close + 1..];
+    }
+    expanded.push_str(rest);
+
+    Ok(sanitize_filename(&expanded))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DeviceKind {
+    Block,
+    Char,
+}
+
+/// The kind of device at `path`, if it's an existing device node.
+#[cfg(unix)]
+fn device_kind(path: &str) -> Option<DeviceKind> {
+    use std::os::unix::fs::FileTypeExt;
+    let file_type = std::fs::metadata(path).ok()?.file_type();
+    if file_type.is_block_device() {
+        Some(DeviceKind::Block)
+    } else if file_type.is_char_device() {
+        Some(DeviceKind::Char)
+    } else {
+        None
+    }
+}
+
+#[cfg(not(unix))]
+fn device_kind(_path: &str) -> Option<DeviceKind> {
+    None
+}
+
+/// Asks before overwriting a block device. Without a terminal to ask on,
+/// `--raw-device` alone is taken as consent.
+fn confirm_device_write(path: &str) -> std::io::Result<bool> {
+    use std::io::{IsTerminal, Write};
+    if !std::io::stdin().is_terminal() {
+        return Ok(true);
+    }
+    eprint!(
+        "Everything on {} will be overwritten. Continue? [y/N] ",
+        path
+    );
+    std::io::stderr().flush()?;
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+/// Makes a URL-derived name safe to create on the current platform.
+fn sanitize_filename(name: &str) -> String {
+    #[cfg(windows)]
+    const ILLEGAL: &[char] = &['<', '>', ':', '"', '/', '\\', '|', '?', '*'];
+    #[cfg(not(windows))]
+    const ILLEGAL: &[char] = &['/'];
+
+    let mut sanitized: String = name
+        .chars()
+        .map(|c| {
+            if ILLEGAL.contains(&c) || c.is_control() {
+                '_'
+            } else {
+                c
+            }
+        })
+        .collect();
+
+    // Windows silently drops trailing dots and spaces, which breaks the rename
+    #[cfg(windows)]
+    {
+        sanitized = sanitized.trim_end_matches(['.', ' ']).to_string();
+    }
+
+    if sanitized.is_empty() || sanitized == "." || sanitized == ".." {
+        sanitized = "index.html".to_string();
+    }
+
+    truncate_filename(&sanitized, MAX_FILENAME_BYTES)
+}
+
+/// The extension of `name` with its dot, counting `.tar.gz` and the like
+/// as one. Empty for a name without one, or a dotfile like `.bashrc`.
+fn filename_extension(name: &str) -> &str {
+    let Some(pos) = name.rfind('.').filter(|&pos| pos > 0) else {
+        return "";
+    };
+    match name[..pos].rfind('.') {
+        Some(tar) if tar > 0 && name[tar..pos].eq_ignore_ascii_case(".tar") => &name[tar..],
+        _ => &name[pos..],
+    }
+}
+
+/// The usual extension for a Content-Type, without its dot. `None` for
+/// unknown types and for `application/octet-stream`, which says nothing
+/// about the contents.
+fn content_type_extension(content_type: &str) -> Option<&'static str> {
+    let mime = content_type.split(';').next()?.trim().to_ascii_lowercase();
+    // mime_guess lists extensions alphabetically, which puts an obscure one
+    // first for a few common types (jfif, asm, htm)
+    let extension = match mime.as_str() {
+        "application/octet-stream" | "binary/octet-stream" => return None,
+        "image/jpeg" => "jpg",
+        "text/plain" => "txt",
+        "text/html" => "html",
+        "audio/mpeg" => "mp3",
+        "image/tiff" => "tiff",
+        "application/gzip" | "application/x-gzip" => "gz",
+        "application/xml" | "text/xml" => "xml",
+        "text/javascript" | "application/javascript" => "js",
+        _ => mime_guess::get_mime_extensions_str(&mime)?.first()?,
+    };
+    Some(extension)
+}
+
+/// Shortens `name` to at most `max_bytes`, keeping the extension when possible.
+/// An extension that doesn't fit is cut along with the rest of the name.
+fn truncate_filename(name: &str, max_bytes: usize) -> String {
+    if name.len() <= max_bytes {
+        return name.to_string();
+    }
+
+    let ext = filename_extension(name);
+    let (stem, ext) = if ext.len() < max_bytes {
+        name.split_at(name.len() - ext.len())
+    } else {
+        (name, "")
+    };
+
+    let mut cut = max_bytes - ext.len();
+    while !stem.is_char_boundary(cut) {
+        cut -= 1;
+    }
+    format!("{}{}", &stem[..cut], ext)
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let matches = Args::command().get_matches();
+    let mut args = Args::from_arg_matches(&matches)?;
+
+    let profile = match args.config {
+        Some(ref path) => Some(config::load(Path::new(path))?),
+        None => config::default_path()
+            .filter(|path| path.exists())
+            .map(|path| config::load(&path))
+            .transpose()?,
+    };
+    let from_env = config::apply_env(&mut args, &matches)?;
+    if let Some(profile) = profile {
+        profile.apply(&mut args, &matches, &from_env)?;
+    }
+    if args.tui {
+        tui::init_logger();
+    } else {
+        console::init_logger();
+    }
+
+    if let Some(simulation) = args.simulate.clone() {
+        simulate::install(simulation);
+    }
+    if let Some(ref path) = args.log_file {
+        transfer_log::open(path, args.log_max_size, args.log_rotate);
+    }
+    if let Some(preset) = args.ua_preset {
+        args.user_agent = preset.user_agent().to_string();
+    }
+    // Quiet in every other respect, and still printed with -q
+    if args.status_only {
+        args.quiet = true;
+    }
+
+    if args.version {
+        println!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
+        return Ok(());
+    }
+    if let Some(ref path) = args.test_integrity {
+        if test_integrity(&args, path).await? {
+            println!("{}: OK", path);
+            return Ok(());
+        }
+        println!("{}: FAILED", path);
+        std::process::exit(EXIT_CHECKSUM);
+    }
+    // Checked up front, so a typo doesn't surface halfway through
+    let file_style = bar_style(&args, false)?;
+    let total_style = bar_style(&args, true)?;
+    let spinner_style = spinner_style(&args)?;
+
+    if !args.combine.is_empty() {
+        let output = args.output.as_deref().unwrap_or_default();
+        let (checksum, parts) = match args.combine.split_last() {
+            Some((last, parts)) if !parts.is_empty() => match Checksum::parse(last) {
+                Some(checksum) => (Some(checksum), parts),
+                None => (None, &args.combine[..]),
+            },
+            _ => (None, &args.combine[..]),
+        };
+        let manifest = match args.chunk_manifest {
+            Some(ref path) => Some(parse_chunk_manifest(
+                &tokio::fs::read_to_string(path).await?,
+            )?),
+            None => None,
         };
+        let pb = if args.quiet {
+            ProgressBar::hidden()
+        } else {
+            ProgressBar::new(0)
+        };
+        pb.set_style(file_style);
+        pb.set_prefix(output.to_string());
+        if !combine::combine(
+            parts,
+            output,
+            checksum.as_ref(),
+            manifest.as_deref(),
+            args.fsync.syncs_final(),
+            &pb,
+        )
+        .await?
+        {
+            eprintln!("{}: checksum mismatch", output);
+            std::process::exit(EXIT_CHECKSUM);
+        }
+        return Ok(());
+    }
+
+    // The same dry run, just printed for scripts
+    if args.metadata_json {
+        args.dry_run = true;
+    }
+
+    // List of (URL, Optional Checksum)
+    let mut download_tasks: Vec<(String, Option<Checksum>)> = Vec::new();
+
+    // Parse URLs and Checksums from arguments
+    let mut i = 0;
+    while i < args.urls.len() {
+        let url = args.urls[i].clone();
+        let mut checksum = None;
+        if i + 1 < args.urls.len()
+            && args.urls[i + 1].contains(':')
+            && let Some(parsed) = Checksum::parse(&args.urls[i + 1])
+        {
+            checksum = Some(parsed);
+            i += 1; // Consume the checksum argument
+        }
+        download_tasks.push((url, checksum));
+        i += 1;
+    }
 
-        let finished = self
-            .state
-            .finished_files
-            .fetch_add(1, std::sync::atomic::Ordering::Relaxed)
-            + 1;
-        self.state
-            .total_pb
-            .set_message(format!("({}/{})", finished, self.state.total_files));
+    if let Some(ref path) = args.input_file {
+        let contents = tokio::fs::read_to_string(path)
+            .await
+            .map_err(|e| format!("Failed to read {}: {}", path, e))?;
+        let base = Path::new(path).parent().unwrap_or(Path::new(""));
+        for (url, checksum) in contents.lines().filter_map(parse_task_line) {
+            if !local::is_file_glob(&url) {
+                download_tasks.push((url, checksum));
+                continue;
+            }
+            if checksum.is_some() {
+                return Err(format!("A checksum can't apply to every file of {}", url).into());
+            }
+            let files = local::expand_file_glob(&url, base)?;
+            if files.is_empty() {
+                eprintln!("Warning: {} matches no files", url);
+            }
+            download_tasks.extend(files.into_iter().map(|file| (file, None)));
+        }
+    }
 
-        if res.is_ok() {
-            // Verify final size
-            if let Ok(meta) = metadata(&part_path).await {
-                if meta.len() != total_size && total_size > 0 {
-                    pb.finish_with_message(format!(
-                        "Size mismatch: expected {}, got {}",
-                        total_size,
-                        meta.len()
-                    ));
-                    return Err("Size mismatch".into());
+    if args.stdin_url {
+        use tokio::io::AsyncBufReadExt;
+        let mut lines = tokio::io::BufReader::new(tokio::io::stdin()).lines();
+        loop {
+            let Some(line) = lines.next_line().await? else {
+                return Err("--stdin-url: no URL on stdin".into());
+            };
+            if let Some(task) = parse_task_line(&line) {
+                download_tasks.push(task);
+                break;
+            }
+        }
+    }
+
+    // For a container parameterized with `-e`; URLs given any other way win
+    if download_tasks.is_empty()
+        && !args.history
+        && let Ok(value) = std::env::var("GRAB_URL")
+        && let Some(task) = parse_task_line(&value)
+    {
+        download_tasks.push(task);
+    }
+
+    // Encoded once up front, so HEAD, GET and every chunk request use the
+    // same URL
+    for (url, _) in &mut download_tasks {
+        *url = normalize_url(url);
+    }
+
+    if args.history {
+        match history::pick(HISTORY_PICK_COUNT)? {
+            Some(entry) => {
+                if args.output.is_none() {
+                    args.output = Some(entry.output);
                 }
+                download_tasks.insert(0, (entry.url, None));
+            }
+            None => return Ok(()),
+        }
+    }
+
+    // Read from stdin if no URLs provided
+    if download_tasks.is_empty() {
+        use std::io::IsTerminal;
+        if !std::io::stdin().is_terminal() {
+            use tokio::io::AsyncBufReadExt;
+            let stdin = tokio::io::stdin();
+            let mut reader = tokio::io::BufReader::new(stdin).lines();
+            while let Some(line) = reader.next_line().await? {
+                download_tasks.extend(parse_task_line(&line));
             }
+        }
+    }
 
-            if let Some(ref checksum) = self.config.checksum {
-                pb.set_message("Verifying...");
-                match self.verify_checksum(checksum, &part_path).await {
-                    Ok(true) => {
-                        tokio::fs::rename(&part_path, output_path).await?;
-                        pb.finish_with_message("Verified");
-                    }
-                    Ok(false) => pb.finish_with_message("Checksum mismatch!"),
-                    Err(e) => pb.finish_with_message(format!("Verification error: {}", e)),
+    if download_tasks.is_empty() {
+        Args::command().print_help()?;
+        println!();
+        return Ok(());
+    }
+
+    // A batch from --input-file can be run again after an interruption, and
+    // picks up with the files that weren't finished
+    let ledger = (args.input_file.is_some() && !args.dry_run && !args.spider).then(|| {
+        Arc::new(batch_state::Ledger::load(Path::new(
+            args.dir.as_deref().unwrap_or("."),
+        )))
+    });
+    if let Some(ref ledger) = ledger
+        && !args.force_redownload
+    {
+        let listed = download_tasks.len();
+        download_tasks.retain(|(url, _)| match ledger.completed(url) {
+            Some(output) => {
+                info!("{} was saved to {} in an earlier run", url, output);
+                false
+            }
+            None => true,
+        });
+        let skipped = listed - download_tasks.len();
+        if skipped > 0 && !args.quiet {
+            eprintln!(
+                "Skipping {} of {} entries finished in an earlier run (--force-redownload to fetch them again)",
+                skipped, listed
+            );
+        }
+        if download_tasks.is_empty() {
+            return Ok(());
+        }
+    }
+
+    if args.insecure {
+        eprintln!(
+            "WARNING: TLS certificate verification is disabled (--insecure). \
+             Connections can be intercepted without notice."
+        );
+    }
+
+    let chunk_manifest = match args.chunk_manifest {
+        Some(ref path) => {
+            let contents = tokio::fs::read_to_string(path).await?;
+            Some(Arc::new(parse_chunk_manifest(&contents)?))
+        }
+        None => None,
+    };
+
+    // One jar for every client, so cookies set along the way (including on
+    // redirects) are shared by all requests
+    let cookie_jar = if args.cookie.is_empty() && args.cookie_jar.is_none() {
+        None
+    } else {
+        let jar = reqwest::cookie::Jar::default();
+        if let Some(ref path) = args.cookie_jar {
+            let loaded = cookies::load_netscape(&jar, Path::new(path))?;
+            debug!("Loaded {} cookies from {}", loaded, path);
+        }
+        let urls: Vec<reqwest::Url> = download_tasks
+            .iter()
+            .filter_map(|(url, _)| reqwest::Url::parse(url).ok())
+            .collect();
+        for cookie in &args.cookie {
+            cookies::add_cookie(&jar, cookie, &urls)?;
+        }
+        Some(Arc::new(jar))
+    };
+
+    let mut headers = match args.headers_file {
+        Some(ref path) => {
+            let contents = std::fs::read_to_string(path)
+                .map_err(|e| format!("Failed to read headers file {}: {}", path, e))?;
+            parse_headers_file(&contents).map_err(|e| format!("{}:{}", path, e))?
+        }
+        None => HeaderMap::new(),
+    };
+    for header in &args.header {
+        let (name, value) = parse_header(header)?;
+        headers.insert(name, value);
+    }
+    for (name, value) in [(ORIGIN, &args.origin), (REFERER, &args.referer)] {
+        if let Some(value) = value
+            && value != "auto"
+            && !headers.contains_key(&name)
+        {
+            let value = HeaderValue::from_str(value)
+                .map_err(|_| format!("Invalid {} value: {:?}", name, value))?;
+            headers.insert(name, value);
+        }
+    }
+    if args.no_compressed && !headers.contains_key(ACCEPT_ENCODING) {
+        headers.insert(ACCEPT_ENCODING, HeaderValue::from_static("identity"));
+    }
+    if let Some(ref path) = args.dump_headers {
+        header_dump::open(
+            path,
+            header_dump::Options {
+                all: args.dump_all_headers,
+                secrets: args.dump_secrets,
+                headers: headers.clone(),
+                user_agent: args.user_agent.clone(),
+                compressed: args.compressed,
+                cookie_jar: cookie_jar.clone(),
+            },
+        );
+    }
+
+    if args.list_links || args.download_links {
+        let links = collect_links(&args, &download_tasks, &headers, cookie_jar.as_ref()).await?;
+        if args.list_links {
+            for link in &links {
+                println!("{}", link);
+            }
+            return Ok(());
+        }
+        if links.is_empty() {
+            return Err("--download-links: no links to download".into());
+        }
+        info!("Downloading {} links", links.len());
+        download_tasks = links.into_iter().map(|link| (link, None)).collect();
+    }
+
+    if let Some(ref dir) = args.dir {
+        if let Some(ref output) = args.output
+            && Path::new(output).is_absolute()
+        {
+            return Err(format!(
+                "--output {} is absolute and conflicts with --dir {}",
+                output, dir
+            )
+            .into());
+        }
+        if !args.create_dirs && !Path::new(dir).is_dir() {
+            return Err(format!("Directory {} does not exist (use --create-dirs)", dir).into());
+        }
+    }
+    if let Some(ref dir) = args.temp_dir {
+        if args.create_dirs {
+            std::fs::create_dir_all(dir)?;
+        } else if !Path::new(dir).is_dir() {
+            return Err(format!("Directory {} does not exist (use --create-dirs)", dir).into());
+        }
+    }
+
+    if args.rotate_on_failure {
+        if !args.mirror.is_empty() && download_tasks.len() > 1 {
+            return Err("--mirror needs a single URL to download".into());
+        }
+        // Every mirror gets at least one go
+        args.retries = args.retries.max(args.mirror.len() as u32);
+        if let Some(url) = download_tasks
+            .iter()
+            .map(|(url, _)| url)
+            .chain(&args.mirror)
+            .find(|url| !url.starts_with("http://") && !url.starts_with("https://"))
+        {
+            return Err(format!("--rotate-on-failure only works over HTTP: {}", url).into());
+        }
+    }
+
+    let data = match (&args.data, &args.data_file) {
+        (Some(data), _) => Some(data.clone().into_bytes()),
+        (None, Some(path)) => Some(
+            tokio::fs::read(path)
+                .await
+                .map_err(|e| format!("Failed to read {}: {}", path, e))?,
+        ),
+        (None, None) => None,
+    };
+    if data.is_some()
+        && !matches!(
+            matches.value_source("method"),
+            Some(ValueSource::CommandLine)
+        )
+    {
+        args.method = RequestMethod::Post;
+    }
+    if args.method != RequestMethod::Get {
+        if args.resume || args.continue_at.is_some() || !args.range.is_empty() {
+            return Err(format!(
+                "--method {} can't resume or fetch ranges",
+                args.method.as_reqwest()
+            )
+            .into());
+        }
+        if let Some((url, _)) = download_tasks
+            .iter()
+            .find(|(url, _)| !url.starts_with("http://") && !url.starts_with("https://"))
+        {
+            return Err(format!("--method only works over HTTP: {}", url).into());
+        }
+    }
+
+    // Started afresh on each run; chunks are appended as downloads finish
+    if let Some(ref path) = args.chunk_timings
+        && let Err(e) = std::fs::write(path, CHUNK_TIMINGS_HEADER)
+    {
+        eprintln!("Warning: can't write chunk timings to {}: {}", path, e);
+    }
+
+    if !args.no_history
+        && let Err(e) = history::trim()
+    {
+        debug!("Could not trim download history: {}", e);
+    }
+
+    let stderr_is_terminal = {
+        use std::io::IsTerminal;
+        std::io::stderr().is_terminal()
+    };
+    // Keys are read from stdin, as for the bars. A profile's `tui` gives
+    // way to flags that mean no progress at all.
+    let tui = args.tui && !args.quiet && !args.dry_run && !args.spider && stderr_is_terminal && {
+        use std::io::IsTerminal;
+        std::io::stdin().is_terminal()
+    };
+    if args.tui && !tui {
+        info!("--tui needs a terminal; showing the progress bars instead");
+    }
+    let multi_progress = indicatif::MultiProgress::new();
+    if args.quiet
+        || args.dry_run
+        || args.spider
+        || (args.benchmark && !args.benchmark_then_download)
+        || tui
+    {
+        multi_progress.set_draw_target(indicatif::ProgressDrawTarget::hidden());
+    } else if args.force_progress && !stderr_is_terminal {
+        multi_progress.set_draw_target(indicatif::ProgressDrawTarget::term_like(Box::new(
+            ForcedStderr,
+        )));
+    }
+    console::set_bars(&multi_progress);
+    let semaphore = Arc::new(Semaphore::new(args.parallel_downloads));
+    let limiter = if args.schedule.is_some() {
+        // The schedule sets the rate as soon as it starts
+        Some(Arc::new(BandwidthLimiter::new(0)))
+    } else {
+        args.limit_rate
+            .map(|limit| Arc::new(BandwidthLimiter::new(limit)))
+    };
+    let _schedule = match (args.schedule.clone(), &limiter) {
+        (Some(schedule), Some(limiter)) => Some(start_schedule(
+            schedule,
+            args.limit_rate.unwrap_or(0),
+            limiter.clone(),
+        )),
+        _ => None,
+    };
+
+    // Total progress bar
+    let (control_tx, control) = watch::channel(TransferState::Running);
+
+    let total_pb = multi_progress.add(ProgressBar::new(0));
+    total_pb.set_style(total_style.with_key("eta", eta_column(None, control.clone())));
+    total_pb.set_message(format!("(0/{})", download_tasks.len()));
+    let deadline = args
+        .max_time
+        .map(|max_time| tokio::time::Instant::now() + max_time);
+    // Tells transfers to stop; `before_deadline` ends what doesn't
+    let _deadline = deadline.map(|deadline| {
+        let control = control_tx.clone();
+        AbortOnDrop(tokio::spawn(async move {
+            tokio::time::sleep_until(deadline).await;
+            control.send_replace(TransferState::TimedOut);
+        }))
+    });
+
+    let state = Arc::new(DownloadState {
+        total_files: download_tasks.len(),
+        finished_files: std::sync::atomic::AtomicUsize::new(0),
+        total_pb: total_pb.clone(),
+        bar_style: file_style,
+        spinner_style,
+        control,
+        stats: Arc::new(TransferStats::default()),
+        buffer_budget: args.max_buffer.map(BufferBudget::new),
+        board: tui.then(tui::Board::default),
+        deadline,
+    });
+    let retried = Arc::new(AtomicU64::new(0));
+    let retry = RetryPolicy {
+        retries: args.retries,
+        tries_per_chunk: args.tries_per_chunk,
+        max_total: args.max_total_retries,
+        statuses: args.retry_on_status.clone(),
+        all_errors: args.retry_all_errors,
+        retried: retried.clone(),
+        file_retried: Arc::default(),
+        waiting: Arc::default(),
+        control: state.control.clone(),
+    };
+
+    let mut host_slots: std::collections::HashMap<String, Arc<Semaphore>> =
+        std::collections::HashMap::new();
+    let mut downloaders = Vec::new();
+
+    // A file that isn't there yet can't be newer than anything
+    let if_modified_since = match args.if_modified_since {
+        Some(ref since) => match std::fs::metadata(since) {
+            Ok(meta) => Some(meta.modified()?.into()),
+            Err(_) => match chrono::DateTime::parse_from_rfc2822(since) {
+                Ok(date) => Some(date.with_timezone(&chrono::Utc)),
+                // Meant as a date rather than a path: say what's wrong with it
+                Err(_) if since.ends_with("GMT") || since.contains(", ") => {
+                    return Err(format!("Invalid HTTP date: {}", since).into());
                 }
+                Err(_) => {
+                    eprintln!(
+                        "Warning: {} doesn't exist; downloading unconditionally",
+                        since
+                    );
+                    None
+                }
+            },
+        },
+        None => None,
+    };
+
+    for (url, checksum) in download_tasks {
+        // Only a name derived from the URL gets an extension added
+        let mut add_extension = false;
+        let output_path = if args.output.is_some() && downloaders.is_empty() {
+            args.output.clone().unwrap()
+        } else {
+            let name = match args.output_template {
+                Some(ref template) => {
+                    expand_output_template(template, &url, downloaders.len() + 1, args.keep_query)?
+                }
+                None => derive_filename(&url, args.keep_query),
+            };
+            add_extension = args.add_extension
+                && args.output_template.is_none()
+                && filename_extension(&name).is_empty();
+            let max = args.max_filename_length;
+            let shortened = truncate_filename(&name, max);
+            if !shortened.ends_with(filename_extension(&name)) {
+                eprintln!(
+                    "Warning: the extension of {} is too long for --max-filename-length {}; saving as {}",
+                    name, max, shortened
+                );
+            }
+            shortened
+        };
+        let output_path = output_in_dir(args.dir.as_deref(), output_path);
+        if args.create_dirs {
+            create_parent_dirs(&output_path).await?;
+        }
+        let device = device_kind(&output_path);
+        if device == Some(DeviceKind::Block) {
+            if !args.raw_device {
+                return Err(format!(
+                    "{} is a block device; pass --raw-device to write to it",
+                    output_path
+                )
+                .into());
+            }
+            if !confirm_device_write(&output_path)? {
+                return Ok(());
+            }
+        }
+        let output_path = if args.existing == ExistingPolicy::Rename && device.is_none() {
+            next_free_path(&output_path, &args.rename_pattern)
+        } else {
+            output_path
+        };
+
+        let host_slots_for_url = args.max_connections_per_host.map(|max| {
+            let host = reqwest::Url::parse(&url)
+                .ok()
+                .and_then(|u| u.host_str().map(str::to_string))
+                .unwrap_or_default();
+            host_slots
+                .entry(host)
+                .or_insert_with(|| Arc::new(Semaphore::new(max)))
+                .clone()
+        });
+        let mut headers_for_url = headers.clone();
+        if args.referer.as_deref() == Some("auto")
+            && !headers_for_url.contains_key(REFERER)
+            && let Some(origin) = url_origin(&url)
+        {
+            headers_for_url.insert(REFERER, origin);
+        }
+
+        let config = DownloadConfig {
+            url,
+            output_path,
+            device_target: device.is_some(),
+            temp_dir: args.temp_dir.clone(),
+            extract: args.extract.clone(),
+            concurrent_chunks: match args.threads {
+                Connections::Count(count) => count,
+                Connections::Auto => 1,
+            },
+            adaptive_connections: args.adaptive_connections.then_some(args.max_connections),
+            auto_connections: (args.threads == Connections::Auto).then_some(args.max_connections),
+            tail_optimize: args.tail_optimize || args.adaptive_connections,
+            interleave: args.interleave,
+            host_slots: host_slots_for_url,
+            chunk_size: args.chunk_size,
+            write_buffer_size: args.write_buffer_size as usize,
+            fsync: args.fsync,
+            resume: args.resume
+                || args.existing == ExistingPolicy::Resume
+                || matches!(args.continue_at, Some(ContinueAt::Auto)),
+            verify_resume: args.verify_resume,
+            continue_at: match args.continue_at {
+                Some(ContinueAt::Offset(offset)) => Some(offset),
+                _ => None,
+            },
+            skip_existing: args.existing == ExistingPolicy::Skip,
+            add_extension,
+            metadata_json: args.metadata_json,
+            user_agent: if args.random_user_agent || args.rotate_user_agent {
+                random_user_agent().to_string()
             } else {
-                tokio::fs::rename(&part_path, output_path).await?;
-                pb.finish();
+                args.user_agent.clone()
+            },
+            rotate_user_agent: args.rotate_user_agent,
+            timeout: args.timeout,
+            retry: retry.for_file(),
+            target_duration: args.target_duration,
+            max_file_size: args.max_file_size,
+            ranges: args.range.clone(),
+            mirrors: args.mirror.clone(),
+            rotate_on_failure: args.rotate_on_failure,
+            rotate_user_agents: args.user_agent == DEFAULT_USER_AGENT,
+            clean_on_error: args.clean_on_error,
+            expect_type: args.expect_type.clone(),
+            expected_size: args.expected_size,
+            no_clobber: args.no_clobber,
+            store_xattr: args.store_xattr,
+            dedup: args
+                .dedup_dir
+                .as_ref()
+                .map(|dir| (PathBuf::from(dir), args.dedup_link)),
+            if_modified_since,
+            min_speed: args.min_speed,
+            min_speed_time: args.min_speed_time,
+            http_version: args.http_version,
+            method: args.method.as_reqwest(),
+            data: data.clone(),
+            pool_idle_timeout: args.pool_idle_timeout,
+            pool_max_idle_per_host: args.pool_max_idle_per_host,
+            tcp_nodelay: args.tcp_nodelay,
+            force_ipv4: args.inet4_only,
+            force_ipv6: args.inet6_only,
+            max_redirects: args.max_redirects,
+            follow_redirects: !args.no_redirects,
+            compressed: args.compressed,
+            cacert: args.cacert.clone(),
+            client_cert: args.cert.clone(),
+            client_key: args.key.clone(),
+            insecure: args.insecure,
+            checksum,
+            auto_checksum: args.auto_checksum,
+            cookie_jar: cookie_jar.clone(),
+            headers: headers_for_url,
+            chunk_manifest: chunk_manifest.clone(),
+            chunk_timings: args.chunk_timings.clone(),
+            post: args.post.clone(),
+            on_complete: args.on_complete.clone(),
+            webhook: args.webhook.clone(),
+            hooks_always: args.on_complete_always,
+            #[cfg(feature = "notify")]
+            desktop_notify: args.notify,
+            units: args.units,
+        };
+
+        downloaders.push(Arc::new(FileDownloader::new(
+            config,
+            multi_progress.clone(),
+            limiter.clone(),
+            state.clone(),
+        )?));
+    }
+
+    if args.benchmark || args.benchmark_then_download {
+        for downloader in downloaders.iter_mut() {
+            let best = downloader.benchmark().await?;
+            if args.benchmark_then_download {
+                let config = DownloadConfig {
+                    concurrent_chunks: best,
+                    auto_connections: None,
+                    ..(*downloader.config).clone()
+                };
+                *downloader = Arc::new(FileDownloader::new(
+                    config,
+                    multi_progress.clone(),
+                    limiter.clone(),
+                    state.clone(),
+                )?);
             }
         }
+        if !args.benchmark_then_download {
+            return Ok(());
+        }
+    }
 
-        res
+    // What the pre-scan added to the total bar for each file
+    let mut prescanned = vec![0; downloaders.len()];
+    if downloaders.len() > 1 && !args.no_prescan && !args.dry_run && !args.spider {
+        let mut scans = tokio::task::JoinSet::new();
+        for (i, downloader) in downloaders.iter().enumerate() {
+            let downloader = downloader.clone();
+            let sem = semaphore.clone();
+            scans.spawn(async move {
+                let _permit = sem.acquire().await.unwrap();
+                (i, downloader.prescan().await)
+            });
+        }
+        for (i, size) in scans.join_all().await {
+            prescanned[i] = size;
+        }
     }
 
-    async fn verify_checksum(
-        &self,
-        checksum: &Checksum,
-        path: &str,
-    ) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
-        let mut file = File::open(path).await?;
-        let mut buffer = vec![0u8; 8192];
+    // Raw mode is only enabled once nothing else can fail early, and only
+    // under the bars or the screen: it's what `p` and `q` act on
+    let key_listener = {
+        use std::io::IsTerminal;
+        if !args.quiet
+            && !args.dry_run
+            && !args.spider
+            && std::io::stdin().is_terminal()
+            && stderr_is_terminal
+        {
+            KeyListener::spawn(control_tx).ok()
+        } else {
+            None
+        }
+    };
+    let screen = if tui {
+        match tui::Screen::spawn(state.clone(), retried.clone(), args.units) {
+            Ok(screen) => Some(screen),
+            Err(e) => {
+                // Nothing's been drawn yet, so the bars can take over
+                eprintln!("Warning: can't start --tui: {}", e);
+                multi_progress.set_draw_target(indicatif::ProgressDrawTarget::stderr());
+                None
+            }
+        }
+    } else {
+        None
+    };
 
-        match checksum {
-            Checksum::Sha1(expected) => {
-                let mut hasher = Sha1::new();
-                while let Ok(n) = file.read(&mut buffer).await {
-                    if n == 0 {
-                        break;
-                    }
-                    hasher.update(&buffer[..n]);
+    let started = std::time::Instant::now();
+    let _peak_speed = state.stats.track_peak_speed();
+    // indicatif draws nothing without a terminal; a log still gets word
+    let _progress_lines = (!stderr_is_terminal
+        && !args.force_progress
+        && !args.quiet
+        && !args.dry_run
+        && !args.spider)
+        .then(|| state.start_progress_lines(args.units));
+    let progress_file = args
+        .progress_file
+        .clone()
+        .map(|path| state.start_progress_file(path));
+    let mut handles = Vec::new();
+
+    for (downloader, prescanned_size) in downloaders.into_iter().zip(prescanned) {
+        let sem = semaphore.clone();
+        let dry_run = args.dry_run;
+        let spider = args.spider;
+        let record_history = !args.no_history;
+        let status_only = args.status_only;
+        let ledger = ledger.clone();
+
+        let handle = tokio::spawn(async move {
+            let _permit = sem.acquire().await.unwrap();
+            let mut run = RunState {
+                prescanned_size,
+                ..Default::default()
+            };
+            let res = if spider {
+                // Reported on its own line either way
+                return downloader.spider().await;
+            } else if dry_run {
+                downloader.state.before_deadline(downloader.dry_run()).await
+            } else {
+                #[cfg(feature = "notify")]
+                let started = std::time::Instant::now();
+                let mut res = downloader
+                    .state
+                    .before_deadline(downloader.download(&mut run))
+                    .await;
+                if let Err(ref e) = res {
+                    downloader.handle_partial(e.as_ref()).await;
                 }
-                let hash = hex::encode(hasher.finalize());
-                Ok(hash == expected.to_lowercase())
-            }
-            Checksum::Sha224(expected) => {
-                let mut hasher = Sha224::new();
-                while let Ok(n) = file.read(&mut buffer).await {
-                    if n == 0 {
-                        break;
-                    }
-                    hasher.update(&buffer[..n]);
+                if res.is_ok() {
+                    res = downloader.post_process(&mut run).await;
                 }
-                let hash = hex::encode(hasher.finalize());
-                Ok(hash == expected.to_lowercase())
-            }
-            Checksum::Sha256(expected) => {
-                let mut hasher = Sha256::new();
-                while let Ok(n) = file.read(&mut buffer).await {
-                    if n == 0 {
-                        break;
+                match res {
+                    Ok(()) => transfer_log::event(format_args!(
+                        "DONE {} -> {}",
+                        downloader.config.url,
+                        downloader.final_path(&run)
+                    )),
+                    Err(ref e) => {
+                        transfer_log::event(format_args!("FAILED {}: {}", downloader.config.url, e))
                     }
-                    hasher.update(&buffer[..n]);
                 }
-                let hash = hex::encode(hasher.finalize());
-                Ok(hash == expected.to_lowercase())
-            }
-            Checksum::Sha384(expected) => {
-                let mut hasher = Sha384::new();
-                while let Ok(n) = file.read(&mut buffer).await {
-                    if n == 0 {
-                        break;
-                    }
-                    hasher.update(&buffer[..n]);
+                downloader.run_hooks(&run, &res).await;
+                #[cfg(feature = "notify")]
+                downloader
+                    .notify_desktop(&run, &res, started.elapsed())
+                    .await;
+                if res.is_ok() && record_history {
+                    downloader.record_history(&run).await;
                 }
-                let hash = hex::encode(hasher.finalize());
-                Ok(hash == expected.to_lowercase())
-            }
-            Checksum::Sha512(expected) => {
-                let mut hasher = Sha512::new();
-                while let Ok(n) = file.read(&mut buffer).await {
-                    if n == 0 {
-                        break;
-                    }
-                    hasher.update(&buffer[..n]);
+                if res.is_ok()
+                    && let Some(ledger) = ledger
+                {
+                    downloader.record_batch_state(&run, &ledger).await;
                 }
-                let hash = hex::encode(hasher.finalize());
-                Ok(hash == expected.to_lowercase())
+                res
+            };
+            if let Err(ref e) = res {
+                error!("{}: {}", downloader.config.url, e);
             }
-            Checksum::Blake2b(expected) => {
-                let mut hasher = Blake2b512::new();
-                while let Ok(n) = file.read(&mut buffer).await {
-                    if n == 0 {
-                        break;
+            if status_only {
+                match res {
+                    Ok(()) => {
+                        let path = downloader.final_path(&run);
+                        let size = metadata(path).await.map(|m| m.len()).unwrap_or(0);
+                        console::println(format_args!("OK {} {}", path, size));
                     }
-                    hasher.update(&buffer[..n]);
-                }
-                let hash = hex::encode(hasher.finalize());
-                Ok(hash == expected.to_lowercase())
-            }
-            Checksum::Blake3(expected) => {
-                let mut hasher = blake3::Hasher::new();
-                while let Ok(n) = file.read(&mut buffer).await {
-                    if n == 0 {
-                        break;
+                    Err(ref e) => {
+                        console::println(format_args!("FAIL {}: {}", downloader.config.url, e))
                     }
-                    hasher.update(&buffer[..n]);
                 }
-                let hash = hasher.finalize().to_hex().to_string();
-                Ok(hash == expected.to_lowercase())
             }
+            res
+        });
+        handles.push(handle);
+    }
+
+    let checked = handles.len();
+    let mut exit_codes = Vec::new();
+    for handle in handles {
+        if let Err(e) = handle.await? {
+            exit_codes.push(exit_code(e.as_ref()));
+        }
+    }
+    let failed = exit_codes.len();
+    let elapsed = started.elapsed();
+
+    drop(key_listener);
+    // Out of raw mode, for the log messages it held back, and before the
+    // summary, which goes on the normal screen
+    if let Some(screen) = screen {
+        screen.stop();
+    }
+
+    if matches!(
+        *state.control.borrow(),
+        TransferState::Cancelled | TransferState::TimedOut
+    ) {
+        total_pb.abandon();
+    } else {
+        total_pb.finish();
+    }
+
+    if let Some(ref path) = args.progress_file {
+        drop(progress_file);
+        // In case the writer was stopped part-way
+        let _ = std::fs::remove_file(format!("{}.tmp", path));
+        if args.keep_progress_file {
+            // Left with how the run ended
+            let status = match *state.control.borrow() {
+                TransferState::Cancelled => "cancelled",
+                TransferState::TimedOut => "timed-out",
+                _ if failed > 0 => "failed",
+                _ => "finished",
+            };
+            if let Err(e) = write_progress_file(path, state.progress_json(status)).await {
+                eprintln!("Warning: can't write progress to {}: {}", path, e);
+            }
+        } else {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+
+    if !args.quiet && !args.dry_run && !args.spider {
+        let stats = &state.stats;
+        let received = stats.bytes.load(Ordering::Relaxed);
+        let average = (received as f64 / elapsed.as_secs_f64().max(0.001)) as u64;
+        // Runs shorter than a second never get sampled
+        let peak = stats.peak_speed.load(Ordering::Relaxed).max(average);
+        let speed = |bytes_per_sec: u64| format!("{}/s", format_bytes(bytes_per_sec, args.units));
+        eprintln!(
+            "Summary\n  Size:          {}\n  Time:          {:.1}s\n  Average speed: {}\n  Peak speed:    {}\n  Retries:       {}\n  Connections:   {}",
+            format_bytes(total_pb.position(), args.units),
+            elapsed.as_secs_f64(),
+            speed(average),
+            speed(peak),
+            match args.max_total_retries {
+                Some(max) => format!(
+                    "{} (at most {} per file)",
+                    retry.retried.load(Ordering::Relaxed),
+                    max
+                ),
+                None => retry.retried.load(Ordering::Relaxed).to_string(),
+            },
+            stats.peak_connections.load(Ordering::Relaxed),
+        );
+        let adaptive = stats.adaptive_connections.load(Ordering::Relaxed);
+        if adaptive > 0 {
+            eprintln!("  Adaptive:      settled on {}", adaptive);
+        }
+        if args.tail_optimize || args.adaptive_connections {
+            eprintln!(
+                "  Tail:          about {:.1}s saved by smaller final segments",
+                stats.tail_saved_ms.load(Ordering::Relaxed) as f64 / 1000.0
+            );
+        }
+    }
+
+    if args.spider && checked > 1 {
+        println!("{} of {} URLs reachable", checked - failed, checked);
+    }
+
+    if let Some(&first) = exit_codes.first() {
+        // One kind of failure keeps its code; a mix is just a failure
+        let code = if exit_codes.iter().all(|&code| code == first) {
+            first
+        } else {
+            EXIT_FAILURE
+        };
+        std::process::exit(code);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn format_bytes_switches_unit_at_the_base() {
+        assert_eq!(format_bytes(999, Units::Binary), "999 B");
+        assert_eq!(format_bytes(1000, Units::Binary), "1000 B");
+        assert_eq!(format_bytes(1023, Units::Binary), "1023 B");
+        assert_eq!(format_bytes(1024, Units::Binary), "1.00 KiB");
+
+        assert_eq!(format_bytes(999, Units::Decimal), "999 B");
+        assert_eq!(format_bytes(1000, Units::Decimal), "1.00 KB");
+        assert_eq!(format_bytes(1023, Units::Decimal), "1.02 KB");
+        assert_eq!(format_bytes(1024, Units::Decimal), "1.02 KB");
+    }
+
+    #[test]
+    fn sanitize_filename_replaces_reserved_characters() {
+        assert_eq!(sanitize_filename("a/b"), "a_b");
+        assert_eq!(sanitize_filename("tab\there"), "tab_here");
+        #[cfg(windows)]
+        {
+            assert_eq!(sanitize_filename("file?v=2"), "file_v=2");
+            assert_eq!(sanitize_filename("a<b>c:d|e*"), "a_b_c_d_e_");
+            assert_eq!(sanitize_filename("name. "), "name");
         }
+        #[cfg(not(windows))]
+        assert_eq!(sanitize_filename("file?v=2"), "file?v=2");
+    }
+
+    #[test]
+    fn sanitize_filename_never_gives_an_empty_name() {
+        assert_eq!(sanitize_filename(""), "index.html");
+        assert_eq!(sanitize_filename("."), "index.html");
+        assert_eq!(sanitize_filename(".."), "index.html");
     }
 
-    async fn download_single_threaded(
-        &self,
-        start_pos: u64,
-        pb: ProgressBar,
-    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-        let mut headers = HeaderMap::new();
-        if start_pos > 0 {
-            headers.insert(RANGE, format!("bytes={}-", start_pos).parse().unwrap());
-        }
+    #[test]
+    fn derive_filename_drops_the_query_unless_kept() {
+        assert_eq!(derive_filename("http://host/file?v=2", false), "file");
+        assert_eq!(
+            derive_filename("http://host/file.bin?v=2", true),
+            "file_v=2.bin"
+        );
+        assert_eq!(derive_filename("http://host/", false), "index.html");
+    }
 
-        let response = tokio::time::timeout(
-            self.config.timeout,
-            self.client.get(&self.config.url).headers(headers).send(),
-        )
-        .await??;
+    #[test]
+    fn cleared_queue_hands_out_nothing_more() {
+        let queue = SegmentQueue::new(100, 10);
+        let (start, end) = queue.next().unwrap();
+        queue.complete(start, end);
+        queue.clear();
+        assert_eq!(queue.next(), None);
+        assert_eq!(queue.len(), 0);
+        assert_eq!(queue.completed_prefix(), 10);
+    }
 
-        if start_pos > 0 && response.status() != reqwest::StatusCode::PARTIAL_CONTENT {
-            return Err("Server does not support resume (Range request ignored)".into());
+    #[test]
+    fn interleaved_queue_strides_segments_across_workers() {
+        let queue = SegmentQueue::interleaved(100, 10, 3);
+        assert_eq!(queue.len(), 10);
+        let mut lane = Vec::new();
+        while let Some((start, _)) = queue.next_for(1) {
+            lane.push(start);
         }
+        assert_eq!(lane, [10, 40, 70]);
+        assert_eq!(queue.next_for(2), Some((20, 29)));
+        assert_eq!(queue.len(), 6);
+        queue.clear();
+        assert_eq!(queue.next_for(0), None);
+        assert_eq!(queue.len(), 0);
+    }
 
-        if !response.status().is_success() {
-            return Err(format!("Server returned error: {}", response.status()).into());
+    /// Asserts that `segments`, in any order, cover `[start, start + len)`
+    /// exactly: back to back, with no gaps, overlaps or empty segments.
+    fn assert_covers(segments: &[(u64, u64)], start: u64, len: u64) {
+        let mut sorted = segments.to_vec();
+        sorted.sort();
+        let mut next = start;
+        for &(from, to) in &sorted {
+            assert_eq!(from, next, "gap or overlap in {:?}", sorted);
+            assert!(to >= from, "empty segment in {:?}", sorted);
+            next = to + 1;
         }
+        assert_eq!(
+            next,
+            start + len,
+            "{:?} doesn't end at {}",
+            sorted,
+            start + len
+        );
+    }
 
-        let mut response = response;
+    /// Runs workers against `queue` on a virtual clock, worker `i` taking
+    /// `cost[i]` ticks per byte; the one with the earliest clock takes the
+    /// next segment. Returns each worker's segments and when it found the
+    /// queue empty.
+    fn simulate_workers(queue: &SegmentQueue, cost: &[u64]) -> (Vec<Vec<(u64, u64)>>, Vec<u64>) {
+        let mut taken = vec![Vec::new(); cost.len()];
+        let mut clock = vec![0; cost.len()];
+        let mut done = vec![false; cost.len()];
+        while let Some(worker) = (0..cost.len())
+            .filter(|&i| !done[i])
+            .min_by_key(|&i| clock[i])
+        {
+            match queue.next_for(worker) {
+                Some((start, end)) => {
+                    clock[worker] += (end - start + 1) * cost[worker];
+                    taken[worker].push((start, end));
+                }
+                None => done[worker] = true,
+            }
+        }
+        (taken, clock)
+    }
 
-        let part_path = format!("{}.part", self.config.output_path);
-        let mut file = if start_pos > 0 {
-            OpenOptions::new().write(true).open(&part_path).await?
-        } else {
-            File::create(&part_path).await?
-        };
+    #[test]
+    fn fast_worker_takes_over_from_a_slow_one() {
+        let queue = SegmentQueue::new(1000, 50);
+        let (taken, finished) = simulate_workers(&queue, &[1, 4]);
 
-        if start_pos > 0 {
-            file.seek(SeekFrom::Start(start_pos)).await?;
-        }
+        // An even split would leave the slow worker 10 of the 20 segments;
+        // the fast one pulled most of those from the shared queue
+        assert_eq!(taken[0].len() + taken[1].len(), 20);
+        assert_eq!(taken[1].len(), 4);
+        assert_eq!(taken[0].len(), 16);
+        // So they finish within a segment of each other, not 3000 ticks
+        // apart
+        assert!(
+            finished[0].abs_diff(finished[1]) <= 50 * 4,
+            "{:?}",
+            finished
+        );
+        assert_covers(&taken.concat(), 0, 1000);
+    }
 
-        while let Some(chunk) =
-            tokio::time::timeout(self.config.timeout, response.chunk()).await??
-        {
-            file.write_all(&chunk).await?;
-            pb.inc(chunk.len() as u64);
-            self.state.total_pb.inc(chunk.len() as u64);
-            if let Some(ref limiter) = self.limiter {
-                limiter.throttle(chunk.len() as u64).await;
+    #[test]
+    fn tail_segments_are_split_and_finish_closer_together() {
+        const MIB: u64 = 1 << 20;
+        let queue = SegmentQueue::with_tail(16 * MIB, MIB, 4);
+        assert_eq!(queue.tail_split, Some(4.0));
+        let segments: Vec<_> = queue.segments.lock().unwrap().iter().copied().collect();
+        // A segment's worth per worker at the end, in quarters
+        let (body, tail) = segments.split_at(12);
+        assert!(body.iter().all(|(start, end)| end - start + 1 == MIB));
+        assert_eq!(tail.len(), 16);
+        assert!(tail.iter().all(|(start, end)| end - start + 1 == MIB / 4));
+        assert_covers(&segments, 0, 16 * MIB);
+
+        // One connection at a third of the speed of the others
+        let cost = [1, 1, 1, 3];
+        let spread =
+            |finished: &[u64]| finished.iter().max().unwrap() - finished.iter().min().unwrap();
+        let (taken, finished) = simulate_workers(&queue, &cost);
+        assert_covers(&taken.concat(), 0, 16 * MIB);
+        let (plain, plain_finished) = simulate_workers(&SegmentQueue::new(16 * MIB, MIB), &cost);
+        assert_covers(&plain.concat(), 0, 16 * MIB);
+        assert!(
+            spread(&finished) < spread(&plain_finished),
+            "{:?} vs {:?}",
+            finished,
+            plain_finished
+        );
+    }
+
+    #[test]
+    fn tail_is_left_whole_when_splitting_cannot_help() {
+        const MIB: u64 = 1 << 20;
+        // One worker has no one to finish alongside
+        assert_eq!(SegmentQueue::with_tail(16 * MIB, MIB, 1).tail_split, None);
+        // Segments already at the smallest tail size
+        assert_eq!(
+            SegmentQueue::with_tail(16 * MIB, MIN_TAIL_SEGMENT, 4).tail_split,
+            None
+        );
+        // No more than half of a small file
+        let queue = SegmentQueue::with_tail(3 * MIB, MIB, 8);
+        let segments: Vec<_> = queue.segments.lock().unwrap().iter().copied().collect();
+        let tail = segments
+            .iter()
+            .filter(|(start, _)| *start >= 3 * MIB / 2)
+            .count();
+        assert_eq!(tail, 6);
+        assert_covers(&segments, 0, 3 * MIB);
+    }
+
+    #[test]
+    fn split_segments_cover_the_range_exactly() {
+        let lens = [
+            0, 1, 2, 3, 5, 7, 10, 63, 64, 65, 100, 999, 1000, 1001, 65_537,
+        ];
+        let sizes = [1, 2, 3, 4, 7, 10, 64, 1000, 4096, 1 << 20];
+        for offset in [0, 1, 12_345, u64::MAX / 2] {
+            for len in lens {
+                for size in sizes {
+                    let mut segments = std::collections::VecDeque::new();
+                    SegmentQueue::split(&mut segments, offset, len, size);
+                    let segments: Vec<_> = segments.into_iter().collect();
+                    assert_eq!(
+                        segments.len() as u64,
+                        len.div_ceil(size),
+                        "{} / {}",
+                        len,
+                        size
+                    );
+                    assert!(
+                        segments.iter().all(|(from, to)| to - from < size),
+                        "{:?} has a segment over {}",
+                        segments,
+                        size
+                    );
+                    // In order, too, as the queue hands them out
+                    assert!(segments.windows(2).all(|pair| pair[0].1 + 1 == pair[1].0));
+                    assert_covers(&segments, offset, len);
+                }
             }
         }
+        // Near the top of the range, where `i * len` overflows a u64
+        let mut segments = std::collections::VecDeque::new();
+        SegmentQueue::split(&mut segments, 1, u64::MAX - 1, u64::MAX / 3);
+        assert_eq!(segments.len(), 3);
+        assert_covers(&segments.into_iter().collect::<Vec<_>>(), 1, u64::MAX - 1);
+    }
 
-        // pb.finish();
-        Ok(())
+    fn tls_options(
+        cert: Option<&str>,
+        key: Option<&str>,
+    ) -> Result<reqwest::ClientBuilder, Box<dyn std::error::Error + Send + Sync>> {
+        with_tls_options(Client::builder(), None, cert, key, false)
     }
 
-    async fn download_multi_threaded(
-        &self,
-        total_size: u64,
-        pb: ProgressBar,
-    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-        let num_chunks = std::cmp::min(
-            self.config.concurrent_chunks,
-            (total_size / self.config.chunk_size + 1) as usize,
+    #[test]
+    fn client_certificate_loads_with_its_key() {
+        let cert = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/data/client.pem");
+        let key = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/data/client.key");
+        assert!(tls_options(Some(cert), Some(key)).unwrap().build().is_ok());
+    }
+
+    #[test]
+    fn client_certificate_without_key_is_rejected() {
+        let cert = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/data/client.pem");
+        let err = tls_options(Some(cert), None).unwrap_err().to_string();
+        assert!(
+            err.starts_with("Invalid client certificate or key"),
+            "{}",
+            err
         );
+    }
 
-        let semaphore = Arc::new(Semaphore::new(num_chunks));
-        let pb = Arc::new(pb);
-        let mut handles = Vec::new();
+    #[test]
+    fn missing_client_certificate_names_the_file() {
+        let err = tls_options(Some("/nonexistent/client.pem"), None)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("/nonexistent/client.pem"), "{}", err);
+    }
 
-        let part_path = format!("{}.part", self.config.output_path);
-        File::create(&part_path).await?;
+    /// An empty directory for a test to work in, under the system's temp dir.
+    pub(crate) fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("grab-test-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
 
-        for i in 0..num_chunks {
-            let chunk_range_size = total_size / num_chunks as u64;
-            let start = i as u64 * chunk_range_size;
-            let end = if i == num_chunks - 1 {
-                total_size - 1
-            } else {
-                ((i + 1) as u64 * chunk_range_size) - 1
-            };
+    #[test]
+    fn next_free_path_keeps_a_double_extension_together() {
+        let dir = scratch_dir("double-extension");
+        let path = dir.join("a.tar.gz");
+        std::fs::write(&path, "").unwrap();
+        assert_eq!(
+            next_free_path(path.to_str().unwrap(), DEFAULT_RENAME_PATTERN),
+            dir.join("a (1).tar.gz").to_str().unwrap()
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
 
-            let client = self.client.clone();
-            let url = self.config.url.clone();
-            let output_path = part_path.clone();
-            let pb_clone = pb.clone();
-            let semaphore_clone = semaphore.clone();
+    /// The first bytes a client built with `version` sends to a server.
+    async fn first_bytes_sent(version: HttpVersion) -> Vec<u8> {
+        use std::io::Read;
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut received = vec![0; 24];
+            stream.read_exact(&mut received).unwrap();
+            received
+        });
+        let client = version
+            .apply(Client::builder().timeout(Duration::from_secs(2)))
+            .build()
+            .unwrap();
+        // The server hangs up without answering
+        let _ = client.get(&url).send().await;
+        server.join().unwrap()
+    }
 
-            let timeout = self.config.timeout;
-            let limiter = self.limiter.clone();
-            let total_pb = self.state.total_pb.clone();
-            let handle = tokio::spawn(async move {
-                let _permit = semaphore_clone.acquire().await.unwrap();
-                download_chunk(
-                    client,
-                    url,
-                    output_path,
-                    start,
-                    end,
-                    pb_clone,
-                    timeout,
-                    limiter,
-                    total_pb,
-                )
+    #[tokio::test]
+    async fn http_version_2_starts_with_the_h2_preface() {
+        assert_eq!(
+            first_bytes_sent(HttpVersion::Http2).await,
+            b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn http_version_1_1_sends_a_plain_request() {
+        assert!(
+            first_bytes_sent(HttpVersion::Http1)
                 .await
-            });
+                .starts_with(b"GET / HTTP/1.1\r\n")
+        );
+    }
 
-            handles.push(handle);
+    #[test]
+    fn output_goes_under_dir() {
+        assert_eq!(output_in_dir(None, "file.bin".into()), "file.bin");
+        assert_eq!(
+            output_in_dir(Some("downloads"), "file.bin".into()),
+            Path::new("downloads").join("file.bin").to_str().unwrap()
+        );
+        assert_eq!(
+            output_in_dir(Some("downloads"), "sub/file.bin".into()),
+            Path::new("downloads")
+                .join("sub/file.bin")
+                .to_str()
+                .unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn create_dirs_makes_every_missing_parent() {
+        let dir = scratch_dir("create-dirs");
+        let path = dir.join("a").join("b").join("file.bin");
+        create_parent_dirs(path.to_str().unwrap()).await.unwrap();
+        assert!(dir.join("a").join("b").is_dir());
+        // A bare name has nothing to create
+        create_parent_dirs("file.bin").await.unwrap();
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn accept_ranges_values() {
+        let accepts =
+            |value: &'static str| accepts_byte_ranges(Some(&HeaderValue::from_static(value)));
+        assert!(accepts("bytes"));
+        assert!(accepts("Bytes"));
+        assert!(accepts("BYTES"));
+        assert!(accepts("none, bytes"));
+        assert!(!accepts("none"));
+        assert!(!accepts(""));
+        assert!(!accepts("bytesx"));
+        assert!(!accepts_byte_ranges(None));
+    }
+
+    #[test]
+    fn output_template_placeholders() {
+        let url = "https://example.com/dir/file.tar.gz?x=1";
+        assert_eq!(
+            expand_output_template("{host}-{index}-{basename}", url, 3, false).unwrap(),
+            "example.com-3-file.tar.gz"
+        );
+        assert_eq!(
+            expand_output_template("out.{ext}", url, 1, false).unwrap(),
+            "out.gz"
+        );
+        assert_eq!(
+            expand_output_template("{date}", url, 1, false).unwrap(),
+            chrono::Local::now().format("%Y-%m-%d").to_string()
+        );
+        assert_eq!(
+            expand_output_template("plain", url, 1, false).unwrap(),
+            "plain"
+        );
+    }
+
+    #[test]
+    fn output_template_rejects_unknown_placeholders() {
+        let url = "https://example.com/file";
+        let err = expand_output_template("{name}", url, 1, false).unwrap_err();
+        assert!(err.contains("Unknown placeholder {name}"), "{}", err);
+        let err = expand_output_template("{host", url, 1, false).unwrap_err();
+        assert!(err.starts_with("Unclosed {"), "{}", err);
+    }
+
+    #[test]
+    fn content_range_total() {
+        assert_eq!(parse_content_range_total("bytes 0-0/123"), Some(123));
+        assert_eq!(parse_content_range_total("bytes */123"), Some(123));
+        assert_eq!(parse_content_range_total(" Bytes 0-9/10 "), Some(10));
+        assert_eq!(parse_content_range_total("bytes 0-9/*"), None);
+    }
+
+    #[test]
+    fn malformed_content_range() {
+        for value in [
+            "",
+            "bytes",
+            "bytes 0-0",
+            "items 0-0/5",
+            "bytes 5-2/10",
+            "bytes a-b/10",
+            "bytes 0-0/ten",
+        ] {
+            assert_eq!(parse_content_range_total(value), None, "{:?}", value);
         }
+    }
 
-        for handle in handles {
-            handle.await??;
+    #[test]
+    fn retry_after_values() {
+        let now = chrono::DateTime::parse_from_rfc2822("Wed, 21 Oct 2015 07:28:00 GMT")
+            .unwrap()
+            .with_timezone(&chrono::Utc);
+        assert_eq!(
+            parse_retry_after("120", now),
+            Some(Duration::from_secs(120))
+        );
+        assert_eq!(parse_retry_after(" 0 ", now), Some(Duration::ZERO));
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:29:30 GMT", now),
+            Some(Duration::from_secs(90))
+        );
+        // Already past: retry at once
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:00:00 GMT", now),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(parse_retry_after("soon", now), None);
+        assert_eq!(parse_retry_after("-5", now), None);
+        assert_eq!(parse_retry_after("", now), None);
+    }
+
+    fn retry_policy(control: watch::Receiver<TransferState>) -> RetryPolicy {
+        RetryPolicy {
+            retries: 0,
+            tries_per_chunk: 1,
+            max_total: None,
+            statuses: Vec::new(),
+            all_errors: false,
+            retried: Arc::default(),
+            file_retried: Arc::default(),
+            waiting: Arc::default(),
+            control,
         }
+    }
 
-        // pb.finish();
-        Ok(())
+    #[tokio::test]
+    async fn back_off_stops_when_cancelled() {
+        let (control_tx, control) = watch::channel(TransferState::Running);
+        let policy = retry_policy(control);
+        let wait = tokio::spawn(async move { policy.back_off(Duration::from_secs(3600)).await });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        control_tx.send_replace(TransferState::Cancelled);
+        let stopped = tokio::time::timeout(Duration::from_secs(5), wait).await;
+        assert!(matches!(
+            stopped.unwrap().unwrap(),
+            Err(DownloadError::Cancelled)
+        ));
     }
-}
 
-async fn download_chunk(
-    client: Client,
-    url: String,
-    output_path: String,
-    start: u64,
-    end: u64,
-    pb: Arc<ProgressBar>,
-    timeout: Duration,
-    limiter: Option<Arc<BandwidthLimiter>>,
-    total_pb: ProgressBar,
-) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let mut headers = HeaderMap::new();
-    headers.insert(RANGE, format!("bytes={}-{}", start, end).parse().unwrap());
+    #[tokio::test]
+    async fn back_off_waits_through_a_pause() {
+        let (control_tx, control) = watch::channel(TransferState::Running);
+        let policy = retry_policy(control);
+        let wait = tokio::spawn(async move { policy.back_off(Duration::from_millis(200)).await });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        control_tx.send_replace(TransferState::Paused);
+        assert!(matches!(wait.await.unwrap(), Ok(())));
+    }
 
-    let response =
-        tokio::time::timeout(timeout, client.get(&url).headers(headers).send()).await??;
+    #[test]
+    fn truncate_filename_keeps_the_extension() {
+        assert_eq!(truncate_filename("short.txt", 20), "short.txt");
+        assert_eq!(truncate_filename("abcdefghij.txt", 10), "abcdef.txt");
+        assert_eq!(truncate_filename("abcdefghij.tar.gz", 12), "abcde.tar.gz");
+        // No room for the extension: it's cut like the rest
+        assert_eq!(truncate_filename("abc.verylongext", 5), "abc.v");
+    }
 
-    if response.status() != reqwest::StatusCode::PARTIAL_CONTENT {
-        return Err("Server did not return partial content for chunk request".into());
+    #[test]
+    fn truncate_filename_never_splits_a_character() {
+        // "é" is two bytes; a cut at 5 bytes would land inside the third
+        let name = "ééééé.txt";
+        let cut = truncate_filename(name, 9);
+        assert_eq!(cut, "éé.txt");
+        assert!(cut.len() <= 9);
+        assert_eq!(truncate_filename("日本語", 4), "日");
+        assert_eq!(truncate_filename("日本語", 2), "");
     }
 
-    let mut response = response;
+    #[test]
+    fn fsync_modes() {
+        assert!(!FsyncMode::None.syncs_final());
+        assert!(FsyncMode::Final.syncs_final());
+        assert!(FsyncMode::PerChunk.syncs_final());
+        assert_eq!(FsyncMode::None.interval(1024), None);
+        assert_eq!(FsyncMode::Final.interval(1024), None);
+        assert_eq!(FsyncMode::PerChunk.interval(1024), Some(1024));
+        assert_eq!(FsyncMode::PerChunk.interval(0), Some(1));
+    }
 
-    let mut file = OpenOptions::new().write(true).open(&output_path).await?;
+    #[tokio::test]
+    async fn synced_part_file_is_moved_into_place() {
+        let dir = scratch_dir("fsync-final");
+        let part = dir.join("file.bin.part").to_string_lossy().into_owned();
+        let output = dir.join("file.bin").to_string_lossy().into_owned();
+        std::fs::write(&part, b"complete").unwrap();
 
-    file.seek(SeekFrom::Start(start)).await?;
+        sync_file(&part).await.unwrap();
+        move_file(&part, &output, true).await.unwrap();
+        assert_eq!(std::fs::read(&output).unwrap(), b"complete");
+        assert!(!Path::new(&part).exists());
+        assert!(sync_file(&part).await.is_err());
+    }
+
+    #[test]
+    fn schedule_window_wraps_past_midnight() {
+        let schedule = parse_schedule("22:00-06:00:unlimited,1M").unwrap();
+        let day = parse_bandwidth("1M").unwrap();
+        assert_eq!(schedule.rate_at(23 * 60, 5), 0);
+        assert_eq!(schedule.rate_at(0, 5), 0);
+        assert_eq!(schedule.rate_at(5 * 60 + 59, 5), 0);
+        assert_eq!(schedule.rate_at(6 * 60, 5), day);
+        assert_eq!(schedule.rate_at(21 * 60 + 59, 5), day);
+        // 24:00 is midnight
+        let evening = parse_schedule("18:00-24:00:2M").unwrap();
+        assert_eq!(
+            evening.rate_at(23 * 60 + 59, 5),
+            parse_bandwidth("2M").unwrap()
+        );
+        assert_eq!(evening.rate_at(0, 5), 5);
+    }
 
-    while let Some(chunk) = tokio::time::timeout(timeout, response.chunk()).await?? {
-        file.write_all(&chunk).await?;
-        pb.inc(chunk.len() as u64);
-        total_pb.inc(chunk.len() as u64);
-        if let Some(ref lim) = limiter {
-            lim.throttle(chunk.len() as u64).await;
+    #[test]
+    fn schedule_takes_the_first_overlapping_window() {
+        let schedule = parse_schedule("09:00-17:00:1M,12:00-13:00:unlimited").unwrap();
+        let work = parse_bandwidth("1M").unwrap();
+        assert_eq!(schedule.rate_at(12 * 60 + 30, 7), work);
+        assert_eq!(schedule.rate_at(8 * 60, 7), 7);
+        let lunch_first = parse_schedule("12:00-13:00:unlimited,09:00-17:00:1M").unwrap();
+        assert_eq!(lunch_first.rate_at(12 * 60 + 30, 7), 0);
+        assert_eq!(lunch_first.rate_at(13 * 60, 7), work);
+    }
+
+    #[test]
+    fn schedule_rejects_bad_entries() {
+        assert!(parse_schedule("").is_err());
+        assert!(parse_schedule("1M,2M").is_err());
+        assert!(parse_schedule("25:00-06:00:1M").is_err());
+        assert!(parse_schedule("22:00-06:00").is_err());
+    }
+
+    #[test]
+    fn exit_code_for_each_download_error() {
+        let cases = [
+            (DownloadError::Cancelled, EXIT_CANCELLED),
+            (
+                DownloadError::HttpStatus(reqwest::StatusCode::NOT_FOUND),
+                EXIT_NETWORK,
+            ),
+            (
+                DownloadError::SizeMismatch {
+                    expected: 2,
+                    actual: 1,
+                },
+                EXIT_NETWORK,
+            ),
+            (
+                DownloadError::ChunkCutOff {
+                    start: 0,
+                    end: 9,
+                    at: 5,
+                },
+                EXIT_NETWORK,
+            ),
+            (DownloadError::ChecksumMismatch, EXIT_CHECKSUM),
+            (DownloadError::Deadline, EXIT_DEADLINE),
+            (DownloadError::TooSlow { speed: 1 }, EXIT_NETWORK),
+            (
+                DownloadError::UnexpectedType {
+                    expected: "text/*".to_string(),
+                    actual: "image/png".to_string(),
+                },
+                EXIT_FAILURE,
+            ),
+            (DownloadError::NotModified, EXIT_FAILURE),
+            (
+                DownloadError::FileTooLarge { size: 2, max: 1 },
+                EXIT_FAILURE,
+            ),
+            (
+                DownloadError::RetryBudgetExhausted {
+                    max: 3,
+                    last: "reset".to_string(),
+                },
+                EXIT_NETWORK,
+            ),
+            (DownloadError::Simulated("reset".to_string()), EXIT_NETWORK),
+        ];
+        for (error, code) in cases {
+            let name = format!("{:?}", error);
+            assert_eq!(exit_code(&error), code, "{}", name);
+            // Also when wrapped, as errors reach main
+            let boxed: Box<dyn std::error::Error + Send + Sync> = error.into();
+            assert_eq!(exit_code(boxed.as_ref()), code, "{}", name);
         }
     }
 
-    Ok(())
-}
+    #[test]
+    fn exit_code_for_io_errors() {
+        let io = |kind| std::io::Error::from(kind);
+        assert_eq!(
+            exit_code(&io(std::io::ErrorKind::StorageFull)),
+            EXIT_DISK_FULL
+        );
+        assert_eq!(
+            exit_code(&io(std::io::ErrorKind::ConnectionReset)),
+            EXIT_NETWORK
+        );
+        assert_eq!(
+            exit_code(&io(std::io::ErrorKind::PermissionDenied)),
+            EXIT_FAILURE
+        );
+    }
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let args = Args::parse();
+    #[test]
+    fn normalize_url_encodes_what_a_url_cannot_hold() {
+        assert_eq!(
+            normalize_url("http://host/my file.zip"),
+            "http://host/my%20file.zip"
+        );
+        assert_eq!(
+            normalize_url("https://host/café/ü.txt?q=a b"),
+            "https://host/caf%C3%A9/%C3%BC.txt?q=a%20b"
+        );
+        assert_eq!(normalize_url("http://host/a\"b"), "http://host/a%22b");
+    }
 
-    if args.version {
-        println!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
-        return Ok(());
+    #[test]
+    fn normalize_url_leaves_escapes_alone() {
+        // Already encoded: no %2520
+        assert_eq!(
+            normalize_url("http://host/my%20file.zip"),
+            "http://host/my%20file.zip"
+        );
+        assert_eq!(
+            normalize_url("http://host/caf%C3%A9?q=%2F"),
+            "http://host/caf%C3%A9?q=%2F"
+        );
+        // Encoding twice changes nothing more
+        let once = normalize_url("http://host/a b%20c");
+        assert_eq!(once, "http://host/a%20b%20c");
+        assert_eq!(normalize_url(&once), once);
     }
 
-    // List of (URL, Optional Checksum)
-    let mut download_tasks: Vec<(String, Option<Checksum>)> = Vec::new();
+    #[test]
+    fn normalize_url_passes_other_inputs_through() {
+        assert_eq!(normalize_url("./my file.zip"), "./my file.zip");
+        assert_eq!(normalize_url("file:///tmp/a b"), "file:///tmp/a b");
+        assert_eq!(normalize_url("not a url"), "not a url");
+    }
 
-    // Parse URLs and Checksums from arguments
-    let mut i = 0;
-    while i < args.urls.len() {
-        let url = args.urls[i].clone();
-        let mut checksum = None;
-        if i + 1 < args.urls.len() && args.urls[i + 1].contains(':') {
-            if let Some(parsed) = Checksum::parse(&args.urls[i + 1]) {
-                checksum = Some(parsed);
-                i += 1; // Consume the checksum argument
-            }
+    #[test]
+    fn progress_line_is_a_plain_line() {
+        let pb = ProgressBar::hidden();
+        pb.set_length(2 * 1024 * 1024);
+        pb.set_position(1024 * 1024);
+        let line = progress_line(&pb, 1, 3, true, Units::Binary);
+        assert!(
+            line.starts_with("Progress: 1.00 MiB of 2.00 MiB (50%) at "),
+            "{}",
+            line
+        );
+        assert!(line.ends_with(", 1/3 files, paused"), "{}", line);
+        assert!(!line.contains(['\r', '\n', '\x1b']), "{:?}", line);
+
+        let unsized_pb = ProgressBar::hidden();
+        unsized_pb.set_position(10);
+        let line = progress_line(&unsized_pb, 0, 1, false, Units::Binary);
+        assert!(line.starts_with("Progress: 10 B at "), "{}", line);
+    }
+
+    #[test]
+    fn content_type_extension_mapping() {
+        assert_eq!(content_type_extension("application/pdf"), Some("pdf"));
+        assert_eq!(content_type_extension("image/jpeg"), Some("jpg"));
+        assert_eq!(content_type_extension("text/html"), Some("html"));
+        assert_eq!(content_type_extension("application/x-gzip"), Some("gz"));
+        assert_eq!(content_type_extension("image/png"), Some("png"));
+        // Parameters, case and spacing don't matter
+        assert_eq!(
+            content_type_extension("text/plain; charset=utf-8"),
+            Some("txt")
+        );
+        assert_eq!(
+            content_type_extension("Text/HTML;charset=ISO-8859-1"),
+            Some("html")
+        );
+        assert_eq!(
+            content_type_extension(" application/json ; q=1"),
+            Some("json")
+        );
+    }
+
+    #[test]
+    fn content_type_extension_needs_a_known_type() {
+        assert_eq!(content_type_extension("application/octet-stream"), None);
+        assert_eq!(content_type_extension("binary/octet-stream"), None);
+        assert_eq!(content_type_extension("application/x-made-up"), None);
+        assert_eq!(content_type_extension(""), None);
+    }
+
+    #[test]
+    fn auto_connections_by_size() {
+        const MIB: u64 = 1024 * 1024;
+        let cases = [
+            (0, 1),
+            (MIB - 1, 1),
+            (MIB, 2),
+            (10 * MIB - 1, 2),
+            (10 * MIB, 4),
+            (100 * MIB - 1, 4),
+            (100 * MIB, 8),
+            (1000 * MIB - 1, 8),
+            (1000 * MIB, 16),
+            (u64::MAX, 16),
+        ];
+        for (size, connections) in cases {
+            assert_eq!(auto_connections(size, true, 16), connections, "{}", size);
         }
-        download_tasks.push((url, checksum));
-        i += 1;
     }
 
-    // Read from stdin if no URLs provided
-    if download_tasks.is_empty() {
-        use std::io::IsTerminal;
-        if !std::io::stdin().is_terminal() {
-            use tokio::io::AsyncBufReadExt;
-            let stdin = tokio::io::stdin();
-            let mut reader = tokio::io::BufReader::new(stdin).lines();
-            while let Some(line) = reader.next_line().await? {
-                let line = line.trim();
-                if !line.is_empty() {
-                    let parts: Vec<&str> = line.split_whitespace().collect();
-                    if parts.len() >= 2 {
-                        let url = parts[0].to_string();
-                        let checksum = Checksum::parse(parts[1]);
-                        download_tasks.push((url, checksum));
-                    } else {
-                        download_tasks.push((parts[0].to_string(), None));
-                    }
-                }
-            }
+    #[test]
+    fn auto_connections_limits() {
+        const MIB: u64 = 1024 * 1024;
+        // Without ranges there's only ever one
+        assert_eq!(auto_connections(5000 * MIB, false, 16), 1);
+        // --max-connections caps it, and even 0 still means one
+        assert_eq!(auto_connections(5000 * MIB, true, 6), 6);
+        assert_eq!(auto_connections(50 * MIB, true, 64), 4);
+        assert_eq!(auto_connections(5000 * MIB, true, 0), 1);
+    }
+
+    #[test]
+    fn next_free_path_takes_the_next_unused_number() {
+        let dir = scratch_dir("successive-names");
+        let path = |name: &str| dir.join(name).to_string_lossy().into_owned();
+        let pattern = "{name}-{n}{ext}";
+        // Nothing in the way: the name itself
+        assert_eq!(next_free_path(&path("file.bin"), pattern), path("file.bin"));
+
+        for name in ["file.bin", "file-1.bin", "file-2.bin", "file-4.bin"] {
+            std::fs::write(path(name), "").unwrap();
         }
+        assert_eq!(
+            next_free_path(&path("file.bin"), pattern),
+            path("file-3.bin")
+        );
+        std::fs::write(path("file-3.bin"), "").unwrap();
+        assert_eq!(
+            next_free_path(&path("file.bin"), pattern),
+            path("file-5.bin")
+        );
+        // The default pattern numbers separately
+        assert_eq!(
+            next_free_path(&path("file.bin"), DEFAULT_RENAME_PATTERN),
+            path("file (1).bin")
+        );
+        // Without an extension, and with {n} first
+        std::fs::write(path("README"), "").unwrap();
+        assert_eq!(
+            next_free_path(&path("README"), "{n}_{name}"),
+            path("1_README")
+        );
+        std::fs::remove_dir_all(dir).unwrap();
     }
 
-    if download_tasks.is_empty() {
-        use clap::CommandFactory;
-        Args::command().print_help()?;
-        println!();
-        return Ok(());
+    #[test]
+    fn rename_pattern_validation() {
+        assert!(parse_rename_pattern("{name}-{n}{ext}").is_ok());
+        assert!(parse_rename_pattern("copy{n}").is_ok());
+        assert!(
+            parse_rename_pattern("{name}{ext}")
+                .unwrap_err()
+                .contains("needs {n}")
+        );
+        assert!(
+            parse_rename_pattern("{name}-{count}{ext}")
+                .unwrap_err()
+                .contains("Unknown placeholder {count}")
+        );
+        assert!(
+            parse_rename_pattern("{name}-{n")
+                .unwrap_err()
+                .contains("Unclosed")
+        );
+        assert!(
+            parse_rename_pattern("old/{name}-{n}{ext}")
+                .unwrap_err()
+                .contains("only change the file name")
+        );
     }
 
-    let multi_progress = indicatif::MultiProgress::new();
-    let semaphore = Arc::new(Semaphore::new(args.parallel_downloads));
-    let limiter = args
-        .limit_rate
-        .map(|limit| Arc::new(BandwidthLimiter::new(limit)));
+    /// The counts a tuner capped at `max` picks over 20 intervals when
+    /// `curve` gives the throughput for each count.
+    fn tune(max: usize, curve: impl Fn(usize) -> f64) -> (Vec<usize>, bool) {
+        let mut tuner = ConnectionTuner::new(max);
+        let counts = (0..20)
+            .map(|_| {
+                let throughput = curve(tuner.active);
+                tuner.observe(throughput)
+            })
+            .collect();
+        (counts, tuner.settled)
+    }
 
-    // Total progress bar
-    let total_pb = multi_progress.add(ProgressBar::new(0));
-    total_pb.set_style(
-        ProgressStyle::default_bar()
-            .template("Total {msg:<22} {bytes:>10}/{total_bytes:<10} {bytes_per_sec:>12} {eta:>6} [ {wide_bar} ] {percent:3}%")
-            .unwrap()
-            .progress_chars("---c  o "),
-    );
-    total_pb.set_message(format!("(0/{})", download_tasks.len()));
+    #[test]
+    fn connection_tuner_settles_on_a_saturated_link() {
+        // 1 MB/s per connection up to a 5 MB/s link
+        let (counts, settled) = tune(32, |n| n.min(5) as f64 * 1e6);
+        assert!(settled);
+        // Doubling while it pays, then back from 16 to 8 for good
+        assert_eq!(counts[..4], [4, 8, 16, 8]);
+        assert!(counts[3..].iter().all(|&n| n == 8), "{:?}", counts);
+    }
 
-    let state = Arc::new(DownloadState {
-        total_files: download_tasks.len(),
-        finished_files: std::sync::atomic::AtomicUsize::new(0),
-        total_pb: total_pb.clone(),
-    });
+    #[test]
+    fn connection_tuner_steps_back_when_more_connections_hurt() {
+        // A server that slows down with every extra connection
+        let (counts, settled) = tune(32, |n| (10 - n.min(9)) as f64 * 1e6);
+        assert!(settled);
+        assert_eq!(counts[0], 4);
+        assert!(counts[1..].iter().all(|&n| n == 2), "{:?}", counts);
+    }
 
-    let mut handles = Vec::new();
+    #[test]
+    fn connection_tuner_stops_at_the_cap() {
+        let (counts, settled) = tune(6, |n| n as f64 * 1e6);
+        assert!(settled);
+        assert_eq!(counts[..2], [4, 6]);
+        assert!(counts[1..].iter().all(|&n| n == 6), "{:?}", counts);
+    }
 
-    for (url, checksum) in download_tasks {
-        let output_path = if args.output.is_some() && handles.is_empty() {
-            args.output.clone().unwrap()
-        } else {
-            url.split('/')
-                .last()
-                .filter(|s| !s.is_empty())
-                .unwrap_or("index.html")
-                .to_string()
-        };
+    #[test]
+    fn connection_tuner_ignores_small_gains() {
+        // Under ADAPTIVE_MIN_GAIN from 2 to 4 connections
+        let (counts, settled) = tune(32, |n| if n <= 2 { 1e6 } else { 1.05e6 });
+        assert!(settled);
+        assert_eq!(counts[0], 4);
+        assert!(counts[1..].iter().all(|&n| n == 2), "{:?}", counts);
+    }
 
-        let config = DownloadConfig {
-            url,
-            output_path,
-            concurrent_chunks: args.threads,
-            chunk_size: args.chunk_size,
-            resume: args.resume,
-            user_agent: args.user_agent.clone(),
-            timeout: args.timeout,
-            force_ipv4: args.inet4_only,
-            force_ipv6: args.inet6_only,
-            checksum,
-        };
+    #[tokio::test]
+    async fn writers_never_buffer_past_the_shared_budget() {
+        const BUDGET: usize = 16 * 1024;
+        let dir = scratch_dir("buffer-budget");
+        let budget = BufferBudget::new(BUDGET as u64);
+        let mut writers = Vec::new();
+        for i in 0..4 {
+            let file = File::create(dir.join(i.to_string())).await.unwrap();
+            // Room for far more than the budget, if the buffers had their way
+            writers.push(PartWriter::new(file, 64 * 1024, None, Some(budget.clone())));
+        }
 
-        let downloader = Arc::new(FileDownloader::new(
-            config,
-            multi_progress.clone(),
-            limiter.clone(),
-            state.clone(),
-        ));
-        let sem = semaphore.clone();
+        let piece: Vec<u8> = (0..1000).map(|i| i as u8).collect();
+        for _ in 0..100 {
+            for writer in &mut writers {
+                writer.write_all(&piece).await.unwrap();
+                // Every buffered byte is backed by a permit of the budget
+                assert!(writer.file.buffer().len() <= writer.held);
+            }
+            let held: usize = writers.iter().map(|writer| writer.held).sum();
+            assert!(held <= BUDGET, "{} bytes held", held);
+            assert_eq!(budget.permits.available_permits(), BUDGET - held);
+        }
 
-        let handle = tokio::spawn(async move {
-            let _permit = sem.acquire().await.unwrap();
-            downloader.download().await
-        });
-        handles.push(handle);
+        for writer in &mut writers {
+            writer.finish().await.unwrap();
+        }
+        drop(writers);
+        assert_eq!(budget.permits.available_permits(), BUDGET);
+        for i in 0..4 {
+            let written = std::fs::read(dir.join(i.to_string())).unwrap();
+            assert_eq!(written, piece.repeat(100));
+        }
     }
 
-    for handle in handles {
-        let _ = handle.await?;
+    #[tokio::test]
+    async fn writer_waits_for_budget_another_holds() {
+        let dir = scratch_dir("buffer-budget-wait");
+        let budget = BufferBudget::new(4000);
+        let new_writer = |name: &str| {
+            let path = dir.join(name);
+            let budget = budget.clone();
+            async move {
+                let file = File::create(path).await.unwrap();
+                PartWriter::new(file, 64 * 1024, None, Some(budget))
+            }
+        };
+        let mut first = new_writer("first").await;
+        let mut second = new_writer("second").await;
+
+        first.write_all(&[1; 4000]).await.unwrap();
+        assert_eq!(first.file.buffer().len(), 4000);
+        // Nothing of its own to flush, so the second can only wait
+        let blocked =
+            tokio::time::timeout(Duration::from_millis(100), second.write_all(&[2; 1000])).await;
+        assert!(blocked.is_err());
+
+        // The first flushes rather than wait on itself
+        first.write_all(&[1; 1000]).await.unwrap();
+        assert_eq!(first.file.buffer().len(), 1000);
+        second.write_all(&[2; 1000]).await.unwrap();
+        first.finish().await.unwrap();
+        second.finish().await.unwrap();
+        assert_eq!(std::fs::read(dir.join("first")).unwrap(), [1; 5000]);
+        assert_eq!(std::fs::read(dir.join("second")).unwrap(), [2; 1000]);
     }
 
-    total_pb.finish();
+    #[tokio::test]
+    async fn streamed_digest_matches_the_finished_file() {
+        let dir = scratch_dir("streamed-digest");
+        let path = dir.join("file.bin");
+        let data: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+        std::fs::write(&path, &data).unwrap();
+        let path = path.to_str().unwrap();
 
-    Ok(())
+        for kind in [
+            "md5", "sha1", "sha224", "sha256", "sha384", "sha512", "b2sum", "b3sum",
+        ] {
+            // The kind alone picks the hash; the expected value isn't used
+            let kind_only = Checksum::parse(&format!("{}:", kind)).unwrap();
+            let mut whole = StreamingHash::new(&kind_only);
+            whole.update(&data);
+            let whole = whole.finish();
+
+            // As a response arrives: pieces of every size, some empty
+            let mut pieces = StreamingHash::new(&kind_only);
+            let mut rest = &data[..];
+            for size in (0..).map(|i: usize| i % 4099) {
+                if rest.is_empty() {
+                    break;
+                }
+                let (piece, after) = rest.split_at(size.min(rest.len()));
+                pieces.update(piece);
+                rest = after;
+            }
+            assert_eq!(pieces.finish(), whole, "{}", kind);
+
+            let checksum = Checksum::parse(&format!("{}:{}", kind, whole)).unwrap();
+            assert!(
+                verify_checksum(&checksum, path, None).await.unwrap(),
+                "{}",
+                kind
+            );
+            if kind == "sha256" {
+                assert_eq!(sha256_file(path).await.unwrap(), whole);
+            }
+        }
+    }
 }
@@ -0,0 +1,278 @@
+//! `multipart/byteranges` bodies: a server's answer to a request for
+//! several ranges at once (`--range 0-99,5000-5999`).
+//!
+//! The parser works on the body as it arrives and never holds a part's
+//! data: each part's `Content-Range` says how long it is, so the data is
+//! passed through and only the delimiters and part headers are buffered.
+
+/// The `boundary` parameter of a `multipart/byteranges` Content-Type, or
+/// `None` for any other type.
+pub fn boundary(content_type: &str) -> Option<String> {
+    let mut params = content_type.split(';');
+    let media_type = params.next()?.trim();
+    if !media_type.eq_ignore_ascii_case("multipart/byteranges") {
+        return None;
+    }
+    params.find_map(|param| {
+        let (name, value) = param.split_once('=')?;
+        name.trim()
+            .eq_ignore_ascii_case("boundary")
+            .then(|| value.trim().trim_matches('"').to_string())
+    })
+}
+
+/// `(start, end)` of a `Content-Range: bytes START-END/TOTAL` value.
+pub fn content_range(value: &str) -> Option<(u64, u64)> {
+    let (unit, rest) = value.trim().split_once(' ')?;
+    if !unit.eq_ignore_ascii_case("bytes") {
+        return None;
+    }
+    let (range, _total) = rest.trim().split_once('/')?;
+    let (start, end) = range.split_once('-')?;
+    let (start, end) = (start.parse().ok()?, end.parse().ok()?);
+    (start <= end).then_some((start, end))
+}
+
+#[derive(Debug)]
+enum State {
+    /// Looking for the next delimiter, skipping anything before it
+    Delimiter,
+    /// Just past a delimiter: either `--` (the end) or a line break
+    AfterDelimiter,
+    /// In a part's headers, up to the blank line
+    Headers,
+    /// In a part's data, at remote `offset` with `remaining` bytes to go
+    Body { offset: u64, remaining: u64 },
+    /// Past the closing delimiter; the epilogue is ignored
+    Done,
+}
+
+/// Splits a `multipart/byteranges` body into the file data it carries.
+pub struct Parser {
+    /// `\r\n--BOUNDARY`
+    delimiter: Vec<u8>,
+    buffer: Vec<u8>,
+    state: State,
+}
+
+impl Parser {
+    pub fn new(boundary: &str) -> Self {
+        Parser {
+            delimiter: format!("\r\n--{}", boundary).into_bytes(),
+            // The first delimiter has no line break of its own to follow
+            buffer: b"\r\n".to_vec(),
+            state: State::Delimiter,
+        }
+    }
+
+    /// Takes the next piece of the body and returns the file data found in
+    /// it, as `(remote offset, bytes)`.
+    pub fn feed(&mut self, data: &[u8]) -> Result<Vec<(u64, Vec<u8>)>, String> {
+        self.buffer.extend_from_slice(data);
+        let mut found = Vec::new();
+
+        loop {
+            match self.state {
+                State::Delimiter => {
+                    let Some(pos) = find(&self.buffer, &self.delimiter) else {
+                        // Keep just enough to spot a delimiter cut in two
+                        let keep = self.buffer.len().min(self.delimiter.len() - 1);
+                        self.buffer.drain(..self.buffer.len() - keep);
+                        return Ok(found);
+                    };
+                    self.buffer.drain(..pos + self.delimiter.len());
+                    self.state = State::AfterDelimiter;
+                }
+                State::AfterDelimiter => {
+                    if self.buffer.starts_with(b"--") {
+                        self.state = State::Done;
+                        continue;
+                    }
+                    let Some(pos) = find(&self.buffer, b"\r\n") else {
+                        return Ok(found);
+                    };
+                    // Only transport padding may follow the boundary
+                    if self.buffer[..pos]
+                        .iter()
+                        .any(|b| !matches!(b, b' ' | b'\t'))
+                    {
+                        return Err("Malformed multipart delimiter".to_string());
+                    }
+                    self.buffer.drain(..pos + 2);
+                    self.state = State::Headers;
+                }
+                State::Headers => {
+                    let Some(pos) = find(&self.buffer, b"\r\n\r\n") else {
+                        return Ok(found);
+                    };
+                    let headers = String::from_utf8_lossy(&self.buffer[..pos]).into_owned();
+                    self.buffer.drain(..pos + 4);
+                    let (start, end) = headers
+                        .lines()
+                        .find_map(|line| {
+                            let (name, value) = line.split_once(':')?;
+                            name.trim()
+                                .eq_ignore_ascii_case("content-range")
+                                .then(|| content_range(value))
+                                .flatten()
+                        })
+                        .ok_or("Multipart part without a usable Content-Range")?;
+                    self.state = State::Body {
+                        offset: start,
+                        remaining: end - start + 1,
+                    };
+                }
+                State::Body { offset, remaining } => {
+                    if self.buffer.is_empty() {
+                        return Ok(found);
+                    }
+                    let n = self.buffer.len().min(remaining as usize);
+                    found.push((offset, self.buffer.drain(..n).collect()));
+                    self.state = if remaining == n as u64 {
+                        State::Delimiter
+                    } else {
+                        State::Body {
+                            offset: offset + n as u64,
+                            remaining: remaining - n as u64,
+                        }
+                    };
+                }
+                State::Done => {
+                    self.buffer.clear();
+                    return Ok(found);
+                }
+            }
+        }
+    }
+
+    /// Checks that the body ended with its closing delimiter.
+    pub fn finish(&self) -> Result<(), String> {
+        match self.state {
+            State::Done => Ok(()),
+            _ => Err("Multipart response ended early".to_string()),
+        }
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// As Apache sends it for `Range: bytes=0-4,10-19,95-99` of a 100-byte
+    /// file, with the parts' data filled in by `data`.
+    fn captured_body(data: impl Fn(u64, u64) -> Vec<u8>) -> Vec<u8> {
+        let mut body = b"\r\n".to_vec();
+        for (start, end) in [(0, 4), (10, 19), (95, 99)] {
+            body.extend_from_slice(
+                format!(
+                    "--3d6b6a416f9b5\r\n\
+                     Content-Type: application/octet-stream\r\n\
+                     Content-Range: bytes {}-{}/100\r\n\r\n",
+                    start, end
+                )
+                .as_bytes(),
+            );
+            body.extend(data(start, end));
+            body.extend_from_slice(b"\r\n");
+        }
+        body.extend_from_slice(b"--3d6b6a416f9b5--\r\n");
+        body
+    }
+
+    fn file_bytes(start: u64, end: u64) -> Vec<u8> {
+        (start..=end).map(|i| b'a' + (i % 26) as u8).collect()
+    }
+
+    /// Feeds `body` in `piece`-sized pieces and returns each run of data
+    /// found, joined where one piece carries on from the last.
+    fn parse_in_pieces(body: &[u8], piece: usize) -> Vec<(u64, Vec<u8>)> {
+        let mut parser = Parser::new("3d6b6a416f9b5");
+        let mut parts: Vec<(u64, Vec<u8>)> = Vec::new();
+        for chunk in body.chunks(piece) {
+            for (offset, data) in parser.feed(chunk).unwrap() {
+                match parts.last_mut() {
+                    Some((start, bytes)) if *start + bytes.len() as u64 == offset => {
+                        bytes.extend(data)
+                    }
+                    _ => parts.push((offset, data)),
+                }
+            }
+        }
+        parser.finish().unwrap();
+        parts
+    }
+
+    #[test]
+    fn parts_come_out_whole_however_the_body_is_cut() {
+        let body = captured_body(file_bytes);
+        let expected = vec![
+            (0, file_bytes(0, 4)),
+            (10, file_bytes(10, 19)),
+            (95, file_bytes(95, 99)),
+        ];
+        // Every cut from single bytes up, so some fall inside a boundary,
+        // a header line and the blank line after the headers
+        for piece in 1..=body.len() {
+            assert_eq!(
+                parse_in_pieces(&body, piece),
+                expected,
+                "pieces of {}",
+                piece
+            );
+        }
+    }
+
+    #[test]
+    fn data_that_looks_like_a_boundary_is_data() {
+        // Only the declared length counts, so a part can hold anything
+        let fake = b"\r\n--3d6b6a416f9b5--\r\n".to_vec();
+        let body = captured_body(|start, end| {
+            let len = (end - start + 1) as usize;
+            fake.iter().cycle().take(len).copied().collect()
+        });
+        let parts = parse_in_pieces(&body, 3);
+        assert_eq!(parts.len(), 3);
+        assert_eq!(parts[1].0, 10);
+        assert_eq!(parts[1].1, fake[..10]);
+    }
+
+    #[test]
+    fn body_cut_short_is_an_error() {
+        let body = captured_body(file_bytes);
+        let mut parser = Parser::new("3d6b6a416f9b5");
+        parser.feed(&body[..body.len() - 10]).unwrap();
+        assert!(parser.finish().is_err());
+    }
+
+    #[test]
+    fn part_without_content_range_is_an_error() {
+        let mut parser = Parser::new("b");
+        let err = parser
+            .feed(b"--b\r\nContent-Type: text/plain\r\n\r\nhello\r\n--b--\r\n")
+            .unwrap_err();
+        assert!(err.contains("Content-Range"), "{}", err);
+    }
+
+    #[test]
+    fn boundary_and_content_range_values() {
+        assert_eq!(
+            boundary("multipart/byteranges; boundary=\"abc def\"").as_deref(),
+            Some("abc def")
+        );
+        assert_eq!(
+            boundary("Multipart/ByteRanges;charset=x;BOUNDARY=x1").as_deref(),
+            Some("x1")
+        );
+        assert_eq!(boundary("multipart/mixed; boundary=x"), None);
+        assert_eq!(content_range("bytes 10-19/100"), Some((10, 19)));
+        assert_eq!(content_range("bytes 10-19/*"), Some((10, 19)));
+        assert_eq!(content_range("bytes 19-10/100"), None);
+        assert_eq!(content_range("items 0-1/2"), None);
+    }
+}
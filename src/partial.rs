@@ -0,0 +1,276 @@
+//! The `.part` file a download is written to until it's complete.
+//!
+//! A new transfer creates it, while `--resume` and `--continue-at` pick up
+//! one left by an earlier run, checked with `--verify-resume` if asked. A
+//! device target is written in place instead.
+
+use crate::remote::Plan;
+use crate::{
+    DownloadError, FileDownloader, ManifestEntry, PartWriter, console, format_bytes, hash_range,
+    send_with_retry,
+};
+use indicatif::ProgressBar;
+use log::{debug, info};
+use reqwest::header::RANGE;
+use std::path::Path;
+use tokio::fs::{File, OpenOptions, metadata};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, SeekFrom};
+
+impl FileDownloader {
+    /// Where data goes until the download is complete: `FILE.part`, in
+    /// `--temp-dir` if given, or the device itself.
+    pub(super) fn part_path(&self) -> String {
+        if self.config.device_target {
+            return self.config.output_path.clone();
+        }
+        match self.config.temp_dir {
+            Some(ref dir) => {
+                let name = Path::new(&self.config.output_path)
+                    .file_name()
+                    .map(|name| name.to_string_lossy())
+                    .unwrap_or_default();
+                Path::new(dir)
+                    .join(format!("{}.part", name))
+                    .to_string_lossy()
+                    .into_owned()
+            }
+            None => format!("{}.part", self.config.output_path),
+        }
+    }
+
+    /// Opens the write target for a transfer from the start. A device can't
+    /// be created or truncated, only opened for writing.
+    pub(super) async fn create_part(&self, path: &str) -> std::io::Result<File> {
+        if self.config.device_target {
+            OpenOptions::new().write(true).open(path).await
+        } else {
+            File::create(path).await
+        }
+    }
+
+    /// Opens the write target for a transfer starting at `start_pos`.
+    pub(super) async fn open_part_at(
+        &self,
+        path: &str,
+        start_pos: u64,
+    ) -> std::io::Result<PartWriter> {
+        let file = if start_pos > 0 {
+            let mut file = OpenOptions::new().write(true).open(path).await?;
+            file.seek(SeekFrom::Start(start_pos)).await?;
+            file
+        } else {
+            self.create_part(path).await?
+        };
+        Ok(PartWriter::new(
+            file,
+            self.config.write_buffer_size,
+            self.sync_every(),
+            self.state.buffer_budget.clone(),
+        ))
+    }
+
+    pub(super) fn sync_every(&self) -> Option<u64> {
+        self.config.fsync.interval(self.config.chunk_size)
+    }
+
+    /// Readies the `.part` file for the transfer `plan` describes and
+    /// returns the byte it starts from: where an earlier run stopped for
+    /// `--resume`, the `--continue-at` offset, or 0 in a new file. `None` if
+    /// the output is already complete.
+    pub(super) async fn prepare_part(
+        &self,
+        plan: &Plan,
+        pb: &ProgressBar,
+    ) -> Result<Option<u64>, Box<dyn std::error::Error + Send + Sync>> {
+        let output_path = &self.config.output_path;
+        let total_size = plan.total_size;
+        if total_size == 0 && self.config.continue_at.is_some() {
+            return Err("--continue-at needs a known file size".into());
+        }
+
+        let part_path = self.part_path();
+        let mut already_downloaded = 0u64;
+        let file_exists = Path::new(output_path).exists();
+        let part_exists = Path::new(&part_path).exists();
+
+        if self.config.resume {
+            if file_exists
+                && total_size > 0
+                && let Ok(meta) = metadata(output_path).await
+                && meta.len() >= total_size
+            {
+                pb.finish_with_message("Completed");
+                return Ok(None);
+            }
+            if part_exists && let Ok(meta) = metadata(&part_path).await {
+                already_downloaded = meta.len();
+                // Nothing past the end of the remote file can be right, and
+                // leaving it would only fail the final size check
+                if total_size > 0 && already_downloaded > total_size && !self.config.device_target {
+                    console::eprintln(format_args!(
+                        "Warning: {} is {} bytes, longer than the remote file; cutting it to {}",
+                        part_path, already_downloaded, total_size
+                    ));
+                    OpenOptions::new()
+                        .write(true)
+                        .open(&part_path)
+                        .await?
+                        .set_len(total_size)
+                        .await?;
+                    already_downloaded = total_size;
+                }
+                if self.config.verify_resume && already_downloaded > 0 {
+                    already_downloaded = self
+                        .verify_partial(&part_path, already_downloaded, plan.supports_range)
+                        .await?;
+                }
+                info!("Resuming {} at byte {}", part_path, already_downloaded);
+                pb.set_position(already_downloaded);
+                self.state.total_pb.inc(already_downloaded);
+            }
+        }
+
+        if let Some(offset) = self.config.continue_at {
+            if offset >= total_size {
+                return Err(format!(
+                    "--continue-at {} is beyond the end of the file ({} bytes)",
+                    offset, total_size
+                )
+                .into());
+            }
+            if !plan.supports_range {
+                return Err("--continue-at requires a server that supports ranges".into());
+            }
+            // Drop anything past the offset so the final size check holds
+            if !self.config.device_target {
+                let part = OpenOptions::new()
+                    .write(true)
+                    .create(true)
+                    .truncate(false)
+                    .open(&part_path)
+                    .await?;
+                part.set_len(offset).await?;
+            }
+            already_downloaded = offset;
+            info!("Continuing {} at byte {}", part_path, offset);
+            pb.set_position(offset);
+            self.state.total_pb.inc(offset);
+        } else if !part_exists || !self.config.resume {
+            self.create_part(&part_path).await?;
+        }
+        Ok(Some(already_downloaded))
+    }
+
+    /// `--verify-resume`: checks the first `len` bytes of the `.part` file
+    /// before building on them, and cuts it back to the part that holds up.
+    /// Returns the length kept.
+    pub(super) async fn verify_partial(
+        &self,
+        part_path: &str,
+        len: u64,
+        supports_range: bool,
+    ) -> Result<u64, Box<dyn std::error::Error + Send + Sync>> {
+        let (kept, validated) = match self.config.chunk_manifest {
+            Some(ref manifest) => {
+                // Only whole ranges can be checked; the first bad one and
+                // everything after it is fetched again
+                let mut covered: Vec<&ManifestEntry> =
+                    manifest.iter().filter(|entry| entry.end < len).collect();
+                covered.sort_by_key(|entry| entry.start);
+                let mut kept = len;
+                let mut validated = 0;
+                for entry in covered {
+                    if hash_range(part_path, entry.start, entry.end).await? != entry.sha256 {
+                        debug!(
+                            "{}: bytes {}-{} are corrupt",
+                            part_path, entry.start, entry.end
+                        );
+                        kept = entry.start;
+                        break;
+                    }
+                    validated += entry.end - entry.start + 1;
+                }
+                (kept, validated)
+            }
+            None if supports_range && self.is_http() => {
+                let tail = len.min(1024);
+                let start = len - tail;
+                let response = send_with_retry(&self.config.retry, self.config.timeout, || {
+                    self.client
+                        .get(&self.config.url)
+                        .header(RANGE, format!("bytes={}-{}", start, len - 1))
+                })
+                .await
+                .map_err(|e| self.request_error(e))?;
+                if response.status() != reqwest::StatusCode::PARTIAL_CONTENT {
+                    return Err("Server ignored the range request for --verify-resume".into());
+                }
+                let remote = tokio::time::timeout(self.config.timeout, response.bytes()).await??;
+
+                let mut local = vec![0u8; tail as usize];
+                let mut file = File::open(part_path).await?;
+                file.seek(SeekFrom::Start(start)).await?;
+                file.read_exact(&mut local).await?;
+
+                if remote[..] == local[..] {
+                    (len, tail)
+                } else {
+                    // Without a manifest there's no telling where it went
+                    // wrong, so nothing can be trusted
+                    debug!(
+                        "{}: last {} bytes differ from the server's",
+                        part_path, tail
+                    );
+                    (0, 0)
+                }
+            }
+            None => {
+                debug!("{}: nothing to verify the partial file against", part_path);
+                (len, 0)
+            }
+        };
+
+        if kept < len {
+            OpenOptions::new()
+                .write(true)
+                .open(part_path)
+                .await?
+                .set_len(kept)
+                .await?;
+        }
+        console::eprintln(format_args!(
+            "{}: re-validated {} of the partial file, discarded {}",
+            self.config.output_path,
+            format_bytes(validated, self.config.units),
+            format_bytes(len - kept, self.config.units)
+        ));
+        Ok(kept)
+    }
+
+    /// Applies `--clean-on-error` to a failed download's partial file, and
+    /// removes that of one stopped by `--max-file-size`. Otherwise it stays
+    /// for `-c`: a sequential transfer's is as long as what arrived, and a
+    /// multi-connection one's was already cut back to its finished run of
+    /// segments.
+    pub(super) async fn handle_partial(
+        &self,
+        error: &(dyn std::error::Error + Send + Sync + 'static),
+    ) {
+        // Nothing of a file over --max-file-size is worth resuming
+        let too_large = matches!(
+            error.downcast_ref(),
+            Some(DownloadError::FileTooLarge { .. })
+        );
+        // A device is the destination itself
+        if !(self.config.clean_on_error || too_large) || self.config.device_target {
+            return;
+        }
+        let part_path = self.part_path();
+        match tokio::fs::remove_file(&part_path).await {
+            Ok(()) if too_large => info!("Removed {} (--max-file-size)", part_path),
+            Ok(()) => info!("Removed {} (--clean-on-error)", part_path),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => debug!("Could not remove {}: {}", part_path, e),
+        }
+    }
+}
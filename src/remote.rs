@@ -0,0 +1,359 @@
+//! Finding out about the remote file before any of it is fetched.
+//!
+//! A HEAD request, or the request itself for another `--method`, gives the
+//! size and whether ranges work; FTP and local sources answer the same
+//! questions their own way. From that and the options comes the plan of
+//! what to fetch. With `--rotate-on-failure`, a source that can't be reached
+//! or fails part way passes the download on to the next mirror.
+
+use crate::{
+    Checksum, DownloadError, FileDownloader, RemoteMetadata, RunState, accepts_byte_ranges,
+    console, extract, ftp, local, parse_content_range_total, send_with_retry,
+};
+use indicatif::ProgressBar;
+use log::{debug, info};
+use reqwest::header::RANGE;
+use tokio::fs::metadata;
+
+/// The source that answered for a download.
+pub struct Reached {
+    pub remote: RemoteMetadata,
+    /// With `--rotate-on-failure`, the downloader for the mirror and
+    /// User-Agent in use, if not the original
+    pub rotated: Option<FileDownloader>,
+    /// How many times the source has changed
+    pub rotation: usize,
+}
+
+/// What a download fetches, settled before the transfer starts.
+pub struct Plan {
+    /// `--range` spans, as remote `(start, end)` offsets
+    pub spans: Vec<(u64, u64)>,
+    /// Where a single span starts in the remote file
+    pub range_start: u64,
+    /// Bytes to fetch, 0 if unknown
+    pub total_size: u64,
+    /// What the bars count towards: `total_size`, or `--expected-size`
+    /// without one
+    pub shown_size: u64,
+    pub supports_range: bool,
+    pub checksum: Option<Checksum>,
+    /// With `--extract`, the kind of archive to unpack
+    pub archive: Option<extract::ArchiveKind>,
+}
+
+impl FileDownloader {
+    /// Asks whichever kind of source the URL names about the file.
+    pub(super) async fn fetch_remote_metadata(
+        &self,
+    ) -> Result<RemoteMetadata, Box<dyn std::error::Error + Send + Sync>> {
+        if ftp::is_ftp_url(&self.config.url) {
+            self.fetch_ftp_metadata().await
+        } else if local::is_local_url(&self.config.url) {
+            self.fetch_local_metadata().await
+        } else {
+            self.fetch_metadata().await
+        }
+    }
+
+    /// Adds the file's size to the total bar ahead of time, so a batch shows
+    /// its overall progress from the start. Returns the size added.
+    pub(super) async fn prescan(&self) -> u64 {
+        match self
+            .state
+            .before_deadline(self.fetch_remote_metadata())
+            .await
+        {
+            Ok(remote) => {
+                self.state.total_pb.inc_length(remote.total_size);
+                remote.total_size
+            }
+            Err(e) => {
+                debug!("Pre-scan of {} failed: {}", self.config.url, e);
+                0
+            }
+        }
+    }
+
+    pub(super) async fn fetch_metadata(
+        &self,
+    ) -> Result<RemoteMetadata, Box<dyn std::error::Error + Send + Sync>> {
+        // Another method can't be tried out with a HEAD; the request itself
+        // is sent, and only its headers are read
+        let head = self.config.method == reqwest::Method::GET;
+        let method = if head {
+            "HEAD"
+        } else {
+            self.config.method.as_str()
+        };
+        debug!("{} {}", method, self.config.url);
+        let response = send_with_retry(&self.config.retry, self.config.timeout, || {
+            let request = if head {
+                self.client.head(&self.config.url)
+            } else {
+                self.file_request()
+            };
+            match self.config.if_modified_since {
+                Some(since) => request.header(
+                    reqwest::header::IF_MODIFIED_SINCE,
+                    since.format("%a, %d %b %Y %H:%M:%S GMT").to_string(),
+                ),
+                None => request,
+            }
+        })
+        .await
+        .map_err(|e| self.request_error(e))?;
+        if response.status() == reqwest::StatusCode::NOT_MODIFIED {
+            return Err(DownloadError::NotModified.into());
+        }
+        let headers = response.headers();
+        debug!(
+            "{} {} -> {} {:?}",
+            method,
+            self.config.url,
+            response.status(),
+            headers
+        );
+        if !head && !response.status().is_success() {
+            return Err(DownloadError::HttpStatus(response.status()).into());
+        }
+
+        let mut total_size = headers
+            .get(reqwest::header::CONTENT_LENGTH)
+            .and_then(|ct_len| ct_len.to_str().ok())
+            .and_then(|ct_len| ct_len.parse().ok())
+            .unwrap_or(0);
+
+        let accept_ranges = headers.get(reqwest::header::ACCEPT_RANGES);
+        let mut supports_range = head && accepts_byte_ranges(accept_ranges);
+
+        // Servers often omit Accept-Ranges (or Content-Length) on HEAD; a
+        // one-byte ranged GET reveals both through Content-Range instead.
+        if head && (accept_ranges.is_none() || total_size == 0) {
+            debug!("GET {} bytes=0-0 (probe)", self.config.url);
+            let probe = send_with_retry(&self.config.retry, self.config.timeout, || {
+                self.client.get(&self.config.url).header(RANGE, "bytes=0-0")
+            })
+            .await
+            .map_err(|e| self.request_error(e))?;
+            if probe.status() == reqwest::StatusCode::PARTIAL_CONTENT {
+                supports_range = true;
+                // Content-Range carries the full size even when
+                // Content-Length only covers the one-byte body
+                if let Some(size) = probe
+                    .headers()
+                    .get(reqwest::header::CONTENT_RANGE)
+                    .and_then(|v| v.to_str().ok())
+                    .and_then(parse_content_range_total)
+                {
+                    total_size = size;
+                }
+            }
+        }
+
+        let header_string = |name| {
+            headers
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .map(|v| v.to_string())
+        };
+
+        Ok(RemoteMetadata {
+            final_url: response.url().to_string(),
+            total_size,
+            supports_range,
+            content_type: header_string(reqwest::header::CONTENT_TYPE),
+            last_modified: header_string(reqwest::header::LAST_MODIFIED),
+            etag: header_string(reqwest::header::ETAG),
+        })
+    }
+
+    /// Fetches the metadata to start the download from. A source that can't
+    /// be reached passes to the mirrors under `--rotate-on-failure`. `None`
+    /// if there's nothing to download, as the file is not modified.
+    pub(super) async fn reach(
+        &self,
+        run: &RunState,
+    ) -> Result<Option<Reached>, Box<dyn std::error::Error + Send + Sync>> {
+        let reached = match self.fetch_remote_metadata().await {
+            Err(e) if matches!(e.downcast_ref(), Some(DownloadError::NotModified)) => {
+                info!("{} is not modified, skipping", self.config.url);
+                self.state.total_pb.dec_length(run.prescanned_size);
+                self.mark_finished();
+                let pb = self.multi_progress.insert(0, ProgressBar::new(0));
+                pb.set_style(self.state.bar_style.clone());
+                pb.set_prefix(self.file_name().to_string());
+                pb.finish_with_message("Not modified");
+                return Ok(None);
+            }
+            Err(e) if self.config.rotate_on_failure && !self.config.mirrors.is_empty() => {
+                let mut reached = None;
+                let mut last = (self.config.url.clone(), e);
+                for n in 1..=self.config.mirrors.len() {
+                    info!("{}: {}", last.0, last.1);
+                    let next = self.rotate(n)?;
+                    match next.fetch_remote_metadata().await {
+                        Ok(remote) => {
+                            reached = Some(Reached {
+                                remote,
+                                rotated: Some(next),
+                                rotation: n,
+                            });
+                            break;
+                        }
+                        Err(e) => last = (next.config.url.clone(), e),
+                    }
+                }
+                reached.ok_or(last.1)?
+            }
+            remote => Reached {
+                remote: remote?,
+                rotated: None,
+                rotation: 0,
+            },
+        };
+        self.check_content_type(&reached.remote)?;
+        Ok(Some(reached))
+    }
+
+    /// Works out what to fetch of the file `remote` describes, from
+    /// `--range`, `--extract`, `--compressed` and the checksum options.
+    pub(super) async fn plan(
+        &self,
+        remote: &RemoteMetadata,
+    ) -> Result<Plan, Box<dyn std::error::Error + Send + Sync>> {
+        let archive = match self.config.extract {
+            Some(_) => {
+                if !self.is_http() {
+                    return Err("--extract only works over HTTP".into());
+                }
+                if self.config.checksum.is_some() {
+                    return Err(
+                        "A checksum can't be verified with --extract, as nothing is saved".into(),
+                    );
+                }
+                let kind =
+                    extract::ArchiveKind::detect(&remote.final_url, remote.content_type.as_deref());
+                if kind.is_none() {
+                    console::eprintln(format_args!(
+                        "Warning: {} is not a .tar.gz or .zip archive; saving it instead of extracting",
+                        self.config.url
+                    ));
+                }
+                kind
+            }
+            None => None,
+        };
+
+        let checksum = match self.config.checksum {
+            Some(ref checksum) => Some(checksum.clone()),
+            None if self.config.auto_checksum
+                && self.is_http()
+                && self.config.ranges.is_empty() =>
+            {
+                self.discover_checksum().await
+            }
+            None => None,
+        };
+
+        // With --range, the output is just the slices, so they're what gets
+        // sized and checked
+        let mut spans = Vec::new();
+        if !self.config.ranges.is_empty()
+            && (!self.is_http() || !remote.supports_range || remote.total_size == 0)
+        {
+            return Err(
+                "--range needs an HTTP server that supports ranges and reports a size".into(),
+            );
+        }
+        for range in &self.config.ranges {
+            let end = range.end.unwrap_or(remote.total_size - 1);
+            if range.start > end || end >= remote.total_size {
+                return Err(format!(
+                    "--range {}-{} is outside the file ({} bytes)",
+                    range.start, end, remote.total_size
+                )
+                .into());
+            }
+            spans.push((range.start, end));
+        }
+        let (range_start, total_size) = match spans[..] {
+            [] => (0, remote.total_size),
+            [(start, end)] => (start, end - start + 1),
+            _ => (0, spans.iter().map(|(start, end)| end - start + 1).sum()),
+        };
+        // Content-Length is then the compressed size, and ranges would be of
+        // the compressed data; the file is one stream of unknown length
+        let total_size = if self.config.compressed && self.is_http() {
+            0
+        } else {
+            total_size
+        };
+
+        // Without a size, e.g. for a chunked response, progress is shown
+        // against --expected-size or not at all; nothing else relies on it
+        let shown_size = match self.config.expected_size {
+            Some(expected) if total_size == 0 => expected,
+            _ => total_size,
+        };
+
+        Ok(Plan {
+            spans,
+            range_start,
+            total_size,
+            shown_size,
+            supports_range: remote.supports_range,
+            checksum,
+            archive,
+        })
+    }
+
+    /// Moves the download over to `next` after a failure, taking its
+    /// metadata as `remote`; a file of another size starts over. Returns how
+    /// much of the `.part` file carries on: a sequential transfer leaves one
+    /// unbroken run of bytes, which the next source can continue if its file
+    /// is the same size.
+    pub(super) async fn switch_to(
+        &self,
+        next: &FileDownloader,
+        sequential: bool,
+        plan: &mut Plan,
+        remote: &mut RemoteMetadata,
+        pb: &ProgressBar,
+    ) -> u64 {
+        let mut keep = 0;
+        match next.fetch_remote_metadata().await {
+            Ok(next_remote) => {
+                if sequential
+                    && plan.total_size > 0
+                    && next_remote.total_size == plan.total_size
+                    && next_remote.supports_range
+                {
+                    keep = metadata(self.part_path())
+                        .await
+                        .map(|m| m.len())
+                        .unwrap_or(0);
+                } else if next_remote.total_size != remote.total_size {
+                    info!(
+                        "{} is {} bytes, not {}; starting over",
+                        next.config.url, next_remote.total_size, remote.total_size
+                    );
+                    let size = if self.config.compressed {
+                        0
+                    } else {
+                        next_remote.total_size
+                    };
+                    self.state.total_pb.inc_length(size);
+                    self.state.total_pb.dec_length(plan.total_size);
+                    pb.set_length(size);
+                    plan.total_size = size;
+                }
+                plan.supports_range = next_remote.supports_range;
+                *remote = next_remote;
+            }
+            // Left for the transfer itself to fail and move on
+            Err(e) => debug!("HEAD {} failed: {}", next.config.url, e),
+        }
+        keep
+    }
+}
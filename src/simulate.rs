@@ -0,0 +1,118 @@
+//! `--simulate`: a bad network on demand, for testing retry, resume and
+//! `--min-speed` against any server, e.g.
+//! `--simulate latency=200ms,rate=256K,drop-at=40%,drop-every=1M`.
+//!
+//! For testing only, and hidden from `--help`. The settings hold for the
+//! whole process, so they sit in a static instead of being passed along
+//! with every request.
+
+use crate::{parse_bandwidth, parse_size};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+
+#[derive(Debug, Clone, Default)]
+pub struct Simulation {
+    /// Added before every request
+    latency: Duration,
+    /// Bytes per second, per connection
+    rate: Option<u64>,
+    /// Fraction of each file at which its transfer fails, once per file
+    drop_at: Option<f64>,
+    /// A connection fails after every this many bytes
+    drop_every: Option<u64>,
+}
+
+static ACTIVE: OnceLock<Simulation> = OnceLock::new();
+
+/// Files that have had their `drop-at` failure
+static DROPPED: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+/// Parses comma-separated `key=value` settings: `latency` (`200ms`, `2s`),
+/// `rate` (as `--limit-rate`), `drop-at` (`40%`) and `drop-every` (a size).
+pub fn parse(spec: &str) -> Result<Simulation, String> {
+    let mut simulation = Simulation::default();
+    for setting in spec.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+        let (key, value) = setting
+            .split_once('=')
+            .ok_or_else(|| format!("Expected key=value in --simulate: {}", setting))?;
+        match key {
+            "latency" => {
+                let (number, scale) = match value.strip_suffix("ms") {
+                    Some(number) => (number, 0.001),
+                    None => (value.strip_suffix('s').unwrap_or(value), 1.0),
+                };
+                let seconds: f64 = number
+                    .parse()
+                    .map_err(|_| format!("Invalid latency: {}", value))?;
+                simulation.latency = Duration::try_from_secs_f64(seconds * scale)
+                    .map_err(|_| format!("Invalid latency: {}", value))?;
+            }
+            "rate" => simulation.rate = Some(parse_bandwidth(value)?.max(1)),
+            "drop-at" => {
+                let percent: f64 = value
+                    .strip_suffix('%')
+                    .unwrap_or(value)
+                    .parse()
+                    .map_err(|_| format!("Invalid drop-at: {}", value))?;
+                if !(0.0..100.0).contains(&percent) {
+                    return Err(format!("drop-at must be below 100%: {}", value));
+                }
+                simulation.drop_at = Some(percent / 100.0);
+            }
+            "drop-every" => simulation.drop_every = Some(parse_size(value)?.max(1)),
+            _ => return Err(format!("Unknown --simulate setting: {}", key)),
+        }
+    }
+    Ok(simulation)
+}
+
+pub fn install(simulation: Simulation) {
+    let _ = ACTIVE.set(simulation);
+}
+
+/// Waits out the latency before a request is sent.
+pub async fn before_request() {
+    if let Some(simulation) = ACTIVE.get()
+        && !simulation.latency.is_zero()
+    {
+        tokio::time::sleep(simulation.latency).await;
+    }
+}
+
+/// Called with each `bytes` received for `file`, which is now at `position`
+/// of `len`. `connection` counts what the connection has received since it
+/// last dropped. Fails to simulate a dropped connection.
+pub async fn after_read(
+    file: &str,
+    position: u64,
+    len: Option<u64>,
+    connection: &AtomicU64,
+    bytes: u64,
+) -> Result<(), String> {
+    let Some(simulation) = ACTIVE.get() else {
+        return Ok(());
+    };
+
+    if let Some(rate) = simulation.rate {
+        tokio::time::sleep(Duration::from_secs_f64(bytes as f64 / rate as f64)).await;
+    }
+    if let Some(every) = simulation.drop_every {
+        let received = connection.fetch_add(bytes, Ordering::Relaxed) + bytes;
+        if received >= every {
+            connection.store(0, Ordering::Relaxed);
+            return Err(format!("connection dropped after {} bytes", received));
+        }
+    }
+    if let (Some(at), Some(len)) = (simulation.drop_at, len)
+        && len > 0
+        && position as f64 >= at * len as f64
+    {
+        let mut dropped = DROPPED.lock().unwrap();
+        if !dropped.iter().any(|f| f == file) {
+            dropped.push(file.to_string());
+            return Err(format!("connection dropped at {:.0}%", at * 100.0));
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,116 @@
+//! `--log-file`: an audit trail of what each download did (started, chunks
+//! finished, retries, finished or failed), one timestamped line per event.
+//!
+//! Unlike `RUST_LOG`, which is for debugging, this is meant to be left on
+//! for unattended runs. Runs append to the same file, so a resumed download
+//! carries on where its earlier attempts left off. With `--log-max-size`
+//! the file is rotated to `FILE.1`, `FILE.2`, ... before it would grow past
+//! that size.
+//!
+//! The log must never get in the way of a download: a file that can't be
+//! opened or written is a warning, after which events are dropped.
+
+use std::fmt::Arguments;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::sync::{Mutex, OnceLock};
+
+struct TransferLog {
+    path: String,
+    /// `None` once writing has failed
+    file: Option<File>,
+    len: u64,
+    max_size: Option<u64>,
+    keep: usize,
+}
+
+static LOG: OnceLock<Mutex<TransferLog>> = OnceLock::new();
+
+/// Opens `path` for appending. Warns and carries on without a log if it
+/// can't be opened.
+pub fn open(path: &str, max_size: Option<u64>, keep: usize) {
+    let opened = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .and_then(|file| Ok((file.metadata()?.len(), file)));
+    match opened {
+        Ok((len, file)) => {
+            let _ = LOG.set(Mutex::new(TransferLog {
+                path: path.to_string(),
+                file: Some(file),
+                len,
+                max_size,
+                keep,
+            }));
+        }
+        Err(e) => eprintln!("Warning: can't open log file {}: {}", path, e),
+    }
+}
+
+/// Records an event, if there is a log.
+pub fn event(message: Arguments) {
+    let Some(log) = LOG.get() else {
+        return;
+    };
+    let mut log = log.lock().unwrap();
+    if log.file.is_none() {
+        return;
+    }
+    let line = format!(
+        "{} {}\n",
+        chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+        message
+    );
+    if let Err(e) = log.write(&line) {
+        crate::console::eprintln(format_args!(
+            "Warning: can't write log file {}: {}; logging stopped",
+            log.path, e
+        ));
+        log.file = None;
+    }
+}
+
+impl TransferLog {
+    fn write(&mut self, line: &str) -> std::io::Result<()> {
+        if let Some(max) = self.max_size
+            && self.len > 0
+            && self.len + line.len() as u64 > max
+        {
+            self.rotate()?;
+        }
+        if let Some(ref mut file) = self.file {
+            file.write_all(line.as_bytes())?;
+        }
+        self.len += line.len() as u64;
+        Ok(())
+    }
+
+    /// Shifts `FILE.1` to `FILE.2` and so on, dropping the oldest, moves
+    /// the log to `FILE.1` and starts it over. With nothing to keep, the
+    /// log is just emptied.
+    fn rotate(&mut self) -> std::io::Result<()> {
+        if self.keep == 0 {
+            if let Some(ref file) = self.file {
+                file.set_len(0)?;
+            }
+        } else {
+            let _ = std::fs::remove_file(format!("{}.{}", self.path, self.keep));
+            for n in (1..self.keep).rev() {
+                let _ = std::fs::rename(
+                    format!("{}.{}", self.path, n),
+                    format!("{}.{}", self.path, n + 1),
+                );
+            }
+            std::fs::rename(&self.path, format!("{}.1", self.path))?;
+            self.file = Some(
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&self.path)?,
+            );
+        }
+        self.len = 0;
+        Ok(())
+    }
+}
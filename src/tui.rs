@@ -0,0 +1,544 @@
+//! `--tui`: a full-screen view of the downloads in place of the progress
+//! bars, with each connection's segment, a graph of the speed over the
+//! last minute, and how often things were retried.
+//!
+//! It's drawn on the terminal's alternate screen, which is left again when
+//! the downloads end, on `q` or Ctrl-C, and on a panic, so the scrollback
+//! is as it was. Log messages go to a panel meanwhile and are printed
+//! once the screen is gone.
+
+use crate::{DownloadState, TransferState, Units, format_bytes};
+use crossterm::cursor::{Hide, Show};
+use crossterm::terminal::{EnterAlternateScreen, LeaveAlternateScreen};
+use indicatif::ProgressBar;
+use ratatui::Terminal;
+use ratatui::backend::{Backend, ClearType, CrosstermBackend};
+use ratatui::layout::{Alignment, Constraint, Layout, Rect};
+use ratatui::style::{Color, Style, Stylize};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, LineGauge, Paragraph, Sparkline};
+use std::collections::VecDeque;
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// How often the screen is redrawn
+const REFRESH: Duration = Duration::from_millis(250);
+
+/// Redraws between full repaints, which clear anything else printed to
+/// the terminal meanwhile, like warnings
+const REPAINT_EVERY: u32 = 4;
+
+/// Speed samples kept for the graph: a minute's worth
+const HISTORY: usize = 240;
+
+/// Samples averaged for the speed shown, one second's worth
+const SPEED_WINDOW: usize = 4;
+
+/// Samples averaged for the time left
+const ETA_WINDOW: usize = 20;
+
+/// Log messages held back while the screen is up
+const MAX_MESSAGES: usize = 1000;
+
+/// Width of the name column
+const NAME_WIDTH: u16 = 32;
+
+/// Width of the numbers after each bar
+const DETAIL_WIDTH: u16 = 52;
+
+/// Every file that has started, in order, for the screen to show.
+#[derive(Default)]
+pub struct Board {
+    files: Mutex<Vec<Arc<FileView>>>,
+}
+
+impl Board {
+    /// Adds a file whose progress is `pb`, with `retried` counting its
+    /// retries of any kind.
+    pub fn add(&self, pb: &ProgressBar, retried: Arc<AtomicU64>) -> Arc<FileView> {
+        let file = Arc::new(FileView {
+            pb: pb.clone(),
+            retried,
+            connections: Mutex::default(),
+        });
+        self.files.lock().unwrap().push(file.clone());
+        file
+    }
+}
+
+pub struct FileView {
+    pb: ProgressBar,
+    retried: Arc<AtomicU64>,
+    connections: Mutex<Vec<Arc<ConnectionView>>>,
+}
+
+impl FileView {
+    /// The line for connection `worker`, kept when the transfer restarts.
+    pub fn connection(&self, worker: usize) -> Arc<ConnectionView> {
+        let mut connections = self.connections.lock().unwrap();
+        if let Some(connection) = connections.iter().find(|c| c.worker == worker) {
+            return connection.clone();
+        }
+        let connection = Arc::new(ConnectionView {
+            worker,
+            ..ConnectionView::default()
+        });
+        connections.push(connection.clone());
+        connection
+    }
+}
+
+/// The segment a connection is fetching and how far it has got.
+#[derive(Default)]
+pub struct ConnectionView {
+    worker: usize,
+    /// Remote offsets; `end` is included
+    start: AtomicU64,
+    end: AtomicU64,
+    received: AtomicU64,
+    retries: AtomicU64,
+    /// Set when the queue had nothing more for it
+    idle: AtomicBool,
+}
+
+impl ConnectionView {
+    pub fn begin(&self, start: u64, end: u64) {
+        self.start.store(start, Ordering::Relaxed);
+        self.end.store(end, Ordering::Relaxed);
+        self.received.store(0, Ordering::Relaxed);
+        self.idle.store(false, Ordering::Relaxed);
+    }
+
+    pub fn add(&self, bytes: u64) {
+        self.received.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn retried(&self) {
+        self.retries.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn finish(&self) {
+        self.idle.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Log messages taken while the screen is up; `None` the rest of the time.
+static MESSAGES: Mutex<Option<VecDeque<String>>> = Mutex::new(None);
+
+/// Where log messages go with `--tui`: stderr, or the panel while the
+/// screen is up.
+struct LogSink;
+
+impl Write for LogSink {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let mut messages = MESSAGES.lock().unwrap();
+        match *messages {
+            Some(ref mut messages) => {
+                for line in String::from_utf8_lossy(buf).lines() {
+                    if messages.len() == MAX_MESSAGES {
+                        messages.pop_front();
+                    }
+                    messages.push_back(line.to_string());
+                }
+                Ok(buf.len())
+            }
+            None => std::io::stderr().write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        std::io::stderr().flush()
+    }
+}
+
+/// Sets up logging as `env_logger::init` does, but through `LogSink` so
+/// messages don't land in the middle of the screen.
+pub fn init_logger() {
+    env_logger::Builder::from_default_env()
+        .target(env_logger::Target::Pipe(Box::new(LogSink)))
+        .init();
+}
+
+/// Puts the terminal back as it was.
+fn restore() {
+    let _ = crossterm::execute!(std::io::stderr(), LeaveAlternateScreen, Show);
+}
+
+/// The screen, redrawn from its own thread until stopped.
+pub struct Screen {
+    done: Arc<AtomicBool>,
+    handle: std::thread::JoinHandle<()>,
+}
+
+impl Screen {
+    /// Takes over the terminal to show `state`'s downloads, with `retried`
+    /// counting the run's retries.
+    pub fn spawn(
+        state: Arc<DownloadState>,
+        retried: Arc<AtomicU64>,
+        units: Units,
+    ) -> std::io::Result<Self> {
+        let mut terminal = Terminal::new(CrosstermBackend::new(std::io::stderr()))?;
+        crossterm::execute!(std::io::stderr(), EnterAlternateScreen, Hide)?;
+        *MESSAGES.lock().unwrap() = Some(VecDeque::new());
+
+        // A panic message printed on the alternate screen would vanish
+        // with it
+        let previous = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            restore();
+            previous(info);
+        }));
+
+        let done = Arc::new(AtomicBool::new(false));
+        let done_flag = done.clone();
+        let handle = std::thread::spawn(move || {
+            let mut speeds = Speeds::default();
+            let mut last = state.stats.bytes.load(Ordering::Relaxed);
+            let mut draws = 0u32;
+            while !done_flag.load(Ordering::Relaxed) {
+                let bytes = state.stats.bytes.load(Ordering::Relaxed);
+                speeds.push(bytes - last);
+                last = bytes;
+                if draws.is_multiple_of(REPAINT_EVERY) {
+                    // Rather than `Terminal::clear`, which asks the terminal
+                    // where the cursor is and waits for the answer: with
+                    // nothing to compare against, everything is drawn again
+                    let _ = terminal.backend_mut().clear_region(ClearType::All);
+                    terminal.swap_buffers();
+                }
+                draws = draws.wrapping_add(1);
+                let retried = retried.load(Ordering::Relaxed);
+                let _ = terminal.draw(|frame| {
+                    draw(frame, &state, &speeds, retried, units);
+                });
+                std::thread::sleep(REFRESH);
+            }
+        });
+
+        Ok(Self { done, handle })
+    }
+
+    /// Leaves the screen and prints the log messages it held back.
+    pub fn stop(self) {
+        self.done.store(true, Ordering::Relaxed);
+        let _ = self.handle.join();
+        restore();
+        let messages = MESSAGES.lock().unwrap().take().unwrap_or_default();
+        for message in messages {
+            eprintln!("{}", message);
+        }
+    }
+}
+
+/// Bytes per second, one sample per redraw, each averaged over the second
+/// before it.
+#[derive(Default)]
+struct Speeds {
+    /// Bytes received between redraws
+    received: VecDeque<u64>,
+    averaged: VecDeque<u64>,
+}
+
+impl Speeds {
+    fn push(&mut self, bytes: u64) {
+        self.received.push_back(bytes);
+        if self.received.len() > SPEED_WINDOW {
+            self.received.pop_front();
+        }
+        let speed = self.received.iter().sum::<u64>() as f64
+            / (self.received.len() as f64 * REFRESH.as_secs_f64());
+        self.averaged.push_back(speed as u64);
+        if self.averaged.len() > HISTORY {
+            self.averaged.pop_front();
+        }
+    }
+
+    fn current(&self) -> u64 {
+        self.averaged.back().copied().unwrap_or(0)
+    }
+
+    /// The speed over the last few seconds, steadier for estimating
+    fn recent(&self) -> u64 {
+        let recent = self.averaged.iter().rev().take(ETA_WINDOW);
+        let count = recent.len().max(1) as u64;
+        recent.sum::<u64>() / count
+    }
+}
+
+/// `-` when there's no estimate
+fn time_left(remaining: u64, speed: u64) -> String {
+    if speed == 0 {
+        return "-".to_string();
+    }
+    format!(
+        "{:#}",
+        indicatif::HumanDuration(Duration::from_secs(remaining / speed))
+    )
+}
+
+fn ratio(done: u64, total: u64) -> f64 {
+    if total == 0 {
+        0.0
+    } else {
+        (done as f64 / total as f64).min(1.0)
+    }
+}
+
+/// Cuts `text` to `width` characters, marking the cut.
+fn fit(text: &str, width: usize) -> String {
+    if text.chars().count() <= width {
+        return text.to_string();
+    }
+    let mut cut: String = text.chars().take(width.saturating_sub(1)).collect();
+    cut.push('…');
+    cut
+}
+
+fn draw(
+    frame: &mut ratatui::Frame,
+    state: &DownloadState,
+    speeds: &Speeds,
+    retried: u64,
+    units: Units,
+) {
+    let messages: Vec<String> = MESSAGES
+        .lock()
+        .unwrap()
+        .as_ref()
+        .map(|messages| messages.iter().rev().take(4).rev().cloned().collect())
+        .unwrap_or_default();
+    let message_rows = if messages.is_empty() {
+        0
+    } else {
+        messages.len() as u16 + 2
+    };
+    let [header, graph, files, log, footer] = Layout::vertical([
+        Constraint::Length(1),
+        Constraint::Length(8),
+        Constraint::Min(3),
+        Constraint::Length(message_rows),
+        Constraint::Length(1),
+    ])
+    .areas(frame.area());
+
+    let total = &state.total_pb;
+    let length = total.length().unwrap_or(0);
+    let size = if length > 0 {
+        format!(
+            "{} of {} ({}%)",
+            format_bytes(total.position(), units),
+            format_bytes(length, units),
+            total.position() * 100 / length
+        )
+    } else {
+        format_bytes(total.position(), units)
+    };
+    let left = if length > 0 {
+        time_left(length.saturating_sub(total.position()), speeds.recent())
+    } else {
+        "-".to_string()
+    };
+    let status = match *state.control.borrow() {
+        TransferState::Paused => "  PAUSED",
+        TransferState::Cancelled => "  stopping",
+        TransferState::TimedOut => "  out of time",
+        TransferState::Running => "",
+    };
+    frame.render_widget(
+        Paragraph::new(Line::from(vec![
+            " grab ".bold().reversed(),
+            format!(
+                "  {}  {}/s  {} left  {}/{} files  {} connections  {} retries",
+                size,
+                format_bytes(speeds.current(), units),
+                left,
+                state.finished_files.load(Ordering::Relaxed),
+                state.total_files,
+                state.stats.active_connections.load(Ordering::Relaxed),
+                retried
+            )
+            .into(),
+            status.yellow().bold(),
+        ])),
+        header,
+    );
+
+    // The newest samples, as many as fit
+    let width = graph.width.saturating_sub(2) as usize;
+    let samples: Vec<u64> = speeds
+        .averaged
+        .iter()
+        .skip(speeds.averaged.len().saturating_sub(width))
+        .copied()
+        .collect();
+    let peak = samples.iter().copied().max().unwrap_or(0);
+    frame.render_widget(
+        Sparkline::default()
+            .block(Block::bordered().title(format!(
+                " Speed, last {}s (peak {}/s) ",
+                (samples.len() as f64 * REFRESH.as_secs_f64()).round(),
+                format_bytes(peak, units)
+            )))
+            .data(&samples)
+            .style(Style::new().fg(Color::Cyan)),
+        graph,
+    );
+
+    let block = Block::bordered().title(" Downloads ");
+    let inner = block.inner(files);
+    frame.render_widget(block, files);
+    draw_files(frame, inner, state, units);
+
+    if !messages.is_empty() {
+        let lines: Vec<Line> = messages.into_iter().map(Line::from).collect();
+        frame.render_widget(
+            Paragraph::new(lines).block(Block::bordered().title(" Messages ")),
+            log,
+        );
+    }
+
+    frame.render_widget(
+        Paragraph::new(" p pause/resume  q quit").dark_gray(),
+        footer,
+    );
+}
+
+/// One line per file, and under each unfinished one, a line per connection;
+/// what doesn't fit is counted on the last line.
+fn draw_files(frame: &mut ratatui::Frame, area: Rect, state: &DownloadState, units: Units) {
+    let Some(ref board) = state.board else {
+        return;
+    };
+    let files = board.files.lock().unwrap().clone();
+
+    let mut rows = Vec::new();
+    for file in &files {
+        let pb = &file.pb;
+        let length = pb.length().unwrap_or(0);
+        let size = if length > 0 {
+            format!(
+                "{} of {}",
+                format_bytes(pb.position(), units),
+                format_bytes(length, units)
+            )
+        } else {
+            format_bytes(pb.position(), units)
+        };
+        let retries = file.retried.load(Ordering::Relaxed);
+        let detail = if pb.is_finished() {
+            match pb.message() {
+                message if message.is_empty() => format!("{}  done", size),
+                message => message,
+            }
+        } else {
+            let mut detail = format!("{}  {}/s", size, format_bytes(pb.per_sec() as u64, units));
+            if retries > 0 {
+                detail.push_str(&format!("  {} retries", retries));
+            }
+            match pb.message() {
+                message if message.is_empty() => detail,
+                message => format!("{}  {}", detail, message),
+            }
+        };
+        rows.push(Row {
+            name: pb.prefix(),
+            ratio: if pb.is_finished() && length == 0 {
+                1.0
+            } else {
+                ratio(pb.position(), length)
+            },
+            detail,
+            color: Color::Green,
+        });
+        if pb.is_finished() {
+            continue;
+        }
+
+        for connection in file.connections.lock().unwrap().iter() {
+            let start = connection.start.load(Ordering::Relaxed);
+            let end = connection.end.load(Ordering::Relaxed);
+            let received = connection.received.load(Ordering::Relaxed);
+            let retries = connection.retries.load(Ordering::Relaxed);
+            let idle = connection.idle.load(Ordering::Relaxed);
+            let mut detail = if idle {
+                "idle".to_string()
+            } else {
+                format!(
+                    "{} of {}",
+                    format_bytes(received, units),
+                    format_bytes(end - start + 1, units)
+                )
+            };
+            if retries > 0 {
+                detail.push_str(&format!("  {} retries", retries));
+            }
+            rows.push(Row {
+                name: format!("  #{} bytes {}-{}", connection.worker + 1, start, end),
+                ratio: if idle {
+                    1.0
+                } else {
+                    ratio(received, end - start + 1)
+                },
+                detail,
+                color: Color::Blue,
+            });
+        }
+    }
+
+    let fit_rows = area.height as usize;
+    let hidden = rows.len().saturating_sub(fit_rows);
+    // Room for the count of what's left out
+    let shown = if hidden > 0 {
+        fit_rows.saturating_sub(1)
+    } else {
+        rows.len()
+    };
+    for (i, row) in rows.iter().take(shown).enumerate() {
+        let line = Rect {
+            y: area.y + i as u16,
+            height: 1,
+            ..area
+        };
+        let [name, gauge, detail] = Layout::horizontal([
+            Constraint::Length(NAME_WIDTH),
+            Constraint::Min(10),
+            Constraint::Length(DETAIL_WIDTH),
+        ])
+        .spacing(1)
+        .areas(line);
+        frame.render_widget(Paragraph::new(fit(&row.name, NAME_WIDTH as usize)), name);
+        frame.render_widget(
+            LineGauge::default()
+                .ratio(row.ratio)
+                .label(format!("{:>3}%", (row.ratio * 100.0) as u32))
+                .filled_style(Style::new().fg(row.color))
+                .unfilled_style(Style::new().fg(Color::DarkGray)),
+            gauge,
+        );
+        frame.render_widget(
+            Paragraph::new(fit(&row.detail, DETAIL_WIDTH as usize)).alignment(Alignment::Right),
+            detail,
+        );
+    }
+    if hidden > 0 && fit_rows > 0 {
+        let line = Rect {
+            y: area.y + shown as u16,
+            height: 1,
+            ..area
+        };
+        frame.render_widget(
+            Paragraph::new(format!("  … {} more", rows.len() - shown)).dark_gray(),
+            line,
+        );
+    }
+}
+
+struct Row {
+    name: String,
+    ratio: f64,
+    detail: String,
+    color: Color,
+}
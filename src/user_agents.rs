@@ -0,0 +1,129 @@
+//! User-Agent strings for `--ua-preset`, `--random-user-agent`,
+//! `--rotate-user-agent` and `--rotate-on-failure`.
+//!
+//! Sites that screen clients tend to flag browser versions that are more
+//! than a few releases old, so these need bumping now and then.
+
+use clap::ValueEnum;
+
+/// A client `--ua-preset` can pass for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum UaPreset {
+    Firefox,
+    Chrome,
+    Safari,
+    Edge,
+    Curl,
+    Wget,
+}
+
+impl UaPreset {
+    pub fn user_agent(self) -> &'static str {
+        match self {
+            UaPreset::Firefox => {
+                "Mozilla/5.0 (Windows NT 10.0; Win64; x64; rv:144.0) Gecko/20100101 Firefox/144.0"
+            }
+            UaPreset::Chrome => {
+                "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/141.0.0.0 Safari/537.36"
+            }
+            UaPreset::Safari => {
+                "Mozilla/5.0 (Macintosh; Intel Mac OS X 10_15_7) AppleWebKit/605.1.15 (KHTML, like Gecko) Version/26.0 Safari/605.1.15"
+            }
+            UaPreset::Edge => {
+                "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/141.0.0.0 Safari/537.36 Edg/141.0.0.0"
+            }
+            UaPreset::Curl => "curl/8.16.0",
+            UaPreset::Wget => "Wget/1.25.0",
+        }
+    }
+}
+
+/// Browser User-Agents the random picks choose from: the browser presets
+/// plus the same browsers on other platforms.
+const BROWSER_USER_AGENTS: &[&str] = &[
+    "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/141.0.0.0 Safari/537.36",
+    "Mozilla/5.0 (Windows NT 10.0; Win64; x64; rv:144.0) Gecko/20100101 Firefox/144.0",
+    "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/141.0.0.0 Safari/537.36 Edg/141.0.0.0",
+    "Mozilla/5.0 (Macintosh; Intel Mac OS X 10_15_7) AppleWebKit/605.1.15 (KHTML, like Gecko) Version/26.0 Safari/605.1.15",
+    "Mozilla/5.0 (Macintosh; Intel Mac OS X 10_15_7) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/141.0.0.0 Safari/537.36",
+    "Mozilla/5.0 (X11; Linux x86_64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/141.0.0.0 Safari/537.36",
+    "Mozilla/5.0 (X11; Linux x86_64; rv:144.0) Gecko/20100101 Firefox/144.0",
+];
+
+/// The `n`th browser User-Agent, wrapping around.
+pub fn browser_user_agent(n: usize) -> &'static str {
+    BROWSER_USER_AGENTS[n % BROWSER_USER_AGENTS.len()]
+}
+
+pub fn random_user_agent() -> &'static str {
+    use std::hash::{BuildHasher, Hasher};
+    // RandomState is seeded randomly per instance, which is all the
+    // randomness this needs
+    let seed = std::collections::hash_map::RandomState::new()
+        .build_hasher()
+        .finish();
+    BROWSER_USER_AGENTS[(seed % BROWSER_USER_AGENTS.len() as u64) as usize]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn is_browser(user_agent: &str) -> bool {
+        user_agent.starts_with("Mozilla/5.0 (")
+            && ["Firefox/", "Chrome/", "Safari/"]
+                .iter()
+                .any(|browser| user_agent.contains(browser))
+    }
+
+    #[test]
+    fn every_preset_is_a_usable_header() {
+        for preset in UaPreset::value_variants() {
+            let user_agent = preset.user_agent();
+            assert!(!user_agent.trim().is_empty(), "{:?}", preset);
+            assert!(
+                reqwest::header::HeaderValue::from_str(user_agent).is_ok(),
+                "{:?}",
+                preset
+            );
+            // A product token with a version, as servers parse them
+            let (product, version) = user_agent.split_once('/').unwrap();
+            assert!(!product.is_empty() && version.starts_with(|c: char| c.is_ascii_digit()));
+        }
+    }
+
+    #[test]
+    fn browser_presets_look_like_browsers_and_are_in_the_random_set() {
+        let tools = [UaPreset::Curl, UaPreset::Wget];
+        for preset in UaPreset::value_variants() {
+            let user_agent = preset.user_agent();
+            if tools.contains(preset) {
+                assert!(!is_browser(user_agent), "{:?}", preset);
+            } else {
+                assert!(is_browser(user_agent), "{:?}", preset);
+                assert!(BROWSER_USER_AGENTS.contains(&user_agent), "{:?}", preset);
+            }
+        }
+        assert!(
+            BROWSER_USER_AGENTS
+                .iter()
+                .all(|user_agent| is_browser(user_agent))
+        );
+    }
+
+    #[test]
+    fn random_picks_come_from_the_browser_set() {
+        let picks: std::collections::HashSet<_> = (0..200).map(|_| random_user_agent()).collect();
+        assert!(picks.iter().all(|pick| BROWSER_USER_AGENTS.contains(pick)));
+        // Seven to choose from; 200 picks that all agree would mean no
+        // randomness at all
+        assert!(picks.len() > 1, "{:?}", picks);
+    }
+
+    #[test]
+    fn browser_user_agents_wrap_around() {
+        let count = BROWSER_USER_AGENTS.len();
+        assert_eq!(browser_user_agent(0), browser_user_agent(count));
+        assert_ne!(browser_user_agent(0), browser_user_agent(1));
+    }
+}
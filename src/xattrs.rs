@@ -0,0 +1,118 @@
+//! `--store-xattr`: where a file came from, kept in extended attributes on
+//! the file itself (`user.grab.url`, `user.grab.etag`, `user.grab.date`), so
+//! it travels with renames and can be read with `getfattr -d`.
+//!
+//! `--no-clobber` falls back on these when there's no `.grabmeta` sidecar.
+//! Filesystems and platforms without extended attributes get a warning,
+//! once, and the downloads carry on without them.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+const URL: &str = "user.grab.url";
+const ETAG: &str = "user.grab.etag";
+const DATE: &str = "user.grab.date";
+
+pub struct Provenance {
+    pub url: String,
+    pub etag: Option<String>,
+    /// When the download finished, as RFC 3339
+    pub date: String,
+}
+
+static WARNED: AtomicBool = AtomicBool::new(false);
+
+/// Writes `provenance` onto `path`, warning if it can't be.
+pub fn store(path: &str, provenance: &Provenance) {
+    if let Err(e) = write(path, provenance)
+        && !WARNED.swap(true, Ordering::Relaxed)
+    {
+        crate::console::eprintln(format_args!(
+            "Warning: can't store extended attributes on {}: {}; --store-xattr has no effect there",
+            path, e
+        ));
+    }
+}
+
+#[cfg(unix)]
+fn write(path: &str, provenance: &Provenance) -> std::io::Result<()> {
+    xattr::set(path, URL, provenance.url.as_bytes())?;
+    match provenance.etag {
+        Some(ref etag) => xattr::set(path, ETAG, etag.as_bytes())?,
+        // A re-download may no longer have one; there's usually nothing to
+        // remove
+        None => {
+            let _ = xattr::remove(path, ETAG);
+        }
+    }
+    xattr::set(path, DATE, provenance.date.as_bytes())
+}
+
+#[cfg(not(unix))]
+fn write(_path: &str, _provenance: &Provenance) -> std::io::Result<()> {
+    Err(std::io::ErrorKind::Unsupported.into())
+}
+
+/// What `store` left on `path`, if anything.
+#[cfg(unix)]
+pub fn load(path: &str) -> Option<Provenance> {
+    let get = |name| {
+        xattr::get(path, name)
+            .ok()
+            .flatten()
+            .and_then(|value| String::from_utf8(value).ok())
+    };
+    Some(Provenance {
+        url: get(URL)?,
+        etag: get(ETAG),
+        date: get(DATE).unwrap_or_default(),
+    })
+}
+
+#[cfg(not(unix))]
+pub fn load(_path: &str) -> Option<Provenance> {
+    None
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use crate::tests::scratch_dir;
+
+    #[test]
+    fn provenance_round_trips() {
+        let dir = scratch_dir("xattrs");
+        let path = dir.join("file.bin").to_string_lossy().into_owned();
+        std::fs::write(&path, b"data").unwrap();
+        let provenance = Provenance {
+            url: "http://host/file.bin".to_string(),
+            etag: Some("\"abc\"".to_string()),
+            date: "2026-10-15T00:00:00Z".to_string(),
+        };
+        match write(&path, &provenance) {
+            Err(e) if e.kind() == std::io::ErrorKind::Unsupported => {
+                eprintln!("Skipped: no extended attributes in {}", dir.display());
+                return;
+            }
+            res => res.unwrap(),
+        }
+
+        let loaded = load(&path).unwrap();
+        assert_eq!(loaded.url, provenance.url);
+        assert_eq!(loaded.etag, provenance.etag);
+        assert_eq!(loaded.date, provenance.date);
+
+        // A later download without an ETag drops the old one
+        store(
+            &path,
+            &Provenance {
+                etag: None,
+                ..provenance
+            },
+        );
+        assert_eq!(load(&path).unwrap().etag, None);
+
+        std::fs::write(dir.join("plain.bin"), b"").unwrap();
+        assert!(load(&dir.join("plain.bin").to_string_lossy()).is_none());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,47 @@
+//! `--input-file` batches run a second time.
+
+mod common;
+
+use common::{grab, pattern, scratch_dir, serve};
+
+#[test]
+fn rerun_skips_finished_files() {
+    let body = pattern(10_000);
+    let server = serve(body.clone());
+    let dir = scratch_dir("batch-rerun");
+    std::fs::write(
+        dir.join("urls.txt"),
+        format!("{0}/a.bin\n{0}/b.bin\n", server.url),
+    )
+    .unwrap();
+
+    let output = grab(&dir, &["-i", "urls.txt"]);
+    assert!(output.status.success(), "{:?}", output);
+    assert_eq!(std::fs::read(dir.join("a.bin")).unwrap(), body);
+    assert_eq!(std::fs::read(dir.join("b.bin")).unwrap(), body);
+    assert!(dir.join(".grab-batch-state").exists());
+    let first_run = server.requests().len();
+
+    // b.bin is gone, so only it is fetched again
+    std::fs::remove_file(dir.join("b.bin")).unwrap();
+    let output = grab(&dir, &["-i", "urls.txt"]);
+    assert!(output.status.success(), "{:?}", output);
+    assert_eq!(std::fs::read(dir.join("b.bin")).unwrap(), body);
+    let again: Vec<_> = server.requests()[first_run..]
+        .iter()
+        .map(|request| request.path.clone())
+        .collect();
+    assert!(!again.is_empty());
+    assert!(again.iter().all(|path| path == "/b.bin"), "{:?}", again);
+
+    // With everything there, nothing is requested
+    let second_run = server.requests().len();
+    let output = grab(&dir, &["-i", "urls.txt"]);
+    assert!(output.status.success(), "{:?}", output);
+    assert_eq!(server.requests().len(), second_run);
+
+    // Unless asked to
+    let output = grab(&dir, &["-i", "urls.txt", "--force-redownload"]);
+    assert!(output.status.success(), "{:?}", output);
+    assert!(server.requests().len() > second_run);
+}
@@ -0,0 +1,34 @@
+//! A checksum given after the URL, and the exit code when it doesn't match.
+
+mod common;
+
+use common::{grab, pattern, scratch_dir, serve};
+use sha2::{Digest, Sha256};
+
+#[test]
+fn mismatched_checksum_exits_with_4() {
+    let server = serve(pattern(20_000));
+    let dir = scratch_dir("checksum-mismatch");
+
+    let url = format!("{}/file.bin", server.url);
+    let wrong = format!("sha256:{}", hex::encode(Sha256::digest(b"something else")));
+    let output = grab(&dir, &["-O", "file.bin", &url, &wrong]);
+    assert_eq!(output.status.code(), Some(4), "{:?}", output);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("Checksum mismatch"), "{}", stderr);
+    // Nothing unverified takes the output's place
+    assert!(!dir.join("file.bin").exists());
+}
+
+#[test]
+fn matching_checksum_succeeds() {
+    let body = pattern(20_000);
+    let server = serve(body.clone());
+    let dir = scratch_dir("checksum-match");
+
+    let url = format!("{}/file.bin", server.url);
+    let right = format!("sha256:{}", hex::encode(Sha256::digest(&body)));
+    let output = grab(&dir, &["-O", "file.bin", &url, &right]);
+    assert!(output.status.success(), "{:?}", output);
+    assert_eq!(std::fs::read(dir.join("file.bin")).unwrap(), body);
+}
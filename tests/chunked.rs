@@ -0,0 +1,98 @@
+//! Chunked responses, whose size is only known once they end.
+
+mod common;
+
+use common::{grab, grab_command, pattern, respond_chunked, scratch_dir, serve_with};
+use sha2::{Digest, Sha256};
+
+fn serve_chunked(body: Vec<u8>) -> common::Server {
+    serve_with(move |request, stream| respond_chunked(stream, request, &body))
+}
+
+#[test]
+fn chunked_download_gets_every_byte() {
+    let body = pattern(150_000);
+    let server = serve_chunked(body.clone());
+    let dir = scratch_dir("chunked");
+
+    let url = format!("{}/file.bin", server.url);
+    let checksum = format!("sha256:{}", hex::encode(Sha256::digest(&body)));
+    let output = grab_command(&dir, &["-t", "4", "-O", "file.bin", &url, &checksum])
+        .env("RUST_LOG", "grab=info")
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{:?}", output);
+    assert_eq!(std::fs::read(dir.join("file.bin")).unwrap(), body);
+
+    // One sequential transfer, as there's nothing to cut into segments
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("as its size is unknown"), "{}", stderr);
+    let gets = server
+        .requests()
+        .iter()
+        .filter(|request| request.method == "GET" && request.range().is_none())
+        .count();
+    assert_eq!(gets, 1);
+}
+
+#[test]
+fn expected_size_that_turns_out_wrong_is_only_noted() {
+    let body = pattern(40_000);
+    let server = serve_chunked(body.clone());
+    let dir = scratch_dir("chunked-expected-wrong");
+
+    let url = format!("{}/file.bin", server.url);
+    let output = grab_command(&dir, &["--expected-size", "50000", "-O", "file.bin", &url])
+        .env("RUST_LOG", "grab=info")
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{:?}", output);
+    assert_eq!(std::fs::read(dir.join("file.bin")).unwrap(), body);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("is 40000 bytes, not the 50000 of --expected-size"),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn expected_size_that_matches_says_nothing() {
+    let body = pattern(40_000);
+    let server = serve_chunked(body.clone());
+    let dir = scratch_dir("chunked-expected-right");
+
+    let url = format!("{}/file.bin", server.url);
+    let output = grab_command(&dir, &["--expected-size", "40000", "-O", "file.bin", &url])
+        .env("RUST_LOG", "grab=info")
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{:?}", output);
+    assert_eq!(std::fs::read(dir.join("file.bin")).unwrap(), body);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(!stderr.contains("--expected-size"), "{}", stderr);
+}
+
+#[test]
+fn expected_size_does_not_trip_max_file_size() {
+    // Only a reported size is held to --max-file-size before the transfer
+    let body = pattern(10_000);
+    let server = serve_chunked(body.clone());
+    let dir = scratch_dir("chunked-expected-max");
+
+    let url = format!("{}/file.bin", server.url);
+    let output = grab(
+        &dir,
+        &[
+            "--expected-size",
+            "90000",
+            "--max-file-size",
+            "50000",
+            "-O",
+            "file.bin",
+            &url,
+        ],
+    );
+    assert!(output.status.success(), "{:?}", output);
+    assert_eq!(std::fs::read(dir.join("file.bin")).unwrap(), body);
+}
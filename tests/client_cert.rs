@@ -0,0 +1,116 @@
+//! `--cert`/`--key` against a server that requires a client certificate.
+
+mod common;
+
+use common::{grab, pattern, scratch_dir};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use std::io::{Read, Write};
+use std::net::TcpListener;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Once};
+
+fn data(name: &str) -> String {
+    format!("{}/tests/data/{}", env!("CARGO_MANIFEST_DIR"), name)
+}
+
+/// Serves `body` over TLS to clients with a certificate signed by the test
+/// CA. Returns the URL and how many requests got an answer.
+fn serve_mutual_tls(body: Vec<u8>) -> (String, Arc<AtomicUsize>) {
+    static PROVIDER: Once = Once::new();
+    PROVIDER.call_once(|| {
+        let _ = rustls::crypto::aws_lc_rs::default_provider().install_default();
+    });
+
+    let mut roots = rustls::RootCertStore::empty();
+    roots
+        .add(CertificateDer::from_pem_file(data("ca.pem")).unwrap())
+        .unwrap();
+    let verifier = rustls::server::WebPkiClientVerifier::builder(Arc::new(roots))
+        .build()
+        .unwrap();
+    let config = Arc::new(
+        rustls::ServerConfig::builder()
+            .with_client_cert_verifier(verifier)
+            .with_single_cert(
+                CertificateDer::pem_file_iter(data("server.pem"))
+                    .unwrap()
+                    .collect::<Result<_, _>>()
+                    .unwrap(),
+                PrivateKeyDer::from_pem_file(data("server.key")).unwrap(),
+            )
+            .unwrap(),
+    );
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!(
+        "https://127.0.0.1:{}",
+        listener.local_addr().unwrap().port()
+    );
+    let answered = Arc::new(AtomicUsize::new(0));
+    let count = answered.clone();
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let Ok(stream) = stream else { break };
+            let (config, body, count) = (config.clone(), body.clone(), count.clone());
+            std::thread::spawn(move || {
+                let connection = rustls::ServerConnection::new(config).unwrap();
+                let mut tls = rustls::StreamOwned::new(connection, stream);
+                // The handshake, and the client's certificate with it, is
+                // checked on the first read
+                let mut request = Vec::new();
+                let mut buffer = [0; 1024];
+                while !request.ends_with(b"\r\n\r\n") {
+                    match tls.read(&mut buffer) {
+                        Ok(0) | Err(_) => return,
+                        Ok(n) => request.extend_from_slice(&buffer[..n]),
+                    }
+                }
+                let head = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nAccept-Ranges: none\r\nConnection: close\r\n\r\n",
+                    body.len()
+                );
+                let _ = tls.write_all(head.as_bytes());
+                if !request.starts_with(b"HEAD ") {
+                    let _ = tls.write_all(&body);
+                }
+                tls.conn.send_close_notify();
+                let _ = tls.flush();
+                count.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+    });
+    (url, answered)
+}
+
+#[test]
+fn client_certificate_is_accepted() {
+    let body = pattern(30_000);
+    let (server, answered) = serve_mutual_tls(body.clone());
+    let dir = scratch_dir("client-cert");
+
+    let url = format!("{}/file.bin", server);
+    let (cacert, cert, key) = (data("ca.pem"), data("client.pem"), data("client.key"));
+    let output = grab(
+        &dir,
+        &[
+            "--cacert", &cacert, "--cert", &cert, "--key", &key, "-O", "file.bin", &url,
+        ],
+    );
+    assert!(output.status.success(), "{:?}", output);
+    assert_eq!(std::fs::read(dir.join("file.bin")).unwrap(), body);
+    assert!(answered.load(Ordering::SeqCst) >= 2);
+}
+
+#[test]
+fn missing_client_certificate_is_refused() {
+    let (server, answered) = serve_mutual_tls(pattern(30_000));
+    let dir = scratch_dir("client-cert-missing");
+
+    let url = format!("{}/file.bin", server);
+    let cacert = data("ca.pem");
+    let output = grab(&dir, &["--cacert", &cacert, "-O", "file.bin", &url]);
+    assert!(!output.status.success(), "{:?}", output);
+    assert!(!dir.join("file.bin").exists());
+    assert_eq!(answered.load(Ordering::SeqCst), 0);
+}
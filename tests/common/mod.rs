@@ -0,0 +1,193 @@
+//! A small HTTP/1.1 server on a local port, and a way to run the built
+//! `grab` against it.
+
+#![allow(dead_code)]
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+use std::sync::{Arc, Mutex};
+
+/// One request as the server saw it.
+#[derive(Clone, Debug)]
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// The start of a `Range: bytes=START-` or `bytes=START-END` header.
+    pub fn range(&self) -> Option<(u64, Option<u64>)> {
+        let spec = self.header("range")?.strip_prefix("bytes=")?;
+        let (start, end) = spec.split_once('-')?;
+        Some((start.parse().ok()?, end.parse().ok()))
+    }
+}
+
+pub struct Server {
+    pub url: String,
+    requests: Arc<Mutex<Vec<Request>>>,
+}
+
+impl Server {
+    /// Every request so far, in the order they arrived.
+    pub fn requests(&self) -> Vec<Request> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+/// Serves `body` at every path, with HEAD and byte ranges.
+pub fn serve(body: Vec<u8>) -> Server {
+    serve_with(move |request, stream| respond(stream, request, &body))
+}
+
+/// Calls `handler` for each request; it writes the whole response.
+/// Connections are closed after one response.
+pub fn serve_with(handler: impl Fn(&Request, &mut TcpStream) + Send + Sync + 'static) -> Server {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let requests = Arc::new(Mutex::new(Vec::new()));
+    let handler = Arc::new(handler);
+    let seen = requests.clone();
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let Ok(mut stream) = stream else { break };
+            let handler = handler.clone();
+            let seen = seen.clone();
+            std::thread::spawn(move || {
+                if let Some(request) = read_request(&mut stream) {
+                    seen.lock().unwrap().push(request.clone());
+                    handler(&request, &mut stream);
+                }
+            });
+        }
+    });
+    Server { url, requests }
+}
+
+fn read_request(stream: &mut TcpStream) -> Option<Request> {
+    let mut reader = BufReader::new(stream.try_clone().ok()?);
+    let mut line = String::new();
+    reader.read_line(&mut line).ok()?;
+    let mut parts = line.split_whitespace();
+    let method = parts.next()?.to_string();
+    let path = parts.next()?.to_string();
+
+    let mut headers = Vec::new();
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line).ok()?;
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        let (name, value) = line.split_once(':')?;
+        headers.push((name.trim().to_string(), value.trim().to_string()));
+    }
+    let mut request = Request {
+        method,
+        path,
+        headers,
+        body: Vec::new(),
+    };
+    let length: usize = request
+        .header("content-length")
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(0);
+    request.body = vec![0; length];
+    reader.read_exact(&mut request.body).ok()?;
+    Some(request)
+}
+
+/// Answers `request` with `body`, or the part of it a Range asks for.
+pub fn respond(stream: &mut TcpStream, request: &Request, body: &[u8]) {
+    let total = body.len() as u64;
+    let (status, content, range) = match request.range() {
+        Some((start, end)) if start < total => {
+            let end = end.unwrap_or(total - 1).min(total - 1);
+            let content = &body[start as usize..=end as usize];
+            let range = format!("Content-Range: bytes {}-{}/{}\r\n", start, end, total);
+            ("206 Partial Content", content, range)
+        }
+        _ => ("200 OK", body, String::new()),
+    };
+    let head = format!(
+        "HTTP/1.1 {}\r\nContent-Length: {}\r\nAccept-Ranges: bytes\r\n{}Connection: close\r\n\r\n",
+        status,
+        content.len(),
+        range
+    );
+    let _ = stream.write_all(head.as_bytes());
+    if request.method != "HEAD" {
+        let _ = stream.write_all(content);
+    }
+}
+
+/// Answers `request` with `body` sent chunked, with no Content-Length even
+/// for HEAD, the way a generated response would. A Range gets the rest of
+/// the body from its start, its Content-Range giving no total.
+pub fn respond_chunked(stream: &mut TcpStream, request: &Request, body: &[u8]) {
+    let (status, content, range) = match request.range() {
+        Some((start, _)) if (start as usize) < body.len() => {
+            let range = format!("Content-Range: bytes {}-{}/*\r\n", start, body.len() - 1);
+            ("206 Partial Content", &body[start as usize..], range)
+        }
+        _ => ("200 OK", body, String::new()),
+    };
+    let head = format!(
+        "HTTP/1.1 {}\r\nTransfer-Encoding: chunked\r\nAccept-Ranges: bytes\r\n{}Connection: close\r\n\r\n",
+        status, range
+    );
+    let _ = stream.write_all(head.as_bytes());
+    if request.method == "HEAD" {
+        return;
+    }
+    for piece in content.chunks(7_000) {
+        let _ = stream.write_all(format!("{:x}\r\n", piece.len()).as_bytes());
+        let _ = stream.write_all(piece);
+        let _ = stream.write_all(b"\r\n");
+    }
+    let _ = stream.write_all(b"0\r\n\r\n");
+}
+
+/// `len` bytes that differ at every offset a test is likely to check.
+pub fn pattern(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i % 251) as u8).collect()
+}
+
+/// An empty directory for one test.
+pub fn scratch_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("grab-it-{}-{}", std::process::id(), name));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// `grab` in `dir` with `args`, history off, no profile and no terminal.
+pub fn grab_command(dir: &Path, args: &[&str]) -> Command {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_grab"));
+    command
+        .current_dir(dir)
+        .arg("--no-history")
+        .args(args)
+        .env("HOME", dir)
+        .env_remove("XDG_CONFIG_HOME")
+        .env_remove("RUST_LOG")
+        .stdin(Stdio::null());
+    command
+}
+
+/// Runs `grab_command` to completion.
+pub fn grab(dir: &Path, args: &[&str]) -> Output {
+    grab_command(dir, args).output().unwrap()
+}
@@ -0,0 +1,99 @@
+//! Range responses without a Content-Length that end before the range does.
+
+mod common;
+
+use common::{Request, grab, pattern, respond, scratch_dir, serve_with};
+use std::collections::HashSet;
+use std::io::Write;
+use std::net::TcpStream;
+use std::sync::Mutex;
+
+/// Sends half of the requested range with no Content-Length and hangs up,
+/// which looks like a complete body. With `every_time`, for each request;
+/// otherwise only for the first one that ends where a segment does.
+fn cut_range(
+    body: &[u8],
+    request: &Request,
+    stream: &mut TcpStream,
+    cut: &Mutex<HashSet<u64>>,
+    every_time: bool,
+) {
+    let range = match request.range() {
+        Some((start, end)) if request.method == "GET" && end != Some(0) => (start, end),
+        _ => return respond(stream, request, body),
+    };
+    let start = range.0;
+    let end = range.1.unwrap_or(body.len() as u64 - 1);
+    if !cut.lock().unwrap().insert(end) && !every_time {
+        return respond(stream, request, body);
+    }
+    let half = (start + (end - start) / 2) as usize;
+    let head = format!(
+        "HTTP/1.1 206 Partial Content\r\nContent-Range: bytes {}-{}/{}\r\nConnection: close\r\n\r\n",
+        start,
+        end,
+        body.len()
+    );
+    let _ = stream.write_all(head.as_bytes());
+    let _ = stream.write_all(&body[start as usize..half]);
+}
+
+#[test]
+fn cut_off_chunk_is_fetched_again_from_where_it_stopped() {
+    let body = pattern(200_000);
+    let served = body.clone();
+    let cut = Mutex::new(HashSet::new());
+    let server =
+        serve_with(move |request, stream| cut_range(&served, request, stream, &cut, false));
+    let dir = scratch_dir("cutoff-retry");
+
+    let url = format!("{}/file.bin", server.url);
+    let output = grab(&dir, &["-t", "2", "-s", "100000", "-O", "file.bin", &url]);
+    assert!(output.status.success(), "{:?}", output);
+    assert_eq!(std::fs::read(dir.join("file.bin")).unwrap(), body);
+
+    // Each segment was asked for again from its cut, not from the start
+    let ranges: Vec<_> = server
+        .requests()
+        .iter()
+        .filter(|request| request.method == "GET")
+        .filter_map(|request| request.range())
+        .filter(|&(_, end)| end != Some(0))
+        .collect();
+    assert!(ranges.contains(&(49_999, Some(99_999))), "{:?}", ranges);
+    assert!(ranges.contains(&(149_999, Some(199_999))), "{:?}", ranges);
+}
+
+#[test]
+fn chunk_cut_off_every_time_fails_the_download() {
+    let body = pattern(200_000);
+    let served = body.clone();
+    let cut = Mutex::new(HashSet::new());
+    let server = serve_with(move |request, stream| cut_range(&served, request, stream, &cut, true));
+    let dir = scratch_dir("cutoff-fail");
+
+    let url = format!("{}/file.bin", server.url);
+    let output = grab(
+        &dir,
+        &[
+            "-t",
+            "2",
+            "-s",
+            "100000",
+            "--tries-per-chunk",
+            "1",
+            "-O",
+            "file.bin",
+            &url,
+        ],
+    );
+    // A network failure
+    assert_eq!(output.status.code(), Some(3), "{:?}", output);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("Server closed the connection at byte"),
+        "{}",
+        stderr
+    );
+    assert!(!dir.join("file.bin").exists());
+}
@@ -0,0 +1,112 @@
+//! `--existing`: what happens to an output file that's already there.
+
+mod common;
+
+use common::{grab, pattern, scratch_dir, serve};
+
+const OLD: &[u8] = b"an earlier download";
+
+#[test]
+fn skip_leaves_the_file_alone() {
+    let server = serve(pattern(20_000));
+    let dir = scratch_dir("existing-skip");
+    std::fs::write(dir.join("file.bin"), OLD).unwrap();
+
+    let url = format!("{}/file.bin", server.url);
+    let output = grab(&dir, &["--existing", "skip", "-O", "file.bin", &url]);
+    assert!(output.status.success(), "{:?}", output);
+    assert_eq!(std::fs::read(dir.join("file.bin")).unwrap(), OLD);
+    assert!(
+        server
+            .requests()
+            .iter()
+            .all(|request| request.method != "GET"),
+        "{:?}",
+        server.requests()
+    );
+}
+
+#[test]
+fn overwrite_replaces_the_file() {
+    let body = pattern(20_000);
+    let server = serve(body.clone());
+    let dir = scratch_dir("existing-overwrite");
+    std::fs::write(dir.join("file.bin"), OLD).unwrap();
+
+    let url = format!("{}/file.bin", server.url);
+    let output = grab(&dir, &["--existing", "overwrite", "-O", "file.bin", &url]);
+    assert!(output.status.success(), "{:?}", output);
+    assert_eq!(std::fs::read(dir.join("file.bin")).unwrap(), body);
+}
+
+#[test]
+fn rename_saves_next_to_the_file() {
+    let body = pattern(20_000);
+    let server = serve(body.clone());
+    let dir = scratch_dir("existing-rename");
+    std::fs::write(dir.join("file.bin"), OLD).unwrap();
+    std::fs::write(dir.join("file (1).bin"), OLD).unwrap();
+
+    let url = format!("{}/file.bin", server.url);
+    let output = grab(&dir, &["--existing", "rename", "-O", "file.bin", &url]);
+    assert!(output.status.success(), "{:?}", output);
+    assert_eq!(std::fs::read(dir.join("file.bin")).unwrap(), OLD);
+    assert_eq!(std::fs::read(dir.join("file (1).bin")).unwrap(), OLD);
+    assert_eq!(std::fs::read(dir.join("file (2).bin")).unwrap(), body);
+
+    let output = grab(
+        &dir,
+        &[
+            "--existing",
+            "rename",
+            "--rename-pattern",
+            "{name}.{n}{ext}",
+            "-O",
+            "file.bin",
+            &url,
+        ],
+    );
+    assert!(output.status.success(), "{:?}", output);
+    assert_eq!(std::fs::read(dir.join("file.1.bin")).unwrap(), body);
+}
+
+#[test]
+fn resume_continues_the_partial_file() {
+    let body = pattern(200_000);
+    let server = serve(body.clone());
+    let dir = scratch_dir("existing-resume");
+    std::fs::write(dir.join("file.bin.part"), &body[..80_000]).unwrap();
+
+    let url = format!("{}/file.bin", server.url);
+    let output = grab(&dir, &["--existing", "resume", "-O", "file.bin", &url]);
+    assert!(output.status.success(), "{:?}", output);
+    assert_eq!(std::fs::read(dir.join("file.bin")).unwrap(), body);
+    let ranges: Vec<_> = server
+        .requests()
+        .iter()
+        .filter(|request| request.method == "GET")
+        .filter_map(|request| request.range())
+        .collect();
+    assert_eq!(ranges, [(80_000, None)]);
+}
+
+#[test]
+fn resume_leaves_a_complete_file_alone() {
+    let body = pattern(20_000);
+    let server = serve(body.clone());
+    let dir = scratch_dir("existing-resume-complete");
+    std::fs::write(dir.join("file.bin"), &body).unwrap();
+
+    let url = format!("{}/file.bin", server.url);
+    let output = grab(&dir, &["--existing", "resume", "-O", "file.bin", &url]);
+    assert!(output.status.success(), "{:?}", output);
+    assert_eq!(std::fs::read(dir.join("file.bin")).unwrap(), body);
+    assert!(
+        server
+            .requests()
+            .iter()
+            .all(|request| request.method == "HEAD"),
+        "{:?}",
+        server.requests()
+    );
+}
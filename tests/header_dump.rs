@@ -0,0 +1,85 @@
+//! `--dump-headers` shows what each file's requests really carried.
+
+mod common;
+
+use common::{grab, pattern, respond, scratch_dir, serve_with};
+use std::io::Write;
+
+#[test]
+fn dump_has_the_user_agent_and_referer_each_file_sent() {
+    let body = pattern(10_000);
+    let served = body.clone();
+    let server = serve_with(move |request, stream| respond(stream, request, &served));
+    let dir = scratch_dir("header-dump-per-file");
+
+    let url = format!("{}/file.bin", server.url);
+    let output = grab(
+        &dir,
+        &[
+            "--random-user-agent",
+            "--referer",
+            "auto",
+            "--dump-headers",
+            "headers.txt",
+            "-O",
+            "file.bin",
+            &url,
+        ],
+    );
+    assert!(output.status.success(), "{:?}", output);
+
+    let requests = server.requests();
+    let get = requests
+        .iter()
+        .find(|request| request.method == "GET")
+        .unwrap();
+    let user_agent = get.header("user-agent").unwrap();
+    let referer = get.header("referer").unwrap();
+    assert_eq!(referer, format!("{}/", server.url));
+
+    let dump = std::fs::read_to_string(dir.join("headers.txt")).unwrap();
+    assert!(dump.contains("> GET /file.bin"), "{}", dump);
+    assert!(
+        dump.contains(&format!("> user-agent: {}\n", user_agent)),
+        "{}",
+        dump
+    );
+    assert!(!dump.contains("Grab/2.0"), "{}", dump);
+    assert!(
+        dump.contains(&format!("> referer: {}\n", referer)),
+        "{}",
+        dump
+    );
+}
+
+#[test]
+fn dump_names_the_url_a_redirect_ended_at() {
+    let body = pattern(10_000);
+    let server = serve_with(move |request, stream| {
+        if request.path == "/old.bin" {
+            let _ = stream.write_all(
+                b"HTTP/1.1 302 Found\r\nLocation: /file.bin\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+            );
+        } else {
+            respond(stream, request, &body);
+        }
+    });
+    let dir = scratch_dir("header-dump-redirect");
+
+    let url = format!("{}/old.bin", server.url);
+    let output = grab(
+        &dir,
+        &["--dump-headers", "headers.txt", "-O", "file.bin", &url],
+    );
+    assert!(output.status.success(), "{:?}", output);
+
+    let dump = std::fs::read_to_string(dir.join("headers.txt")).unwrap();
+    assert!(
+        dump.contains(&format!(
+            "(after redirects, final URL {}/file.bin)",
+            server.url
+        )),
+        "{}",
+        dump
+    );
+}
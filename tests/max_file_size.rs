@@ -0,0 +1,60 @@
+//! `--max-file-size`, before and during the transfer.
+
+mod common;
+
+use common::{grab, pattern, scratch_dir, serve, serve_with};
+use std::io::Write;
+
+#[test]
+fn known_size_over_the_limit_is_not_fetched() {
+    let server = serve(pattern(100_000));
+    let dir = scratch_dir("too-large-known");
+
+    let url = format!("{}/file.bin", server.url);
+    let output = grab(&dir, &["--max-file-size", "50000", "-O", "file.bin", &url]);
+    assert_eq!(output.status.code(), Some(1), "{:?}", output);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("File too large: 100000 bytes, over --max-file-size 50000"),
+        "{}",
+        stderr
+    );
+    // Turned down from the HEAD alone
+    assert!(
+        server
+            .requests()
+            .iter()
+            .all(|request| request.method == "HEAD"),
+        "{:?}",
+        server.requests()
+    );
+    assert!(!dir.join("file.bin").exists());
+    assert!(!dir.join("file.bin.part").exists());
+}
+
+#[test]
+fn unknown_size_is_stopped_once_over_the_limit() {
+    let body = pattern(1_000_000);
+    // No Content-Length and no ranges: the size only shows as it arrives
+    let server = serve_with(move |request, stream| {
+        let _ = stream.write_all(b"HTTP/1.1 200 OK\r\nConnection: close\r\n\r\n");
+        if request.method == "GET" {
+            for chunk in body.chunks(10_000) {
+                if stream.write_all(chunk).is_err() {
+                    break;
+                }
+            }
+        }
+    });
+    let dir = scratch_dir("too-large-streamed");
+
+    let url = format!("{}/file.bin", server.url);
+    let output = grab(&dir, &["--max-file-size", "50000", "-O", "file.bin", &url]);
+    assert_eq!(output.status.code(), Some(1), "{:?}", output);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("File too large: "), "{}", stderr);
+    assert!(stderr.contains("over --max-file-size 50000"), "{}", stderr);
+    // Nothing is kept to resume from
+    assert!(!dir.join("file.bin").exists());
+    assert!(!dir.join("file.bin.part").exists());
+}
@@ -0,0 +1,88 @@
+//! `--method` and `--data` for endpoints that only serve a file to a POST.
+
+mod common;
+
+use common::{grab, pattern, respond, scratch_dir, serve_with};
+use std::io::Write;
+
+const QUERY: &[u8] = b"{\"report\":42}";
+
+fn post_only_server(body: Vec<u8>) -> common::Server {
+    serve_with(move |request, stream| {
+        if request.method == "POST" && request.body == QUERY {
+            respond(stream, request, &body);
+        } else {
+            let _ = stream.write_all(
+                b"HTTP/1.1 405 Method Not Allowed\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+            );
+        }
+    })
+}
+
+#[test]
+fn post_with_data_fetches_the_file() {
+    let body = pattern(20_000);
+    let server = post_only_server(body.clone());
+    let dir = scratch_dir("post-data");
+
+    let url = format!("{}/report", server.url);
+    let query = String::from_utf8(QUERY.to_vec()).unwrap();
+    let output = grab(
+        &dir,
+        &[
+            "--method",
+            "POST",
+            "--data",
+            &query,
+            "-O",
+            "report.bin",
+            &url,
+        ],
+    );
+    assert!(output.status.success(), "{:?}", output);
+    assert_eq!(std::fs::read(dir.join("report.bin")).unwrap(), body);
+
+    // Every request was the POST itself: no HEAD, and no ranges
+    let requests = server.requests();
+    assert!(!requests.is_empty());
+    for request in requests {
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.body, QUERY);
+        assert_eq!(request.range(), None);
+    }
+}
+
+#[test]
+fn post_body_can_come_from_a_file() {
+    let body = pattern(5_000);
+    let server = post_only_server(body.clone());
+    let dir = scratch_dir("post-data-file");
+    std::fs::write(dir.join("query.json"), QUERY).unwrap();
+
+    let url = format!("{}/report", server.url);
+    let output = grab(
+        &dir,
+        &[
+            "--method",
+            "POST",
+            "--data-file",
+            "query.json",
+            "-O",
+            "report.bin",
+            &url,
+        ],
+    );
+    assert!(output.status.success(), "{:?}", output);
+    assert_eq!(std::fs::read(dir.join("report.bin")).unwrap(), body);
+}
+
+#[test]
+fn get_is_refused_by_a_post_only_endpoint() {
+    let server = post_only_server(pattern(100));
+    let dir = scratch_dir("post-only-get");
+
+    let url = format!("{}/report", server.url);
+    let output = grab(&dir, &["-O", "report.bin", &url]);
+    assert!(!output.status.success());
+    assert!(!dir.join("report.bin").exists());
+}
@@ -0,0 +1,26 @@
+//! What grab writes when its output isn't a terminal.
+
+mod common;
+
+use common::{grab_command, pattern, scratch_dir, serve};
+
+#[test]
+fn piped_output_has_no_carriage_returns() {
+    let body = pattern(200_000);
+    let server = serve(body.clone());
+    let dir = scratch_dir("piped-output");
+
+    let url = format!("{}/file.bin", server.url);
+    // Logging on, so there's something on stderr to check
+    let output = grab_command(&dir, &["-t", "4", "-s", "50000", "-O", "file.bin", &url])
+        .env("RUST_LOG", "grab=info")
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{:?}", output);
+    assert_eq!(std::fs::read(dir.join("file.bin")).unwrap(), body);
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("connections"), "{}", stderr);
+    assert!(!stderr.contains('\r'), "{:?}", stderr);
+    assert!(!output.stdout.contains(&b'\r'));
+}
@@ -0,0 +1,132 @@
+//! `--resume` against a `.part` file that no longer matches the remote file.
+
+mod common;
+
+use common::{grab, pattern, respond_chunked, scratch_dir, serve, serve_with};
+use sha2::{Digest, Sha256};
+
+#[test]
+fn shrunk_part_file_fetches_the_missing_tail() {
+    let body = pattern(300_000);
+    let server = serve(body.clone());
+    let dir = scratch_dir("shrunk-part");
+    // As if a copy across filesystems lost everything past 100 000 bytes
+    std::fs::write(dir.join("file.bin.part"), &body[..100_000]).unwrap();
+
+    let url = format!("{}/file.bin", server.url);
+    let output = grab(&dir, &["--resume", "-O", "file.bin", &url]);
+    assert!(output.status.success(), "{:?}", output);
+
+    assert_eq!(std::fs::read(dir.join("file.bin")).unwrap(), body);
+    assert!(!dir.join("file.bin.part").exists());
+    let ranges: Vec<_> = server
+        .requests()
+        .iter()
+        .filter(|request| request.method == "GET")
+        .filter_map(|request| request.range())
+        .collect();
+    assert!(ranges.contains(&(100_000, None)), "{:?}", ranges);
+}
+
+#[test]
+fn overlong_part_file_is_cut_to_the_remote_size() {
+    let body = pattern(50_000);
+    let server = serve(body.clone());
+    let dir = scratch_dir("overlong-part");
+    let mut part = body.clone();
+    part.extend_from_slice(b"stale tail");
+    std::fs::write(dir.join("file.bin.part"), &part).unwrap();
+
+    let url = format!("{}/file.bin", server.url);
+    let output = grab(&dir, &["--resume", "-O", "file.bin", &url]);
+    assert!(output.status.success(), "{:?}", output);
+
+    assert_eq!(std::fs::read(dir.join("file.bin")).unwrap(), body);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("longer than the remote file"), "{}", stderr);
+}
+
+#[test]
+fn resume_of_unknown_size_checks_the_whole_file() {
+    // With no size, the continued transfer isn't hashed as it streams, so
+    // the checksum is taken from the finished file
+    let body = pattern(40_000);
+    let checksum = format!("sha256:{}", hex::encode(Sha256::digest(&body)));
+    let served = body.clone();
+    let server = serve_with(move |request, stream| respond_chunked(stream, request, &served));
+    let dir = scratch_dir("unknown-size-resume");
+    std::fs::write(dir.join("file.bin.part"), &body[..15_000]).unwrap();
+
+    let url = format!("{}/file.bin", server.url);
+    let output = grab(&dir, &["--resume", "-O", "file.bin", &url, &checksum]);
+    assert!(output.status.success(), "{:?}", output);
+
+    assert_eq!(std::fs::read(dir.join("file.bin")).unwrap(), body);
+    let ranges: Vec<_> = server
+        .requests()
+        .iter()
+        .filter(|request| request.method == "GET")
+        .filter_map(|request| request.range())
+        .collect();
+    assert!(ranges.contains(&(15_000, None)), "{:?}", ranges);
+}
+
+#[test]
+fn empty_and_one_byte_files_download() {
+    for body in [Vec::new(), vec![42]] {
+        let server = serve(body.clone());
+        let dir = scratch_dir(&format!("tiny-{}", body.len()));
+
+        let url = format!("{}/file.bin", server.url);
+        let output = grab(&dir, &["-O", "file.bin", &url]);
+        assert!(output.status.success(), "{:?}", output);
+        assert_eq!(std::fs::read(dir.join("file.bin")).unwrap(), body);
+        assert!(!dir.join("file.bin.part").exists());
+    }
+}
+
+#[test]
+fn part_file_at_the_end_needs_no_request() {
+    for body in [vec![42], pattern(100_000)] {
+        let server = serve(body.clone());
+        let dir = scratch_dir(&format!("complete-part-{}", body.len()));
+        std::fs::write(dir.join("file.bin.part"), &body).unwrap();
+
+        let url = format!("{}/file.bin", server.url);
+        let output = grab(&dir, &["--resume", "-O", "file.bin", &url]);
+        assert!(output.status.success(), "{:?}", output);
+        assert_eq!(std::fs::read(dir.join("file.bin")).unwrap(), body);
+        // `bytes=<size>-` would only have got a 416
+        assert!(
+            server
+                .requests()
+                .iter()
+                .all(|request| request.method == "HEAD"),
+            "{:?}",
+            server.requests()
+        );
+    }
+}
+
+#[test]
+fn part_file_at_a_chunk_boundary_continues_from_it() {
+    let body = pattern(200_000);
+    let server = serve(body.clone());
+    let dir = scratch_dir("boundary-part");
+    std::fs::write(dir.join("file.bin.part"), &body[..100_000]).unwrap();
+
+    let url = format!("{}/file.bin", server.url);
+    let output = grab(
+        &dir,
+        &["--resume", "-t", "4", "-s", "50000", "-O", "file.bin", &url],
+    );
+    assert!(output.status.success(), "{:?}", output);
+    assert_eq!(std::fs::read(dir.join("file.bin")).unwrap(), body);
+    let gets: Vec<_> = server
+        .requests()
+        .into_iter()
+        .filter(|request| request.method == "GET")
+        .collect();
+    assert_eq!(gets.len(), 1, "{:?}", gets);
+    assert_eq!(gets[0].range(), Some((100_000, None)));
+}
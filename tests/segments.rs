@@ -0,0 +1,40 @@
+//! Multi-connection downloads split into segments.
+
+mod common;
+
+use common::{grab, pattern, scratch_dir, serve};
+
+#[test]
+fn interleaved_segments_assemble_the_file() {
+    let body = pattern(100_000);
+    let server = serve(body.clone());
+    let dir = scratch_dir("interleave");
+
+    let url = format!("{}/file.bin", server.url);
+    let output = grab(
+        &dir,
+        &[
+            "--interleave",
+            "-t",
+            "3",
+            "-s",
+            "10000",
+            "-O",
+            "file.bin",
+            &url,
+        ],
+    );
+    assert!(output.status.success(), "{:?}", output);
+
+    assert_eq!(std::fs::read(dir.join("file.bin")).unwrap(), body);
+    let mut starts: Vec<_> = server
+        .requests()
+        .iter()
+        .filter(|request| request.method == "GET")
+        .filter_map(|request| request.range())
+        .filter(|&(_, end)| end != Some(0))
+        .map(|(start, _)| start)
+        .collect();
+    starts.sort();
+    assert_eq!(starts, (0..10).map(|k| k * 10_000).collect::<Vec<_>>());
+}
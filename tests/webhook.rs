@@ -0,0 +1,48 @@
+//! `--webhook` reports to its own host, without the download's headers.
+
+mod common;
+
+use common::{grab, pattern, respond, scratch_dir, serve_with};
+
+#[test]
+fn webhook_gets_none_of_the_download_headers() {
+    let body = pattern(10_000);
+    let served = body.clone();
+    let server = serve_with(move |request, stream| respond(stream, request, &served));
+    let dir = scratch_dir("webhook-headers");
+
+    let url = format!("{}/file.bin", server.url);
+    let hook = format!("{}/hook", server.url);
+    let output = grab(
+        &dir,
+        &[
+            "-H",
+            "Authorization: Bearer secret",
+            "-H",
+            "X-Api-Key: key",
+            "--webhook",
+            &hook,
+            "-O",
+            "file.bin",
+            &url,
+        ],
+    );
+    assert!(output.status.success(), "{:?}", output);
+    assert_eq!(std::fs::read(dir.join("file.bin")).unwrap(), body);
+
+    let requests = server.requests();
+    let download = requests
+        .iter()
+        .find(|request| request.path == "/file.bin" && request.method == "GET")
+        .unwrap();
+    assert_eq!(download.header("authorization"), Some("Bearer secret"));
+    let webhook = requests
+        .iter()
+        .find(|request| request.path == "/hook")
+        .expect("webhook was not called");
+    assert_eq!(webhook.method, "POST");
+    assert_eq!(webhook.header("authorization"), None);
+    assert_eq!(webhook.header("x-api-key"), None);
+    let summary: serde_json::Value = serde_json::from_slice(&webhook.body).unwrap();
+    assert_eq!(summary["status"], "success");
+}